@@ -0,0 +1,282 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::__macro_helpers::extract_event_name_payload;
+
+/// Context of the command being handled, provided by the runtime.
+///
+/// Aggregates must be deterministic, so rather than reading the clock or
+/// generating random values through WASI, they should use the timestamp and
+/// random number generator of the context. The runtime records the context
+/// each command was handled with, so replaying a command produces the same
+/// events.
+///
+/// Commands can be sent to other aggregates with [`Context::send_command`],
+/// or scheduled for later with [`Context::schedule_command`].
+///
+/// # Example
+///
+/// ```
+/// use thalo::{events, Context, Handle};
+/// # use thalo::Aggregate;
+/// #
+/// # pub struct Todos;
+/// #
+/// # impl Aggregate for Todos {
+/// #     type Command = AddTodo;
+/// #     type Event = TodosEvent;
+/// #
+/// #     fn init(_id: String) -> Self {
+/// #         Todos
+/// #     }
+/// # }
+/// #
+/// # pub enum TodosEvent {
+/// #     AddedTodo(AddedTodo),
+/// # }
+/// #
+/// # impl From<AddedTodo> for TodosEvent {
+/// #     fn from(event: AddedTodo) -> Self {
+/// #         TodosEvent::AddedTodo(event)
+/// #     }
+/// # }
+/// #
+/// # pub struct AddedTodo {
+/// #     id: String,
+/// #     description: String,
+/// #     created_at: u64,
+/// # }
+///
+/// pub struct AddTodo {
+///     pub description: String,
+/// }
+///
+/// impl Handle<AddTodo> for Todos {
+///     type Error = &'static str;
+///
+///     fn handle(&self, cmd: AddTodo, ctx: &mut Context) -> Result<Vec<TodosEvent>, Self::Error> {
+///         events![AddedTodo {
+///             id: ctx.uuid(),
+///             description: cmd.description,
+///             created_at: ctx.timestamp(),
+///         }]
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Context {
+    timestamp: u64,
+    version: Option<u64>,
+    metadata: Map<String, Value>,
+    rng: u64,
+    commands: Vec<OutboundCommand>,
+}
+
+/// A command sent to another aggregate with [`Context::send_command`] or
+/// [`Context::schedule_command`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutboundCommand {
+    /// Name of the aggregate the command is sent to.
+    pub name: String,
+    /// ID of the entity the command is sent to.
+    pub id: String,
+    pub command: String,
+    pub payload: Value,
+    /// Schedule of the command, or `None` if it's delivered straight away.
+    pub schedule: Option<CommandSchedule>,
+}
+
+/// When a command sent with [`Context::schedule_command`] is delivered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommandSchedule {
+    /// Key the command is scheduled with.
+    pub key: String,
+    /// Unix timestamp in milliseconds of when the command is due.
+    pub due: u64,
+}
+
+impl Context {
+    /// Creates a context, seeding its random number generator with `seed`.
+    ///
+    /// Contexts are created by the runtime, but can be created manually for
+    /// testing aggregates.
+    pub fn new(
+        timestamp: u64,
+        seed: u64,
+        version: Option<u64>,
+        metadata: Map<String, Value>,
+    ) -> Self {
+        Context {
+            timestamp,
+            version,
+            metadata,
+            rng: seed,
+            commands: Vec::new(),
+        }
+    }
+
+    /// Unix timestamp in milliseconds of when the command was received.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Time the command was received, as a [`SystemTime`].
+    pub fn now(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.timestamp)
+    }
+
+    /// Position of the entity's last event, or `None` if the entity has no
+    /// events.
+    pub fn version(&self) -> Option<u64> {
+        self.version
+    }
+
+    /// Correlation metadata the command was executed with.
+    pub fn metadata(&self) -> &Map<String, Value> {
+        &self.metadata
+    }
+
+    /// The `correlation_id` of the command's metadata, if it's a string.
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.metadata.get("correlation_id")?.as_str()
+    }
+
+    /// Sends a command to the entity `id` of the aggregate `name`.
+    ///
+    /// The command is persisted along with the events returned by the
+    /// handler, and dispatched by the runtime afterwards. Commands are
+    /// delivered at least once, so the receiving aggregate should handle
+    /// duplicates. If the handler returns an error, no commands are sent.
+    ///
+    /// The command must serialize to an object with the command name as its
+    /// only key, such as a variant of the receiving aggregate's command enum.
+    pub fn send_command(
+        &mut self,
+        name: impl Into<String>,
+        id: impl Into<String>,
+        command: impl Serialize,
+    ) -> Result<(), serde_json::Error> {
+        self.push_command(name.into(), id.into(), command, None)
+    }
+
+    /// Schedules a command to be sent to the entity `id` of the aggregate
+    /// `name` once `delay` has passed since the command being handled was
+    /// received.
+    ///
+    /// Scheduled commands are persisted by the runtime until they're due, and
+    /// scheduling a command with the same `key` as a pending command scheduled
+    /// by the same entity replaces it. Keys of other entities are unaffected.
+    /// Pending commands can be cancelled by the entity's stream name and their
+    /// key through the runtime's API. The state of the receiving aggregate may have changed by
+    /// the time the command is delivered, so it should check the command
+    /// still applies, such as a payment deadline which has since been met.
+    ///
+    /// Otherwise, scheduled commands behave like those sent with
+    /// [`Context::send_command`], and are handled with the time they were due
+    /// as their [`timestamp`](Context::timestamp).
+    pub fn schedule_command(
+        &mut self,
+        key: impl Into<String>,
+        delay: Duration,
+        name: impl Into<String>,
+        id: impl Into<String>,
+        command: impl Serialize,
+    ) -> Result<(), serde_json::Error> {
+        let due = self.timestamp.saturating_add(delay.as_millis() as u64);
+        let schedule = CommandSchedule {
+            key: key.into(),
+            due,
+        };
+        self.push_command(name.into(), id.into(), command, Some(schedule))
+    }
+
+    /// Schedules a command to be sent at a time.
+    ///
+    /// See [`Context::schedule_command`].
+    pub fn schedule_command_at(
+        &mut self,
+        key: impl Into<String>,
+        at: SystemTime,
+        name: impl Into<String>,
+        id: impl Into<String>,
+        command: impl Serialize,
+    ) -> Result<(), serde_json::Error> {
+        let due = at
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or(0);
+        let schedule = CommandSchedule {
+            key: key.into(),
+            due,
+        };
+        self.push_command(name.into(), id.into(), command, Some(schedule))
+    }
+
+    /// Commands sent with [`Context::send_command`] and
+    /// [`Context::schedule_command`].
+    pub fn commands(&self) -> &[OutboundCommand] {
+        &self.commands
+    }
+
+    #[doc(hidden)]
+    pub fn into_commands(self) -> Vec<OutboundCommand> {
+        self.commands
+    }
+
+    fn push_command(
+        &mut self,
+        name: String,
+        id: String,
+        command: impl Serialize,
+        schedule: Option<CommandSchedule>,
+    ) -> Result<(), serde_json::Error> {
+        let value = serde_json::to_value(command)?;
+        let (command, payload) =
+            extract_event_name_payload(value).map_err(serde::ser::Error::custom)?;
+        self.commands.push(OutboundCommand {
+            name,
+            id,
+            command,
+            payload,
+            schedule,
+        });
+
+        Ok(())
+    }
+
+    /// Generates a random `u64`.
+    ///
+    /// Values are generated with [SplitMix64], and are not suitable for
+    /// cryptographic purposes.
+    ///
+    /// [SplitMix64]: https://prng.di.unimi.it/splitmix64.c
+    pub fn random_u64(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Generates a random version 4 UUID, formatted as a hyphenated lowercase
+    /// string.
+    pub fn uuid(&mut self) -> String {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&self.random_u64().to_be_bytes());
+        bytes[8..].copy_from_slice(&self.random_u64().to_be_bytes());
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+        let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+}
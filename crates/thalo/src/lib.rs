@@ -17,7 +17,7 @@
 //! # use std::convert::Infallible;
 //! #
 //! use serde::{Deserialize, Serialize};
-//! use thalo::{events, export_aggregate, Aggregate, Apply, Command, Event, Handle};
+//! use thalo::{events, export_aggregate, Aggregate, Apply, Command, Context, Event, Handle};
 //!
 //! export_aggregate!(Counter);
 //!
@@ -42,7 +42,7 @@
 //! impl Handle<CounterCommand> for Counter {
 //!     type Error = Infallible;
 //!
-//!     fn handle(&self, cmd: CounterCommand) -> Result<Vec<CounterEvent>, Self::Error> {
+//!     fn handle(&self, cmd: CounterCommand, _ctx: &mut Context) -> Result<Vec<CounterEvent>, Self::Error> {
 //!         match cmd {
 //!             CounterCommand::Increment { amount } => events![Incremented { amount }],
 //!         }
@@ -68,8 +68,12 @@
 
 #[macro_use]
 mod macros;
+mod context;
+mod process_manager;
 pub mod stream_name;
 
+pub use context::{CommandSchedule, Context, OutboundCommand};
+pub use process_manager::{EventInterest, ProcessManager};
 pub use thalo_derive::*;
 /// Re-exports of [tracing](::tracing) macros.
 pub mod tracing {
@@ -113,10 +117,14 @@ pub trait Aggregate {
 /// Commands use the aggregates state to validate business rules, and returns
 /// events which are later used to update the aggregate state.
 ///
+/// The [`Context`] provides the command's timestamp and a random number
+/// generator, which must be used instead of the system clock or randomness so
+/// that handling the command is deterministic.
+///
 /// # Example
 ///
 /// ```
-/// use thalo::{events, Handle};
+/// use thalo::{events, Context, Handle};
 ///
 /// pub struct Increment {
 ///     pub amount: u64,
@@ -125,7 +133,7 @@ pub trait Aggregate {
 /// impl Handle<Increment> for Counter {
 ///     type Error = &'static str;
 ///
-///     fn handle(&self, cmd: Increment) -> Result<Vec<CounterEvent>, Self::Error> {
+///     fn handle(&self, cmd: Increment, _ctx: &mut Context) -> Result<Vec<CounterEvent>, Self::Error> {
 ///         if self.count + cmd.amount > 100_000 {
 ///             return Err("count would be too high");
 ///         }
@@ -137,7 +145,7 @@ pub trait Aggregate {
 pub trait Handle<C>: Aggregate {
     type Error;
 
-    fn handle(&self, cmd: C) -> Result<Vec<Self::Event>, Self::Error>;
+    fn handle(&self, cmd: C, ctx: &mut Context) -> Result<Vec<Self::Event>, Self::Error>;
 }
 
 /// Applies an event, updating the aggregate state.
@@ -180,9 +188,35 @@ where
 
 #[doc(hidden)]
 pub mod __macro_helpers {
+    use serde::Serialize;
     use serde_json::Value;
     pub use {serde_json, tracing, tracing_tunnel, wit_bindgen};
 
+    /// Serializes an aggregate's state to json, if it implements [`Serialize`].
+    ///
+    /// Called as `(&StateSerializer(&state)).serialize_state()`, which resolves
+    /// to [`SerializeState`] if the state is serializable, and falls back to
+    /// [`SerializeStateFallback`] otherwise.
+    pub struct StateSerializer<'a, T>(pub &'a T);
+
+    pub trait SerializeState {
+        fn serialize_state(&self) -> Option<Result<String, serde_json::Error>>;
+    }
+
+    impl<T: Serialize> SerializeState for StateSerializer<'_, T> {
+        fn serialize_state(&self) -> Option<Result<String, serde_json::Error>> {
+            Some(serde_json::to_string(self.0))
+        }
+    }
+
+    pub trait SerializeStateFallback {
+        fn serialize_state(&self) -> Option<Result<String, serde_json::Error>> {
+            None
+        }
+    }
+
+    impl<T> SerializeStateFallback for &StateSerializer<'_, T> {}
+
     /// Extracts the event name and payload from an event json value.
     /// `{"EventName": {"foo": 1}}` returns `("EventName", {"foo": 1})`.
     pub fn extract_event_name_payload(value: Value) -> Result<(String, Value), &'static str> {
@@ -20,49 +20,89 @@ macro_rules! export_aggregate {
 
             $crate::__macro_helpers::wit_bindgen::generate!({
                 inline: r#"
-                    package thalo:aggregate;
+                    package thalo:aggregate@0.2.0;
 
                     interface tracing {
                         send-event: func(event: list<u8>);
                     }
 
-                    world aggregate {
-                        import tracing;
+                    interface entities {
+                        record event {
+                            event: string,
+                            payload: string,
+                        }
 
-                        export aggregate: interface {
-                            record event {
-                                event: string,
-                                payload: string,
-                            }
+                        record command {
+                            command: string,
+                            payload: string,
+                        }
 
-                            record command {
-                                command: string,
-                                payload: string,
-                            }
+                        /// When a scheduled command is delivered.
+                        record schedule {
+                            /// Key the command is scheduled with, replacing any command the entity scheduled with the same key.
+                            key: string,
+                            /// Unix timestamp in milliseconds of when the command is due.
+                            due: u64,
+                        }
 
-                            variant error {
-                                command(tuple<string, string>),
-                                deserialize-command(tuple<string, string>),
-                                deserialize-context(string),
-                                deserialize-event(tuple<string, string>),
-                                serialize-error(tuple<string, string>),
-                                serialize-event(string),
-                            }
+                        record outbound-command {
+                            /// Name of the aggregate the command is sent to.
+                            name: string,
+                            id: string,
+                            command: string,
+                            payload: string,
+                            /// Schedule of the command, if it's delivered later rather than straight away.
+                            schedule: option<schedule>,
+                        }
 
-                            resource entity {
-                                constructor(id: string);
-                                apply: func(events: list<event>) -> result<_, error>;
-                                handle: func(command: command) -> result<list<event>, error>;
-                            }
+                        record handled {
+                            events: list<event>,
+                            /// Commands sent to other aggregates.
+                            commands: list<outbound-command>,
+                        }
+
+                        record context {
+                            /// Unix timestamp in milliseconds of when the command was received.
+                            timestamp: u64,
+                            /// Seed for generating random values.
+                            seed: u64,
+                            /// Position of the entity's last event, or none if the entity has no events.
+                            version: option<u64>,
+                            /// Correlation metadata of the command as a json object.
+                            metadata: string,
+                        }
+
+                        variant error {
+                            command(tuple<string, string>),
+                            deserialize-command(tuple<string, string>),
+                            deserialize-context(string),
+                            deserialize-event(tuple<string, string>),
+                            serialize-error(tuple<string, string>),
+                            serialize-event(string),
+                            serialize-state(string),
+                        }
+
+                        resource entity {
+                            constructor(id: string);
+                            apply: func(events: list<event>) -> result<_, error>;
+                            handle: func(command: command, ctx: context) -> result<handled, error>;
+                            /// Returns the entity's state as json, or none if the state is not serializable.
+                            state: func() -> result<option<string>, error>;
                         }
                     }
+
+                    world aggregate {
+                        import tracing;
+
+                        export entities;
+                    }
                 "#,
                 exports: {
-                    "aggregate/entity": AggWrapper
+                    "thalo:aggregate/entities/entity": AggWrapper
                 }
             });
 
-            use exports::aggregate as wit;
+            use exports::thalo::aggregate::entities as wit;
 
             pub struct AggWrapper(RefCell<$crate::State<Agg>>);
 
@@ -87,11 +127,19 @@ macro_rules! export_aggregate {
                     })
                 }
 
-                fn handle(&self, command: wit::Command) -> Result<Vec<wit::Event>, wit::Error> {
+                fn handle(&self, command: wit::Command, ctx: wit::Context) -> Result<wit::Handled, wit::Error> {
                     with_subscriber(|| {
-                        handle_aggregate_command(self, command)
+                        handle_aggregate_command(self, command, ctx)
                     })
                 }
+
+                fn state(&self) -> Result<Option<String>, wit::Error> {
+                    let state = self.0.borrow();
+                    (&StateSerializer(&state.0))
+                        .serialize_state()
+                        .transpose()
+                        .map_err(|err| wit::Error::SerializeState(err.to_string()))
+                }
             }
 
             fn init_aggregate(id: String) -> AggWrapper {
@@ -136,8 +184,21 @@ macro_rules! export_aggregate {
                     command,
                     payload,
                 }: wit::Command,
-            ) -> Result<Vec<wit::Event>, wit::Error> {
+                wit::Context {
+                    timestamp,
+                    seed,
+                    version,
+                    metadata,
+                }: wit::Context,
+            ) -> Result<wit::Handled, wit::Error> {
                 let state = state.borrow();
+                let metadata: serde_json::Map<String, serde_json::Value> = match serde_json::from_str(&metadata) {
+                    Ok(metadata) => metadata,
+                    Err(err) => {
+                        return Err(wit::Error::DeserializeContext(err.to_string()));
+                    }
+                };
+                let mut ctx = $crate::Context::new(timestamp, seed, version, metadata);
                 let payload: serde_json::Value = match serde_json::from_str(&payload) {
                     Ok(payload) => payload,
                     Err(err) => {
@@ -154,7 +215,7 @@ macro_rules! export_aggregate {
                         return Err(wit::Error::DeserializeCommand((command, err.to_string())));
                     }
                 };
-                let events = <$crate::State<Agg> as $crate::Handle<<$crate::State<Agg> as $crate::Aggregate>::Command>>::handle(&state, cmd)
+                let events = <$crate::State<Agg> as $crate::Handle<<$crate::State<Agg> as $crate::Aggregate>::Command>>::handle(&state, cmd, &mut ctx)
                     .map_err(|err|
                         match serde_json::to_string(&err) {
                             Ok(err) => wit::Error::Command((command, err)),
@@ -176,7 +237,19 @@ macro_rules! export_aggregate {
                     })
                     .collect::<Result<Vec<_>, _>>()?;
 
-                Ok(events)
+                let commands = ctx
+                    .into_commands()
+                    .into_iter()
+                    .map(|$crate::OutboundCommand { name, id, command, payload, schedule }| wit::OutboundCommand {
+                        name,
+                        id,
+                        command,
+                        payload: payload.to_string(),
+                        schedule: schedule.map(|$crate::CommandSchedule { key, due }| wit::Schedule { key, due }),
+                    })
+                    .collect();
+
+                Ok(wit::Handled { events, commands })
             }
 
             #[derive(serde::Deserialize)]
@@ -188,6 +261,303 @@ macro_rules! export_aggregate {
     };
 }
 
+/// Exports a process manager for use with the thalo runtime.
+///
+/// The type must implement [`ProcessManager`](crate::ProcessManager), and
+/// handle and apply events like an aggregate.
+///
+/// # Example
+///
+/// ```ignore
+/// export_process_manager!(WelcomeBonus);
+///
+/// pub struct WelcomeBonus {}
+/// impl Aggregate for WelcomeBonus { /* ... */ }
+/// impl ProcessManager for WelcomeBonus { /* ... */ }
+/// ```
+#[macro_export]
+macro_rules! export_process_manager {
+    ($t: ident) => {
+        mod __process_manager_export {
+            use std::cell::RefCell;
+
+            use $crate::__macro_helpers::*;
+
+            pub type Pm = super::$t;
+
+            $crate::__macro_helpers::wit_bindgen::generate!({
+                inline: r#"
+                    package thalo:process-manager@0.2.0;
+
+                    interface tracing {
+                        send-event: func(event: list<u8>);
+                    }
+
+                    world process-manager {
+                        import tracing;
+
+                        export process-manager: interface {
+                            record event-interest {
+                                /// Category of the event, or none for events of any category.
+                                category: option<string>,
+                                event: string,
+                            }
+
+                            /// An event of another aggregate.
+                            record message {
+                                stream-name: string,
+                                global-id: u64,
+                                event: string,
+                                payload: string,
+                                /// Correlation metadata of the event as a json object.
+                                metadata: string,
+                            }
+
+                            /// An event of the process manager's own state.
+                            record event {
+                                event: string,
+                                payload: string,
+                            }
+
+                            /// When a scheduled command is delivered.
+                            record schedule {
+                                /// Key the command is scheduled with, replacing any command the entity scheduled with the same key.
+                                key: string,
+                                /// Unix timestamp in milliseconds of when the command is due.
+                                due: u64,
+                            }
+
+                            record outbound-command {
+                                /// Name of the aggregate the command is sent to.
+                                name: string,
+                                id: string,
+                                command: string,
+                                payload: string,
+                                /// Schedule of the command, if it's delivered later rather than straight away.
+                                schedule: option<schedule>,
+                            }
+
+                            record handled {
+                                events: list<event>,
+                                /// Commands sent to aggregates.
+                                commands: list<outbound-command>,
+                            }
+
+                            record context {
+                                /// Unix timestamp in milliseconds of when the message was written.
+                                timestamp: u64,
+                                /// Seed for generating random values.
+                                seed: u64,
+                                /// Position of the process's last event, or none if the process has no events.
+                                version: option<u64>,
+                                /// Correlation metadata of the process as a json object.
+                                metadata: string,
+                            }
+
+                            variant error {
+                                handle(tuple<string, string>),
+                                deserialize-context(string),
+                                deserialize-event(tuple<string, string>),
+                                deserialize-message(tuple<string, string>),
+                                serialize-error(tuple<string, string>),
+                                serialize-event(string),
+                            }
+
+                            /// Events the process manager handles.
+                            interests: func() -> list<event-interest>;
+                            /// Returns the correlation ID of the process which handles a message, or none if the message is ignored.
+                            correlate: func(message: message) -> result<option<string>, error>;
+
+                            resource process {
+                                constructor(correlation-id: string);
+                                apply: func(events: list<event>) -> result<_, error>;
+                                handle: func(message: message, ctx: context) -> result<handled, error>;
+                            }
+                        }
+                    }
+                "#,
+                exports: {
+                    "process-manager": PmExport,
+                    "process-manager/process": ProcessWrapper
+                }
+            });
+
+            use exports::process_manager as wit;
+
+            pub struct PmExport;
+
+            pub struct ProcessWrapper(RefCell<$crate::State<Pm>>);
+
+            fn with_subscriber<F: FnOnce() -> T, T>(f: F) -> T {
+                let subscriber = tracing_tunnel::TracingEventSender::new(|event| {
+                    let event_bytes = serde_json::to_vec(&event).unwrap();
+                    thalo::process_manager::tracing::send_event(&event_bytes);
+                });
+                tracing::subscriber::with_default(subscriber, f)
+            }
+
+            impl wit::Guest for PmExport {
+                fn interests() -> Vec<wit::EventInterest> {
+                    <Pm as $crate::ProcessManager>::interests()
+                        .into_iter()
+                        .map(|$crate::EventInterest { category, event }| wit::EventInterest {
+                            category,
+                            event,
+                        })
+                        .collect()
+                }
+
+                fn correlate(message: wit::Message) -> Result<Option<String>, wit::Error> {
+                    with_subscriber(|| {
+                        correlate_message(message)
+                    })
+                }
+            }
+
+            impl wit::GuestProcess for ProcessWrapper {
+                fn new(correlation_id: String) -> Self {
+                    with_subscriber(|| {
+                        ProcessWrapper(RefCell::new($crate::State(<Pm as $crate::Aggregate>::init(correlation_id))))
+                    })
+                }
+
+                fn apply(&self, events: Vec<wit::Event>) -> Result<(), wit::Error> {
+                    with_subscriber(|| {
+                        apply_process_events(self, events)
+                    })
+                }
+
+                fn handle(&self, message: wit::Message, ctx: wit::Context) -> Result<wit::Handled, wit::Error> {
+                    with_subscriber(|| {
+                        handle_process_message(self, message, ctx)
+                    })
+                }
+            }
+
+            type Input = <$crate::State<Pm> as $crate::Aggregate>::Command;
+
+            fn deserialize_message(
+                event: &str,
+                payload: &str,
+            ) -> Result<Input, wit::Error> {
+                let payload: serde_json::Value = serde_json::from_str(payload)
+                    .map_err(|err| wit::Error::DeserializeMessage((event.to_string(), err.to_string())))?;
+                serde_json::from_value(serde_json::json!({ event: payload }))
+                    .map_err(|err| wit::Error::DeserializeMessage((event.to_string(), err.to_string())))
+            }
+
+            fn deserialize_metadata(
+                metadata: &str,
+            ) -> Result<serde_json::Map<String, serde_json::Value>, wit::Error> {
+                serde_json::from_str(metadata).map_err(|err| wit::Error::DeserializeContext(err.to_string()))
+            }
+
+            fn correlate_message(
+                wit::Message {
+                    stream_name,
+                    event,
+                    payload,
+                    metadata,
+                    ..
+                }: wit::Message,
+            ) -> Result<Option<String>, wit::Error> {
+                let input = deserialize_message(&event, &payload)?;
+                let metadata = deserialize_metadata(&metadata)?;
+                let stream_name = $crate::stream_name::StreamName::new(stream_name)
+                    .map_err(|err| wit::Error::DeserializeMessage((event, err.to_string())))?;
+                Ok(<Pm as $crate::ProcessManager>::correlation_id(&input, &stream_name, &metadata))
+            }
+
+            fn apply_process_events(
+                ProcessWrapper(state): &ProcessWrapper,
+                events: Vec<wit::Event>,
+            ) -> Result<(), wit::Error> {
+                let mut state = state.borrow_mut();
+                for wit::Event {
+                    event,
+                    payload,
+                } in events
+                {
+                    let payload: serde_json::Value = match serde_json::from_str(&payload) {
+                        Ok(payload) => payload,
+                        Err(err) => {
+                            return Err(wit::Error::DeserializeEvent((event, err.to_string())));
+                        }
+                    };
+                    let event_value = {
+                        let event = event.clone();
+                        serde_json::json!({ event: payload })
+                    };
+                    let event: <$crate::State<Pm> as $crate::Aggregate>::Event = match serde_json::from_value(event_value) {
+                        Ok(event) => event,
+                        Err(err) => {
+                            return Err(wit::Error::DeserializeEvent((event, err.to_string())));
+                        }
+                    };
+                    <$crate::State<Pm> as $crate::Apply<<$crate::State<Pm> as $crate::Aggregate>::Event>>::apply(&mut state, event);
+                }
+
+                Ok(())
+            }
+
+            fn handle_process_message(
+                ProcessWrapper(state): &ProcessWrapper,
+                wit::Message {
+                    event,
+                    payload,
+                    ..
+                }: wit::Message,
+                wit::Context {
+                    timestamp,
+                    seed,
+                    version,
+                    metadata,
+                }: wit::Context,
+            ) -> Result<wit::Handled, wit::Error> {
+                let state = state.borrow();
+                let metadata = deserialize_metadata(&metadata)?;
+                let mut ctx = $crate::Context::new(timestamp, seed, version, metadata);
+                let input = deserialize_message(&event, &payload)?;
+                let events = <$crate::State<Pm> as $crate::Handle<Input>>::handle(&state, input, &mut ctx)
+                    .map_err(|err|
+                        match serde_json::to_string(&err) {
+                            Ok(err) => wit::Error::Handle((event, err)),
+                            Err(err) => wit::Error::SerializeError((event, err.to_string())),
+                        }
+                    )?
+                    .into_iter()
+                    .map(|event| {
+                        let event_value = serde_json::to_value(event)
+                            .map_err(|err| wit::Error::SerializeEvent(err.to_string()))?;
+                        let (event, payload_value) = extract_event_name_payload(event_value)
+                            .map_err(|err| wit::Error::SerializeEvent(err.to_string()))?;
+                        let payload = serde_json::to_string(&payload_value)
+                            .map_err(|err| wit::Error::SerializeEvent(err.to_string()))?;
+                        Ok(wit::Event {
+                            event,
+                            payload,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let commands = ctx
+                    .into_commands()
+                    .into_iter()
+                    .map(|$crate::OutboundCommand { name, id, command, payload, schedule }| wit::OutboundCommand {
+                        name,
+                        id,
+                        command,
+                        payload: payload.to_string(),
+                        schedule: schedule.map(|$crate::CommandSchedule { key, due }| wit::Schedule { key, due }),
+                    })
+                    .collect();
+
+                Ok(wit::Handled { events, commands })
+            }
+        }
+    };
+}
+
 /// Shorthand for creating a `Ok(vec![...])` in [`Handle`](crate::Handle)
 /// implmentations.
 ///
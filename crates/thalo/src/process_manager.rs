@@ -0,0 +1,100 @@
+use serde_json::{Map, Value};
+
+use crate::stream_name::StreamName;
+use crate::Aggregate;
+
+/// A process manager, coordinating aggregates by reacting to their events with
+/// commands.
+///
+/// A process manager is an aggregate whose commands are the events of other
+/// aggregates. Its [`Command`](Aggregate::Command) type is an enum of the
+/// events it's interested in, each handled with [`Handle`](crate::Handle),
+/// and its own [`Event`](Aggregate::Event)s are applied to its state with
+/// [`Apply`](crate::Apply). Commands are sent with
+/// [`Context::send_command`](crate::Context::send_command).
+///
+/// A separate process is kept for each correlation ID, initialized with
+/// [`Aggregate::init`] and rebuilt from its own events, so each process only
+/// sees the events correlated to it. Events which return an error from
+/// [`Handle`](crate::Handle) are skipped.
+///
+/// # Example
+///
+/// ```ignore
+/// export_process_manager!(WelcomeBonus);
+///
+/// pub struct WelcomeBonus {
+///     paid: bool,
+/// }
+///
+/// impl Aggregate for WelcomeBonus {
+///     type Command = WelcomeBonusInput;
+///     type Event = WelcomeBonusEvent;
+///
+///     fn init(_correlation_id: String) -> Self {
+///         WelcomeBonus { paid: false }
+///     }
+/// }
+///
+/// impl ProcessManager for WelcomeBonus {
+///     fn interests() -> Vec<EventInterest> {
+///         vec![EventInterest::new("bank_account", "DepositedFunds")]
+///     }
+///
+///     fn correlation_id(
+///         _event: &WelcomeBonusInput,
+///         stream_name: &StreamName<'_>,
+///         _metadata: &Map<String, Value>,
+///     ) -> Option<String> {
+///         stream_name.id().map(|id| id.to_string())
+///     }
+/// }
+/// ```
+pub trait ProcessManager: Aggregate {
+    /// Events the process manager handles.
+    fn interests() -> Vec<EventInterest>;
+
+    /// Returns the correlation ID of the process which handles an event, or
+    /// `None` if the event should be ignored.
+    ///
+    /// Defaults to the `correlation_id` of the event's metadata. Commands sent
+    /// by a process are executed with its correlation ID, so the events they
+    /// produce are correlated back to the process.
+    fn correlation_id(
+        event: &Self::Command,
+        stream_name: &StreamName<'_>,
+        metadata: &Map<String, Value>,
+    ) -> Option<String> {
+        let _ = (event, stream_name);
+        metadata
+            .get("correlation_id")?
+            .as_str()
+            .map(ToOwned::to_owned)
+    }
+}
+
+/// An event a [`ProcessManager`] handles.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EventInterest {
+    /// Category of the event, or `None` for events of any category.
+    pub category: Option<String>,
+    pub event: String,
+}
+
+impl EventInterest {
+    /// Interest in an event of a category.
+    pub fn new(category: impl Into<String>, event: impl Into<String>) -> Self {
+        EventInterest {
+            category: Some(category.into()),
+            event: event.into(),
+        }
+    }
+
+    /// Interest in an event of any category.
+    pub fn any_category(event: impl Into<String>) -> Self {
+        EventInterest {
+            category: None,
+            event: event.into(),
+        }
+    }
+}
@@ -2,16 +2,40 @@
 //!
 //! Checkout the `README.md` for guidance.
 
+mod activate;
 mod build;
+mod canary;
+mod cancel;
+mod checkpoint;
 mod execute;
+mod migrate;
 mod publish;
+mod rollback;
+mod schedule;
+mod scheduled;
+mod state;
+mod stats;
+mod unquarantine;
+mod verify_chain;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 
+use self::activate::Activate;
 use self::build::Build;
+use self::canary::Canary;
+use self::cancel::Cancel;
+use self::checkpoint::Checkpoint;
 use self::execute::Execute;
+use self::migrate::Migrate;
 use self::publish::Publish;
+use self::rollback::Rollback;
+use self::schedule::Schedule;
+use self::scheduled::Scheduled;
+use self::state::State;
+use self::stats::Stats;
+use self::unquarantine::Unquarantine;
+use self::verify_chain::VerifyChain;
 
 /// Thalo cli
 #[derive(Parser, Debug)]
@@ -22,25 +46,73 @@ struct Cli {
 
 #[derive(Subcommand, Clone, Debug)]
 enum Command {
+    Activate(Activate),
     #[clap(alias = "b")]
     Build(Build),
+    Cancel(Cancel),
+    Canary(Canary),
+    Checkpoint(Checkpoint),
     Execute(Execute),
+    Migrate(Migrate),
     Publish(Publish),
+    Rollback(Rollback),
+    Schedule(Schedule),
+    Scheduled(Scheduled),
+    State(State),
+    Stats(Stats),
+    Unquarantine(Unquarantine),
+    VerifyChain(VerifyChain),
 }
 
 pub async fn run() -> Result<()> {
     let cli = Cli::try_parse()?;
 
     match cli.command {
+        Command::Activate(cmd) => {
+            cmd.activate().await?;
+        }
         Command::Build(cmd) => {
             cmd.build().await?;
         }
+        Command::Cancel(cmd) => {
+            cmd.cancel().await?;
+        }
+        Command::Canary(cmd) => {
+            cmd.canary().await?;
+        }
+        Command::Checkpoint(cmd) => {
+            cmd.checkpoint().await?;
+        }
         Command::Execute(cmd) => {
             cmd.execute().await?;
         }
+        Command::Migrate(cmd) => {
+            cmd.migrate().await?;
+        }
         Command::Publish(cmd) => {
             cmd.publish().await?;
         }
+        Command::Rollback(cmd) => {
+            cmd.rollback().await?;
+        }
+        Command::Schedule(cmd) => {
+            cmd.schedule().await?;
+        }
+        Command::Scheduled(cmd) => {
+            cmd.scheduled().await?;
+        }
+        Command::State(cmd) => {
+            cmd.state().await?;
+        }
+        Command::Stats(cmd) => {
+            cmd.stats().await?;
+        }
+        Command::Unquarantine(cmd) => {
+            cmd.unquarantine().await?;
+        }
+        Command::VerifyChain(cmd) => {
+            cmd.verify_chain().await?;
+        }
     }
 
     Ok(())
@@ -0,0 +1,28 @@
+use anyhow::{Context, Result};
+use clap::Args;
+use thalo_runtime::registry::ModuleID;
+use thalo_runtime::rpc::client::*;
+
+/// Activate a published version of a module
+#[derive(Args, Clone, Debug)]
+pub struct Activate {
+    /// Url of thalo runtime
+    #[clap(short, long, default_value = "http://localhost:4433")]
+    url: String,
+    /// Module name and version (eg. `counter@1.2.0`)
+    module: ModuleID,
+}
+
+impl Activate {
+    pub async fn activate(self) -> Result<()> {
+        let ModuleID { name, version } = self.module;
+        let version = version.context("missing module version, expected name@version")?;
+
+        let mut client = CommandCenterClient::connect(self.url).await?;
+        CommandCenterClientExt::activate_module(&mut client, name.clone(), version.clone()).await?;
+
+        println!("Activated {name}@{version}");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::{bail, Result};
+use clap::Args;
+use thalo_runtime::registry::{CanaryRouting, ModuleID};
+use thalo_runtime::rpc::client::*;
+
+/// Route a percentage of a module's entities to a canary version
+#[derive(Args, Clone, Debug)]
+pub struct Canary {
+    /// Url of thalo runtime
+    #[clap(short, long, default_value = "http://localhost:4433")]
+    url: String,
+    /// Module name and canary version (eg. `counter@1.3.0`), or just the
+    /// module name to stop routing entities to a canary
+    module: ModuleID,
+    /// Percentage of entities routed to the canary version
+    #[clap(value_parser = clap::value_parser!(u8).range(0..=100))]
+    percent: Option<u8>,
+}
+
+impl Canary {
+    pub async fn canary(self) -> Result<()> {
+        let ModuleID { name, version } = self.module;
+        let canary = match (version, self.percent) {
+            (Some(version), Some(percent)) => Some(CanaryRouting::new(version, percent)?),
+            (Some(_), None) => bail!("missing canary percent"),
+            (None, Some(_)) => bail!("missing canary version, expected name@version"),
+            (None, None) => None,
+        };
+
+        let mut client = CommandCenterClient::connect(self.url).await?;
+        CommandCenterClientExt::set_canary(&mut client, name.clone(), canary.clone()).await?;
+
+        match canary {
+            Some(CanaryRouting { version, percent }) => {
+                println!("Routing {percent}% of {name} entities to {name}@{version}")
+            }
+            None => println!("Routing all {name} entities to the active version"),
+        }
+
+        Ok(())
+    }
+}
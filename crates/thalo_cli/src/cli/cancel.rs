@@ -0,0 +1,40 @@
+use anyhow::Result;
+use clap::Args;
+use thalo::stream_name::StreamName;
+use thalo_runtime::rpc::client::*;
+
+/// Cancel a scheduled command
+#[derive(Args, Clone, Debug)]
+pub struct Cancel {
+    /// Url of thalo runtime
+    #[clap(short, long, default_value = "http://localhost:4433")]
+    url: String,
+    /// Stream name of the entity which scheduled the command, such as
+    /// `account-123`, if it wasn't scheduled through the API
+    #[clap(long)]
+    scheduled_by: Option<String>,
+    /// Key of the scheduled command
+    key: String,
+}
+
+impl Cancel {
+    pub async fn cancel(self) -> Result<()> {
+        let scheduled_by = self.scheduled_by.map(StreamName::new).transpose()?;
+
+        let mut client = CommandCenterClient::connect(self.url).await?;
+        let cancelled = CommandCenterClientExt::cancel_scheduled_command(
+            &mut client,
+            scheduled_by,
+            self.key.clone(),
+        )
+        .await?;
+
+        if cancelled {
+            println!("Cancelled scheduled command {}", self.key);
+        } else {
+            println!("No scheduled command {} is pending", self.key);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,23 @@
+use anyhow::Result;
+use clap::Args;
+use thalo_runtime::rpc::client::*;
+
+/// Export a signed checkpoint of the global event log's hash chain, as JSON
+#[derive(Args, Clone, Debug)]
+pub struct Checkpoint {
+    /// Url of thalo runtime
+    #[clap(short, long, default_value = "http://localhost:4433")]
+    url: String,
+}
+
+impl Checkpoint {
+    pub async fn checkpoint(self) -> Result<()> {
+        let mut client = CommandCenterClient::connect(self.url).await?;
+        match CommandCenterClientExt::export_checkpoint(&mut client).await? {
+            Some(checkpoint) => println!("{}", serde_json::to_string_pretty(&checkpoint)?),
+            None => println!("Hash chain is empty"),
+        }
+
+        Ok(())
+    }
+}
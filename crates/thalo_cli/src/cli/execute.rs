@@ -2,6 +2,7 @@ use anyhow::Result;
 use clap::Args;
 use thalo::stream_name::{Category, ID};
 use thalo_runtime::rpc::client::*;
+use thalo_runtime::{CommandContextOverrides, ExpectedVersion, WrongExpectedVersion};
 
 /// Execute a command for a given module
 #[derive(Args, Clone, Debug)]
@@ -17,6 +18,21 @@ pub struct Execute {
     command: String,
     /// Command data in JSON
     payload: String,
+    /// Version the aggregate must be at (`any`, `no-stream`, `exists`, or a
+    /// version number)
+    #[clap(short, long, default_value = "any")]
+    expected_version: ExpectedVersion,
+    /// Correlation metadata in JSON as an object
+    #[clap(long)]
+    metadata: Option<String>,
+    /// Unix timestamp in milliseconds to handle the command with, defaulting
+    /// to now
+    #[clap(long)]
+    timestamp: Option<u64>,
+    /// Seed of the aggregate's random number generator, defaulting to a
+    /// random seed
+    #[clap(long)]
+    seed: Option<u64>,
 }
 
 impl Execute {
@@ -24,13 +40,25 @@ impl Execute {
         let name = Category::new(self.name)?;
         let id = ID::new(self.id)?;
         let payload = serde_json::from_str(&self.payload)?;
+        let metadata = self
+            .metadata
+            .map(|metadata| serde_json::from_str(&metadata))
+            .transpose()?
+            .unwrap_or_default();
+        let context = CommandContextOverrides {
+            timestamp: self.timestamp,
+            seed: self.seed,
+            metadata,
+        };
         let mut client = CommandCenterClient::connect(self.url).await?;
-        let res = CommandCenterClientExt::execute_anonymous_command(
+        let res = CommandCenterClientExt::execute_anonymous_command_with_context(
             &mut client,
             name,
             id,
             self.command,
             &payload,
+            self.expected_version,
+            context,
         )
         .await;
         match res {
@@ -44,6 +72,9 @@ impl Execute {
                 let err = serde_json::to_string_pretty(&err)?;
                 println!("Failed to execute command: {err}");
             }
+            Err(err) if WrongExpectedVersion::from_status(&err).is_some() => {
+                println!("Failed to execute command: {}", err.message());
+            }
             Err(err) => {
                 println!("Failed to execute command with status {}:", err.code());
                 println!("{}", err.message());
@@ -0,0 +1,38 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Args;
+use thalo_message_store::format::FORMAT_VERSION;
+use thalo_message_store::MessageStore;
+
+/// Upgrade a message store to the current on-disk format
+#[derive(Args, Clone, Debug)]
+pub struct Migrate {
+    /// Message store path
+    #[clap(short = 's', long, default_value = "message-store.db")]
+    message_store_path: PathBuf,
+    /// Write the migrated store into a new directory, leaving the original
+    /// untouched
+    #[clap(long)]
+    into: Option<PathBuf>,
+}
+
+impl Migrate {
+    pub async fn migrate(self) -> Result<()> {
+        let migrated = match self.into {
+            Some(into) => MessageStore::migrate_into(self.message_store_path, into)?,
+            None => MessageStore::migrate(self.message_store_path)?,
+        };
+
+        if migrated.is_up_to_date() {
+            println!("Message store is up to date (format version {FORMAT_VERSION})");
+        } else {
+            println!(
+                "Message store migrated from format version {} to {}",
+                migrated.from, migrated.to
+            );
+        }
+
+        Ok(())
+    }
+}
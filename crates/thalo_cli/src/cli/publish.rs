@@ -2,8 +2,9 @@ use std::path::PathBuf;
 
 use anyhow::Result;
 use clap::Args;
-use thalo::stream_name::Category;
+use thalo_runtime::registry::ModuleID;
 use thalo_runtime::rpc::client::*;
+use thalo_runtime::Verification;
 use tokio::fs;
 
 /// Publish a schema and module
@@ -12,19 +13,49 @@ pub struct Publish {
     /// Url of thalo runtime
     #[clap(short, long, default_value = "http://localhost:4433")]
     url: String,
-    /// Module name
-    name: String,
+    /// Module name, optionally with a semver version (eg. `counter@1.2.0`)
+    name: ModuleID,
     /// Path to wasm module
     module: PathBuf,
+    /// Replay the aggregate's streams through both the new and active module
+    /// before activating the new module
+    #[clap(long)]
+    verify: bool,
+    /// Number of streams to replay when verifying, defaulting to every stream
+    #[clap(long, requires = "verify")]
+    sample: Option<usize>,
+    /// Activate the new module even if verification fails
+    #[clap(long, requires = "verify")]
+    force: bool,
 }
 
 impl Publish {
     pub async fn publish(self) -> Result<()> {
-        let name = Category::new(self.name)?;
+        let ModuleID { name, version } = self.name;
         let module_bytes = fs::read(self.module).await?;
 
         let mut client = CommandCenterClient::connect(self.url).await?;
-        CommandCenterClientExt::publish(&mut client, name, module_bytes).await?;
+        if self.verify {
+            let verification = Verification {
+                sample: self.sample,
+                force: self.force,
+            };
+            let report = CommandCenterClientExt::publish_verified(
+                &mut client,
+                name,
+                version,
+                module_bytes,
+                verification,
+            )
+            .await?;
+            println!("Verified {} streams", report.streams);
+            for failure in report.failures {
+                eprintln!("  {failure}");
+            }
+        } else {
+            CommandCenterClientExt::publish_version(&mut client, name, version, module_bytes)
+                .await?;
+        }
 
         println!("Module published");
 
@@ -0,0 +1,27 @@
+use anyhow::Result;
+use clap::Args;
+use thalo::stream_name::Category;
+use thalo_runtime::rpc::client::*;
+
+/// Roll back a module to the version before its active version
+#[derive(Args, Clone, Debug)]
+pub struct Rollback {
+    /// Url of thalo runtime
+    #[clap(short, long, default_value = "http://localhost:4433")]
+    url: String,
+    /// Module name
+    name: String,
+}
+
+impl Rollback {
+    pub async fn rollback(self) -> Result<()> {
+        let name = Category::new(self.name)?;
+
+        let mut client = CommandCenterClient::connect(self.url).await?;
+        let version = CommandCenterClientExt::rollback_module(&mut client, name.clone()).await?;
+
+        println!("Rolled back to {name}@{version}");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use clap::Args;
+use thalo::stream_name::{Category, ID};
+use thalo_runtime::rpc::client::*;
+
+/// Schedule a command to be executed later
+#[derive(Args, Clone, Debug)]
+pub struct Schedule {
+    /// Url of thalo runtime
+    #[clap(short, long, default_value = "http://localhost:4433")]
+    url: String,
+    /// Key of the scheduled command, replacing any pending command scheduled
+    /// through the API with the same key
+    key: String,
+    /// Name of aggregate
+    name: String,
+    /// ID of aggregate instance
+    id: String,
+    /// Command to execute
+    command: String,
+    /// Command data in JSON
+    payload: String,
+    /// Seconds from now the command is due
+    #[clap(long, conflicts_with = "at")]
+    delay: Option<u64>,
+    /// Unix timestamp in milliseconds of when the command is due
+    #[clap(long)]
+    at: Option<u64>,
+}
+
+impl Schedule {
+    pub async fn schedule(self) -> Result<()> {
+        let name = Category::new(self.name)?;
+        let id = ID::new(self.id)?;
+        let payload = serde_json::from_str(&self.payload)?;
+        let due = match (self.delay, self.at) {
+            (Some(delay), None) => SystemTime::now() + Duration::from_secs(delay),
+            (None, Some(at)) => UNIX_EPOCH + Duration::from_millis(at),
+            _ => bail!("expected either --delay or --at"),
+        };
+
+        let mut client = CommandCenterClient::connect(self.url).await?;
+        CommandCenterClientExt::schedule_anonymous_command(
+            &mut client,
+            self.key.clone(),
+            due,
+            name.clone(),
+            id.clone(),
+            self.command.clone(),
+            &payload,
+        )
+        .await?;
+
+        let due = due.duration_since(UNIX_EPOCH)?.as_millis();
+        println!(
+            "Scheduled {} for {name}-{id} at {due} with key {}",
+            self.command, self.key
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+use anyhow::Result;
+use clap::Args;
+use thalo_runtime::rpc::client::*;
+
+/// List pending scheduled commands in the order they're due
+#[derive(Args, Clone, Debug)]
+pub struct Scheduled {
+    /// Url of thalo runtime
+    #[clap(short, long, default_value = "http://localhost:4433")]
+    url: String,
+}
+
+impl Scheduled {
+    pub async fn scheduled(self) -> Result<()> {
+        let mut client = CommandCenterClient::connect(self.url).await?;
+        let commands = CommandCenterClientExt::scheduled_commands(&mut client).await?;
+
+        if commands.is_empty() {
+            println!("No scheduled commands");
+        }
+        for command in commands {
+            let key = if command.scheduled_by.is_empty() {
+                command.key
+            } else {
+                format!("{} (scheduled by {})", command.key, command.scheduled_by)
+            };
+            println!(
+                "{}  due {}  {}-{}  {}  {}",
+                key, command.due, command.name, command.id, command.command, command.payload
+            );
+        }
+
+        Ok(())
+    }
+}
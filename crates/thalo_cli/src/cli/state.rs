@@ -0,0 +1,58 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Result;
+use clap::Args;
+use thalo::stream_name::{Category, ID};
+use thalo_runtime::rpc::client::*;
+use thalo_runtime::{HistoricalState, StateAt};
+
+/// Print the current or past state of an aggregate instance
+#[derive(Args, Clone, Debug)]
+pub struct State {
+    /// Url of thalo runtime
+    #[clap(short, long, default_value = "http://localhost:4433")]
+    url: String,
+    /// Name of aggregate
+    name: String,
+    /// ID of aggregate instance
+    id: String,
+    /// Print the state after the event at this stream position
+    #[clap(short, long, conflicts_with = "time")]
+    position: Option<u64>,
+    /// Print the state after events written at or before this unix timestamp
+    /// in milliseconds
+    #[clap(short, long)]
+    time: Option<u64>,
+}
+
+impl State {
+    pub async fn state(self) -> Result<()> {
+        let name = Category::new(self.name)?;
+        let id = ID::new(self.id)?;
+        let mut client = CommandCenterClient::connect(self.url).await?;
+        let at = match (self.position, self.time) {
+            (Some(position), _) => Some(StateAt::Position(position)),
+            (None, Some(time)) => Some(StateAt::Time(UNIX_EPOCH + Duration::from_millis(time))),
+            (None, None) => None,
+        };
+
+        let state = match at {
+            Some(at) => {
+                let HistoricalState { state, last_event } =
+                    CommandCenterClientExt::get_state_at(&mut client, name, id, at).await?;
+                match last_event {
+                    Some(event) => {
+                        eprintln!("state at position {} ({})", event.position, event.msg_type)
+                    }
+                    None => eprintln!("no events applied"),
+                }
+                state.unwrap_or_default()
+            }
+            None => CommandCenterClientExt::get_state(&mut client, name, id).await?,
+        };
+
+        println!("{}", serde_json::to_string_pretty(&state)?);
+
+        Ok(())
+    }
+}
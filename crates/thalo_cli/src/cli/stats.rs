@@ -0,0 +1,52 @@
+use anyhow::Result;
+use clap::Args;
+use thalo::stream_name::Category;
+use thalo_runtime::rpc::client::*;
+
+/// Show the commands, entity cache and quarantined entities of each running version of a
+/// module
+#[derive(Args, Clone, Debug)]
+pub struct Stats {
+    /// Url of thalo runtime
+    #[clap(short, long, default_value = "http://localhost:4433")]
+    url: String,
+    /// Module name
+    name: String,
+}
+
+impl Stats {
+    pub async fn stats(self) -> Result<()> {
+        let name = Category::new(self.name)?;
+
+        let mut client = CommandCenterClient::connect(self.url).await?;
+        let stats = CommandCenterClientExt::module_stats(&mut client, name.clone()).await?;
+
+        for route in stats.routes {
+            let module = if route.version.is_empty() {
+                name.to_string()
+            } else {
+                format!("{name}@{}", route.version)
+            };
+            match route.canary_percent {
+                Some(percent) => {
+                    println!("{module} (canary {percent}%): {} commands", route.commands)
+                }
+                None => println!("{module} (active): {} commands", route.commands),
+            }
+            if let Some(cache) = route.cache {
+                println!(
+                    "  cache: {} entities, weighing {}, {} hits, {} misses",
+                    cache.entries, cache.weighted_size, cache.hits, cache.misses
+                );
+            }
+            for quarantined in route.quarantined {
+                println!(
+                    "  quarantined: {}: {}",
+                    quarantined.stream_name, quarantined.error
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
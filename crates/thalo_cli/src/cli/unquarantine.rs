@@ -0,0 +1,35 @@
+use anyhow::Result;
+use clap::Args;
+use thalo::stream_name::{Category, ID};
+use thalo_runtime::rpc::client::*;
+
+/// Release an entity quarantined after trapping
+#[derive(Args, Clone, Debug)]
+pub struct Unquarantine {
+    /// Url of thalo runtime
+    #[clap(short, long, default_value = "http://localhost:4433")]
+    url: String,
+    /// Name of aggregate
+    name: String,
+    /// ID of aggregate instance
+    id: String,
+}
+
+impl Unquarantine {
+    pub async fn unquarantine(self) -> Result<()> {
+        let name = Category::new(self.name)?;
+        let id = ID::new(self.id)?;
+
+        let mut client = CommandCenterClient::connect(self.url).await?;
+        let released =
+            CommandCenterClientExt::unquarantine(&mut client, name.clone(), id.clone()).await?;
+
+        if released {
+            println!("Released {name}-{id} from quarantine");
+        } else {
+            println!("{name}-{id} is not quarantined");
+        }
+
+        Ok(())
+    }
+}
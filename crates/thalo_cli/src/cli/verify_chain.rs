@@ -0,0 +1,40 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use hex::FromHex;
+use thalo_message_store::hash_chain::{Checkpoint, VerifyingKey};
+use thalo_runtime::rpc::client::*;
+use tokio::fs;
+
+/// Verify the global event log's hash chain against a checkpoint exported with `checkpoint`
+#[derive(Args, Clone, Debug)]
+pub struct VerifyChain {
+    /// Url of thalo runtime
+    #[clap(short, long, default_value = "http://localhost:4433")]
+    url: String,
+    /// Hex encoded ed25519 public key the checkpoint must be signed by
+    #[clap(long)]
+    public_key: String,
+    /// Path to the checkpoint JSON
+    checkpoint: PathBuf,
+}
+
+impl VerifyChain {
+    pub async fn verify_chain(self) -> Result<()> {
+        let public_key = <[u8; 32]>::from_hex(&self.public_key)
+            .context("public key must be 32 hex encoded bytes")?;
+        let verifying_key = VerifyingKey::from_bytes(&public_key).context("invalid public key")?;
+        let checkpoint: Checkpoint = serde_json::from_slice(&fs::read(&self.checkpoint).await?)
+            .context("invalid checkpoint")?;
+        let global_id = checkpoint.global_id;
+
+        let mut client = CommandCenterClient::connect(self.url).await?;
+        match CommandCenterClientExt::verify_chain(&mut client, checkpoint, &verifying_key).await? {
+            Ok(()) => println!("Hash chain matches the checkpoint at global ID {global_id}"),
+            Err(err) => bail!("hash chain failed verification: {err}"),
+        }
+
+        Ok(())
+    }
+}
@@ -83,7 +83,7 @@ impl DeriveCommand {
                 let arms = commands.iter().map(|(name, path)| {
                     quote! {
                         #ident::#name(cmd) => {
-                            <T as ::thalo::Handle<#path>>::handle(&self.0, cmd)
+                            <T as ::thalo::Handle<#path>>::handle(&self.0, cmd, ctx)
                                 .map_err(|err|
                                     ::thalo::__macro_helpers::serde_json::to_value(err)
                                         .unwrap_or_else(|err|
@@ -104,7 +104,7 @@ impl DeriveCommand {
                     {
                         type Error = ::thalo::__macro_helpers::serde_json::Value;
 
-                        fn handle(&self, event: #ident) -> ::std::result::Result<::std::vec::Vec<<T as ::thalo::Aggregate>::Event>, Self::Error> {
+                        fn handle(&self, event: #ident, ctx: &mut ::thalo::Context) -> ::std::result::Result<::std::vec::Vec<<T as ::thalo::Aggregate>::Event>, Self::Error> {
                             match event {
                                 #( #arms, )*
                             }
@@ -119,8 +119,8 @@ impl DeriveCommand {
                 {
                     type Error = <T as ::thalo::Handle<#ident>>::Error;
 
-                    fn handle(&self, cmd: #ident) -> ::std::result::Result<::std::vec::Vec<<Self as ::thalo::Aggregate>::Event>, Self::Error> {
-                        <T as ::thalo::Handle<#ident>>::handle(&self.0, cmd)
+                    fn handle(&self, cmd: #ident, ctx: &mut ::thalo::Context) -> ::std::result::Result<::std::vec::Vec<<Self as ::thalo::Aggregate>::Event>, Self::Error> {
+                        <T as ::thalo::Handle<#ident>>::handle(&self.0, cmd, ctx)
                     }
                 }
             },
@@ -0,0 +1,36 @@
+//! Access modes for the message store.
+//!
+//! Types in the message store are parameterized by their access mode, which
+//! defaults to [`ReadWrite`]. Stores opened with
+//! [`MessageStore::open_read_only`](crate::MessageStore::open_read_only) use
+//! [`ReadOnly`], and statically lack any methods which write to the store.
+
+use crate::hash_chain::HashChain;
+use crate::id_generator::IdGenerator;
+
+/// An access mode for the message store.
+///
+/// This trait is sealed, and implemented only by [`ReadWrite`] and
+/// [`ReadOnly`].
+pub trait Access: Clone + private::Sealed {}
+
+/// Read-write access, holding the state required to write messages.
+#[derive(Clone)]
+pub struct ReadWrite {
+    pub(crate) id_generator: IdGenerator,
+    pub(crate) hash_chain: Option<HashChain>,
+}
+
+/// Read-only access.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadOnly;
+
+impl Access for ReadWrite {}
+impl Access for ReadOnly {}
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for super::ReadWrite {}
+    impl Sealed for super::ReadOnly {}
+}
@@ -0,0 +1,135 @@
+//! Commands emitted by aggregates, awaiting dispatch to other aggregates.
+//!
+//! Commands are written to the outbox in the same transaction as the events of
+//! the command which emitted them, with
+//! [`Stream::write_messages_with_commands`](crate::stream::Stream::write_messages_with_commands),
+//! and are removed once they've been dispatched. Commands are kept in the
+//! order they were written.
+//!
+//! Commands written alongside messages record the global ID of the first
+//! message written as their causation, unless they already have one.
+
+use std::borrow::Cow;
+use std::marker::PhantomData;
+
+use serde::{Deserialize, Serialize};
+use sled::Tree;
+use thalo::stream_name::StreamName;
+
+use crate::access::{Access, ReadWrite};
+use crate::error::{Error, Result};
+
+pub(crate) const COMMAND_OUTBOX_TREE: &str = "thalo:command_outbox";
+
+/// A command awaiting dispatch.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutboxCommand<'a> {
+    /// Stream name of the entity the command is sent to.
+    pub stream_name: StreamName<'a>,
+    pub command: Cow<'a, str>,
+    pub payload: Cow<'a, serde_json::Value>,
+    /// Metadata the command is executed with.
+    pub metadata: Cow<'a, serde_json::Value>,
+    /// Schedule of a command to be delivered later rather than dispatched
+    /// immediately.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<OutboxSchedule<'a>>,
+    /// Global ID of the message which caused the command.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub causation_global_id: Option<u64>,
+    /// Number of times the command failed to be dispatched.
+    #[serde(default)]
+    pub failed_attempts: u32,
+}
+
+/// When a scheduled [`OutboxCommand`] is delivered.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutboxSchedule<'a> {
+    /// Stream which scheduled the command, whose keys are independent of
+    /// other streams'.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scheduled_by: Option<StreamName<'a>>,
+    /// Key the command is scheduled with, replacing any command scheduled
+    /// by the same stream with the same key.
+    pub key: Cow<'a, str>,
+    /// Time the command is due, in milliseconds since the unix epoch.
+    pub due: u64,
+}
+
+impl OutboxCommand<'_> {
+    pub fn into_owned(self) -> OutboxCommand<'static> {
+        OutboxCommand {
+            stream_name: self.stream_name.into_owned(),
+            command: Cow::Owned(self.command.into_owned()),
+            payload: Cow::Owned(self.payload.into_owned()),
+            metadata: Cow::Owned(self.metadata.into_owned()),
+            schedule: self.schedule.map(|schedule| OutboxSchedule {
+                scheduled_by: schedule.scheduled_by.map(StreamName::into_owned),
+                key: Cow::Owned(schedule.key.into_owned()),
+                due: schedule.due,
+            }),
+            causation_global_id: self.causation_global_id,
+            failed_attempts: self.failed_attempts,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CommandOutbox<A: Access = ReadWrite> {
+    pub(crate) tree: Tree,
+    _access: PhantomData<A>,
+}
+
+impl<A: Access> CommandOutbox<A> {
+    pub(crate) fn new(tree: Tree) -> Self {
+        CommandOutbox {
+            tree,
+            _access: PhantomData,
+        }
+    }
+
+    /// Iterates over the commands awaiting dispatch with their IDs, in the
+    /// order they were written.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(u64, OutboxCommand<'static>)>> {
+        self.tree.iter().map(|res| {
+            let (key, value) = res?;
+            let id = key
+                .as_ref()
+                .try_into()
+                .map(u64::from_be_bytes)
+                .map_err(|_| Error::InvalidU64Id)?;
+            let command: OutboxCommand<'_> =
+                serde_cbor::from_slice(&value).map_err(Error::DeserializeData)?;
+            Ok((id, command.into_owned()))
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+}
+
+impl CommandOutbox {
+    /// Removes a dispatched command.
+    pub fn remove(&self, id: u64) -> Result<()> {
+        self.tree.remove(id.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Replaces a command awaiting dispatch, such as to record a failed
+    /// attempt, unless it was removed.
+    pub fn update(&self, id: u64, command: &OutboxCommand<'_>) -> Result<()> {
+        let value = serde_cbor::to_vec(command).map_err(Error::SerializeData)?;
+        self.tree
+            .fetch_and_update(id.to_be_bytes(), |existing| existing.map(|_| value.clone()))?;
+        Ok(())
+    }
+
+    pub async fn flush_async(&self) -> Result<usize> {
+        Ok(self.tree.flush_async().await?)
+    }
+}
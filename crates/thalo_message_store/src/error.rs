@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use sled::transaction::ConflictableTransactionError;
 use thalo::stream_name::EmptyStreamName;
 use thiserror::Error;
@@ -30,15 +32,60 @@ pub enum Error {
     #[error("failed to serialize projection: {0}")]
     SerializeProjection(bincode::Error),
 
+    #[error("hash chain at global ID {global_id} doesn't match the checkpoint")]
+    CheckpointMismatch { global_id: u64 },
+
+    #[error("stream {stream_name} was specified more than once")]
+    DuplicateStream { stream_name: String },
+
     #[error(transparent)]
     EmptyStreamName(#[from] EmptyStreamName),
 
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("hash chain mismatch at global ID {global_id}")]
+    HashChainMismatch { global_id: u64 },
+
+    #[error("hash chain ends before the checkpoint at global ID {global_id}")]
+    HashChainTruncated { global_id: u64 },
+
+    #[error("invalid checkpoint signature")]
+    InvalidCheckpointSignature,
+
     #[error("invalid event reference: (ID: {id}, Stream Name: {stream_name})")]
     InvalidEventReference { id: u64, stream_name: String },
 
+    #[error("invalid format version")]
+    InvalidFormatVersion,
+
+    #[error("invalid hash")]
+    InvalidHash,
+
+    #[error("invalid stream version")]
+    InvalidStreamVersion,
+
     #[error("invalid u64 ID")]
     InvalidU64Id,
 
+    #[error("missing hash for global ID {global_id}")]
+    MissingHash { global_id: u64 },
+
+    #[error("missing migration to format version {version}")]
+    MissingMigration { version: u32 },
+
+    #[error("message store format version {version} must be migrated to version {current}")]
+    MigrationRequired { version: u32, current: u32 },
+
+    #[error("migration target {path} already exists")]
+    MigrationTargetExists { path: PathBuf },
+
+    #[error("message store not found at {path}")]
+    StoreNotFound { path: PathBuf },
+
+    #[error("unsupported message store format version {version} (supported: {supported})")]
+    UnsupportedFormatVersion { version: u32, supported: u32 },
+
     #[error("wrong expected version: {expected_version} (Stream: {stream_name}, Stream Version: {stream_version:?})")]
     WrongExpectedVersion {
         expected_version: u64,
@@ -0,0 +1,206 @@
+//! On-disk format versioning and migrations.
+//!
+//! The format version of a store is kept in the `thalo:metadata` tree, and
+//! covers the tree names, message references, and the encoding of messages
+//! and projection positions. Stores are refused if their format version is
+//! newer than [`FORMAT_VERSION`], and must be migrated if it is older.
+//!
+//! Stores created before the format was versioned have no marker, and are
+//! treated as version 1.
+//!
+//! # Adding a migration
+//!
+//! When the layout changes, increment [`FORMAT_VERSION`] and add a
+//! [`Migration`] to `MIGRATIONS` which upgrades a store from the previous
+//! version.
+
+use std::fs;
+use std::path::Path;
+
+use sled::Db;
+use tracing::info;
+
+use crate::error::{Error, Result};
+use crate::message_store::open_existing_tree;
+
+const METADATA_TREE: &str = "thalo:metadata";
+const FORMAT_VERSION_KEY: &str = "format_version";
+
+/// Current on-disk format version.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Format version of stores created before the format was versioned.
+const UNVERSIONED_FORMAT_VERSION: u32 = 1;
+
+/// Migrations, each upgrading a store from `to - 1` to `to`.
+static MIGRATIONS: &[Migration] = &[];
+
+/// An upgrade of a store from the previous format version.
+pub struct Migration {
+    /// Format version the store is upgraded to.
+    pub to: u32,
+    /// Description of the changes made.
+    pub description: &'static str,
+    migrate: fn(&Db) -> Result<()>,
+}
+
+/// The result of migrating a store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Migrated {
+    /// Format version before migrating.
+    pub from: u32,
+    /// Format version after migrating.
+    pub to: u32,
+}
+
+impl Migrated {
+    /// Returns whether the store was already up to date.
+    pub fn is_up_to_date(&self) -> bool {
+        self.from == self.to
+    }
+}
+
+/// Returns the format version of a store.
+///
+/// Empty databases have no version, and stores created before the format was
+/// versioned are reported as version 1.
+pub fn format_version(db: &Db) -> Result<Option<u32>> {
+    if is_empty(db)? {
+        return Ok(None);
+    }
+
+    // The metadata tree isn't created here, since read-only stores are checked too.
+    let Some(metadata) = open_existing_tree(db, METADATA_TREE)? else {
+        return Ok(Some(UNVERSIONED_FORMAT_VERSION));
+    };
+    match metadata.get(FORMAT_VERSION_KEY)? {
+        Some(version) => {
+            let bytes = version
+                .as_ref()
+                .try_into()
+                .map_err(|_| Error::InvalidFormatVersion)?;
+            Ok(Some(u32::from_be_bytes(bytes)))
+        }
+        None => Ok(Some(UNVERSIONED_FORMAT_VERSION)),
+    }
+}
+
+/// Ensures a store can be opened with the current format version.
+///
+/// Empty and unversioned stores are stamped with their format version.
+pub(crate) fn check(db: &Db) -> Result<()> {
+    let version = format_version(db)?.unwrap_or(FORMAT_VERSION);
+    check_version(version)?;
+    set_format_version(db, version)
+}
+
+/// Ensures a store can be read with the current format version, without
+/// writing to it.
+pub(crate) fn check_read_only(db: &Db) -> Result<()> {
+    match format_version(db)? {
+        Some(version) => check_version(version),
+        None => Ok(()),
+    }
+}
+
+/// Upgrades a store to the current format version in place.
+///
+/// The version is updated after each migration, so an interrupted migration
+/// resumes from the last completed step. Migrations are not atomic, so the
+/// store should be backed up first, or migrated into a new directory with
+/// [`migrate_into`].
+pub fn migrate(db: &Db) -> Result<Migrated> {
+    let from = format_version(db)?.unwrap_or(FORMAT_VERSION);
+    if from > FORMAT_VERSION {
+        return Err(Error::UnsupportedFormatVersion {
+            version: from,
+            supported: FORMAT_VERSION,
+        });
+    }
+
+    for version in from + 1..=FORMAT_VERSION {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|migration| migration.to == version)
+            .ok_or(Error::MissingMigration { version })?;
+        info!(
+            from = version - 1,
+            to = version,
+            description = migration.description,
+            "migrating message store"
+        );
+        (migration.migrate)(db)?;
+        set_format_version(db, version)?;
+        db.flush()?;
+    }
+
+    set_format_version(db, FORMAT_VERSION)?;
+    db.flush()?;
+
+    Ok(Migrated {
+        from,
+        to: FORMAT_VERSION,
+    })
+}
+
+/// Copies a store into a new directory, and upgrades the copy to the current
+/// format version, leaving the original untouched.
+///
+/// The source database is flushed and held open while copying, which prevents
+/// other processes from writing to it.
+pub fn migrate_into(db: &Db, path: &Path, new_path: &Path) -> Result<Migrated> {
+    if new_path.exists() {
+        return Err(Error::MigrationTargetExists {
+            path: new_path.to_path_buf(),
+        });
+    }
+
+    db.flush()?;
+    copy_dir(path, new_path)?;
+
+    let new_db = crate::message_store::db_config(new_path).open()?;
+    migrate(&new_db)
+}
+
+fn check_version(version: u32) -> Result<()> {
+    if version > FORMAT_VERSION {
+        return Err(Error::UnsupportedFormatVersion {
+            version,
+            supported: FORMAT_VERSION,
+        });
+    }
+    if version < FORMAT_VERSION {
+        return Err(Error::MigrationRequired {
+            version,
+            current: FORMAT_VERSION,
+        });
+    }
+
+    Ok(())
+}
+
+fn set_format_version(db: &Db, version: u32) -> Result<()> {
+    let metadata = db.open_tree(METADATA_TREE)?;
+    metadata.insert(FORMAT_VERSION_KEY, &version.to_be_bytes())?;
+    Ok(())
+}
+
+fn is_empty(db: &Db) -> Result<bool> {
+    // A new database contains only the default tree.
+    Ok(db.tree_names().len() <= 1 && db.is_empty())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let to = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to)?;
+        } else {
+            fs::copy(entry.path(), to)?;
+        }
+    }
+
+    Ok(())
+}
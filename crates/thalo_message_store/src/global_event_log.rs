@@ -1,53 +1,284 @@
+use std::marker::PhantomData;
 use std::ops;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use sled::{Db, Tree};
+use sled::transaction::{ConflictableTransactionError, TransactionalTree};
+use sled::{Db, IVec, Tree};
 
+use crate::access::{Access, ReadOnly, ReadWrite};
 use crate::error::{Error, Result};
+use crate::hash_chain::{
+    self, parse_hash, Checkpoint, SigningKey, VerifyingKey, GENESIS_HASH, HASH_CHAIN_TREE, HASH_LEN,
+};
+use crate::id_generator::IdGenerator;
+use crate::message_store::open_existing_tree;
 use crate::stream::RawMessage;
 
 const GLOBAL_EVENT_LOG_TREE: &str = "thalo:global_event_log";
 
 #[derive(Clone)]
-pub struct GlobalEventLog {
+pub struct GlobalEventLog<A: Access = ReadWrite> {
     pub(crate) db: Db,
     tree: Tree,
+    /// Hashes of the chain, which is always present unless read-only.
+    hashes: Option<Tree>,
+    _access: PhantomData<A>,
 }
 
 impl GlobalEventLog {
     pub(crate) fn new(db: Db) -> Result<Self> {
         let tree = db.open_tree(GLOBAL_EVENT_LOG_TREE)?;
-        Ok(GlobalEventLog { db, tree })
+        let hashes = db.open_tree(HASH_CHAIN_TREE)?;
+        Ok(GlobalEventLog {
+            db,
+            tree,
+            hashes: Some(hashes),
+            _access: PhantomData,
+        })
+    }
+
+    pub(crate) fn hashes(&self) -> &Tree {
+        self.hashes
+            .as_ref()
+            .expect("read-write global event log has a hash tree")
+    }
+}
+
+impl GlobalEventLog<ReadOnly> {
+    /// Opens the global event log without creating it, returning `None` if
+    /// no messages were ever written.
+    pub(crate) fn open(db: Db) -> Result<Option<Self>> {
+        let Some(tree) = open_existing_tree(&db, GLOBAL_EVENT_LOG_TREE)? else {
+            return Ok(None);
+        };
+        let hashes = open_existing_tree(&db, HASH_CHAIN_TREE)?;
+        Ok(Some(GlobalEventLog {
+            db,
+            tree,
+            hashes,
+            _access: PhantomData,
+        }))
     }
+}
 
+impl<A: Access> GlobalEventLog<A> {
     pub fn iter_all_messages(&self) -> GlobalEventLogIter {
         GlobalEventLogIter::new(self.db.clone(), self.tree.iter())
     }
 
+    /// Iterates messages starting from a global ID (inclusive).
+    pub fn iter_from(&self, global_id: u64) -> GlobalEventLogIter {
+        GlobalEventLogIter::new(self.db.clone(), self.tree.range(global_id.to_be_bytes()..))
+    }
+
+    /// Iterates messages written between `from` (inclusive) and `to`
+    /// (exclusive).
+    ///
+    /// See [`GlobalEventLog::position_at`] for how the positions are found.
+    pub fn iter_between(&self, from: SystemTime, to: SystemTime) -> Result<GlobalEventLogIter> {
+        let start = self.position_at(from)?;
+        let end = self.position_at(to)?;
+        let inner = match (start, end) {
+            (Some(start), Some(end)) => self.tree.range(start.to_be_bytes()..end.to_be_bytes()),
+            (Some(start), None) => self.tree.range(start.to_be_bytes()..),
+            (None, _) => self
+                .tree
+                .range(u64::MAX.to_be_bytes()..u64::MAX.to_be_bytes()),
+        };
+
+        Ok(GlobalEventLogIter::new(self.db.clone(), inner))
+    }
+
     pub fn get(&self, id: u64) -> Result<Option<RawMessage<()>>> {
         self.tree
             .get(id.to_be_bytes())?
-            .map(|value| {
-                let (stream_key, stream_name) = value.split_at(8);
-                let tree = self.db.open_tree(stream_name)?;
-                let message = tree.get(stream_key)?.ok_or_else(|| {
-                    let stream_name = String::from_utf8_lossy(stream_name).into_owned();
-                    Error::InvalidEventReference { id, stream_name }
-                })?;
-
-                Ok(RawMessage::new(id.to_be_bytes().to_vec().into(), message))
-            })
+            .map(|value| resolve_message(&self.db, id.to_be_bytes().to_vec().into(), &value))
             .transpose()
     }
 
-    pub fn last_position(&self) -> Result<Option<u64>> {
-        self.tree
+    /// Returns the global ID of the first message written at or after `time`,
+    /// or `None` if every message was written before `time`.
+    ///
+    /// Message times are allocated along with global IDs, and never go
+    /// backwards even if the system clock does, so they follow the order of
+    /// the global event log. This allows the position to be found with a
+    /// binary search rather than scanning the whole log.
+    ///
+    /// Times are compared with millisecond precision, as stored in messages.
+    pub fn position_at(&self, time: SystemTime) -> Result<Option<u64>> {
+        let time = as_millis(time);
+        let (Some(first), Some(last)) = (self.first_position()?, self.last_position()?) else {
+            return Ok(None);
+        };
+
+        let mut low = first;
+        let mut high = last + 1;
+        let mut position = None;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let Some((key, value)) = self
+                .tree
+                .range(mid.to_be_bytes()..high.to_be_bytes())
+                .next()
+                .transpose()?
+            else {
+                // No messages between mid and high, due to a gap in the log.
+                high = mid;
+                continue;
+            };
+
+            let id = parse_id(&key)?;
+            let raw_message = resolve_message(&self.db, key, &value)?;
+            if as_millis(raw_message.message()?.time) < time {
+                low = id + 1;
+            } else {
+                position = Some(id);
+                high = mid;
+            }
+        }
+
+        Ok(position)
+    }
+
+    /// Verifies the hash chain for messages between global IDs `from` and `to`
+    /// (inclusive).
+    ///
+    /// The chain is recomputed from the stored hash preceding `from`, so a
+    /// range in the middle of the log can be verified without reading the
+    /// messages before it. Use [`GlobalEventLog::verify_checkpoint`] to also
+    /// detect a chain rewritten in its entirety.
+    ///
+    /// Returns an error identifying the first message which doesn't match its
+    /// hash, or has no hash.
+    pub fn verify_chain(&self, from: u64, to: u64) -> Result<()> {
+        let range = self.tree.range(from.to_be_bytes()..=to.to_be_bytes());
+        let Some(hashes) = &self.hashes else {
+            // Without a hash tree, any message in the range is missing its hash.
+            return match range.keys().next().transpose()? {
+                Some(key) => Err(Error::MissingHash {
+                    global_id: parse_id(&key)?,
+                }),
+                None => Ok(()),
+            };
+        };
+
+        let mut last_hash = match hashes.range(..from.to_be_bytes()).next_back() {
+            Some(res) => parse_hash(&res?.1)?,
+            None => GENESIS_HASH,
+        };
+        for res in range {
+            let (key, message_ref) = res?;
+            let (_, hash) = self.verify_hash(hashes, &last_hash, key, &message_ref)?;
+            last_hash = hash;
+        }
+
+        Ok(())
+    }
+
+    /// Verifies the hash chain against a trusted checkpoint.
+    ///
+    /// The checkpoint's signature is verified with `verifying_key`, and the
+    /// chain is recomputed from its first entry, so that a chain rewritten in
+    /// its entirety is detected as well as a single altered message. Messages
+    /// written after the checkpoint are verified against their stored hashes.
+    ///
+    /// Returns an error identifying the first message which doesn't match its
+    /// hash or has no hash, or if the chain doesn't reach the checkpoint or
+    /// differs from it.
+    pub fn verify_checkpoint(
+        &self,
+        checkpoint: &Checkpoint,
+        verifying_key: &VerifyingKey,
+    ) -> Result<()> {
+        checkpoint.verify(verifying_key)?;
+
+        let truncated = Error::HashChainTruncated {
+            global_id: checkpoint.global_id,
+        };
+        let Some(hashes) = &self.hashes else {
+            return Err(truncated);
+        };
+        let Some((first_hashed, _)) = hashes.first()? else {
+            return Err(truncated);
+        };
+
+        let mut last_hash = GENESIS_HASH;
+        let mut reached_checkpoint = false;
+        for res in self.tree.range(first_hashed..) {
+            let (key, message_ref) = res?;
+            let global_id = parse_id(&key)?;
+            if !reached_checkpoint && global_id > checkpoint.global_id {
+                return Err(truncated);
+            }
+
+            let (global_id, hash) = self.verify_hash(hashes, &last_hash, key, &message_ref)?;
+            if global_id == checkpoint.global_id {
+                if hash != checkpoint.hash {
+                    return Err(Error::CheckpointMismatch { global_id });
+                }
+                reached_checkpoint = true;
+            }
+
+            last_hash = hash;
+        }
+
+        if !reached_checkpoint {
+            return Err(truncated);
+        }
+
+        Ok(())
+    }
+
+    /// Recomputes the hash of a global event log entry, chained from
+    /// `last_hash`, and checks it matches the stored hash.
+    fn verify_hash(
+        &self,
+        hashes: &Tree,
+        last_hash: &[u8; HASH_LEN],
+        key: IVec,
+        message_ref: &[u8],
+    ) -> Result<(u64, [u8; HASH_LEN])> {
+        let global_id = parse_id(&key)?;
+        let raw_message = resolve_message(&self.db, key, message_ref)?;
+        let stored_hash = hashes
+            .get(global_id.to_be_bytes())?
+            .ok_or(Error::MissingHash { global_id })?;
+        let hash = hash_chain::next_hash(last_hash, global_id, &raw_message.value);
+        if stored_hash != hash {
+            return Err(Error::HashChainMismatch { global_id });
+        }
+
+        Ok((global_id, hash))
+    }
+
+    /// Returns the latest hash in the chain, along with its global ID.
+    pub fn last_hash(&self) -> Result<Option<(u64, [u8; HASH_LEN])>> {
+        let Some(hashes) = &self.hashes else {
+            return Ok(None);
+        };
+
+        hashes
             .last()?
-            .map(|(k, _)| {
-                let slice = k.as_ref().try_into().map_err(|_| Error::InvalidU64Id)?;
-                Ok(u64::from_be_bytes(slice))
-            })
+            .map(|(k, v)| Ok((parse_id(&k)?, parse_hash(&v)?)))
             .transpose()
     }
+
+    /// Signs a checkpoint of the latest hash in the chain.
+    ///
+    /// Returns `None` if the hash chain is empty.
+    pub fn checkpoint(&self, signing_key: &SigningKey) -> Result<Option<Checkpoint>> {
+        Ok(self
+            .last_hash()?
+            .map(|(global_id, hash)| Checkpoint::sign(global_id, hash, signing_key)))
+    }
+
+    pub fn first_position(&self) -> Result<Option<u64>> {
+        self.tree.first()?.map(|(k, _)| parse_id(&k)).transpose()
+    }
+
+    pub fn last_position(&self) -> Result<Option<u64>> {
+        self.tree.last()?.map(|(k, _)| parse_id(&k)).transpose()
+    }
 }
 
 impl ops::Deref for GlobalEventLog {
@@ -58,6 +289,61 @@ impl ops::Deref for GlobalEventLog {
     }
 }
 
+/// The global event log within a transaction, used when writing messages.
+pub(crate) struct GlobalEventLogTx<'a> {
+    tree: &'a TransactionalTree,
+    id_generator: &'a IdGenerator,
+    hash_chain: Option<(&'a TransactionalTree, [u8; HASH_LEN])>,
+}
+
+impl<'a> GlobalEventLogTx<'a> {
+    pub(crate) fn new(
+        tree: &'a TransactionalTree,
+        id_generator: &'a IdGenerator,
+        hash_chain: Option<(&'a TransactionalTree, [u8; HASH_LEN])>,
+    ) -> Self {
+        GlobalEventLogTx {
+            tree,
+            id_generator,
+            hash_chain,
+        }
+    }
+
+    /// Generates the next global ID, and the time of its message.
+    pub(crate) fn generate_id(&self) -> (u64, SystemTime) {
+        self.id_generator.generate_id()
+    }
+
+    pub(crate) fn insert(
+        &mut self,
+        global_id: u64,
+        message_ref: Vec<u8>,
+        raw_message: &[u8],
+    ) -> Result<(), ConflictableTransactionError<Box<Error>>> {
+        self.tree
+            .insert(global_id.to_be_bytes().to_vec(), message_ref)?;
+        if let Some((tx_hashes, last_hash)) = &mut self.hash_chain {
+            let hash = hash_chain::next_hash(last_hash, global_id, raw_message);
+            tx_hashes.insert(global_id.to_be_bytes().to_vec(), hash.to_vec())?;
+            *last_hash = hash;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the latest hash written, if the hash chain is enabled.
+    pub(crate) fn last_hash(&self) -> Option<[u8; HASH_LEN]> {
+        self.hash_chain.map(|(_, last_hash)| last_hash)
+    }
+
+    pub(crate) fn flush(&self) {
+        self.tree.flush();
+        if let Some((tx_hashes, _)) = &self.hash_chain {
+            tx_hashes.flush();
+        }
+    }
+}
+
 pub struct GlobalEventLogIter {
     db: Db,
     inner: sled::Iter,
@@ -74,20 +360,309 @@ impl Iterator for GlobalEventLogIter {
 
     fn next(&mut self) -> Option<Self::Item> {
         self.inner.next().map(|res| {
-            res.map_err(Error::from).and_then(|(global_id, id)| {
-                let (id, stream_name) = id.split_at(8);
-                let tree = self.db.open_tree(stream_name)?;
-                let message = tree.get(id)?.ok_or_else(|| {
-                    let id = id
-                        .try_into()
-                        .map(|id| u64::from_be_bytes(id))
-                        .unwrap_or_default();
-                    let stream_name = String::from_utf8_lossy(stream_name).into_owned();
-                    Error::InvalidEventReference { id, stream_name }
-                })?;
-
-                Ok(RawMessage::new(global_id, message))
-            })
+            res.map_err(Error::from)
+                .and_then(|(global_id, message_ref)| {
+                    resolve_message(&self.db, global_id, &message_ref)
+                })
         })
     }
 }
+
+fn as_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+fn parse_id(key: &[u8]) -> Result<u64> {
+    let slice = key.try_into().map_err(|_| Error::InvalidU64Id)?;
+    Ok(u64::from_be_bytes(slice))
+}
+
+/// Reads the message referenced by a global event log entry.
+///
+/// The stream's tree is never created, as this is used by read-only stores. A
+/// missing tree is reported as an invalid reference, like a missing message.
+fn resolve_message(db: &Db, global_id: IVec, message_ref: &[u8]) -> Result<RawMessage<()>> {
+    let (id, stream_name) = message_ref.split_at(8);
+    let invalid_reference = || {
+        let id = id
+            .try_into()
+            .map(|id| u64::from_be_bytes(id))
+            .unwrap_or_default();
+        let stream_name = String::from_utf8_lossy(stream_name).into_owned();
+        Error::InvalidEventReference { id, stream_name }
+    };
+    let tree = open_existing_tree(db, stream_name)?.ok_or_else(invalid_reference)?;
+    let message = tree.get(id)?.ok_or_else(invalid_reference)?;
+
+    Ok(RawMessage::new(global_id, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::thread;
+    use std::time::Duration;
+
+    use serde_json::json;
+    use thalo::stream_name::StreamName;
+
+    use super::*;
+    use crate::MessageStore;
+
+    const STREAM_NAME: &str = "counter-1";
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn message_store(count: u64) -> Result<MessageStore> {
+        let mut message_store = MessageStore::new(sled::Config::new().temporary(true).open()?)?;
+        message_store.enable_hash_chain()?;
+        let mut stream = message_store.stream(StreamName::new(STREAM_NAME)?)?;
+        for amount in 0..count {
+            stream.write_messages(
+                &[("Incremented", Cow::Owned(json!({ "amount": amount })))],
+                None,
+            )?;
+        }
+
+        Ok(message_store)
+    }
+
+    #[test]
+    fn verifies_untampered_chain() -> Result<()> {
+        let message_store = message_store(3)?;
+        let global_event_log = message_store.global_event_log()?;
+        let checkpoint = global_event_log.checkpoint(&signing_key())?.unwrap();
+        assert_eq!(checkpoint.global_id, 2);
+
+        global_event_log.verify_checkpoint(&checkpoint, &signing_key().verifying_key())?;
+
+        message_store
+            .stream(StreamName::new(STREAM_NAME)?)?
+            .write_messages(&[("Incremented", Cow::Owned(json!({ "amount": 3 })))], None)?;
+        global_event_log.verify_checkpoint(&checkpoint, &signing_key().verifying_key())
+    }
+
+    #[test]
+    fn verifies_range_from_middle_of_chain() -> Result<()> {
+        let message_store = message_store(5)?;
+        let global_event_log = message_store.global_event_log()?;
+        global_event_log.verify_chain(2, 3)?;
+
+        // Tampering outside the range doesn't affect it.
+        let stream = global_event_log.db.open_tree(STREAM_NAME)?;
+        for id in [0u64, 4] {
+            let mut message = stream.get(id.to_be_bytes())?.unwrap().to_vec();
+            *message.last_mut().unwrap() ^= 1;
+            stream.insert(id.to_be_bytes(), message)?;
+        }
+        global_event_log.verify_chain(2, 3)?;
+
+        let res = global_event_log.verify_chain(2, 4);
+        assert!(matches!(
+            res,
+            Err(Error::HashChainMismatch { global_id: 4 })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn detects_tampered_message() -> Result<()> {
+        let message_store = message_store(3)?;
+        let global_event_log = message_store.global_event_log()?;
+        let checkpoint = global_event_log.checkpoint(&signing_key())?.unwrap();
+
+        let stream = global_event_log.db.open_tree(STREAM_NAME)?;
+        let (id, message) = stream.first()?.unwrap();
+        let mut message = message.to_vec();
+        *message.last_mut().unwrap() ^= 1;
+        stream.insert(id, message)?;
+
+        let res = global_event_log.verify_checkpoint(&checkpoint, &signing_key().verifying_key());
+        assert!(matches!(
+            res,
+            Err(Error::HashChainMismatch { global_id: 0 })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn detects_truncated_chain() -> Result<()> {
+        let message_store = message_store(3)?;
+        let global_event_log = message_store.global_event_log()?;
+        let checkpoint = global_event_log.checkpoint(&signing_key())?.unwrap();
+
+        global_event_log.remove(2u64.to_be_bytes())?;
+        global_event_log.hashes().remove(2u64.to_be_bytes())?;
+
+        let res = global_event_log.verify_checkpoint(&checkpoint, &signing_key().verifying_key());
+        assert!(matches!(
+            res,
+            Err(Error::HashChainTruncated { global_id: 2 })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn detects_rewritten_chain() -> Result<()> {
+        let checkpoint = message_store(3)?
+            .global_event_log()?
+            .checkpoint(&signing_key())?
+            .unwrap();
+
+        // A chain rebuilt from different messages is internally consistent.
+        let rewritten = message_store(2)?;
+        rewritten
+            .stream(StreamName::new(STREAM_NAME)?)?
+            .write_messages(&[("Decremented", Cow::Owned(json!({ "amount": 1 })))], None)?;
+        let res = rewritten
+            .global_event_log()?
+            .verify_checkpoint(&checkpoint, &signing_key().verifying_key());
+        assert!(matches!(
+            res,
+            Err(Error::CheckpointMismatch { global_id: 2 })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_checkpoint_signed_by_another_key() -> Result<()> {
+        let message_store = message_store(3)?;
+        let global_event_log = message_store.global_event_log()?;
+        let checkpoint = global_event_log
+            .checkpoint(&SigningKey::from_bytes(&[8; 32]))?
+            .unwrap();
+
+        let res = global_event_log.verify_checkpoint(&checkpoint, &signing_key().verifying_key());
+        assert!(matches!(res, Err(Error::InvalidCheckpointSignature)));
+        Ok(())
+    }
+
+    fn write_message(message_store: &MessageStore, amount: u64) -> Result<()> {
+        message_store
+            .stream(StreamName::new(STREAM_NAME)?)?
+            .write_messages(
+                &[("Incremented", Cow::Owned(json!({ "amount": amount })))],
+                None,
+            )?;
+        Ok(())
+    }
+
+    fn message_times(global_event_log: &GlobalEventLog) -> Result<Vec<SystemTime>> {
+        global_event_log
+            .iter_all_messages()
+            .map(|raw_message| Ok(raw_message?.message()?.time))
+            .collect()
+    }
+
+    fn global_ids(iter: GlobalEventLogIter) -> Result<Vec<u64>> {
+        iter.map(|raw_message| Ok(raw_message?.message()?.global_id))
+            .collect()
+    }
+
+    /// An hour from now, with millisecond precision as stored in messages.
+    fn in_an_hour() -> SystemTime {
+        let time = SystemTime::now() + Duration::from_secs(3600);
+        UNIX_EPOCH + Duration::from_millis(as_millis(time) as u64)
+    }
+
+    #[test]
+    fn finds_position_at_time() -> Result<()> {
+        let message_store = message_store(0)?;
+        for amount in 0..3 {
+            write_message(&message_store, amount)?;
+            thread::sleep(Duration::from_millis(2));
+        }
+        let global_event_log = message_store.global_event_log()?;
+        let times = message_times(&global_event_log)?;
+        let millis = Duration::from_millis(1);
+
+        assert_eq!(global_event_log.position_at(times[0] - millis)?, Some(0));
+        assert_eq!(global_event_log.position_at(times[1])?, Some(1));
+        assert_eq!(global_event_log.position_at(times[1] + millis)?, Some(2));
+        assert_eq!(global_event_log.position_at(times[2] + millis)?, None);
+
+        assert_eq!(
+            global_ids(global_event_log.iter_between(times[1], times[2])?)?,
+            [1]
+        );
+        assert_eq!(
+            global_ids(global_event_log.iter_between(times[0], times[2] + millis)?)?,
+            [0, 1, 2]
+        );
+        assert!(global_ids(
+            global_event_log.iter_between(times[2] + millis, times[2] + millis * 2)?
+        )?
+        .is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn orders_times_when_clock_goes_backwards() -> Result<()> {
+        let mut message_store = message_store(1)?;
+        // A message written an hour from now, after which the clock went back.
+        let future = in_an_hour();
+        message_store.access.id_generator = IdGenerator::new(Some((0, future)));
+        write_message(&message_store, 1)?;
+        write_message(&message_store, 2)?;
+        let global_event_log = message_store.global_event_log()?;
+        let times = message_times(&global_event_log)?;
+
+        assert!(times[0] < future);
+        assert_eq!(times[1..], [future, future]);
+        assert_eq!(
+            global_event_log.position_at(times[0] + Duration::from_millis(1))?,
+            Some(1)
+        );
+        assert_eq!(global_event_log.position_at(future)?, Some(1));
+        assert_eq!(
+            global_ids(global_event_log.iter_between(times[0], future)?)?,
+            [0]
+        );
+        assert_eq!(
+            global_ids(
+                global_event_log.iter_between(times[0], future + Duration::from_millis(1))?
+            )?,
+            [0, 1, 2]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_times_ordered_after_reopening() -> Result<()> {
+        let db = sled::Config::new().temporary(true).open()?;
+        let mut message_store = MessageStore::new(db.clone())?;
+        write_message(&message_store, 0)?;
+        let future = in_an_hour();
+        message_store.access.id_generator = IdGenerator::new(Some((0, future)));
+        write_message(&message_store, 1)?;
+
+        let message_store = MessageStore::new(db)?;
+        write_message(&message_store, 2)?;
+        let times = message_times(&message_store.global_event_log()?)?;
+        assert_eq!(times[1..], [future, future]);
+        Ok(())
+    }
+
+    #[test]
+    fn reports_missing_stream_without_creating_it() -> Result<()> {
+        let message_store = message_store(1)?;
+        let db = message_store.global_event_log()?.db;
+        db.drop_tree(STREAM_NAME)?;
+
+        let global_event_log = GlobalEventLog::open(db.clone())?.unwrap();
+        let res = global_event_log.get(0);
+        assert!(matches!(
+            res,
+            Err(Error::InvalidEventReference { id: 0, stream_name }) if stream_name == STREAM_NAME
+        ));
+        assert!(!db
+            .tree_names()
+            .iter()
+            .any(|name| name == STREAM_NAME.as_bytes()));
+        Ok(())
+    }
+}
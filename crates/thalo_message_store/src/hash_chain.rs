@@ -0,0 +1,128 @@
+//! Tamper-evident hash chain over the global event log.
+//!
+//! When enabled, each entry in the global event log is stored alongside a
+//! running hash, computed as:
+//!
+//! ```text
+//! hash(n) = BLAKE3(hash(n - 1) || global_id(n) as u64 big endian || message(n))
+//! ```
+//!
+//! Where `message(n)` is the raw message bytes as stored in the stream, and the
+//! hash preceding the first entry is 32 zero bytes.
+//!
+//! Altering, removing or reordering any message changes the hash of every
+//! subsequent entry, which is detected by
+//! [`GlobalEventLog::verify_chain`](crate::global_event_log::GlobalEventLog::verify_chain).
+//! Signed [`Checkpoint`]s of the latest hash can be handed to auditors, so that
+//! a chain rewritten in its entirety can also be detected by
+//! [`GlobalEventLog::verify_checkpoint`](crate::global_event_log::GlobalEventLog::verify_checkpoint).
+
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use ed25519_dalek::{Signature, Signer, Verifier};
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+pub(crate) const HASH_CHAIN_TREE: &str = "thalo:global_event_log_hashes";
+
+/// Length of a hash in the chain.
+pub const HASH_LEN: usize = blake3::OUT_LEN;
+
+/// Hash preceding the first entry in the chain.
+pub const GENESIS_HASH: [u8; HASH_LEN] = [0; HASH_LEN];
+
+/// The latest hash in the chain, shared between writers.
+///
+/// Writers hold the lock for the duration of their transaction, so global IDs
+/// are committed in the same order they are chained.
+#[derive(Clone)]
+pub(crate) struct HashChain {
+    last_hash: Arc<Mutex<[u8; HASH_LEN]>>,
+}
+
+impl HashChain {
+    pub(crate) fn new(last_hash: [u8; HASH_LEN]) -> Self {
+        HashChain {
+            last_hash: Arc::new(Mutex::new(last_hash)),
+        }
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, [u8; HASH_LEN]> {
+        // The hash is only updated once a transaction is committed, so it remains valid
+        // even if a writer panicked.
+        self.last_hash
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Computes the next hash in the chain.
+pub fn next_hash(last_hash: &[u8; HASH_LEN], global_id: u64, message: &[u8]) -> [u8; HASH_LEN] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(last_hash);
+    hasher.update(&global_id.to_be_bytes());
+    hasher.update(message);
+    *hasher.finalize().as_bytes()
+}
+
+pub(crate) fn parse_hash(hash: &[u8]) -> Result<[u8; HASH_LEN]> {
+    hash.try_into().map_err(|_| Error::InvalidHash)
+}
+
+/// A signed statement of the hash chain at a given global ID.
+///
+/// The signed message is the global ID as a big endian `u64`, followed by the
+/// hash. Checkpoints serialize with hex encoded bytes, and can be verified
+/// independently with any ed25519 implementation.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Global ID of the last entry covered by the checkpoint.
+    pub global_id: u64,
+    /// Hash of the chain at `global_id`.
+    #[serde(with = "hex")]
+    pub hash: [u8; HASH_LEN],
+    /// Public key of the signer.
+    #[serde(with = "hex")]
+    pub public_key: [u8; 32],
+    /// Ed25519 signature of the checkpoint.
+    #[serde(with = "hex")]
+    pub signature: [u8; 64],
+}
+
+impl Checkpoint {
+    /// Signs a checkpoint of the hash chain at `global_id`.
+    pub fn sign(global_id: u64, hash: [u8; HASH_LEN], signing_key: &SigningKey) -> Self {
+        let signature = signing_key.sign(&Self::signed_message(global_id, &hash));
+        Checkpoint {
+            global_id,
+            hash,
+            public_key: signing_key.verifying_key().to_bytes(),
+            signature: signature.to_bytes(),
+        }
+    }
+
+    /// Verifies the checkpoint was signed by `verifying_key`.
+    ///
+    /// The embedded public key is not trusted, and must match the key given.
+    pub fn verify(&self, verifying_key: &VerifyingKey) -> Result<()> {
+        if self.public_key != verifying_key.to_bytes() {
+            return Err(Error::InvalidCheckpointSignature);
+        }
+
+        verifying_key
+            .verify(
+                &Self::signed_message(self.global_id, &self.hash),
+                &Signature::from_bytes(&self.signature),
+            )
+            .map_err(|_| Error::InvalidCheckpointSignature)
+    }
+
+    fn signed_message(global_id: u64, hash: &[u8; HASH_LEN]) -> Vec<u8> {
+        let mut message = Vec::with_capacity(8 + HASH_LEN);
+        message.extend_from_slice(&global_id.to_be_bytes());
+        message.extend_from_slice(hash);
+        message
+    }
+}
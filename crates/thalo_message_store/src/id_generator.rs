@@ -1,19 +1,70 @@
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
 
+/// Generates global IDs, along with the times of their messages.
+///
+/// Times are allocated in the same order as IDs, and never go backwards even
+/// if the system clock does. This keeps message times ordered by global ID,
+/// which [`GlobalEventLog::position_at`](crate::global_event_log::GlobalEventLog::position_at)
+/// relies on.
 #[derive(Clone)]
 pub struct IdGenerator {
-    next_id: Arc<AtomicU64>,
+    next: Arc<Mutex<(u64, SystemTime)>>,
 }
 
 impl IdGenerator {
-    pub fn new(last_id: Option<u64>) -> Self {
+    /// Creates a generator following the last message written, if any.
+    pub fn new(last: Option<(u64, SystemTime)>) -> Self {
+        let next = last
+            .map(|(id, time)| (id + 1, time))
+            .unwrap_or((0, SystemTime::UNIX_EPOCH));
         IdGenerator {
-            next_id: Arc::new(AtomicU64::new(last_id.map(|id| id + 1).unwrap_or(0))),
+            next: Arc::new(Mutex::new(next)),
         }
     }
 
-    pub fn generate_id(&self) -> u64 {
-        self.next_id.fetch_add(1, Ordering::SeqCst)
+    /// Generates the next global ID, and the time of its message.
+    ///
+    /// The time is the current time, or the time of the previous ID if the
+    /// clock went backwards since.
+    pub fn generate_id(&self) -> (u64, SystemTime) {
+        // The state is updated in a single assignment, so it remains valid even
+        // if a caller panicked.
+        let mut next = self.next.lock().unwrap_or_else(PoisonError::into_inner);
+        let (id, last_time) = *next;
+        // Times are stored with millisecond precision, so truncate them now to
+        // compare them as they will be read.
+        let time = truncate_millis(SystemTime::now()).max(last_time);
+        *next = (id + 1, time);
+        (id, time)
+    }
+}
+
+fn truncate_millis(time: SystemTime) -> SystemTime {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    SystemTime::UNIX_EPOCH + Duration::from_millis(since_epoch.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+
+    #[test]
+    fn generates_sequential_ids() {
+        let id_generator = IdGenerator::new(Some((4, SystemTime::UNIX_EPOCH)));
+        assert_eq!(id_generator.generate_id().0, 5);
+        assert_eq!(id_generator.generate_id().0, 6);
+    }
+
+    #[test]
+    fn clamps_time_when_clock_goes_backwards() {
+        let future = truncate_millis(SystemTime::now() + Duration::from_secs(3600));
+        let id_generator = IdGenerator::new(Some((0, future)));
+        assert_eq!(id_generator.generate_id(), (1, future));
+        assert_eq!(id_generator.generate_id(), (2, future));
     }
 }
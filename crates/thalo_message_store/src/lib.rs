@@ -1,10 +1,15 @@
+pub mod access;
+pub mod command_outbox;
 pub mod error;
+pub mod format;
 pub mod global_event_log;
+pub mod hash_chain;
 mod id_generator;
 pub mod message;
 mod message_store;
 pub mod outbox;
 pub mod projection;
+pub mod scheduled_commands;
 pub mod stream;
 
 pub use message_store::*;
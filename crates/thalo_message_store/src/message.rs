@@ -53,6 +53,11 @@ pub struct Message<'a, T = ()> {
     /// Time message was saved to the message store.
     #[serde(with = "ts_milliseconds")]
     pub time: SystemTime,
+    /// Message metadata, such as the module version which produced an event.
+    ///
+    /// This is `null` if the message was written without metadata.
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub metadata: Cow<'a, serde_json::Value>,
     /// Marker type for the event.
     #[serde(skip)]
     pub _marker: PhantomData<T>,
@@ -84,6 +89,7 @@ impl<'a, T> Message<'a, T> {
             msg_type: self.msg_type,
             data: self.data,
             time: self.time,
+            metadata: self.metadata,
             _marker: PhantomData,
         }
     }
@@ -97,6 +103,7 @@ impl<'a, T> Message<'a, T> {
             msg_type: Cow::Owned(self.msg_type.into_owned()),
             data: Cow::Owned(self.data.into_owned()),
             time: self.time,
+            metadata: Cow::Owned(self.metadata.into_owned()),
             _marker: self._marker,
         }
     }
@@ -1,55 +1,245 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::path::Path;
 
-use sled::{Db, Mode};
+use sled::transaction::{ConflictableTransactionError, Transactional};
+use sled::{Db, Mode, Tree};
 use thalo::stream_name::{Category, StreamName};
 
-use crate::error::Result;
-use crate::global_event_log::GlobalEventLog;
+use crate::access::{Access, ReadOnly, ReadWrite};
+use crate::command_outbox::{CommandOutbox, COMMAND_OUTBOX_TREE};
+use crate::error::{Error, Result};
+use crate::format::{self, Migrated};
+use crate::global_event_log::{GlobalEventLog, GlobalEventLogTx};
+use crate::hash_chain::{HashChain, GENESIS_HASH};
 use crate::id_generator::IdGenerator;
+use crate::message::Message;
 use crate::outbox::Outbox;
 use crate::projection::{Projection, PROJECTION_POSITIONS_TREE};
-use crate::stream::Stream;
+use crate::scheduled_commands::{
+    ScheduledCommands, SCHEDULED_COMMANDS_DUE_TREE, SCHEDULED_COMMANDS_TREE,
+};
+use crate::stream::{Stream, StreamTx, STREAM_HANDLED_TREE, STREAM_VERSIONS_TREE};
 
+/// A write to a single stream as part of [`MessageStore::write_multi`].
+///
+/// Contains the stream name, messages, the metadata of each message, and
+/// expected starting version.
+pub type MultiStreamWrite<'a> = (
+    StreamName<'a>,
+    &'a [(&'a str, Cow<'a, serde_json::Value>)],
+    Cow<'a, serde_json::Value>,
+    Option<u64>,
+);
+
+/// A message store backed by a sled database.
+///
+/// Stores opened with [`MessageStore::open_read_only`] have the [`ReadOnly`]
+/// access mode, and lack any methods which write to the store.
 #[derive(Clone)]
-pub struct MessageStore {
+pub struct MessageStore<A: Access = ReadWrite> {
     db: Db,
-    id_generator: IdGenerator,
+    pub(crate) access: A,
 }
 
 impl MessageStore {
+    /// Creates a message store from a sled database.
+    ///
+    /// The hash chain is enabled automatically if it was previously enabled
+    /// and contains entries.
+    ///
+    /// Fails if the store's format version differs from
+    /// [`FORMAT_VERSION`](crate::format::FORMAT_VERSION). Older stores can be
+    /// upgraded with [`MessageStore::migrate`].
     pub fn new(db: Db) -> Result<Self> {
-        let global_event_log = GlobalEventLog::new(db)?;
-        let last_id = global_event_log.last_position()?;
-        let id_generator = IdGenerator::new(last_id);
+        format::check(&db)?;
+        let global_event_log: GlobalEventLog = GlobalEventLog::new(db)?;
+        let last_message = global_event_log
+            .last_position()?
+            .map(|id| global_event_log.get(id))
+            .transpose()?
+            .flatten();
+        let last = last_message
+            .map(|raw_message| -> Result<_> {
+                let message = raw_message.message()?;
+                Ok((message.global_id, message.time))
+            })
+            .transpose()?;
+        let id_generator = IdGenerator::new(last);
+        let hash_chain = global_event_log
+            .last_hash()?
+            .map(|(_, last_hash)| HashChain::new(last_hash));
 
         Ok(MessageStore {
             db: global_event_log.db,
-            id_generator,
+            access: ReadWrite {
+                id_generator,
+                hash_chain,
+            },
         })
     }
 
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
-        let db = sled::Config::new()
-            .flush_every_ms(None)
-            .mode(Mode::LowSpace)
-            .path(path)
-            .open()?;
+        let db = db_config(path.as_ref()).open()?;
         MessageStore::new(db)
     }
 
+    /// Upgrades the store at `path` to the current format version in place.
+    ///
+    /// See [`format::migrate`] for details.
+    pub fn migrate(path: impl AsRef<Path>) -> Result<Migrated> {
+        let db = open_existing(path.as_ref())?;
+        format::migrate(&db)
+    }
+
+    /// Copies the store at `path` into `new_path`, and upgrades the copy to the
+    /// current format version.
+    ///
+    /// See [`format::migrate_into`] for details.
+    pub fn migrate_into(path: impl AsRef<Path>, new_path: impl AsRef<Path>) -> Result<Migrated> {
+        let path = path.as_ref();
+        let db = open_existing(path)?;
+        format::migrate_into(&db, path, new_path.as_ref())
+    }
+
+    /// Enables the tamper-evident hash chain for newly written messages.
+    ///
+    /// See the [`hash_chain`](crate::hash_chain) module for details.
+    pub fn enable_hash_chain(&mut self) -> Result<()> {
+        if self.access.hash_chain.is_none() {
+            let last_hash = self
+                .global_event_log()?
+                .last_hash()?
+                .map(|(_, last_hash)| last_hash)
+                .unwrap_or(GENESIS_HASH);
+            self.access.hash_chain = Some(HashChain::new(last_hash));
+        }
+
+        Ok(())
+    }
+
+    pub fn is_hash_chain_enabled(&self) -> bool {
+        self.access.hash_chain.is_some()
+    }
+
+    /// Writes messages to multiple streams in a single transaction.
+    ///
+    /// Each entry contains the stream name, the messages to write, their
+    /// metadata, and the expected starting version of the stream, with the
+    /// same semantics as [`Stream::write_messages_with_metadata`]. Either all
+    /// messages are written, or none are.
+    ///
+    /// The returned messages are grouped in the same order as `streams`.
+    pub fn write_multi<'b>(
+        &self,
+        streams: &[MultiStreamWrite<'b>],
+    ) -> Result<Vec<Vec<Message<'b>>>> {
+        let mut seen = HashSet::with_capacity(streams.len());
+        let mut trees = Vec::with_capacity(streams.len() + 2);
+        let mut unindexed_versions = Vec::with_capacity(streams.len());
+        for (stream_name, ..) in streams {
+            if !seen.insert(stream_name) {
+                return Err(Error::DuplicateStream {
+                    stream_name: stream_name.to_string(),
+                });
+            }
+
+            let stream = self.stream(stream_name.as_borrowed())?;
+            unindexed_versions.push(stream.calculate_latest_version());
+            trees.push(Tree::clone(&stream));
+        }
+
+        trees.push(self.db.open_tree(STREAM_VERSIONS_TREE)?);
+        let global_event_log = self.global_event_log()?;
+        trees.push(Tree::clone(&global_event_log));
+        trees.push(global_event_log.hashes().clone());
+
+        let mut last_hash = self.access.hash_chain.as_ref().map(HashChain::lock);
+
+        let (written_messages, new_last_hash) = trees.as_slice().transaction(|tx_trees| {
+            let [tx_streams @ .., tx_versions, tx_global_event_log, tx_hashes] =
+                tx_trees.as_slice()
+            else {
+                unreachable!("transaction contains the versions, global event log and hash trees");
+            };
+            let mut tx_global_event_log = GlobalEventLogTx::new(
+                tx_global_event_log,
+                &self.access.id_generator,
+                last_hash
+                    .as_deref()
+                    .map(|last_hash| (tx_hashes, *last_hash)),
+            );
+            let mut written_messages = Vec::with_capacity(streams.len());
+
+            for (
+                ((stream_name, messages, metadata, expected_starting_version), tx_stream),
+                unindexed_version,
+            ) in streams.iter().zip(tx_streams).zip(&unindexed_versions)
+            {
+                let tx_stream = StreamTx::new(tx_stream, tx_versions, *unindexed_version);
+                let messages = Stream::write_messages_in_tx(
+                    &tx_stream,
+                    &mut tx_global_event_log,
+                    stream_name.clone(),
+                    messages,
+                    metadata,
+                    *expected_starting_version,
+                )
+                .map_err(ConflictableTransactionError::Abort)?;
+                written_messages.push(messages);
+            }
+
+            for tx_stream in tx_streams {
+                tx_stream.flush();
+            }
+            tx_versions.flush();
+            tx_global_event_log.flush();
+
+            Ok((written_messages, tx_global_event_log.last_hash()))
+        })?;
+
+        if let (Some(last_hash), Some(new_last_hash)) = (&mut last_hash, new_last_hash) {
+            **last_hash = new_last_hash;
+        }
+
+        Ok(written_messages)
+    }
+
     pub fn global_event_log(&self) -> Result<GlobalEventLog> {
         GlobalEventLog::new(self.db.clone())
     }
 
     pub fn stream<'a>(&self, stream_name: StreamName<'a>) -> Result<Stream<'a>> {
         Ok(Stream::new(
-            self.id_generator.clone(),
+            self.access.clone(),
             self.db.open_tree(stream_name.as_bytes())?,
+            Some(self.db.open_tree(STREAM_VERSIONS_TREE)?),
+            Some(self.db.open_tree(STREAM_HANDLED_TREE)?),
             self.global_event_log()?,
             stream_name,
         ))
     }
 
+    pub fn outbox(&self, category: Category<'_>) -> Result<Outbox> {
+        let tree_name = Category::from_parts(category, &["outbox"])?;
+        let tree = self.db.open_tree(tree_name.as_bytes())?;
+        let outbox = Outbox::new(tree);
+        Ok(outbox)
+    }
+
+    /// Returns the outbox of commands emitted by aggregates.
+    pub fn command_outbox(&self) -> Result<CommandOutbox> {
+        let tree = self.db.open_tree(COMMAND_OUTBOX_TREE)?;
+        Ok(CommandOutbox::new(tree))
+    }
+
+    /// Returns the commands scheduled for future delivery.
+    pub fn scheduled_commands(&self) -> Result<ScheduledCommands> {
+        let tree = self.db.open_tree(SCHEDULED_COMMANDS_TREE)?;
+        let due_tree = self.db.open_tree(SCHEDULED_COMMANDS_DUE_TREE)?;
+        Ok(ScheduledCommands::new(tree, due_tree))
+    }
+
     pub fn projection(&self, name: impl Into<String>) -> Result<Projection> {
         Projection::new(&self.db, name.into())
     }
@@ -59,10 +249,301 @@ impl MessageStore {
         Ok(tree.flush_async().await?)
     }
 
-    pub fn outbox(&self, category: Category<'_>) -> Result<Outbox> {
+    /// Flushes all dirty trees to disk, returning the number of bytes flushed.
+    pub async fn flush(&self) -> Result<usize> {
+        Ok(self.db.flush_async().await?)
+    }
+}
+
+impl MessageStore<ReadOnly> {
+    /// Opens an existing message store in read-only mode.
+    ///
+    /// No ID generator is constructed, and the returned store has no methods
+    /// for writing messages, deleting outbox messages, or acknowledging
+    /// projection events.
+    ///
+    /// Sled holds an exclusive lock on the database directory, so a store
+    /// cannot be opened by multiple processes at once. To read a production
+    /// store while it is running, open a copy of its directory. Within a
+    /// process, clones of the store share the same database and may be read
+    /// concurrently.
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self> {
+        let db = open_existing(path.as_ref())?;
+        format::check_read_only(&db)?;
+
+        Ok(MessageStore {
+            db,
+            access: ReadOnly,
+        })
+    }
+
+    /// Returns the global event log, or `None` if no messages were ever
+    /// written.
+    ///
+    /// Like the other accessors of a read-only store, this never creates the
+    /// trees it reads.
+    pub fn global_event_log(&self) -> Result<Option<GlobalEventLog<ReadOnly>>> {
+        GlobalEventLog::open(self.db.clone())
+    }
+
+    /// Returns a stream, or `None` if no messages were written to it.
+    pub fn stream<'a>(&self, stream_name: StreamName<'a>) -> Result<Option<Stream<'a, ReadOnly>>> {
+        let Some(tree) = open_existing_tree(&self.db, stream_name.as_bytes())? else {
+            return Ok(None);
+        };
+        let Some(global_event_log) = self.global_event_log()? else {
+            return Ok(None);
+        };
+
+        Ok(Some(Stream::new(
+            self.access,
+            tree,
+            open_existing_tree(&self.db, STREAM_VERSIONS_TREE)?,
+            open_existing_tree(&self.db, STREAM_HANDLED_TREE)?,
+            global_event_log,
+            stream_name,
+        )))
+    }
+
+    /// Returns a category's outbox, or `None` if it doesn't exist.
+    pub fn outbox(&self, category: Category<'_>) -> Result<Option<Outbox<ReadOnly>>> {
         let tree_name = Category::from_parts(category, &["outbox"])?;
-        let tree = self.db.open_tree(tree_name.as_bytes())?;
-        let outbox = Outbox::new(tree);
-        Ok(outbox)
+        Ok(open_existing_tree(&self.db, tree_name.as_bytes())?.map(Outbox::new))
+    }
+
+    /// Returns the outbox of commands emitted by aggregates, or `None` if it
+    /// doesn't exist.
+    pub fn command_outbox(&self) -> Result<Option<CommandOutbox<ReadOnly>>> {
+        Ok(open_existing_tree(&self.db, COMMAND_OUTBOX_TREE)?.map(CommandOutbox::new))
+    }
+
+    /// Returns the commands scheduled for future delivery, or `None` if no
+    /// command was ever scheduled.
+    pub fn scheduled_commands(&self) -> Result<Option<ScheduledCommands<ReadOnly>>> {
+        let tree = open_existing_tree(&self.db, SCHEDULED_COMMANDS_TREE)?;
+        let due_tree = open_existing_tree(&self.db, SCHEDULED_COMMANDS_DUE_TREE)?;
+        Ok(tree
+            .zip(due_tree)
+            .map(|(tree, due_tree)| ScheduledCommands::new(tree, due_tree)))
+    }
+
+    /// Returns a projection's position, or `None` if it has not acknowledged
+    /// any events.
+    pub fn projection(&self, name: impl Into<String>) -> Result<Option<Projection<ReadOnly>>> {
+        Projection::load(&self.db, name.into())
+    }
+}
+
+impl<A: Access> MessageStore<A> {
+    /// Returns the names of the entity streams in a category, in
+    /// lexicographic order.
+    ///
+    /// Streams of categories with types, such as `account:command-123` for
+    /// the `account` category, are not included.
+    pub fn stream_names(&self, category: &Category<'_>) -> Vec<StreamName<'static>> {
+        let prefix = format!("{category}{}", StreamName::ID_SEPARATOR);
+        let mut tree_names: Vec<_> = self
+            .db
+            .tree_names()
+            .into_iter()
+            .filter_map(|tree_name| String::from_utf8(tree_name.to_vec()).ok())
+            .filter(|tree_name| tree_name.starts_with(&prefix))
+            .collect();
+        tree_names.sort_unstable();
+
+        tree_names
+            .into_iter()
+            .filter_map(|tree_name| StreamName::new(tree_name).ok())
+            .collect()
+    }
+}
+
+pub(crate) fn db_config(path: &Path) -> sled::Config {
+    sled::Config::new()
+        .flush_every_ms(None)
+        .mode(Mode::LowSpace)
+        .path(path)
+}
+
+/// Opens a tree, returning `None` rather than creating it if it does not
+/// already exist.
+pub(crate) fn open_existing_tree(db: &Db, name: impl AsRef<[u8]>) -> Result<Option<Tree>> {
+    let name = name.as_ref();
+    if !db.tree_names().iter().any(|tree_name| tree_name == name) {
+        return Ok(None);
+    }
+
+    Ok(Some(db.open_tree(name)?))
+}
+
+/// Opens a database, failing if it does not already exist.
+fn open_existing(path: &Path) -> Result<Db> {
+    if !path.join("conf").is_file() {
+        return Err(Error::StoreNotFound {
+            path: path.to_path_buf(),
+        });
+    }
+
+    Ok(db_config(path).open()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use sled::transaction::TransactionError;
+
+    use super::*;
+
+    fn message_store() -> Result<MessageStore> {
+        MessageStore::new(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn incremented(amount: u64) -> (&'static str, Cow<'static, serde_json::Value>) {
+        ("Incremented", Cow::Owned(json!({ "amount": amount })))
+    }
+
+    #[test]
+    fn writes_each_stream_from_its_own_version() -> Result<()> {
+        let message_store = message_store()?;
+        message_store
+            .stream(StreamName::new("counter-1")?)?
+            .write_messages(&[incremented(0), incremented(1)], None)?;
+
+        let counter_1 = [incremented(2)];
+        let counter_2 = [incremented(0), incremented(1)];
+        let written = message_store.write_multi(&[
+            (
+                StreamName::new("counter-1")?,
+                &counter_1,
+                Cow::Owned(json!({ "user": "alice" })),
+                Some(1),
+            ),
+            (
+                StreamName::new("counter-2")?,
+                &counter_2,
+                Cow::Owned(serde_json::Value::Null),
+                None,
+            ),
+        ])?;
+
+        let positions: Vec<Vec<u64>> = written
+            .iter()
+            .map(|messages| messages.iter().map(|message| message.position).collect())
+            .collect();
+        assert_eq!(positions, [vec![2], vec![0, 1]]);
+        assert_eq!(*written[0][0].metadata, json!({ "user": "alice" }));
+        assert_eq!(*written[1][0].metadata, serde_json::Value::Null);
+        assert_eq!(
+            message_store
+                .stream(StreamName::new("counter-1")?)?
+                .version()?,
+            Some(2)
+        );
+        assert_eq!(
+            message_store
+                .stream(StreamName::new("counter-2")?)?
+                .version()?,
+            Some(1)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn writes_global_event_log_in_order_of_streams() -> Result<()> {
+        let message_store = message_store()?;
+        message_store.write_multi(&[
+            (
+                StreamName::new("counter-2")?,
+                &[incremented(0)],
+                Cow::Owned(serde_json::Value::Null),
+                None,
+            ),
+            (
+                StreamName::new("counter-1")?,
+                &[incremented(0), incremented(1)],
+                Cow::Owned(serde_json::Value::Null),
+                None,
+            ),
+        ])?;
+
+        let messages = message_store
+            .global_event_log()?
+            .iter_all_messages()
+            .map(|raw_message| {
+                let raw_message = raw_message?;
+                let message = raw_message.message()?;
+                Ok((
+                    message.global_id,
+                    message.stream_name.to_string(),
+                    message.position,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            messages,
+            [
+                (0, "counter-2".to_string(), 0),
+                (1, "counter-1".to_string(), 0),
+                (2, "counter-1".to_string(), 1),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn writes_nothing_if_any_expected_version_mismatches() -> Result<()> {
+        let message_store = message_store()?;
+        message_store
+            .stream(StreamName::new("counter-2")?)?
+            .write_messages(&[incremented(0)], None)?;
+
+        let counter_1 = [incremented(0)];
+        let counter_2 = [incremented(1)];
+        let res = message_store.write_multi(&[
+            (
+                StreamName::new("counter-1")?,
+                &counter_1,
+                Cow::Owned(serde_json::Value::Null),
+                None,
+            ),
+            (
+                StreamName::new("counter-2")?,
+                &counter_2,
+                Cow::Owned(serde_json::Value::Null),
+                Some(5),
+            ),
+        ]);
+
+        let Err(Error::DatabaseTransaction(TransactionError::Abort(
+            ConflictableTransactionError::Abort(err),
+        ))) = res
+        else {
+            panic!("expected the transaction to abort");
+        };
+        assert!(matches!(
+            *err,
+            Error::WrongExpectedVersion {
+                expected_version: 5,
+                stream_version: Some(0),
+                ..
+            }
+        ));
+        assert!(message_store
+            .stream(StreamName::new("counter-1")?)?
+            .is_empty());
+        assert_eq!(
+            message_store
+                .stream(StreamName::new("counter-2")?)?
+                .version()?,
+            Some(0)
+        );
+        assert_eq!(
+            message_store
+                .global_event_log()?
+                .iter_all_messages()
+                .count(),
+            1
+        );
+        Ok(())
     }
 }
@@ -1,22 +1,31 @@
+use std::marker::PhantomData;
+
 use sled::{Batch, IVec, Tree};
 
+use crate::access::{Access, ReadWrite};
 use crate::error::Result;
 use crate::stream::MessageIter;
 
 #[derive(Clone)]
-pub struct Outbox {
+pub struct Outbox<A: Access = ReadWrite> {
     tree: Tree,
+    _access: PhantomData<A>,
 }
 
-impl Outbox {
+impl<A: Access> Outbox<A> {
     pub(crate) fn new(tree: Tree) -> Self {
-        Outbox { tree }
+        Outbox {
+            tree,
+            _access: PhantomData,
+        }
     }
 
     pub fn iter_all_messages<T>(&self) -> MessageIter<T> {
         MessageIter::new(self.tree.iter())
     }
+}
 
+impl Outbox {
     pub fn delete_batch(&self, ids: Vec<IVec>) -> Result<()> {
         let mut batch = Batch::default();
         for id in ids {
@@ -1,25 +1,56 @@
+use std::marker::PhantomData;
 use std::ops;
 
 use serde::{Deserialize, Serialize};
 use sled::{Db, IVec, Tree};
 
+use crate::access::{Access, ReadOnly, ReadWrite};
 use crate::error::{Error, Result};
+use crate::message_store::open_existing_tree;
 
 pub(crate) const PROJECTION_POSITIONS_TREE: &str = "thalo:projection_positions";
 
 #[derive(Clone)]
-pub struct Projection {
+pub struct Projection<A: Access = ReadWrite> {
     tree: Tree,
     name: String,
     id: u64,
     last_seen_event_id: Option<u64>,
     last_relevant_event_id: Option<u64>,
+    _access: PhantomData<A>,
 }
 
 impl Projection {
     pub(crate) fn new(db: &Db, name: String) -> Result<Self> {
         let tree = db.open_tree(PROJECTION_POSITIONS_TREE)?;
 
+        match Self::find(&tree, &name)? {
+            Some(projection) => Ok(projection),
+            None => Ok(Projection {
+                tree,
+                name,
+                id: db.generate_id()?,
+                last_seen_event_id: None,
+                last_relevant_event_id: None,
+                _access: PhantomData,
+            }),
+        }
+    }
+}
+
+impl Projection<ReadOnly> {
+    /// Loads an existing projection, returning `None` if it has not
+    /// acknowledged any events.
+    pub(crate) fn load(db: &Db, name: String) -> Result<Option<Self>> {
+        let Some(tree) = open_existing_tree(db, PROJECTION_POSITIONS_TREE)? else {
+            return Ok(None);
+        };
+        Self::find(&tree, &name)
+    }
+}
+
+impl<A: Access> Projection<A> {
+    fn find(tree: &Tree, name: &str) -> Result<Option<Self>> {
         let res = ProjectionPositionIter::new(tree.iter())
             .find_map(|res| {
                 res.and_then(|raw_projection_data| {
@@ -42,20 +73,16 @@ impl Projection {
             })
             .transpose()?;
 
-        let (id, last_seen_event_id, last_relevant_event_id) = match res {
-            Some((id, last_seen_event_id, last_relevant_event_id)) => {
-                (id, Some(last_seen_event_id), last_relevant_event_id)
-            }
-            None => (db.generate_id()?, None, None),
-        };
-
-        Ok(Projection {
-            tree,
-            name,
-            id,
-            last_seen_event_id,
-            last_relevant_event_id,
-        })
+        Ok(res.map(
+            |(id, last_seen_event_id, last_relevant_event_id)| Projection {
+                tree: tree.clone(),
+                name: name.to_string(),
+                id,
+                last_seen_event_id: Some(last_seen_event_id),
+                last_relevant_event_id,
+                _access: PhantomData,
+            },
+        ))
     }
 
     pub fn last_seen_event_id(&self) -> Option<u64> {
@@ -65,7 +92,9 @@ impl Projection {
     pub fn last_relevant_event_id(&self) -> Option<u64> {
         self.last_relevant_event_id
     }
+}
 
+impl Projection {
     pub fn acknowledge_event(&mut self, position: u64, is_relevant: bool) -> Result<()> {
         let new_last_seen_event_id = position;
         let new_last_relevant_event_id = if is_relevant {
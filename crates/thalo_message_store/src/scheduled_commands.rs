@@ -0,0 +1,404 @@
+//! Commands scheduled for delivery at a future time.
+//!
+//! Each scheduled command is identified by a key, which is unique within the
+//! stream which scheduled it, or among commands scheduled through the
+//! runtime's API, and scheduling a command with an existing key replaces it.
+//! Commands are indexed by the time they're due, so they can be delivered in
+//! order, and are removed once they've been delivered or cancelled.
+
+use std::borrow::Cow;
+use std::marker::PhantomData;
+
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+use sled::Tree;
+use thalo::stream_name::StreamName;
+
+use crate::access::{Access, ReadWrite};
+use crate::error::{Error, Result};
+
+pub(crate) const SCHEDULED_COMMANDS_TREE: &str = "thalo:scheduled_commands";
+pub(crate) const SCHEDULED_COMMANDS_DUE_TREE: &str = "thalo:scheduled_commands_due";
+
+/// Separates the stream which scheduled a command from its key.
+const KEY_SEPARATOR: u8 = b'\0';
+
+/// Returns whether a key can be used to schedule a command through the
+/// runtime's API.
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && !key.as_bytes().contains(&KEY_SEPARATOR)
+}
+
+/// A command scheduled for delivery.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ScheduledCommand<'a> {
+    /// Stream which scheduled the command, or `None` if it was scheduled
+    /// through the runtime's API.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scheduled_by: Option<StreamName<'a>>,
+    pub key: Cow<'a, str>,
+    /// Time the command is due, in milliseconds since the unix epoch.
+    pub due: u64,
+    /// Stream name of the entity the command is sent to.
+    pub stream_name: StreamName<'a>,
+    pub command: Cow<'a, str>,
+    pub payload: Cow<'a, serde_json::Value>,
+    /// Metadata the command is executed with.
+    pub metadata: Cow<'a, serde_json::Value>,
+    /// Number of times the command failed to be delivered.
+    #[serde(default)]
+    pub failed_attempts: u32,
+}
+
+impl ScheduledCommand<'_> {
+    pub fn into_owned(self) -> ScheduledCommand<'static> {
+        ScheduledCommand {
+            scheduled_by: self.scheduled_by.map(StreamName::into_owned),
+            key: Cow::Owned(self.key.into_owned()),
+            due: self.due,
+            stream_name: self.stream_name.into_owned(),
+            command: Cow::Owned(self.command.into_owned()),
+            payload: Cow::Owned(self.payload.into_owned()),
+            metadata: Cow::Owned(self.metadata.into_owned()),
+            failed_attempts: self.failed_attempts,
+        }
+    }
+
+    fn command_key(&self) -> Vec<u8> {
+        command_key(self.scheduled_by.as_ref(), &self.key)
+    }
+}
+
+#[derive(Clone)]
+pub struct ScheduledCommands<A: Access = ReadWrite> {
+    /// Commands by scheduling stream and key.
+    tree: Tree,
+    /// Command keys by due time and command key.
+    due_tree: Tree,
+    _access: PhantomData<A>,
+}
+
+impl<A: Access> ScheduledCommands<A> {
+    pub(crate) fn new(tree: Tree, due_tree: Tree) -> Self {
+        ScheduledCommands {
+            tree,
+            due_tree,
+            _access: PhantomData,
+        }
+    }
+
+    /// Returns the command scheduled with a key.
+    pub fn get(
+        &self,
+        scheduled_by: Option<&StreamName<'_>>,
+        key: &str,
+    ) -> Result<Option<ScheduledCommand<'static>>> {
+        self.tree
+            .get(command_key(scheduled_by, key))?
+            .map(|value| deserialize_command(&value))
+            .transpose()
+    }
+
+    /// Iterates over the scheduled commands in the order they're due.
+    pub fn iter(&self) -> impl Iterator<Item = Result<ScheduledCommand<'static>>> + '_ {
+        self.due_tree.iter().filter_map(|res| {
+            let (due_key, _) = match res {
+                Ok(entry) => entry,
+                Err(err) => return Some(Err(err.into())),
+            };
+            // Commands cancelled or completed while iterating are skipped.
+            match self.tree.get(&due_key[8..]) {
+                Ok(value) => value.map(|value| deserialize_command(&value)),
+                Err(err) => Some(Err(err.into())),
+            }
+        })
+    }
+
+    /// Returns the command due soonest.
+    pub fn next_due(&self) -> Result<Option<ScheduledCommand<'static>>> {
+        self.iter().next().transpose()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+}
+
+impl ScheduledCommands {
+    /// Schedules a command, replacing any command scheduled by the same stream
+    /// with the same key.
+    pub fn schedule(&self, command: &ScheduledCommand<'_>) -> Result<()> {
+        let key = command.command_key();
+        let value = serde_cbor::to_vec(command).map_err(Error::SerializeData)?;
+        (&self.tree, &self.due_tree)
+            .transaction(|(tx_tree, tx_due_tree)| {
+                if let Some(previous) = tx_tree.insert(key.as_slice(), value.as_slice())? {
+                    let previous = deserialize_command(&previous)
+                        .map_err(|err| ConflictableTransactionError::Abort(Box::new(err)))?;
+                    tx_due_tree.remove(due_key(previous.due, &key))?;
+                }
+                tx_due_tree.insert(due_key(command.due, &key), &[])?;
+                Ok(())
+            })
+            .map_err(from_transaction_error)
+    }
+
+    /// Cancels a scheduled command, returning it if it was scheduled.
+    pub fn cancel(
+        &self,
+        scheduled_by: Option<&StreamName<'_>>,
+        key: &str,
+    ) -> Result<Option<ScheduledCommand<'static>>> {
+        self.remove_if(&command_key(scheduled_by, key), |_| true)
+    }
+
+    /// Removes a delivered command, unless it was rescheduled with a different
+    /// due time since it was delivered.
+    pub fn complete(&self, command: &ScheduledCommand<'_>) -> Result<bool> {
+        Ok(self
+            .remove_if(&command.command_key(), |scheduled| {
+                scheduled.due == command.due
+            })?
+            .is_some())
+    }
+
+    /// Counts a failed attempt to deliver a command, unless it was rescheduled
+    /// with a different due time since it was delivered.
+    ///
+    /// Returns whether the attempt was counted.
+    pub fn record_failure(&self, command: &ScheduledCommand<'_>) -> Result<bool> {
+        let key = command.command_key();
+        self.tree
+            .transaction(|tx_tree| {
+                let Some(value) = tx_tree.get(&key)? else {
+                    return Ok(false);
+                };
+                let mut scheduled = deserialize_command(&value)
+                    .map_err(|err| ConflictableTransactionError::Abort(Box::new(err)))?;
+                if scheduled.due != command.due {
+                    return Ok(false);
+                }
+                scheduled.failed_attempts += 1;
+                let value = serde_cbor::to_vec(&scheduled).map_err(|err| {
+                    ConflictableTransactionError::Abort(Box::new(Error::SerializeData(err)))
+                })?;
+                tx_tree.insert(key.as_slice(), value)?;
+                Ok(true)
+            })
+            .map_err(from_transaction_error)
+    }
+
+    pub async fn flush_async(&self) -> Result<usize> {
+        Ok(self.tree.flush_async().await? + self.due_tree.flush_async().await?)
+    }
+
+    fn remove_if(
+        &self,
+        key: &[u8],
+        predicate: impl Fn(&ScheduledCommand<'_>) -> bool,
+    ) -> Result<Option<ScheduledCommand<'static>>> {
+        (&self.tree, &self.due_tree)
+            .transaction(|(tx_tree, tx_due_tree)| {
+                let Some(value) = tx_tree.get(key)? else {
+                    return Ok(None);
+                };
+                let command = deserialize_command(&value)
+                    .map_err(|err| ConflictableTransactionError::Abort(Box::new(err)))?;
+                if !predicate(&command) {
+                    return Ok(None);
+                }
+                tx_tree.remove(key)?;
+                tx_due_tree.remove(due_key(command.due, key))?;
+                Ok(Some(command))
+            })
+            .map_err(from_transaction_error)
+    }
+}
+
+/// Key of a command, namespaced by the stream which scheduled it.
+///
+/// Commands scheduled through the runtime's API are keyed by their key alone,
+/// which can't contain the separator.
+fn command_key(scheduled_by: Option<&StreamName<'_>>, key: &str) -> Vec<u8> {
+    match scheduled_by {
+        Some(scheduled_by) => {
+            let mut command_key = Vec::with_capacity(scheduled_by.len() + 1 + key.len());
+            command_key.extend_from_slice(scheduled_by.as_bytes());
+            command_key.push(KEY_SEPARATOR);
+            command_key.extend_from_slice(key.as_bytes());
+            command_key
+        }
+        None => key.as_bytes().to_vec(),
+    }
+}
+
+/// Index key of a command, ordered by due time.
+fn due_key(due: u64, command_key: &[u8]) -> Vec<u8> {
+    let mut due_key = Vec::with_capacity(8 + command_key.len());
+    due_key.extend_from_slice(&due.to_be_bytes());
+    due_key.extend_from_slice(command_key);
+    due_key
+}
+
+fn from_transaction_error(err: TransactionError<Box<Error>>) -> Error {
+    match err {
+        TransactionError::Abort(err) => *err,
+        TransactionError::Storage(err) => err.into(),
+    }
+}
+
+fn deserialize_command(value: &[u8]) -> Result<ScheduledCommand<'static>> {
+    let command: ScheduledCommand<'_> =
+        serde_cbor::from_slice(value).map_err(Error::DeserializeData)?;
+    Ok(command.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::MessageStore;
+
+    fn scheduled_commands() -> Result<ScheduledCommands> {
+        let message_store = MessageStore::new(sled::Config::new().temporary(true).open()?)?;
+        message_store.scheduled_commands()
+    }
+
+    fn command(
+        scheduled_by: Option<&'static str>,
+        key: &'static str,
+        due: u64,
+    ) -> Result<ScheduledCommand<'static>> {
+        Ok(ScheduledCommand {
+            scheduled_by: scheduled_by.map(StreamName::new).transpose()?,
+            key: Cow::Borrowed(key),
+            due,
+            stream_name: StreamName::new("counter-1")?,
+            command: Cow::Borrowed("Increment"),
+            payload: Cow::Owned(json!({ "amount": due })),
+            metadata: Cow::Owned(json!({})),
+            failed_attempts: 0,
+        })
+    }
+
+    fn keys(scheduled_commands: &ScheduledCommands) -> Result<Vec<(u64, String)>> {
+        scheduled_commands
+            .iter()
+            .map(|res| res.map(|command| (command.due, command.key.into_owned())))
+            .collect()
+    }
+
+    #[test]
+    fn iterates_in_due_order() -> Result<()> {
+        let scheduled_commands = scheduled_commands()?;
+        scheduled_commands.schedule(&command(None, "c", 300)?)?;
+        scheduled_commands.schedule(&command(None, "a", 100)?)?;
+        scheduled_commands.schedule(&command(Some("timer-1"), "b", 200)?)?;
+        // Due times are compared numerically, rather than by their digits.
+        scheduled_commands.schedule(&command(None, "d", 1_000)?)?;
+
+        assert_eq!(
+            keys(&scheduled_commands)?,
+            [
+                (100, "a".to_string()),
+                (200, "b".to_string()),
+                (300, "c".to_string()),
+                (1_000, "d".to_string()),
+            ]
+        );
+        assert_eq!(scheduled_commands.next_due()?.unwrap().key, "a");
+        Ok(())
+    }
+
+    #[test]
+    fn replaces_command_with_same_key() -> Result<()> {
+        let scheduled_commands = scheduled_commands()?;
+        scheduled_commands.schedule(&command(None, "a", 100)?)?;
+        scheduled_commands.schedule(&command(None, "b", 200)?)?;
+        scheduled_commands.schedule(&command(None, "a", 300)?)?;
+
+        assert_eq!(scheduled_commands.len(), 2);
+        assert_eq!(
+            keys(&scheduled_commands)?,
+            [(200, "b".to_string()), (300, "a".to_string())]
+        );
+        assert_eq!(scheduled_commands.get(None, "a")?.unwrap().due, 300);
+        Ok(())
+    }
+
+    #[test]
+    fn namespaces_keys_by_scheduling_stream() -> Result<()> {
+        let scheduled_commands = scheduled_commands()?;
+        let timer_1 = StreamName::new("timer-1")?;
+        scheduled_commands.schedule(&command(None, "a", 100)?)?;
+        scheduled_commands.schedule(&command(Some("timer-1"), "a", 200)?)?;
+        scheduled_commands.schedule(&command(Some("timer-2"), "a", 300)?)?;
+
+        assert_eq!(scheduled_commands.len(), 3);
+        assert_eq!(scheduled_commands.get(None, "a")?.unwrap().due, 100);
+        assert_eq!(
+            scheduled_commands.get(Some(&timer_1), "a")?.unwrap().due,
+            200
+        );
+
+        assert!(scheduled_commands.cancel(Some(&timer_1), "a")?.is_some());
+        assert_eq!(
+            keys(&scheduled_commands)?,
+            [(100, "a".to_string()), (300, "a".to_string())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn cancels_pending_command() -> Result<()> {
+        let scheduled_commands = scheduled_commands()?;
+        let command = command(None, "a", 100)?;
+        scheduled_commands.schedule(&command)?;
+
+        assert_eq!(scheduled_commands.cancel(None, "a")?, Some(command));
+        assert!(scheduled_commands.cancel(None, "a")?.is_none());
+        assert!(scheduled_commands.is_empty());
+        assert!(keys(&scheduled_commands)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn completes_delivered_command_unless_rescheduled() -> Result<()> {
+        let scheduled_commands = scheduled_commands()?;
+        let delivered = command(None, "a", 100)?;
+        scheduled_commands.schedule(&delivered)?;
+        scheduled_commands.schedule(&command(None, "a", 200)?)?;
+
+        // Completing a command which was rescheduled while it was delivered keeps it.
+        assert!(!scheduled_commands.complete(&delivered)?);
+        assert_eq!(keys(&scheduled_commands)?, [(200, "a".to_string())]);
+
+        assert!(scheduled_commands.complete(&command(None, "a", 200)?)?);
+        assert!(scheduled_commands.is_empty());
+        assert!(keys(&scheduled_commands)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn records_failed_attempts() -> Result<()> {
+        let scheduled_commands = scheduled_commands()?;
+        let command = command(None, "a", 100)?;
+        scheduled_commands.schedule(&command)?;
+
+        assert!(scheduled_commands.record_failure(&command)?);
+        assert!(scheduled_commands.record_failure(&command)?);
+        assert_eq!(
+            scheduled_commands.get(None, "a")?.unwrap().failed_attempts,
+            2
+        );
+
+        assert!(scheduled_commands.cancel(None, "a")?.is_some());
+        assert!(!scheduled_commands.record_failure(&command)?);
+        Ok(())
+    }
+}
@@ -1,40 +1,60 @@
 use std::borrow::Cow;
 use std::marker::PhantomData;
 use std::ops;
-use std::time::SystemTime;
 
 use sled::transaction::{ConflictableTransactionError, Transactional, TransactionalTree};
 use sled::{IVec, Tree};
 use thalo::stream_name::StreamName;
 use tracing::info;
 
+use crate::access::{Access, ReadWrite};
+use crate::command_outbox::{CommandOutbox, OutboxCommand};
 use crate::error::{Error, Result};
-use crate::global_event_log::GlobalEventLog;
-use crate::id_generator::IdGenerator;
+use crate::global_event_log::{GlobalEventLog, GlobalEventLogTx};
+use crate::hash_chain::HashChain;
 use crate::message::Message;
 
+/// Highest position of each stream, by stream name.
+///
+/// Streams last written before this index existed have no entry until
+/// they're next written to, and their version is counted from their length.
+pub(crate) const STREAM_VERSIONS_TREE: &str = "thalo:stream_versions";
+
+/// Global ID of the last message each stream handled, by stream name.
+///
+/// Recorded by writes which handle a message, such as a process manager
+/// handling an event, so a message which only produced commands isn't handled
+/// again.
+pub(crate) const STREAM_HANDLED_TREE: &str = "thalo:stream_handled";
+
 #[derive(Clone)]
-pub struct Stream<'a> {
-    id_generator: IdGenerator,
+pub struct Stream<'a, A: Access = ReadWrite> {
+    access: A,
     tree: Tree,
-    global_event_log: GlobalEventLog,
+    /// Stream versions index, which is always present unless read-only.
+    versions: Option<Tree>,
+    /// Last handled messages index, which is always present unless read-only.
+    handled: Option<Tree>,
+    global_event_log: GlobalEventLog<A>,
     stream_name: StreamName<'a>,
-    version: Option<Option<u64>>,
 }
 
-impl<'a> Stream<'a> {
+impl<'a, A: Access> Stream<'a, A> {
     pub(crate) fn new(
-        id_generator: IdGenerator,
+        access: A,
         tree: Tree,
-        global_event_log: GlobalEventLog,
+        versions: Option<Tree>,
+        handled: Option<Tree>,
+        global_event_log: GlobalEventLog<A>,
         stream_name: StreamName<'a>,
     ) -> Self {
         Stream {
-            id_generator,
+            access,
             tree,
+            versions,
+            handled,
             global_event_log,
             stream_name,
-            version: None,
         }
     }
 
@@ -46,6 +66,49 @@ impl<'a> Stream<'a> {
         MessageIter::new(self.tree.iter())
     }
 
+    /// Returns the number of messages in the stream.
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Returns whether the stream contains no messages.
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Returns the highest position number in the stream.
+    pub fn version(&self) -> Result<Option<u64>> {
+        let indexed = match &self.versions {
+            Some(versions) => versions.get(self.stream_name.as_bytes())?,
+            None => None,
+        };
+        match indexed {
+            Some(version) => parse_version(&version).map(Some),
+            None => Ok(self.calculate_latest_version()),
+        }
+    }
+
+    /// Returns the global ID of the last message the stream handled, as
+    /// recorded by [`Alongside::handled`].
+    pub fn last_handled(&self) -> Result<Option<u64>> {
+        let handled = match &self.handled {
+            Some(handled) => handled.get(self.stream_name.as_bytes())?,
+            None => None,
+        };
+        handled
+            .map(|global_id| parse_version(&global_id))
+            .transpose()
+    }
+
+    pub(crate) fn calculate_latest_version(&self) -> Option<u64> {
+        match self.len() {
+            0 => None,
+            i => Some(i as u64 - 1),
+        }
+    }
+}
+
+impl<'a> Stream<'a> {
     pub fn write_messages<'b>(
         &'b mut self,
         messages: &[(&'b str, Cow<'b, serde_json::Value>)],
@@ -54,63 +117,276 @@ impl<'a> Stream<'a> {
     where
         'a: 'b,
     {
-        if messages.is_empty() {
-            return Ok(vec![]);
+        self.write_messages_with_metadata(
+            messages,
+            Cow::Owned(serde_json::Value::Null),
+            expected_starting_version,
+        )
+    }
+
+    /// Writes messages to the stream, each with the same metadata.
+    pub fn write_messages_with_metadata<'b>(
+        &'b mut self,
+        messages: &[(&'b str, Cow<'b, serde_json::Value>)],
+        metadata: Cow<'b, serde_json::Value>,
+        expected_starting_version: Option<u64>,
+    ) -> Result<Vec<Message<'b>>>
+    where
+        'a: 'b,
+    {
+        self.write_messages_alongside(
+            messages,
+            metadata,
+            expected_starting_version,
+            Alongside::default(),
+        )
+        .map(|(written_messages, _)| written_messages)
+    }
+
+    /// Writes messages to the stream, each with the same metadata, and
+    /// commands to the command outbox in a single transaction.
+    ///
+    /// Either all messages and commands are written, or none are. Commands may
+    /// be written without any messages.
+    pub fn write_messages_with_commands<'b>(
+        &'b mut self,
+        messages: &[(&'b str, Cow<'b, serde_json::Value>)],
+        metadata: Cow<'b, serde_json::Value>,
+        expected_starting_version: Option<u64>,
+        outbox: &CommandOutbox,
+        commands: &[OutboxCommand<'_>],
+    ) -> Result<Vec<Message<'b>>>
+    where
+        'a: 'b,
+    {
+        self.write_messages_alongside(
+            messages,
+            metadata,
+            expected_starting_version,
+            Alongside {
+                commands: Some((outbox, commands)),
+                ..Default::default()
+            },
+        )
+        .map(|(written_messages, _)| written_messages)
+    }
+
+    /// Writes messages to the stream, each with the same metadata, along with
+    /// messages to another stream and commands to the command outbox in a
+    /// single transaction.
+    ///
+    /// Returns the messages written to the stream, and to the other stream.
+    /// Either everything is written, or nothing is.
+    pub fn write_messages_alongside<'b>(
+        &'b mut self,
+        messages: &[(&'b str, Cow<'b, serde_json::Value>)],
+        metadata: Cow<'b, serde_json::Value>,
+        expected_starting_version: Option<u64>,
+        alongside: Alongside<'_>,
+    ) -> Result<(Vec<Message<'b>>, Vec<Message<'static>>)>
+    where
+        'a: 'b,
+    {
+        let other = alongside
+            .stream
+            .filter(|(_, other_messages)| !other_messages.is_empty());
+        let commands = alongside
+            .commands
+            .filter(|(_, commands)| !commands.is_empty());
+        if messages.is_empty()
+            && other.is_none()
+            && commands.is_none()
+            && alongside.handled.is_none()
+        {
+            return Ok((vec![], vec![]));
         }
 
-        let stream_version = self.version();
+        // Versions are read within the transaction, so that a writer with a stale view of the
+        // stream fails with a wrong expected version.
+        let unindexed_version = self.calculate_latest_version();
+        let other_unindexed_version =
+            other.and_then(|(stream, _)| stream.calculate_latest_version());
+        let mut last_hash = self.access.hash_chain.as_ref().map(HashChain::lock);
+
+        let mut trees = vec![
+            self.tree.clone(),
+            self.versions().clone(),
+            Tree::clone(&self.global_event_log),
+            self.global_event_log.hashes().clone(),
+        ];
+        if let Some((stream, _)) = other {
+            trees.push(stream.tree.clone());
+        }
+        if let Some((outbox, _)) = commands {
+            trees.push(outbox.tree.clone());
+        }
+        if alongside.handled.is_some() {
+            trees.push(self.handled().clone());
+        }
 
-        let (written_messages, new_version) = (&self.tree, &*self.global_event_log).transaction(
-            |(tx_stream, tx_global_event_log)| {
-                let mut written_messages = Vec::with_capacity(messages.len());
-                let mut stream_version = stream_version;
+        let ((written_messages, written_other), new_last_hash) =
+            trees.as_slice().transaction(|tx_trees| {
+                let [tx_stream, tx_versions, tx_global_event_log, tx_hashes, tx_rest @ ..] =
+                    tx_trees.as_slice()
+                else {
+                    unreachable!(
+                    "transaction contains the stream, versions, global event log and hash trees"
+                );
+                };
+                let mut tx_global_event_log = GlobalEventLogTx::new(
+                    tx_global_event_log,
+                    &self.access.id_generator,
+                    last_hash
+                        .as_deref()
+                        .map(|last_hash| (tx_hashes, *last_hash)),
+                );
+                let tx_stream = StreamTx::new(tx_stream, tx_versions, unindexed_version);
+                let written_messages = Self::write_messages_in_tx(
+                    &tx_stream,
+                    &mut tx_global_event_log,
+                    self.stream_name.as_borrowed(),
+                    messages,
+                    &metadata,
+                    expected_starting_version,
+                )
+                .map_err(ConflictableTransactionError::Abort)?;
 
-                for (i, (msg_type, data)) in messages.iter().enumerate() {
-                    let expected_version = if i == 0 {
-                        expected_starting_version.map(|ev| ev + i as u64)
-                    } else {
-                        Some(
-                            expected_starting_version
-                                .map(|ev| ev + i as u64)
-                                .unwrap_or(i as u64 - 1),
+                let mut tx_rest = tx_rest.iter();
+                let written_other = match (other, other.and_then(|_| tx_rest.next())) {
+                    (Some((stream, other_messages)), Some(tx_other)) => {
+                        let tx_other =
+                            StreamTx::new(tx_other, tx_versions, other_unindexed_version);
+                        let written_other = Self::write_messages_in_tx(
+                            &tx_other,
+                            &mut tx_global_event_log,
+                            stream.stream_name.as_borrowed(),
+                            other_messages,
+                            &Cow::Owned(serde_json::Value::Null),
+                            None,
                         )
-                    };
-                    let global_id = self.id_generator.generate_id();
-                    let written_message = Self::write_message_in_tx(
-                        &tx_stream,
-                        &tx_global_event_log,
-                        global_id,
-                        self.stream_name.as_borrowed(),
-                        stream_version,
-                        msg_type,
-                        data.clone(),
-                        expected_version,
-                    )
-                    .map_err(ConflictableTransactionError::Abort)?;
-                    stream_version = Some(written_message.position);
-                    written_messages.push(written_message);
+                        .map_err(ConflictableTransactionError::Abort)?;
+                        tx_other.flush();
+                        written_other.into_iter().map(Message::into_owned).collect()
+                    }
+                    _ => vec![],
+                };
+
+                if let (Some((_, commands)), Some(tx_outbox)) = (commands, tx_rest.next()) {
+                    let causation_global_id = written_messages
+                        .first()
+                        .or(written_other.first())
+                        .map(|message| message.global_id);
+                    Self::write_commands_in_tx(tx_outbox, commands, causation_global_id)
+                        .map_err(ConflictableTransactionError::Abort)?;
+                    tx_outbox.flush();
+                }
+
+                if let (Some(handled), Some(tx_handled)) = (alongside.handled, tx_rest.next()) {
+                    tx_handled.insert(self.stream_name.as_bytes(), &handled.to_be_bytes())?;
+                    tx_handled.flush();
                 }
 
                 tx_stream.flush();
                 tx_global_event_log.flush();
 
-                Ok((written_messages, stream_version))
-            },
-        )?;
+                Ok((
+                    (written_messages, written_other),
+                    tx_global_event_log.last_hash(),
+                ))
+            })?;
+
+        if let (Some(last_hash), Some(new_last_hash)) = (&mut last_hash, new_last_hash) {
+            **last_hash = new_last_hash;
+        }
 
-        self.version = Some(new_version);
+        Ok((written_messages, written_other))
+    }
+
+    fn versions(&self) -> &Tree {
+        self.versions
+            .as_ref()
+            .expect("read-write stream has a versions tree")
+    }
+
+    fn handled(&self) -> &Tree {
+        self.handled
+            .as_ref()
+            .expect("read-write stream has a handled tree")
+    }
+
+    /// Writes messages to a stream within an existing transaction.
+    pub(crate) fn write_messages_in_tx<'b>(
+        tx_stream: &StreamTx<'_>,
+        tx_global_event_log: &mut GlobalEventLogTx<'_>,
+        stream_name: StreamName<'b>,
+        messages: &[(&'b str, Cow<'b, serde_json::Value>)],
+        metadata: &Cow<'b, serde_json::Value>,
+        expected_starting_version: Option<u64>,
+    ) -> Result<Vec<Message<'b>>, ConflictableTransactionError<Box<Error>>> {
+        let mut stream_version = tx_stream.version(&stream_name)?;
+        let mut written_messages = Vec::with_capacity(messages.len());
+
+        for (i, (msg_type, data)) in messages.iter().enumerate() {
+            let expected_version = if i == 0 {
+                expected_starting_version.map(|ev| ev + i as u64)
+            } else {
+                Some(
+                    expected_starting_version
+                        .map(|ev| ev + i as u64)
+                        .unwrap_or(i as u64 - 1),
+                )
+            };
+            let written_message = Self::write_message_in_tx(
+                tx_stream.tree,
+                tx_global_event_log,
+                stream_name.clone(),
+                stream_version,
+                msg_type,
+                data.clone(),
+                metadata.clone(),
+                expected_version,
+            )?;
+            stream_version = Some(written_message.position);
+            written_messages.push(written_message);
+        }
+
+        if let Some(stream_version) = stream_version {
+            tx_stream
+                .versions
+                .insert(stream_name.as_bytes(), &stream_version.to_be_bytes())?;
+        }
 
         Ok(written_messages)
     }
 
+    fn write_commands_in_tx(
+        tx_outbox: &TransactionalTree,
+        commands: &[OutboxCommand<'_>],
+        causation_global_id: Option<u64>,
+    ) -> Result<(), ConflictableTransactionError<Box<Error>>> {
+        for command in commands {
+            let id = tx_outbox.generate_id()?;
+            let command = OutboxCommand {
+                causation_global_id: command.causation_global_id.or(causation_global_id),
+                ..command.clone()
+            };
+            let value = serde_cbor::to_vec(&command).map_err(|err| {
+                ConflictableTransactionError::Abort(Box::new(Error::SerializeData(err)))
+            })?;
+            tx_outbox.insert(&id.to_be_bytes(), value)?;
+        }
+
+        Ok(())
+    }
+
     fn write_message_in_tx<'b>(
         tx_stream: &TransactionalTree,
-        tx_global_event_log: &TransactionalTree,
-        global_id: u64,
+        tx_global_event_log: &mut GlobalEventLogTx<'_>,
         stream_name: StreamName<'b>,
         stream_version: Option<u64>,
         msg_type: &'b str,
         data: Cow<'b, serde_json::Value>,
+        metadata: Cow<'b, serde_json::Value>,
         expected_version: Option<u64>,
     ) -> Result<Message<'b>, ConflictableTransactionError<Box<Error>>> {
         if let Some(expected_version) = expected_version {
@@ -132,6 +408,7 @@ impl<'a> Stream<'a> {
             .map(|stream_version| stream_version + 1)
             .unwrap_or(0);
 
+        let (global_id, time) = tx_global_event_log.generate_id();
         let message_id = tx_stream.generate_id()?;
         let message_id_bytes = message_id.to_be_bytes().to_vec();
         let mut message_ref = message_id_bytes.clone();
@@ -143,38 +420,37 @@ impl<'a> Stream<'a> {
             stream_name,
             msg_type: Cow::Borrowed(msg_type),
             data,
-            time: SystemTime::now(),
+            time,
+            metadata,
             _marker: PhantomData,
         };
         let raw_message = serde_cbor::to_vec(&message).map_err(|err| {
             ConflictableTransactionError::Abort(Box::new(Error::SerializeData(err)))
         })?;
-        tx_stream.insert(message_id_bytes, raw_message.clone())?;
-        tx_global_event_log.insert(global_id.to_be_bytes().to_vec(), message_ref)?;
+        tx_stream.insert(message_id_bytes, raw_message.as_slice())?;
+        tx_global_event_log.insert(global_id, message_ref, &raw_message)?;
 
         info!(id = message.id, global_id = message.global_id, stream_name = %message.stream_name, msg_type = %message.msg_type, position = message.position);
 
         Ok(message)
     }
+}
 
-    /// Returns the highest position number in the stream.
-    pub fn version(&mut self) -> Option<u64> {
-        match self.version {
-            Some(version) => version,
-            None => {
-                let version = self.calculate_latest_version();
-                self.version = Some(version);
-                version
-            }
-        }
-    }
+/// A stream, and the messages written to it without metadata or an expected
+/// version.
+pub type StreamWrite<'w> = (&'w Stream<'w>, &'w [(&'w str, Cow<'w, serde_json::Value>)]);
 
-    fn calculate_latest_version(&self) -> Option<u64> {
-        match self.len() {
-            0 => None,
-            i => Some(i as u64 - 1),
-        }
-    }
+/// Writes made in the same transaction as a stream's messages, with
+/// [`Stream::write_messages_alongside`].
+#[derive(Clone, Copy, Default)]
+pub struct Alongside<'w> {
+    /// Another stream, such as the stream's command stream.
+    pub stream: Option<StreamWrite<'w>>,
+    /// The command outbox, and the commands written to it.
+    pub commands: Option<(&'w CommandOutbox, &'w [OutboxCommand<'w>])>,
+    /// Global ID of the message the write handles, recorded as the stream's
+    /// [last handled](Stream::last_handled) message.
+    pub handled: Option<u64>,
 }
 
 impl ops::Deref for Stream<'_> {
@@ -185,6 +461,55 @@ impl ops::Deref for Stream<'_> {
     }
 }
 
+/// A stream within a transaction, used when writing messages.
+///
+/// The stream's version is read from the versions index within the
+/// transaction, so that it reflects every write committed before it.
+pub(crate) struct StreamTx<'a> {
+    tree: &'a TransactionalTree,
+    versions: &'a TransactionalTree,
+    /// Version of the stream if it has no entry in the versions index.
+    unindexed_version: Option<u64>,
+}
+
+impl<'a> StreamTx<'a> {
+    pub(crate) fn new(
+        tree: &'a TransactionalTree,
+        versions: &'a TransactionalTree,
+        unindexed_version: Option<u64>,
+    ) -> Self {
+        StreamTx {
+            tree,
+            versions,
+            unindexed_version,
+        }
+    }
+
+    fn version(
+        &self,
+        stream_name: &StreamName<'_>,
+    ) -> Result<Option<u64>, ConflictableTransactionError<Box<Error>>> {
+        match self.versions.get(stream_name.as_bytes())? {
+            Some(version) => parse_version(&version)
+                .map(Some)
+                .map_err(|err| ConflictableTransactionError::Abort(Box::new(err))),
+            None => Ok(self.unindexed_version),
+        }
+    }
+
+    pub(crate) fn flush(&self) {
+        self.tree.flush();
+        self.versions.flush();
+    }
+}
+
+fn parse_version(version: &[u8]) -> Result<u64> {
+    let slice = version
+        .try_into()
+        .map_err(|_| Error::InvalidStreamVersion)?;
+    Ok(u64::from_be_bytes(slice))
+}
+
 #[derive(Clone)]
 pub struct RawMessage<T> {
     pub key: IVec,
@@ -1,13 +1,29 @@
+use std::fs;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
+use hex::FromHex;
 use redis::streams::StreamMaxlen;
+use thalo::stream_name::Category;
+use thalo_message_store::hash_chain::SigningKey;
 use thalo_message_store::MessageStore;
+use thalo_runtime::command_log::CommandLogConfig;
+use thalo_runtime::entity_cache::EntityCacheConfig;
+use thalo_runtime::module::budget::ExecutionBudgets;
+use thalo_runtime::module::limits::MemoryLimitsConfig;
 use thalo_runtime::relay::{RedisRelay, Relay};
-use thalo_runtime::{rpc, Runtime};
+use thalo_runtime::{rpc, Runtime, RuntimeConfig};
+use tokio::signal;
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
 use tonic::transport::Server;
+use tracing::info;
 use tracing_subscriber::EnvFilter;
 
 /// Thalo runtime - event sourcing runtime
@@ -20,9 +36,48 @@ struct Cli {
     /// Path to aggregate wasm modules directory
     #[clap(short = 'm', long, default_value = "modules")]
     modules_path: PathBuf,
-    /// Cache size of aggregates (LRU)
-    #[clap(long, default_value = "10000")]
-    cache_size: u64,
+    /// Store a tamper-evident hash chain alongside the global event log
+    #[clap(long)]
+    hash_chain: bool,
+    /// File containing the hex encoded ed25519 secret key which hash chain checkpoints are signed
+    /// with
+    #[clap(long)]
+    checkpoint_signing_key: Option<PathBuf>,
+    /// Capacity of each aggregate's entity cache, as `capacity` or `category=capacity`, weighed
+    /// by `--cache-weigher` (defaults to 10000)
+    #[clap(long)]
+    cache_size: Vec<String>,
+    /// Seconds after which an unused entity is passivated, as `secs` or `category=secs` (never
+    /// by default)
+    #[clap(long)]
+    cache_time_to_idle: Vec<String>,
+    /// How entities are weighed against the cache size, as `weigher` or `category=weigher`,
+    /// where weigher is `entries`, `events` or `state-size` (defaults to `entries`)
+    #[clap(long)]
+    cache_weigher: Vec<String>,
+    /// Number of wasm stores per module, allowing entities of the same aggregate to execute in
+    /// parallel (defaults to the number of cores)
+    #[clap(long)]
+    module_pool_size: Option<usize>,
+    /// Categories to record executed commands for in `category:command-id` streams (`*` for all)
+    #[clap(long)]
+    command_log: Vec<String>,
+    /// Fuel budget for each command handled and entity initialized, as `fuel` or `category=fuel`
+    /// (1000000000 by default)
+    #[clap(long)]
+    handle_fuel: Vec<String>,
+    /// Fuel budget for each batch of events applied, as `fuel` or `category=fuel` (1000000000 by
+    /// default)
+    #[clap(long)]
+    apply_fuel: Vec<String>,
+    /// Maximum size in bytes of each linear memory of an aggregate instance, as `bytes` or
+    /// `category=bytes` (unlimited by default)
+    #[clap(long)]
+    max_memory_size: Vec<String>,
+    /// Maximum number of elements in each table of an aggregate instance, as `elements` or
+    /// `category=elements` (unlimited by default)
+    #[clap(long)]
+    max_table_elements: Vec<String>,
     /// Redis relay
     #[clap(long)]
     redis: Option<String>,
@@ -35,6 +90,10 @@ struct Cli {
     /// Address to listen on
     #[clap(long, default_value = "[::1]:4433")]
     addr: SocketAddr,
+    /// Seconds to wait for in-flight commands to drain when shutting down, after which the message
+    /// store is flushed regardless
+    #[clap(long, default_value = "30")]
+    shutdown_timeout: u64,
     /// Log levels
     #[clap(
         long,
@@ -54,7 +113,15 @@ pub async fn start() -> Result<()> {
         .with_env_filter(EnvFilter::builder().parse_lossy(cli.log))
         .init();
 
-    let message_store = MessageStore::open(&cli.message_store_path)?;
+    let mut message_store = MessageStore::open(&cli.message_store_path)?;
+    if cli.hash_chain {
+        message_store.enable_hash_chain()?;
+    }
+    let checkpoint_signing_key = cli
+        .checkpoint_signing_key
+        .as_deref()
+        .map(read_signing_key)
+        .transpose()?;
     let relay = match cli.redis {
         Some(params) => {
             let conn = redis::Client::open(params)?;
@@ -66,16 +133,159 @@ pub async fn start() -> Result<()> {
         }
         None => Relay::Noop,
     };
-    let runtime = Runtime::new(message_store, relay, cli.modules_path, cli.cache_size).await?;
+    let module_pool_size = match cli.module_pool_size {
+        Some(module_pool_size) => module_pool_size,
+        None => thread::available_parallelism()?.get(),
+    };
+    let command_log = if cli.command_log.iter().any(|category| category == "*") {
+        CommandLogConfig::all()
+    } else {
+        CommandLogConfig::categories(
+            cli.command_log
+                .into_iter()
+                .map(Category::new)
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+    };
+    let mut entity_cache = EntityCacheConfig::default();
+    for arg in &cli.cache_size {
+        let (category, capacity) = parse_module_limit(arg)?;
+        match category {
+            Some(category) => entity_cache.module_mut(category).capacity = Some(capacity),
+            None => entity_cache.default_mut().capacity = capacity,
+        }
+    }
+    for arg in &cli.cache_time_to_idle {
+        let (category, secs) = parse_module_limit(arg)?;
+        let time_to_idle = Some(Duration::from_secs(secs));
+        match category {
+            Some(category) => entity_cache.module_mut(category).time_to_idle = time_to_idle,
+            None => entity_cache.default_mut().time_to_idle = time_to_idle,
+        }
+    }
+    for arg in &cli.cache_weigher {
+        let (category, weigher) = parse_module_limit(arg)?;
+        match category {
+            Some(category) => entity_cache.module_mut(category).weigher = Some(weigher),
+            None => entity_cache.default_mut().weigher = weigher,
+        }
+    }
+    let mut execution_budgets = ExecutionBudgets::default();
+    for arg in &cli.handle_fuel {
+        let (category, fuel) = parse_module_limit(arg)?;
+        match category {
+            Some(category) => execution_budgets.module_mut(category).handle = Some(fuel),
+            None => execution_budgets.default_mut().handle = Some(fuel),
+        }
+    }
+    for arg in &cli.apply_fuel {
+        let (category, fuel) = parse_module_limit(arg)?;
+        match category {
+            Some(category) => execution_budgets.module_mut(category).apply = Some(fuel),
+            None => execution_budgets.default_mut().apply = Some(fuel),
+        }
+    }
+    let mut memory_limits = MemoryLimitsConfig::default();
+    for arg in &cli.max_memory_size {
+        let (category, size) = parse_module_limit(arg)?;
+        match category {
+            Some(category) => memory_limits.module_mut(category).memory_size = Some(size),
+            None => memory_limits.default_mut().memory_size = Some(size),
+        }
+    }
+    for arg in &cli.max_table_elements {
+        let (category, elements) = parse_module_limit(arg)?;
+        match category {
+            Some(category) => memory_limits.module_mut(category).table_elements = Some(elements),
+            None => memory_limits.default_mut().table_elements = Some(elements),
+        }
+    }
+    let runtime = Runtime::new(
+        message_store,
+        relay,
+        cli.modules_path,
+        RuntimeConfig {
+            checkpoint_signing_key,
+            entity_cache,
+            module_pool_size,
+            command_log,
+            execution_budgets,
+            memory_limits,
+        },
+    )
+    .await?;
 
     let command_center_server = rpc::server::CommandCenterServer::new(runtime.clone());
-    let projection_server = rpc::server::ProjectionServer::new(runtime);
+    let projection_server = rpc::server::ProjectionServer::new(runtime.clone());
+
+    let stop_serving = Arc::new(Notify::new());
+    let mut server = tokio::spawn(
+        Server::builder()
+            .add_service(command_center_server)
+            .add_service(projection_server)
+            .serve_with_shutdown(cli.addr, {
+                let stop_serving = Arc::clone(&stop_serving);
+                async move { stop_serving.notified().await }
+            }),
+    );
 
-    Server::builder()
-        .add_service(command_center_server)
-        .add_service(projection_server)
-        .serve(cli.addr)
-        .await?;
+    tokio::select! {
+        res = &mut server => return Ok(res??),
+        res = shutdown_signal() => res?,
+    }
+
+    info!("shutting down");
+    let deadline = Instant::now() + Duration::from_secs(cli.shutdown_timeout);
+    // New RPCs are refused while in-flight commands drain. Projection streams only end once
+    // the runtime stops its projections.
+    stop_serving.notify_one();
+    runtime.shutdown(deadline).await?;
+    time::timeout_at(deadline, server)
+        .await
+        .context("shutdown timed out stopping the rpc server")???;
+    info!("shut down");
 
     Ok(())
 }
+
+/// Waits for SIGTERM or ctrl-c.
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            res = signal::ctrl_c() => res?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await?;
+
+    Ok(())
+}
+
+/// Reads a hex encoded ed25519 signing key from a file.
+fn read_signing_key(path: &Path) -> Result<SigningKey> {
+    let key = fs::read_to_string(path)
+        .with_context(|| format!("failed to read signing key {}", path.display()))?;
+    let key =
+        <[u8; 32]>::from_hex(key.trim()).context("signing key must be 32 hex encoded bytes")?;
+    Ok(SigningKey::from_bytes(&key))
+}
+
+/// Parses a `limit` or `category=limit` argument.
+fn parse_module_limit<T>(arg: &str) -> Result<(Option<Category<'static>>, T)>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let (category, limit) = match arg.split_once('=') {
+        Some((category, limit)) => (Some(Category::new(category.to_string())?), limit),
+        None => (None, arg),
+    };
+    let limit = limit
+        .parse()
+        .with_context(|| format!("invalid limit '{arg}'"))?;
+
+    Ok((category, limit))
+}
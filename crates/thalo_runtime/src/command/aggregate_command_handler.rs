@@ -1,4 +1,5 @@
 use std::borrow::Cow;
+use std::future::Future;
 use std::sync::Arc;
 
 use anyhow::{anyhow, Context, Error, Result};
@@ -8,40 +9,61 @@ use thalo::stream_name::{Category, StreamName, ID};
 use thalo_message_store::message::Message;
 use thalo_message_store::MessageStore;
 use tokio::sync::{mpsc, oneshot};
-use tracing::{error, trace, warn};
+use tokio::task::JoinSet;
+use tracing::{error, info, trace, warn};
 use wasmtime::Trap;
 
-use super::entity_command_handler::EntityCommandHandlerHandle;
+use super::command_dispatcher::CommandDispatcherHandle;
+use super::command_log::command_stream_name;
+use super::entity_cache::{
+    EntityCacheCounters, EntityCacheSettings, EntityCacheStats, LiveEntities,
+};
+use super::entity_command_handler::{EntityCommandHandlerHandle, EntityServices, EntityStopped};
 use super::outbox_relay::OutboxRelayHandle;
-use super::CommandGatewayHandle;
+use super::quarantine::{CircuitBreaker, EntityQuarantined, Quarantine};
+use super::{CommandContext, EventMetadata, ExpectedVersion, HistoricalState, StateAt};
 use crate::broadcaster::BroadcasterHandle;
-use crate::module::{Event, Module};
+use crate::module::budget::ExecutionBudgetExceeded;
+use crate::module::wit_aggregate::AggregateError;
+use crate::module::{Event, Module, StorePoisoned};
+
+/// Number of times a message is resent to an entity whose handler stopped
+/// after it was evicted, or whose store was poisoned.
+const ENTITY_STOPPED_RETRIES: usize = 3;
 
 #[derive(Clone)]
 pub struct AggregateCommandHandlerHandle {
-    sender: mpsc::Sender<ExecuteAggregateCommand>,
+    sender: mpsc::Sender<AggregateCommandHandlerMsg>,
 }
 
 impl AggregateCommandHandlerHandle {
+    /// Spawns a command handler for a module.
+    ///
+    /// The `previous` handlers are drained before any commands are handled, so
+    /// that a replaced module never executes commands alongside its successor.
     pub fn new(
-        command_gateway: CommandGatewayHandle,
         name: Category<'static>,
         outbox_relay: OutboxRelayHandle,
+        command_dispatcher: CommandDispatcherHandle,
         message_store: MessageStore,
         broadcaster: BroadcasterHandle,
-        cache_size: u64,
+        cache: EntityCacheSettings,
+        command_log: bool,
         module: Module,
+        previous: Vec<AggregateCommandHandlerHandle>,
     ) -> Self {
         let (sender, receiver) = mpsc::channel(16);
         tokio::spawn(run_aggregate_command_handler(
             receiver,
-            command_gateway,
             name,
             outbox_relay,
+            command_dispatcher,
             message_store,
             broadcaster,
-            cache_size,
+            cache,
+            command_log,
             module,
+            previous,
         ));
 
         AggregateCommandHandlerHandle { sender }
@@ -53,89 +75,582 @@ impl AggregateCommandHandlerHandle {
         id: ID<'static>,
         command: String,
         payload: Value,
+        expected_version: ExpectedVersion,
+        context: CommandContext,
     ) -> Result<Result<Vec<Message<'static>>, serde_json::Value>> {
         let (reply, recv) = oneshot::channel();
-        let msg = ExecuteAggregateCommand {
+        let msg = AggregateCommandHandlerMsg::Execute {
             name,
             id,
             command,
             payload,
+            expected_version,
+            context,
+            reply,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.context("no response from command handler")?
+    }
+
+    pub async fn get_state(
+        &self,
+        name: Category<'static>,
+        id: ID<'static>,
+    ) -> Result<Option<Value>> {
+        let (reply, recv) = oneshot::channel();
+        let msg = AggregateCommandHandlerMsg::GetState { name, id, reply };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.context("no response from command handler")?
+    }
+
+    pub async fn get_state_at(
+        &self,
+        name: Category<'static>,
+        id: ID<'static>,
+        at: StateAt,
+    ) -> Result<HistoricalState> {
+        let (reply, recv) = oneshot::channel();
+        let msg = AggregateCommandHandlerMsg::GetStateAt {
+            name,
+            id,
+            at,
             reply,
         };
 
         let _ = self.sender.send(msg).await;
         recv.await.context("no response from command handler")?
     }
+
+    pub async fn cache_stats(&self) -> Result<EntityCacheStats> {
+        let (reply, recv) = oneshot::channel();
+        let msg = AggregateCommandHandlerMsg::CacheStats { reply };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.context("no response from command handler")
+    }
+
+    pub async fn quarantined(&self) -> Result<Vec<EntityQuarantined>> {
+        let (reply, recv) = oneshot::channel();
+        let msg = AggregateCommandHandlerMsg::Quarantined { reply };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.context("no response from command handler")
+    }
+
+    /// Releases a quarantined entity, returning whether it was quarantined.
+    pub async fn unquarantine(&self, stream_name: StreamName<'static>) -> Result<bool> {
+        let (reply, recv) = oneshot::channel();
+        let msg = AggregateCommandHandlerMsg::Unquarantine { stream_name, reply };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.context("no response from command handler")
+    }
+
+    /// Stops accepting commands, and waits for queued and in-flight commands
+    /// to complete.
+    pub async fn drain(&self) {
+        // If the handler already stopped, there's nothing to drain.
+        let _ = self.sender.send(AggregateCommandHandlerMsg::Drain).await;
+        self.sender.closed().await;
+    }
 }
 
-struct ExecuteAggregateCommand {
-    name: Category<'static>,
-    id: ID<'static>,
-    command: String,
-    payload: Value,
-    reply: oneshot::Sender<Result<Result<Vec<Message<'static>>, serde_json::Value>>>,
+enum AggregateCommandHandlerMsg {
+    Execute {
+        name: Category<'static>,
+        id: ID<'static>,
+        command: String,
+        payload: Value,
+        expected_version: ExpectedVersion,
+        context: CommandContext,
+        reply: oneshot::Sender<Result<Result<Vec<Message<'static>>, serde_json::Value>>>,
+    },
+    GetState {
+        name: Category<'static>,
+        id: ID<'static>,
+        reply: oneshot::Sender<Result<Option<Value>>>,
+    },
+    GetStateAt {
+        name: Category<'static>,
+        id: ID<'static>,
+        at: StateAt,
+        reply: oneshot::Sender<Result<HistoricalState>>,
+    },
+    CacheStats {
+        reply: oneshot::Sender<EntityCacheStats>,
+    },
+    Quarantined {
+        reply: oneshot::Sender<Vec<EntityQuarantined>>,
+    },
+    Unquarantine {
+        stream_name: StreamName<'static>,
+        reply: oneshot::Sender<bool>,
+    },
+    Drain,
 }
 
 async fn run_aggregate_command_handler(
-    mut receiver: mpsc::Receiver<ExecuteAggregateCommand>,
-    command_gateway: CommandGatewayHandle,
+    mut receiver: mpsc::Receiver<AggregateCommandHandlerMsg>,
     name: Category<'static>,
     outbox_relay: OutboxRelayHandle,
+    command_dispatcher: CommandDispatcherHandle,
     message_store: MessageStore,
     broadcaster: BroadcasterHandle,
-    cache_size: u64,
+    cache: EntityCacheSettings,
+    command_log: bool,
     module: Module,
+    previous: Vec<AggregateCommandHandlerHandle>,
 ) -> Result<()> {
-    let entity_command_handlers = Cache::new(cache_size);
+    for previous in previous {
+        previous.drain().await;
+        trace!(%name, "drained previous aggregate command handler");
+    }
 
-    let handler = AggregateCommandHandler {
+    let event_metadata = EventMetadata {
+        module_version: module.version().cloned(),
+        ..Default::default()
+    };
+    let mut handler = Arc::new(AggregateCommandHandler {
         outbox_relay,
+        command_dispatcher,
         message_store,
         broadcaster,
+        cache,
+        cache_counters: Arc::default(),
+        command_log,
+        event_metadata,
         module,
-        entity_command_handlers,
-    };
+        entity_command_handlers: cache.build(),
+        live_entities: LiveEntities::default(),
+    });
 
-    while let Some(msg) = receiver.recv().await {
-        let res = handler
-            .execute(msg.name, msg.id, msg.command, msg.payload)
-            .await;
-        let res = match res {
-            Ok(res) => Ok(res),
-            Err((err, None)) => Err(err),
-            Err((err, Some(trap))) => {
-                error!("aggregate trapped: {trap}");
-                let _ = msg.reply.send(Err(err));
-                break;
+    // Commands are executed concurrently, and are only serialized per entity by each
+    // entity command handler.
+    let mut executing = JoinSet::new();
+    let mut quarantine = Quarantine::default();
+    let mut circuit_breaker = CircuitBreaker::default();
+    loop {
+        tokio::select! {
+            msg = receiver.recv() => {
+                let msg = match msg {
+                    Some(AggregateCommandHandlerMsg::Drain) => {
+                        // Commands already queued are still handled.
+                        receiver.close();
+                        continue;
+                    }
+                    Some(AggregateCommandHandlerMsg::CacheStats { reply }) => {
+                        let _ = reply.send(handler.cache_stats());
+                        continue;
+                    }
+                    Some(AggregateCommandHandlerMsg::Quarantined { reply }) => {
+                        let _ = reply.send(quarantine.entities());
+                        continue;
+                    }
+                    Some(AggregateCommandHandlerMsg::Unquarantine { stream_name, reply }) => {
+                        let released = quarantine.release(&stream_name);
+                        if released {
+                            info!(%stream_name, "released entity from quarantine");
+                        }
+                        let _ = reply.send(released);
+                        continue;
+                    }
+                    Some(msg) => msg,
+                    None => break,
+                };
+                let Some(msg) = reject_quarantined(&quarantine, msg) else {
+                    continue;
+                };
+
+                let handler = Arc::clone(&handler);
+                executing.spawn(async move {
+                    match msg {
+                        AggregateCommandHandlerMsg::Execute {
+                            name,
+                            id,
+                            command,
+                            payload,
+                            expected_version,
+                            context,
+                            reply,
+                        } => {
+                            let stream_name = StreamName::from_parts(name.clone(), Some(&id)).ok();
+                            let res = handler
+                                .execute(name, id, command, payload, expected_version, context)
+                                .await;
+                            reply_with_trap(reply, stream_name, res)
+                        }
+                        AggregateCommandHandlerMsg::GetState { name, id, reply } => {
+                            let stream_name = StreamName::from_parts(name.clone(), Some(&id)).ok();
+                            let res = handler.get_state(name, id).await;
+                            reply_with_trap(reply, stream_name, res)
+                        }
+                        AggregateCommandHandlerMsg::GetStateAt {
+                            name,
+                            id,
+                            at,
+                            reply,
+                        } => {
+                            let res = handler.get_state_at(name, id, at).await.map_err(|err| {
+                                let trap = err.root_cause().downcast_ref().copied();
+                                (err, trap)
+                            });
+                            // Historical state is replayed on a throwaway instance, so there's no
+                            // entity to quarantine.
+                            reply_with_trap(reply, None, res)
+                        }
+                        AggregateCommandHandlerMsg::CacheStats { .. }
+                        | AggregateCommandHandlerMsg::Quarantined { .. }
+                        | AggregateCommandHandlerMsg::Unquarantine { .. }
+                        | AggregateCommandHandlerMsg::Drain => {
+                            unreachable!("handled by the run loop")
+                        }
+                    }
+                });
             }
-        };
+            Some(res) = executing.join_next() => {
+                let Ok(Some(trap)) = res else {
+                    continue;
+                };
+                if !handle_trap(&name, &handler, &mut quarantine, trap).await {
+                    continue;
+                }
+
+                if circuit_breaker.record_trap() {
+                    warn!(%name, "aggregate command handler restarting after repeated traps");
+
+                    // In-flight commands are drained rather than aborted so that each is replied
+                    // to, and every entity handler is stopped before the store is replaced. All
+                    // entities are then rehydrated on fresh instances.
+                    while let Some(res) = executing.join_next().await {
+                        if let Ok(Some(trap)) = res {
+                            handle_trap(&name, &handler, &mut quarantine, trap).await;
+                        }
+                    }
+                    handler.stop_entities().await;
+                    let module = handler.module.clone().new_instance().await?;
+                    handler = Arc::new(handler.restarted(module));
+                }
+            }
+        }
+    }
+
+    while executing.join_next().await.is_some() {}
+    handler.stop_entities().await;
+    // Drainers wait for the mailbox to be dropped.
+    drop(receiver);
+
+    trace!(%name, "aggregate command handler stopped");
+
+    Ok(())
+}
+
+/// Evicts a trapped entity, and quarantines it if it's at fault, returning
+/// whether it was.
+async fn handle_trap(
+    name: &Category<'static>,
+    handler: &AggregateCommandHandler,
+    quarantine: &mut Quarantine,
+    EntityTrap {
+        stream_name,
+        fault,
+        error,
+    }: EntityTrap,
+) -> bool {
+    // A trapped instance can't be reused, so the entity is dropped and rehydrated on a fresh
+    // store.
+    if let Some(stream_name) = &stream_name {
+        handler
+            .entity_command_handlers
+            .invalidate(stream_name)
+            .await;
+    }
+    if !fault {
+        trace!(%name, ?stream_name, "evicted trapped entity: {error}");
+        return false;
+    }
 
-        let _ = msg.reply.send(res);
+    error!(%name, "aggregate trapped: {error}");
+    if let Some(stream_name) = stream_name {
+        warn!(%stream_name, "quarantining entity");
+        quarantine.insert(stream_name, error);
     }
 
-    warn!(%name, "aggregate command handler restarting");
+    true
+}
+
+/// A trap raised while handling a message.
+struct EntityTrap {
+    /// Entity which trapped, or `None` for throwaway instances.
+    stream_name: Option<StreamName<'static>>,
+    /// Whether the entity itself is at fault, rather than hitting a limit or
+    /// living on a store poisoned by another entity.
+    fault: bool,
+    error: String,
+}
+
+/// Replies with the result, returning the trap if the aggregate trapped.
+fn reply_with_trap<T>(
+    reply: oneshot::Sender<Result<T>>,
+    stream_name: Option<StreamName<'static>>,
+    res: Result<T, (anyhow::Error, Option<Trap>)>,
+) -> Option<EntityTrap> {
+    match res {
+        Ok(res) => {
+            let _ = reply.send(Ok(res));
+            None
+        }
+        Err((err, trap)) => {
+            let trap = trap.map(|trap| EntityTrap {
+                stream_name,
+                fault: !matches!(trap, Trap::CannotEnterComponent | Trap::OutOfFuel)
+                    && !is_memory_limit_exceeded(&err),
+                error: format!("{err:#}"),
+            });
+            let _ = reply.send(Err(err));
+            trap
+        }
+    }
+}
+
+/// Replies to commands for quarantined entities, returning the message if its
+/// entity isn't quarantined.
+fn reject_quarantined(
+    quarantine: &Quarantine,
+    msg: AggregateCommandHandlerMsg,
+) -> Option<AggregateCommandHandlerMsg> {
+    let (name, id) = match &msg {
+        AggregateCommandHandlerMsg::Execute { name, id, .. }
+        | AggregateCommandHandlerMsg::GetState { name, id, .. } => (name, id),
+        _ => return Some(msg),
+    };
+    let Some(quarantined) = StreamName::from_parts(name.clone(), Some(id))
+        .ok()
+        .and_then(|stream_name| quarantine.get(&stream_name))
+    else {
+        return Some(msg);
+    };
+
+    match msg {
+        AggregateCommandHandlerMsg::Execute { reply, .. } => {
+            let _ = reply.send(Err(quarantined.into()));
+        }
+        AggregateCommandHandlerMsg::GetState { reply, .. } => {
+            let _ = reply.send(Err(quarantined.into()));
+        }
+        _ => unreachable!("only entity messages are quarantined"),
+    }
+
+    None
+}
+
+fn is_memory_limit_exceeded(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref(),
+        Some(AggregateError::MemoryLimitExceeded(_))
+    )
+}
 
-    let module = handler.module.new_instance().await?;
-    command_gateway.start_module_from_module(name, module).await
+/// Takes an error shared by the callers awaiting an entity's hydration.
+///
+/// If the error is still shared, it's formatted into a new error, which keeps
+/// the aggregate error or exceeded budget it's classified by.
+fn unshare_error(err: Arc<Error>) -> Error {
+    let err = match Arc::try_unwrap(err) {
+        Ok(err) => return err,
+        Err(err) => err,
+    };
+    let shared = anyhow!("{err:#}");
+    if let Some(aggregate_err) = err.downcast_ref::<AggregateError>() {
+        shared.context(aggregate_err.clone())
+    } else if let Some(exceeded) = err.downcast_ref::<ExecutionBudgetExceeded>() {
+        shared.context(*exceeded)
+    } else {
+        shared
+    }
 }
 
 struct AggregateCommandHandler {
     outbox_relay: OutboxRelayHandle,
+    command_dispatcher: CommandDispatcherHandle,
     message_store: MessageStore,
     broadcaster: BroadcasterHandle,
+    cache: EntityCacheSettings,
+    cache_counters: Arc<EntityCacheCounters>,
+    command_log: bool,
+    event_metadata: EventMetadata,
     module: Module,
     entity_command_handlers: Cache<StreamName<'static>, EntityCommandHandlerHandle>,
+    /// Entities with a running command handler, kept across restarts.
+    live_entities: LiveEntities,
 }
 
 impl AggregateCommandHandler {
+    /// Returns a handler for a new instance of the module, with an empty
+    /// entity cache.
+    fn restarted(&self, module: Module) -> Self {
+        AggregateCommandHandler {
+            outbox_relay: self.outbox_relay.clone(),
+            command_dispatcher: self.command_dispatcher.clone(),
+            message_store: self.message_store.clone(),
+            broadcaster: self.broadcaster.clone(),
+            cache: self.cache,
+            cache_counters: Arc::clone(&self.cache_counters),
+            command_log: self.command_log,
+            event_metadata: self.event_metadata.clone(),
+            module,
+            entity_command_handlers: self.cache.build(),
+            live_entities: self.live_entities.clone(),
+        }
+    }
+
+    fn cache_stats(&self) -> EntityCacheStats {
+        self.cache_counters.stats(&self.entity_command_handlers)
+    }
+
+    /// Evicts every entity, and waits for their command handlers to stop.
+    async fn stop_entities(&self) {
+        self.entity_command_handlers.invalidate_all();
+        // Invalidated entities are only evicted, stopping their handlers, once pending tasks run.
+        self.entity_command_handlers.run_pending_tasks().await;
+        self.live_entities.stopped().await;
+    }
+
     async fn execute(
         &self,
         name: Category<'static>,
         id: ID<'static>,
         command: String,
         payload: Value,
+        expected_version: ExpectedVersion,
+        context: CommandContext,
     ) -> Result<Result<Vec<Message<'static>>, serde_json::Value>, (anyhow::Error, Option<Trap>)>
     {
+        self.with_entity(name, id, |handle| {
+            let (command, payload, context) = (command.clone(), payload.clone(), context.clone());
+            async move {
+                handle
+                    .execute(command, payload, expected_version, context)
+                    .await
+            }
+        })
+        .await
+    }
+
+    async fn get_state(
+        &self,
+        name: Category<'static>,
+        id: ID<'static>,
+    ) -> Result<Option<Value>, (anyhow::Error, Option<Trap>)> {
+        self.with_entity(name, id, |handle| async move { handle.get_state().await })
+            .await
+    }
+
+    /// Sends a message to the entity's command handler with `send`, retrying
+    /// on a rehydrated entity if the handler stopped after being evicted, or
+    /// if its store was poisoned by another entity.
+    async fn with_entity<T, F, Fut>(
+        &self,
+        name: Category<'static>,
+        id: ID<'static>,
+        send: F,
+    ) -> Result<T, (anyhow::Error, Option<Trap>)>
+    where
+        F: Fn(EntityCommandHandlerHandle) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let stream_name = StreamName::from_parts(name.clone(), Some(&id)).ok();
+        let mut retries = 0;
+        let res = loop {
+            let handle = self
+                .entity_command_handler(name.clone(), id.clone())
+                .await?;
+            match send(handle).await {
+                Err(err) if err.is::<EntityStopped>() && retries < ENTITY_STOPPED_RETRIES => {
+                    retries += 1;
+                }
+                Err(err) if err.is::<StorePoisoned>() && retries < ENTITY_STOPPED_RETRIES => {
+                    if let Some(stream_name) = &stream_name {
+                        self.entity_command_handlers.invalidate(stream_name).await;
+                    }
+                    retries += 1;
+                }
+                res => break res,
+            }
+        };
+        if let (Err(err), Some(stream_name)) = (&res, stream_name) {
+            self.evict_on_memory_limit(stream_name, err).await;
+        }
+
+        res.map_err(|err| {
+            let trap = err.root_cause().downcast_ref().copied();
+            (err, trap)
+        })
+    }
+
+    /// Evicts an entity from the cache if it exceeded the module's memory
+    /// limits.
+    async fn evict_on_memory_limit(&self, stream_name: StreamName<'static>, err: &anyhow::Error) {
+        if is_memory_limit_exceeded(err) {
+            warn!(%stream_name, "evicting entity: {err}");
+            self.entity_command_handlers.invalidate(&stream_name).await;
+        }
+    }
+
+    /// Replays an entity's events up to a point in its history on a throwaway
+    /// instance, bypassing the entity cache.
+    async fn get_state_at(
+        &self,
+        name: Category<'static>,
+        id: ID<'static>,
+        at: StateAt,
+    ) -> Result<HistoricalState> {
+        let stream_name = StreamName::from_parts(name, Some(&id))?;
+        let mut instance = self.module.init(&id).await?;
+        let stream = self.message_store.stream(stream_name)?;
+
+        let res = async {
+            let mut last_event = None;
+            for res in stream.iter_all_messages::<()>() {
+                let raw_message = res?;
+                let message = raw_message.message()?;
+                if !at.includes(&message) {
+                    break;
+                }
+
+                let event = Event {
+                    event: Cow::Borrowed(&message.msg_type),
+                    payload: Cow::Owned(serde_json::to_string(&message.data)?),
+                };
+                instance.apply(&[(message.position, event)]).await?;
+                last_event = Some(message.into_owned());
+            }
+
+            let state = instance
+                .state()
+                .await?
+                .map(|state| serde_json::from_str(&state).context("failed to deserialize state"))
+                .transpose()?;
+
+            Ok(HistoricalState { state, last_event })
+        }
+        .await;
+
+        if let Err(err) = instance.resource_drop().await {
+            warn!(stream_name = %stream.stream_name(), "failed to drop throwaway instance: {err}");
+        }
+
+        res
+    }
+
+    /// Returns the entity's command handler, hydrating the entity if it's not
+    /// cached.
+    async fn entity_command_handler(
+        &self,
+        name: Category<'static>,
+        id: ID<'static>,
+    ) -> Result<EntityCommandHandlerHandle, (anyhow::Error, Option<Trap>)> {
         let Ok(stream_name) = StreamName::from_parts(name, Some(&id)) else {
             return Err((anyhow!("invalid name or id"), None));
         };
@@ -144,8 +659,15 @@ impl AggregateCommandHandler {
             .entity_command_handlers
             .entry(stream_name.clone())
             .or_try_insert_with(async {
+                // A previous handler of the entity finishes handling its queued commands first.
+                let lease = self.live_entities.lease(stream_name.clone()).await;
                 let id = stream_name.id().context("missing ID")?;
                 let mut instance = self.module.init(&id).await?;
+                let command_stream = if self.command_log {
+                    Some(self.message_store.stream(command_stream_name(&stream_name)?)?)
+                } else {
+                    None
+                };
                 let stream = self.message_store.stream(stream_name)?;
                 for res in stream.iter_all_messages::<()>() {
                     let raw_message = res?;
@@ -158,27 +680,32 @@ impl AggregateCommandHandler {
                     trace!(stream_name = ?stream.stream_name(), position = message.position, "applied event");
                 }
 
+                let weight = self.cache.weigher.weigh(&instance).await;
+                let services = EntityServices {
+                    outbox_relay: self.outbox_relay.clone(),
+                    command_dispatcher: self.command_dispatcher.clone(),
+                    command_outbox: self.message_store.command_outbox()?,
+                    broadcaster: self.broadcaster.clone(),
+                    event_metadata: self.event_metadata.clone(),
+                };
                 let handle = EntityCommandHandlerHandle::new(
-                    self.outbox_relay.clone(),
-                    self.broadcaster.clone(),
+                    services,
                     instance,
                     stream,
+                    command_stream,
+                    weight,
+                    lease,
                 );
 
                 Ok(handle)
             })
             .await
             .map_err(|err: Arc<Error>| {
-                (anyhow!("{err}"), err.root_cause().downcast_ref().copied())
+                let trap = err.root_cause().downcast_ref().copied();
+                (unshare_error(err), trap)
             })?;
+        self.cache_counters.record(!entry.is_fresh());
 
-        entry
-            .value()
-            .execute(command, payload)
-            .await
-            .map_err(|err| {
-                let trap = err.root_cause().downcast_ref().copied();
-                (err, trap)
-            })
+        Ok(entry.into_value())
     }
 }
@@ -0,0 +1,73 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Context a command is handled with, passed to the aggregate as a
+/// [`thalo::Context`].
+///
+/// The context is recorded in the command log, so the command can be replayed
+/// with the same timestamp and seed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandContext {
+    /// Unix timestamp in milliseconds of when the command was received.
+    pub timestamp: u64,
+    /// Seed of the aggregate's random number generator.
+    pub seed: u64,
+    /// Correlation metadata of the command, such as a `correlation_id`.
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub metadata: Map<String, Value>,
+}
+
+impl CommandContext {
+    /// Creates a context with the current time and a random seed.
+    pub fn new(metadata: Map<String, Value>) -> Self {
+        CommandContextOverrides {
+            metadata,
+            ..Default::default()
+        }
+        .resolve()
+    }
+}
+
+/// Context requested for a command.
+///
+/// Values left as `None` are chosen by the runtime when the command is
+/// received.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommandContextOverrides {
+    pub timestamp: Option<u64>,
+    pub seed: Option<u64>,
+    pub metadata: Map<String, Value>,
+}
+
+impl CommandContextOverrides {
+    /// Resolves the context, using the current time and a random seed for
+    /// values which aren't overridden.
+    pub fn resolve(self) -> CommandContext {
+        let timestamp = self.timestamp.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_millis() as u64)
+                .unwrap_or(0)
+        });
+
+        CommandContext {
+            timestamp,
+            seed: self.seed.unwrap_or_else(rand::random),
+            metadata: self.metadata,
+        }
+    }
+}
+
+impl From<CommandContext> for CommandContextOverrides {
+    /// Overrides every value, so a recorded command is replayed with the same
+    /// context.
+    fn from(context: CommandContext) -> Self {
+        CommandContextOverrides {
+            timestamp: Some(context.timestamp),
+            seed: Some(context.seed),
+            metadata: context.metadata,
+        }
+    }
+}
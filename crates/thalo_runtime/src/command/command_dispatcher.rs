@@ -0,0 +1,323 @@
+//! Dispatcher of commands sent between aggregates.
+//!
+//! Commands sent by an aggregate are written to the command outbox in the same
+//! transaction as the events of the command which sent them. The dispatcher
+//! executes each command through the [`CommandGatewayHandle`], and removes it
+//! from the outbox once it's been handled, whether it was accepted or
+//! rejected.
+//!
+//! Commands which fail to execute, for example because their module isn't
+//! running, are retried, and are moved to a dead letter stream once they fail
+//! permanently or run out of attempts (see [`dead_letter`](super::dead_letter)).
+//! Commands are delivered at least once, and may be handled again if the
+//! runtime stops before removing them from the outbox. Commands to the same
+//! entity are dispatched in the order they were sent.
+//!
+//! Commands are executed with the global ID of the message which caused them
+//! as `causation_global_id` in their correlation metadata.
+//!
+//! Commands sent with a schedule are handed to the scheduler rather than
+//! executed, and are delivered once they're due.
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+use thalo::stream_name::{Category, StreamName, ID};
+use thalo_message_store::command_outbox::{CommandOutbox, OutboxCommand, OutboxSchedule};
+use thalo_message_store::scheduled_commands::ScheduledCommand;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{error, info, trace, warn};
+
+use super::dead_letter::{DeadLetter, DeadLetters, DeliveryFailed, MAX_DELIVERY_ATTEMPTS};
+use super::{CommandContext, CommandGatewayHandle, ExpectedVersion};
+use crate::module::OutboundCommand;
+
+/// Maximum number of commands dispatched in a batch.
+const BATCH_SIZE: usize = 100;
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct CommandDispatcherHandle {
+    sender: mpsc::Sender<CommandDispatcherMsg>,
+}
+
+enum CommandDispatcherMsg {
+    DispatchPending,
+    Stop { reply: oneshot::Sender<()> },
+}
+
+impl CommandDispatcherHandle {
+    pub fn new(
+        outbox: CommandOutbox,
+        dead_letters: DeadLetters,
+        command_gateway: CommandGatewayHandle,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(1);
+        tokio::spawn(run_command_dispatcher(
+            receiver,
+            outbox,
+            dead_letters,
+            command_gateway,
+        ));
+
+        CommandDispatcherHandle { sender }
+    }
+
+    /// Notifies the dispatcher of new commands in the outbox.
+    pub fn dispatch_pending(&self) -> Result<()> {
+        match self.sender.try_send(CommandDispatcherMsg::DispatchPending) {
+            // A full channel already holds a notification.
+            Ok(()) | Err(mpsc::error::TrySendError::Full(_)) => Ok(()),
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                bail!("command dispatcher is not running")
+            }
+        }
+    }
+
+    /// Stops the dispatcher, abandoning any command being dispatched.
+    ///
+    /// Commands left in the outbox are dispatched once the runtime is started
+    /// again.
+    pub async fn stop(&self) -> Result<()> {
+        let (reply, recv) = oneshot::channel();
+        let _ = self.sender.send(CommandDispatcherMsg::Stop { reply }).await;
+        recv.await.context("no response from command dispatcher")
+    }
+}
+
+async fn run_command_dispatcher(
+    mut receiver: mpsc::Receiver<CommandDispatcherMsg>,
+    outbox: CommandOutbox,
+    dead_letters: DeadLetters,
+    command_gateway: CommandGatewayHandle,
+) {
+    // Commands left from a previous run are dispatched straight away.
+    let mut pending = !outbox.is_empty();
+    let mut dispatching: Option<JoinHandle<Result<bool>>> = None;
+    let mut timer = interval(RETRY_INTERVAL);
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        if pending && dispatching.is_none() {
+            pending = false;
+            dispatching = Some(tokio::spawn(dispatch_batch(
+                outbox.clone(),
+                dead_letters.clone(),
+                command_gateway.clone(),
+            )));
+        }
+
+        tokio::select! {
+            msg = receiver.recv() => match msg {
+                Some(CommandDispatcherMsg::DispatchPending) => pending = true,
+                Some(CommandDispatcherMsg::Stop { reply }) => {
+                    if let Some(dispatching) = dispatching.take() {
+                        dispatching.abort();
+                    }
+                    let _ = reply.send(());
+                    break;
+                }
+                None => break,
+            },
+            res = async { dispatching.as_mut().unwrap().await }, if dispatching.is_some() => {
+                dispatching = None;
+                match res {
+                    Ok(Ok(more)) => pending |= more,
+                    Ok(Err(err)) => error!("failed to dispatch commands: {err}"),
+                    Err(err) => error!("command dispatcher task failed: {err}"),
+                }
+            }
+            _ = timer.tick() => {
+                // Retries commands which failed to be dispatched.
+                pending |= !outbox.is_empty();
+            }
+        }
+    }
+
+    info!("command dispatcher stopped");
+}
+
+/// Dispatches up to [`BATCH_SIZE`] commands from the outbox, returning
+/// whether there may be more commands to dispatch.
+///
+/// Once a command fails to be dispatched, later commands to the same entity
+/// are skipped until the next batch, so that they're handled in order. Commands
+/// which fail permanently, or for the last time, are moved to the entity's dead
+/// letter stream instead.
+async fn dispatch_batch(
+    outbox: CommandOutbox,
+    dead_letters: DeadLetters,
+    command_gateway: CommandGatewayHandle,
+) -> Result<bool> {
+    let mut failed = HashSet::new();
+    let mut dispatched = 0;
+    for res in outbox.iter() {
+        if dispatched == BATCH_SIZE {
+            return Ok(true);
+        }
+
+        let (id, command) = res?;
+        if failed.contains(&command.stream_name) {
+            continue;
+        }
+
+        let stream_name = command.stream_name.clone();
+        dispatched += 1;
+        let Err(DeliveryFailed { error, permanent }) =
+            dispatch_command(&command_gateway, command.clone()).await
+        else {
+            outbox.remove(id)?;
+            continue;
+        };
+
+        let attempts = command.failed_attempts + 1;
+        if permanent || attempts >= MAX_DELIVERY_ATTEMPTS {
+            warn!(%stream_name, attempts, "moving command to dead letter stream: {error:#}");
+            let dead_letter = DeadLetter {
+                payload: command.payload,
+                metadata: command.metadata,
+                error: format!("{error:#}"),
+                attempts,
+            };
+            dead_letters
+                .write(&stream_name, &command.command, dead_letter)
+                .await?;
+            outbox.remove(id)?;
+        } else {
+            warn!(%stream_name, attempts, "failed to dispatch command, retrying: {error:#}");
+            let command = OutboxCommand {
+                failed_attempts: attempts,
+                ..command
+            };
+            outbox.update(id, &command)?;
+            failed.insert(stream_name);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Executes a command, succeeding if it was accepted or rejected by the
+/// aggregate, or schedules it if it was sent with a schedule.
+async fn dispatch_command(
+    command_gateway: &CommandGatewayHandle,
+    OutboxCommand {
+        stream_name,
+        command,
+        payload,
+        metadata,
+        schedule,
+        causation_global_id,
+        ..
+    }: OutboxCommand<'static>,
+) -> Result<(), DeliveryFailed> {
+    let mut context: CommandContext = serde_json::from_value(metadata.into_owned())
+        .context("invalid command context")
+        .map_err(DeliveryFailed::permanent)?;
+    if let Some(causation_global_id) = causation_global_id {
+        context
+            .metadata
+            .entry("causation_global_id")
+            .or_insert(causation_global_id.into());
+    }
+
+    if let Some(OutboxSchedule {
+        scheduled_by,
+        key,
+        due,
+    }) = schedule
+    {
+        let metadata = serde_json::to_value(context)
+            .context("invalid command context")
+            .map_err(DeliveryFailed::permanent)?;
+        command_gateway
+            .schedule_command(ScheduledCommand {
+                scheduled_by,
+                key,
+                due,
+                stream_name: stream_name.clone(),
+                command: command.clone(),
+                payload,
+                metadata: Cow::Owned(metadata),
+                failed_attempts: 0,
+            })
+            .await?;
+        trace!(%stream_name, %command, due, "scheduled command");
+        return Ok(());
+    }
+
+    let name = stream_name.category().into_owned();
+    let id = stream_name
+        .id()
+        .context("missing ID")
+        .map_err(DeliveryFailed::permanent)?
+        .into_owned();
+    let res = command_gateway
+        .execute(
+            name,
+            id,
+            command.to_string(),
+            payload.into_owned(),
+            ExpectedVersion::Any,
+            context,
+        )
+        .await?;
+    match res {
+        Ok(_) => trace!(%stream_name, %command, "dispatched command"),
+        Err(err) => warn!(%stream_name, %command, "dispatched command was rejected: {err}"),
+    }
+
+    Ok(())
+}
+
+/// Prepares a command sent by a module for the command outbox.
+///
+/// The command is executed with the `timestamp` and `metadata` of the message
+/// which caused it, along with the stream which sent it, and a random seed.
+/// Scheduled commands are executed with the time they're due as their
+/// timestamp, and keyed within the stream which sent them.
+pub(crate) fn outbox_command(
+    outbound: OutboundCommand,
+    causation_stream_name: &StreamName<'_>,
+    timestamp: u64,
+    mut metadata: Map<String, Value>,
+) -> Result<OutboxCommand<'static>> {
+    metadata.insert(
+        "causation_stream_name".to_string(),
+        Value::String(causation_stream_name.to_string()),
+    );
+    let name = Category::new(outbound.name)?;
+    let id = ID::new(outbound.id)?;
+    let stream_name = StreamName::from_parts(name, Some(&id))?;
+    let payload = serde_json::from_str(&outbound.payload).with_context(|| {
+        format!(
+            "invalid payload of command '{}' sent to '{stream_name}'",
+            outbound.command
+        )
+    })?;
+    let schedule = outbound.schedule.map(|schedule| OutboxSchedule {
+        scheduled_by: Some(causation_stream_name.clone().into_owned()),
+        key: Cow::Owned(schedule.key),
+        due: schedule.due,
+    });
+    let context = CommandContext {
+        timestamp: schedule.as_ref().map_or(timestamp, |schedule| schedule.due),
+        seed: rand::random(),
+        metadata,
+    };
+
+    Ok(OutboxCommand {
+        stream_name,
+        command: Cow::Owned(outbound.command),
+        payload: Cow::Owned(payload),
+        metadata: Cow::Owned(serde_json::to_value(context)?),
+        schedule,
+        causation_global_id: None,
+        failed_attempts: 0,
+    })
+}
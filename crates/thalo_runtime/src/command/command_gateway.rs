@@ -1,21 +1,41 @@
 use std::collections::HashMap;
+use std::future::Future;
+use std::iter;
 use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use anyhow::{anyhow, Context, Result};
+use futures::future;
+use semver::Version;
 use serde_json::Value;
-use thalo::stream_name::{Category, ID};
+use thalo::stream_name::{Category, StreamName, ID};
 use thalo_message_store::message::Message;
+use thalo_message_store::scheduled_commands::ScheduledCommand;
 use thalo_message_store::MessageStore;
 use tokio::fs;
 use tokio::sync::{mpsc, oneshot};
-use tracing::{error, warn};
+use tracing::{error, info, trace};
 use wasmtime::Engine;
 
 use super::aggregate_command_handler::AggregateCommandHandlerHandle;
+use super::command_dispatcher::CommandDispatcherHandle;
+use super::dead_letter::DeadLetters;
+use super::module_stats::{ModuleStats, RouteStats};
 use super::outbox_relay::OutboxRelayHandle;
+use super::scheduler::SchedulerHandle;
+use super::{
+    shadow_replay, CommandContext, ExpectedVersion, HistoricalState, StateAt, VerificationReport,
+};
 use crate::broadcaster::BroadcasterHandle;
+use crate::module::budget::ExecutionBudget;
+use crate::module::cache::{ComponentCache, COMPONENT_CACHE_DIR};
+use crate::module::limits::MemoryLimits;
 use crate::module::Module;
+use crate::registry::{CanaryRouting, ModuleID, Registry};
 use crate::relay::Relay;
+use crate::runtime::RuntimeConfig;
 
 #[derive(Clone)]
 pub struct CommandGatewayHandle {
@@ -28,22 +48,38 @@ impl CommandGatewayHandle {
         message_store: MessageStore,
         relay: Relay,
         broadcaster: BroadcasterHandle,
-        cache_size: u64,
+        config: RuntimeConfig,
         modules_path: PathBuf,
-    ) -> Self {
+    ) -> Result<Self> {
         let (sender, receiver) = mpsc::channel(16);
+        let command_dispatcher = CommandDispatcherHandle::new(
+            message_store.command_outbox()?,
+            DeadLetters::new(message_store.clone(), broadcaster.clone()),
+            CommandGatewayHandle {
+                sender: sender.clone(),
+            },
+        );
+        let scheduler = SchedulerHandle::new(
+            message_store.scheduled_commands()?,
+            DeadLetters::new(message_store.clone(), broadcaster.clone()),
+            CommandGatewayHandle {
+                sender: sender.clone(),
+            },
+        );
         tokio::spawn(run_command_gateway(
-            sender.clone(),
             receiver,
+            sender.downgrade(),
+            command_dispatcher,
+            scheduler,
             engine,
             message_store,
             relay,
             broadcaster,
-            cache_size,
+            config,
             modules_path,
         ));
 
-        CommandGatewayHandle { sender }
+        Ok(CommandGatewayHandle { sender })
     }
 
     pub async fn execute(
@@ -52,6 +88,8 @@ impl CommandGatewayHandle {
         id: ID<'static>,
         command: String,
         payload: Value,
+        expected_version: ExpectedVersion,
+        context: CommandContext,
     ) -> Result<Result<Vec<Message<'static>>, serde_json::Value>> {
         let (reply, recv) = oneshot::channel();
         let msg = CommandGatewayMsg::Execute {
@@ -59,6 +97,38 @@ impl CommandGatewayHandle {
             id,
             command,
             payload,
+            expected_version,
+            context,
+            reply,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.context("no response from command handler")?
+    }
+
+    pub async fn get_state(
+        &self,
+        name: Category<'static>,
+        id: ID<'static>,
+    ) -> Result<Option<Value>> {
+        let (reply, recv) = oneshot::channel();
+        let msg = CommandGatewayMsg::GetState { name, id, reply };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.context("no response from command handler")?
+    }
+
+    pub async fn get_state_at(
+        &self,
+        name: Category<'static>,
+        id: ID<'static>,
+        at: StateAt,
+    ) -> Result<HistoricalState> {
+        let (reply, recv) = oneshot::channel();
+        let msg = CommandGatewayMsg::GetStateAt {
+            name,
+            id,
+            at,
             reply,
         };
 
@@ -66,33 +136,156 @@ impl CommandGatewayHandle {
         recv.await.context("no response from command handler")?
     }
 
-    pub async fn start_module_from_file(
+    /// Adds a module file to the registry, activating it if it's versioned.
+    pub async fn publish_module(&self, id: ModuleID, path: PathBuf) -> Result<()> {
+        let (reply, recv) = oneshot::channel();
+        let msg = CommandGatewayMsg::PublishModule { id, path, reply };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.context("no response from command gateway")?
+    }
+
+    /// Adds or removes a module file in the registry after it's changed on
+    /// disk, reloading the module if its active version changed.
+    pub async fn module_file_changed(&self, id: ModuleID, path: PathBuf) -> Result<()> {
+        let (reply, recv) = oneshot::channel();
+        let msg = CommandGatewayMsg::ModuleFileChanged { id, path, reply };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.context("no response from command gateway")?
+    }
+
+    /// Replays the category's streams through a module file and the active
+    /// version of the module, reporting differences.
+    pub async fn verify_module(
         &self,
         name: Category<'static>,
         path: PathBuf,
-    ) -> Result<()> {
+        sample: Option<usize>,
+    ) -> Result<VerificationReport> {
         let (reply, recv) = oneshot::channel();
-        let msg = CommandGatewayMsg::StartModuleFromFile { name, path, reply };
+        let msg = CommandGatewayMsg::VerifyModule {
+            name,
+            path,
+            sample,
+            reply,
+        };
 
         let _ = self.sender.send(msg).await;
         recv.await.context("no response from command gateway")?
     }
 
-    pub async fn start_module_from_module(
+    /// Pins the active version of a module.
+    pub async fn activate_module(&self, name: Category<'static>, version: Version) -> Result<()> {
+        let (reply, recv) = oneshot::channel();
+        let msg = CommandGatewayMsg::ActivateModule {
+            name,
+            version,
+            reply,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.context("no response from command gateway")?
+    }
+
+    /// Activates the version before the active version of a module, returning
+    /// the version rolled back to.
+    pub async fn rollback_module(&self, name: Category<'static>) -> Result<Version> {
+        let (reply, recv) = oneshot::channel();
+        let msg = CommandGatewayMsg::RollbackModule { name, reply };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.context("no response from command gateway")?
+    }
+
+    /// Sets or clears the canary version of a module.
+    pub async fn set_canary(
         &self,
         name: Category<'static>,
-        module: Module,
+        canary: Option<CanaryRouting>,
     ) -> Result<()> {
         let (reply, recv) = oneshot::channel();
-        let msg = CommandGatewayMsg::StartModuleFromModule {
+        let msg = CommandGatewayMsg::SetCanary {
             name,
-            module,
+            canary,
             reply,
         };
 
         let _ = self.sender.send(msg).await;
         recv.await.context("no response from command gateway")?
     }
+
+    /// Releases a quarantined entity, returning whether it was quarantined.
+    pub async fn unquarantine(&self, name: Category<'static>, id: ID<'static>) -> Result<bool> {
+        let (reply, recv) = oneshot::channel();
+        let msg = CommandGatewayMsg::Unquarantine { name, id, reply };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.context("no response from command gateway")?
+    }
+
+    /// Notifies the command dispatcher of new commands in the command outbox.
+    pub async fn dispatch_pending_commands(&self) -> Result<()> {
+        self.sender
+            .send(CommandGatewayMsg::DispatchPendingCommands)
+            .await
+            .map_err(|_| anyhow!("command gateway is not running"))
+    }
+
+    /// Schedules a command to be executed once it's due, replacing any
+    /// command scheduled by the same stream with the same key.
+    pub async fn schedule_command(&self, command: ScheduledCommand<'static>) -> Result<()> {
+        let (reply, recv) = oneshot::channel();
+        let msg = CommandGatewayMsg::ScheduleCommand { command, reply };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.context("no response from command gateway")?
+    }
+
+    /// Cancels a command scheduled by a stream, or through the runtime's API if
+    /// `scheduled_by` is `None`, returning it if it hadn't been delivered.
+    pub async fn cancel_scheduled_command(
+        &self,
+        scheduled_by: Option<StreamName<'static>>,
+        key: String,
+    ) -> Result<Option<ScheduledCommand<'static>>> {
+        let (reply, recv) = oneshot::channel();
+        let msg = CommandGatewayMsg::CancelScheduledCommand {
+            scheduled_by,
+            key,
+            reply,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.context("no response from command gateway")?
+    }
+
+    /// Returns the pending scheduled commands, in the order they're due.
+    pub async fn scheduled_commands(&self) -> Result<Vec<ScheduledCommand<'static>>> {
+        let (reply, recv) = oneshot::channel();
+        let msg = CommandGatewayMsg::ListScheduledCommands { reply };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.context("no response from command gateway")?
+    }
+
+    /// Drains every running module and relays their remaining outbox batches,
+    /// then stops the gateway.
+    pub async fn shutdown(&self) -> Result<()> {
+        let (reply, recv) = oneshot::channel();
+        let msg = CommandGatewayMsg::Shutdown { reply };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.context("no response from command gateway")?
+    }
+
+    pub async fn module_stats(&self, name: Category<'static>) -> Result<ModuleStats> {
+        let (reply, recv) = oneshot::channel();
+        let msg = CommandGatewayMsg::ModuleStats { name, reply };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.context("no response from command gateway")?
+    }
 }
 
 enum CommandGatewayMsg {
@@ -101,41 +294,120 @@ enum CommandGatewayMsg {
         id: ID<'static>,
         command: String,
         payload: Value,
+        expected_version: ExpectedVersion,
+        context: CommandContext,
         reply: oneshot::Sender<Result<Result<Vec<Message<'static>>, serde_json::Value>>>,
     },
-    StartModuleFromFile {
+    GetState {
         name: Category<'static>,
+        id: ID<'static>,
+        reply: oneshot::Sender<Result<Option<Value>>>,
+    },
+    GetStateAt {
+        name: Category<'static>,
+        id: ID<'static>,
+        at: StateAt,
+        reply: oneshot::Sender<Result<HistoricalState>>,
+    },
+    PublishModule {
+        id: ModuleID,
         path: PathBuf,
         reply: oneshot::Sender<Result<()>>,
     },
-    StartModuleFromModule {
+    ModuleFileChanged {
+        id: ModuleID,
+        path: PathBuf,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    VerifyModule {
         name: Category<'static>,
-        module: Module,
+        path: PathBuf,
+        sample: Option<usize>,
+        reply: oneshot::Sender<Result<VerificationReport>>,
+    },
+    ActivateModule {
+        name: Category<'static>,
+        version: Version,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    RollbackModule {
+        name: Category<'static>,
+        reply: oneshot::Sender<Result<Version>>,
+    },
+    SetCanary {
+        name: Category<'static>,
+        canary: Option<CanaryRouting>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Unquarantine {
+        name: Category<'static>,
+        id: ID<'static>,
+        reply: oneshot::Sender<Result<bool>>,
+    },
+    ModuleStats {
+        name: Category<'static>,
+        reply: oneshot::Sender<Result<ModuleStats>>,
+    },
+    DispatchPendingCommands,
+    ScheduleCommand {
+        command: ScheduledCommand<'static>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    CancelScheduledCommand {
+        scheduled_by: Option<StreamName<'static>>,
+        key: String,
+        reply: oneshot::Sender<Result<Option<ScheduledCommand<'static>>>>,
+    },
+    ListScheduledCommands {
+        reply: oneshot::Sender<Result<Vec<ScheduledCommand<'static>>>>,
+    },
+    Shutdown {
         reply: oneshot::Sender<Result<()>>,
     },
+    /// Sent by the gateway to itself once a reload's modules are compiled.
+    ModulesLoaded {
+        name: Category<'static>,
+        generation: u64,
+        loaded: Result<Box<LoadedVersions>>,
+        after: AfterReload,
+    },
 }
 
+/// Called with the result of reloading a module, once it's started or failed
+/// to load.
+type AfterReload = Box<dyn FnOnce(&mut CommandGateway, Result<()>) + Send>;
+
+/// Compiles the versions of a module for a reload.
+type LoadVersions = Pin<Box<dyn Future<Output = Result<LoadedVersions>> + Send>>;
+
 async fn run_command_gateway(
-    sender: mpsc::Sender<CommandGatewayMsg>,
     mut receiver: mpsc::Receiver<CommandGatewayMsg>,
+    sender: mpsc::WeakSender<CommandGatewayMsg>,
+    command_dispatcher: CommandDispatcherHandle,
+    scheduler: SchedulerHandle,
     engine: Engine,
     message_store: MessageStore,
     relay: Relay,
     broadcaster: BroadcasterHandle,
-    cache_size: u64,
+    config: RuntimeConfig,
     modules_path: PathBuf,
 ) {
     let mut cmd_gateway = CommandGateway {
-        handle: CommandGatewayHandle { sender },
+        sender,
+        command_dispatcher,
+        scheduler,
         engine,
         message_store,
         relay,
         broadcaster,
-        cache_size,
+        config,
+        component_cache: ComponentCache::new(modules_path.join(COMPONENT_CACHE_DIR)),
+        registry: Registry::new(modules_path.clone()),
         modules: HashMap::new(),
+        reloads: HashMap::new(),
     };
 
-    if let Err(err) = cmd_gateway.load_modules_in_dir(modules_path.clone()).await {
+    if let Err(err) = cmd_gateway.load_modules().await {
         error!(
             modules_path = %modules_path.display(),
             "failed to load modules in dir: {err}"
@@ -149,22 +421,153 @@ async fn run_command_gateway(
                 id,
                 command,
                 payload,
+                expected_version,
+                context,
                 reply,
             } => {
-                let res = cmd_gateway.execute(name, id, command, payload).await;
-                let _ = reply.send(res);
+                // Executed in a separate task, so that slow commands don't block other
+                // aggregates.
+                let res = cmd_gateway
+                    .route(&name, &id)
+                    .map(|route| route.execute_handler());
+                tokio::spawn(async move {
+                    let res = match res {
+                        Ok(aggregate_command_handler) => {
+                            aggregate_command_handler
+                                .execute(name, id, command, payload, expected_version, context)
+                                .await
+                        }
+                        Err(err) => Err(err),
+                    };
+                    let _ = reply.send(res);
+                });
             }
-            CommandGatewayMsg::StartModuleFromFile { name, path, reply } => {
-                let res = cmd_gateway.start_module_from_file(name, path).await;
-                let _ = reply.send(res);
+            CommandGatewayMsg::GetState { name, id, reply } => {
+                let res = cmd_gateway
+                    .route(&name, &id)
+                    .map(|route| route.handler.clone());
+                tokio::spawn(async move {
+                    let res = match res {
+                        Ok(aggregate_command_handler) => {
+                            aggregate_command_handler.get_state(name, id).await
+                        }
+                        Err(err) => Err(err),
+                    };
+                    let _ = reply.send(res);
+                });
+            }
+            CommandGatewayMsg::GetStateAt {
+                name,
+                id,
+                at,
+                reply,
+            } => {
+                let res = cmd_gateway
+                    .route(&name, &id)
+                    .map(|route| route.handler.clone());
+                tokio::spawn(async move {
+                    let res = match res {
+                        Ok(aggregate_command_handler) => {
+                            aggregate_command_handler.get_state_at(name, id, at).await
+                        }
+                        Err(err) => Err(err),
+                    };
+                    let _ = reply.send(res);
+                });
+            }
+            // Modules are compiled in separate tasks, and reply once they're started.
+            CommandGatewayMsg::PublishModule { id, path, reply } => {
+                cmd_gateway.publish_module(id, path, reply);
+            }
+            CommandGatewayMsg::ModuleFileChanged { id, path, reply } => {
+                cmd_gateway.module_file_changed(id, path, reply);
+            }
+            CommandGatewayMsg::VerifyModule {
+                name,
+                path,
+                sample,
+                reply,
+            } => {
+                // Replayed in a separate task, as replaying a large category can take a while.
+                let verification = cmd_gateway.verify_module(name, path, sample);
+                tokio::spawn(async move {
+                    let _ = reply.send(verification.await);
+                });
             }
-            CommandGatewayMsg::StartModuleFromModule {
+            CommandGatewayMsg::ActivateModule {
                 name,
-                module,
+                version,
                 reply,
             } => {
-                let res = cmd_gateway.start_module_from_module(name, module).await;
+                cmd_gateway.activate_module(name, version, reply);
+            }
+            CommandGatewayMsg::RollbackModule { name, reply } => {
+                cmd_gateway.rollback_module(name, reply);
+            }
+            CommandGatewayMsg::SetCanary {
+                name,
+                canary,
+                reply,
+            } => {
+                cmd_gateway.set_canary(name, canary, reply);
+            }
+            CommandGatewayMsg::ModulesLoaded {
+                name,
+                generation,
+                loaded,
+                after,
+            } => {
+                let loaded = loaded.map(|loaded| *loaded);
+                cmd_gateway.modules_loaded(name, generation, loaded, after);
+            }
+            CommandGatewayMsg::Unquarantine { name, id, reply } => {
+                let released = cmd_gateway.unquarantine(&name, id);
+                tokio::spawn(async move {
+                    let _ = reply.send(released.await);
+                });
+            }
+            CommandGatewayMsg::ModuleStats { name, reply } => {
+                // Collected in a separate task, as handlers only respond once they've drained
+                // the handlers they replaced.
+                let stats = cmd_gateway.module_stats(&name);
+                tokio::spawn(async move {
+                    let _ = reply.send(stats.await);
+                });
+            }
+            CommandGatewayMsg::DispatchPendingCommands => {
+                if let Err(err) = cmd_gateway.command_dispatcher.dispatch_pending() {
+                    error!("failed to notify command dispatcher: {err}");
+                }
+            }
+            // Handled in separate tasks, as the scheduler may be waiting on the gateway to
+            // execute a due command.
+            CommandGatewayMsg::ScheduleCommand { command, reply } => {
+                let scheduler = cmd_gateway.scheduler.clone();
+                tokio::spawn(async move {
+                    let _ = reply.send(scheduler.schedule(command).await);
+                });
+            }
+            CommandGatewayMsg::CancelScheduledCommand {
+                scheduled_by,
+                key,
+                reply,
+            } => {
+                let scheduler = cmd_gateway.scheduler.clone();
+                tokio::spawn(async move {
+                    let _ = reply.send(scheduler.cancel(scheduled_by, key).await);
+                });
+            }
+            CommandGatewayMsg::ListScheduledCommands { reply } => {
+                let scheduler = cmd_gateway.scheduler.clone();
+                tokio::spawn(async move {
+                    let _ = reply.send(scheduler.list().await);
+                });
+            }
+            CommandGatewayMsg::Shutdown { reply } => {
+                let res = cmd_gateway.shutdown().await;
                 let _ = reply.send(res);
+                info!("command gateway stopped");
+                return;
             }
         }
     }
@@ -173,98 +576,680 @@ async fn run_command_gateway(
 }
 
 struct CommandGateway {
-    handle: CommandGatewayHandle,
+    sender: mpsc::WeakSender<CommandGatewayMsg>,
+    command_dispatcher: CommandDispatcherHandle,
+    scheduler: SchedulerHandle,
     engine: Engine,
     message_store: MessageStore,
     relay: Relay,
     broadcaster: BroadcasterHandle,
-    cache_size: u64,
-    modules: HashMap<Category<'static>, AggregateCommandHandlerHandle>,
+    config: RuntimeConfig,
+    component_cache: ComponentCache,
+    registry: Registry,
+    modules: HashMap<Category<'static>, RunningModule>,
+    /// Latest reload of each module, so that a reload finishing after a newer
+    /// one started is discarded.
+    reloads: HashMap<Category<'static>, u64>,
+}
+
+/// Handlers of a running module, with entities routed between the active
+/// version and an optional canary version.
+struct RunningModule {
+    active: RunningVersion,
+    canary: Option<(CanaryRouting, RunningVersion)>,
+    outbox_relay: OutboxRelayHandle,
+}
+
+/// A module version serving commands.
+struct RunningVersion {
+    version: Option<Version>,
+    /// Hash of the module file, to skip reloading unchanged files.
+    hash: blake3::Hash,
+    module: Module,
+    handler: AggregateCommandHandlerHandle,
+    /// Number of commands routed to the version.
+    commands: Arc<AtomicU64>,
+}
+
+impl RunningModule {
+    fn route(&self, id: &ID<'_>) -> &RunningVersion {
+        match &self.canary {
+            Some((canary, running)) if canary.routes(id) => running,
+            _ => &self.active,
+        }
+    }
+
+    fn handlers(&self) -> impl Iterator<Item = &AggregateCommandHandlerHandle> {
+        iter::once(&self.active.handler)
+            .chain(self.canary.as_ref().map(|(_, running)| &running.handler))
+    }
+
+    fn stats(&self) -> impl Future<Output = Result<ModuleStats>> {
+        let active = self.active.stats(None);
+        let canary = self
+            .canary
+            .as_ref()
+            .map(|(canary, running)| running.stats(Some(canary.percent)));
+
+        async move {
+            let mut routes = vec![active.await?];
+            if let Some(canary) = canary {
+                routes.push(canary.await?);
+            }
+
+            Ok(ModuleStats { routes })
+        }
+    }
+}
+
+impl RunningVersion {
+    /// Returns the handler for executing a command, counting the command.
+    fn execute_handler(&self) -> AggregateCommandHandlerHandle {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+        self.handler.clone()
+    }
+
+    fn is_unchanged(&self, desired: &DesiredVersion) -> bool {
+        self.version == desired.version && self.hash == desired.hash
+    }
+
+    fn stats(&self, canary_percent: Option<u8>) -> impl Future<Output = Result<RouteStats>> {
+        let version = self.version.clone();
+        let commands = self.commands.load(Ordering::Relaxed);
+        let handler = self.handler.clone();
+
+        async move {
+            Ok(RouteStats {
+                version,
+                canary_percent,
+                commands,
+                cache: handler.cache_stats().await?,
+                quarantined: handler.quarantined().await?,
+            })
+        }
+    }
+}
+
+/// A module version to run, loaded from the registry.
+struct DesiredVersion {
+    version: Option<Version>,
+    path: PathBuf,
+    hash: blake3::Hash,
+}
+
+impl DesiredVersion {
+    async fn load(version: Option<Version>, path: PathBuf) -> Result<Self> {
+        let hash = blake3::hash(&fs::read(&path).await?);
+        Ok(DesiredVersion {
+            version,
+            path,
+            hash,
+        })
+    }
+}
+
+/// The active and canary versions of a module, compiled for a reload.
+struct LoadedVersions {
+    active: (DesiredVersion, Module),
+    canary: Option<(CanaryRouting, DesiredVersion, Module)>,
+}
+
+/// Compiles module versions outside of the gateway.
+#[derive(Clone)]
+struct ModuleLoader {
+    engine: Engine,
+    pool_size: usize,
+    budget: ExecutionBudget,
+    memory_limits: MemoryLimits,
+    component_cache: ComponentCache,
+}
+
+impl ModuleLoader {
+    /// Loads a module version, reusing the `running` version's module if it's
+    /// unchanged.
+    async fn load(
+        &self,
+        desired: &DesiredVersion,
+        running: Option<&(Option<Version>, blake3::Hash, Module)>,
+    ) -> Result<Module> {
+        if let Some((_, _, module)) = running
+            .filter(|(version, hash, _)| *version == desired.version && *hash == desired.hash)
+        {
+            return Ok(module.clone());
+        }
+
+        let module = Module::from_file(
+            self.engine.clone(),
+            &desired.path,
+            self.pool_size,
+            self.budget,
+            self.memory_limits,
+            Some(&self.component_cache),
+        )
+        .await?
+        .with_version(desired.version.clone());
+
+        Ok(module)
+    }
 }
 
 impl CommandGateway {
-    async fn execute(
-        &mut self,
-        name: Category<'static>,
+    fn route(&self, name: &Category<'static>, id: &ID<'_>) -> Result<&RunningVersion> {
+        self.modules
+            .get(name)
+            .map(|running| running.route(id))
+            .ok_or_else(|| anyhow!("aggregate '{name}' does not exist or is not running"))
+    }
+
+    fn module_stats(&self, name: &Category<'static>) -> impl Future<Output = Result<ModuleStats>> {
+        let stats = self
+            .modules
+            .get(name)
+            .map(RunningModule::stats)
+            .ok_or_else(|| anyhow!("aggregate '{name}' does not exist or is not running"));
+
+        async move { stats?.await }
+    }
+
+    /// Releases an entity from quarantine in each running version of its
+    /// module.
+    fn unquarantine(
+        &self,
+        name: &Category<'static>,
         id: ID<'static>,
-        command: String,
-        payload: Value,
-    ) -> Result<Result<Vec<Message<'static>>, serde_json::Value>> {
-        let Some(aggregate_command_handler) = self.modules.get(&name).cloned() else {
-            return Err(anyhow!(
-                "aggregate '{name}' does not exist or is not running"
-            ));
+    ) -> impl Future<Output = Result<bool>> {
+        let handlers = self
+            .modules
+            .get(name)
+            .map(|running| running.handlers().cloned().collect::<Vec<_>>())
+            .ok_or_else(|| anyhow!("aggregate '{name}' does not exist or is not running"));
+        let stream_name = StreamName::from_parts(name.clone(), Some(&id));
+
+        async move {
+            let stream_name = stream_name?;
+            let mut released = false;
+            for handler in handlers? {
+                released |= handler.unquarantine(stream_name.clone()).await?;
+            }
+
+            Ok(released)
+        }
+    }
+
+    /// Loads the registry from the modules directory, starting the active
+    /// version of each module.
+    ///
+    /// Commands aren't served until the modules are started, which are
+    /// compiled concurrently.
+    async fn load_modules(&mut self) -> Result<()> {
+        self.registry = Registry::load(self.registry.modules_path())?;
+
+        let names: Vec<_> = self.registry.module_names().cloned().collect();
+        let mut reloads = Vec::new();
+        for name in names {
+            match self.prepare_reload(&name) {
+                Ok(Some((generation, load))) => {
+                    reloads.push(async move { (name, generation, load.await) });
+                }
+                Ok(None) => {}
+                Err(err) => error!(%name, "failed to start module: {err}"),
+            }
+        }
+
+        for (name, generation, loaded) in future::join_all(reloads).await {
+            let after: AfterReload = Box::new({
+                let name = name.clone();
+                move |_, res| {
+                    if let Err(err) = res {
+                        error!(%name, "failed to start module: {err}");
+                    }
+                }
+            });
+            self.modules_loaded(name, generation, loaded, after);
+        }
+
+        Ok(())
+    }
+
+    /// Starts the active and canary versions of a module if they're not
+    /// already running, or stops the module if it no longer has any versions,
+    /// calling `after` with the result.
+    ///
+    /// Modules are compiled in a separate task, so commands are still served
+    /// meanwhile, and are started once they're sent back to the gateway. A
+    /// reload which finishes after a newer reload of the module started is
+    /// discarded, leaving the newer reload to start the module.
+    fn reload_module(&mut self, name: Category<'static>, after: AfterReload) {
+        let (generation, load) = match self.prepare_reload(&name) {
+            Ok(Some(reload)) => reload,
+            Ok(None) => return after(self, Ok(())),
+            Err(err) => return after(self, Err(err)),
         };
 
-        aggregate_command_handler
-            .execute(name, id, command, payload)
-            .await
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            let loaded = load.await.map(Box::new);
+            if let Some(sender) = sender.upgrade() {
+                let msg = CommandGatewayMsg::ModulesLoaded {
+                    name,
+                    generation,
+                    loaded,
+                    after,
+                };
+                let _ = sender.send(msg).await;
+            }
+        });
     }
 
-    async fn start_module(&mut self, name: Category<'static>, module: Module) -> Result<()> {
-        let outbox = self.message_store.outbox(name.clone())?;
-        let outbox_relay = OutboxRelayHandle::new(name.clone(), outbox, self.relay.clone());
+    /// Starts a reload of a module, returning its generation and the future
+    /// compiling its versions, or `None` if the module has no active version
+    /// and was stopped.
+    fn prepare_reload(&mut self, name: &Category<'static>) -> Result<Option<(u64, LoadVersions)>> {
+        let generation = self.reloads.entry(name.clone()).or_default();
+        *generation += 1;
+        let generation = *generation;
+
+        let active = self
+            .registry
+            .get_module_versions(name)
+            .and_then(|versions| Some((versions, versions.active()?)));
+        let Some((versions, (version, path))) = active else {
+            if self.modules.contains_key(name) {
+                self.stop_module(name.clone())?;
+            }
+            return Ok(None);
+        };
+        let version = version.cloned();
+        let path = path.to_path_buf();
+        let canary = versions
+            .canary()
+            .filter(|(canary, _)| version.as_ref() != Some(&canary.version))
+            .map(|(canary, path)| (canary.clone(), path.to_path_buf()));
+
+        let snapshot = |running: &RunningVersion| {
+            (
+                running.version.clone(),
+                running.hash,
+                running.module.clone(),
+            )
+        };
+        let running = self.modules.get(name);
+        let running_active = running.map(|running| snapshot(&running.active));
+        let running_canary = running
+            .and_then(|running| running.canary.as_ref())
+            .map(|(_, running)| snapshot(running));
+        let loader = ModuleLoader {
+            engine: self.engine.clone(),
+            pool_size: self.config.module_pool_size,
+            budget: self.config.execution_budgets.get(name),
+            memory_limits: self.config.memory_limits.get(name),
+            component_cache: self.component_cache.clone(),
+        };
+
+        let load = async move {
+            let active = DesiredVersion::load(version, path).await?;
+            let active_module = loader.load(&active, running_active.as_ref()).await?;
+            let canary = match canary {
+                Some((canary, path)) => {
+                    let desired = DesiredVersion::load(Some(canary.version.clone()), path).await?;
+                    let module = loader.load(&desired, running_canary.as_ref()).await?;
+                    Some((canary, desired, module))
+                }
+                None => None,
+            };
+
+            Ok(LoadedVersions {
+                active: (active, active_module),
+                canary,
+            })
+        };
+
+        Ok(Some((generation, Box::pin(load))))
+    }
 
-        let aggregate_command_handler = AggregateCommandHandlerHandle::new(
-            self.handle.clone(),
+    /// Starts the versions of a module compiled by a reload.
+    fn modules_loaded(
+        &mut self,
+        name: Category<'static>,
+        generation: u64,
+        loaded: Result<LoadedVersions>,
+        after: AfterReload,
+    ) {
+        // The newer reload reports whether the module started.
+        if self.reloads.get(&name) != Some(&generation) {
+            trace!(%name, "discarding superseded module reload");
+            after(self, Ok(()));
+            return;
+        }
+
+        let res = loaded.and_then(|loaded| self.start_module(name, loaded));
+        after(self, res);
+    }
+
+    /// Starts the active and canary versions of a module, replacing the
+    /// running versions which changed.
+    ///
+    /// Changing the canary routing moves entities between versions, so both
+    /// versions are restarted, each draining all previous handlers first. This
+    /// way an entity is never served by two versions at once.
+    fn start_module(&mut self, name: Category<'static>, loaded: LoadedVersions) -> Result<()> {
+        let LoadedVersions {
+            active: (active, active_module),
+            canary,
+        } = loaded;
+
+        let running = self.modules.get(&name);
+        let routing_changed = running
+            .map(|running| running.canary.as_ref().map(|(canary, _)| canary))
+            != Some(canary.as_ref().map(|(canary, _, _)| canary));
+        let active_unchanged = running.is_some_and(|running| running.active.is_unchanged(&active));
+        let canary_unchanged = match (running.and_then(|running| running.canary.as_ref()), &canary)
+        {
+            (Some((_, running)), Some((_, desired, _))) => running.is_unchanged(desired),
+            (None, None) => true,
+            _ => false,
+        };
+        if !routing_changed && active_unchanged && canary_unchanged {
+            trace!(%name, "module unchanged");
+            return Ok(());
+        }
+
+        let (previous_active, previous_canary, outbox_relay) = match self.modules.remove(&name) {
+            Some(RunningModule {
+                active,
+                canary,
+                outbox_relay,
+            }) => {
+                info!(%name, "replacing module");
+                (
+                    Some(active),
+                    canary.map(|(_, running)| running),
+                    outbox_relay,
+                )
+            }
+            None => {
+                let outbox = self.message_store.outbox(name.clone())?;
+                let outbox_relay = OutboxRelayHandle::new(name.clone(), outbox, self.relay.clone());
+                (None, None, outbox_relay)
+            }
+        };
+        let all_previous: Vec<_> = previous_active
+            .iter()
+            .chain(&previous_canary)
+            .map(|running| running.handler.clone())
+            .collect();
+        let drain = |previous: Option<RunningVersion>| {
+            if routing_changed {
+                all_previous.clone()
+            } else {
+                previous
+                    .map(|previous| previous.handler)
+                    .into_iter()
+                    .collect()
+            }
+        };
+
+        let active = match previous_active {
+            Some(previous) if active_unchanged && !routing_changed => previous,
+            previous => {
+                self.start_version(&name, &outbox_relay, active, active_module, drain(previous))
+            }
+        };
+        let canary = canary.map(|(canary, desired, module)| {
+            let running = match previous_canary {
+                Some(previous) if canary_unchanged && !routing_changed => previous,
+                previous => {
+                    self.start_version(&name, &outbox_relay, desired, module, drain(previous))
+                }
+            };
+            (canary, running)
+        });
+
+        self.modules.insert(
+            name,
+            RunningModule {
+                active,
+                canary,
+                outbox_relay,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Starts a handler for a module version, once the `previous` handlers are
+    /// drained.
+    fn start_version(
+        &self,
+        name: &Category<'static>,
+        outbox_relay: &OutboxRelayHandle,
+        desired: DesiredVersion,
+        module: Module,
+        previous: Vec<AggregateCommandHandlerHandle>,
+    ) -> RunningVersion {
+        let handler = AggregateCommandHandlerHandle::new(
             name.clone(),
             outbox_relay.clone(),
+            self.command_dispatcher.clone(),
             self.message_store.clone(),
             self.broadcaster.clone(),
-            self.cache_size,
-            module,
+            self.config.entity_cache.get(name),
+            self.config.command_log.is_enabled(name),
+            module.clone(),
+            previous,
         );
 
-        self.modules.insert(name, aggregate_command_handler);
+        RunningVersion {
+            version: desired.version,
+            hash: desired.hash,
+            module,
+            handler,
+            commands: Arc::new(AtomicU64::new(0)),
+        }
+    }
 
-        Ok(())
+    fn publish_module(&mut self, id: ModuleID, path: PathBuf, reply: oneshot::Sender<Result<()>>) {
+        self.registry.insert(id.clone(), path);
+        match id.version {
+            Some(version) => self.repin_module(
+                id.name,
+                |registry, name| registry.activate(name, version),
+                reply,
+            ),
+            None => self.reload_module(id.name, reply_after_reload(reply)),
+        }
     }
 
-    async fn start_module_from_file(
+    fn module_file_changed(
         &mut self,
+        id: ModuleID,
+        path: PathBuf,
+        reply: oneshot::Sender<Result<()>>,
+    ) {
+        if path.is_file() {
+            self.registry.insert(id.clone(), path);
+        } else if let Err(err) = self.registry.remove(&id) {
+            let _ = reply.send(Err(err));
+            return;
+        }
+
+        self.reload_module(id.name, reply_after_reload(reply));
+    }
+
+    fn verify_module(
+        &self,
         name: Category<'static>,
         path: PathBuf,
-    ) -> Result<()> {
-        let module = Module::from_file(self.engine.clone(), path).await?;
-        self.start_module(name, module).await
+        sample: Option<usize>,
+    ) -> impl Future<Output = Result<VerificationReport>> {
+        let engine = self.engine.clone();
+        let message_store = self.message_store.clone();
+        let budget = self.config.execution_budgets.get(&name);
+        let memory_limits = self.config.memory_limits.get(&name);
+        let component_cache = self.component_cache.clone();
+        let active_path = self
+            .registry
+            .get_module_versions(&name)
+            .and_then(|versions| versions.active())
+            .map(|(_, path)| path.to_path_buf());
+
+        async move {
+            let load = |path| {
+                Module::from_file(
+                    engine.clone(),
+                    path,
+                    1,
+                    budget,
+                    memory_limits,
+                    Some(&component_cache),
+                )
+            };
+            let active = match active_path {
+                Some(active_path) => Some(load(active_path).await?),
+                None => None,
+            };
+            let new = load(path).await?;
+
+            shadow_replay(&message_store, &name, active, new, sample).await
+        }
     }
 
-    async fn start_module_from_module(
+    fn activate_module(
         &mut self,
         name: Category<'static>,
-        module: Module,
-    ) -> Result<()> {
-        self.start_module(name, module).await
+        version: Version,
+        reply: oneshot::Sender<Result<()>>,
+    ) {
+        self.repin_module(
+            name,
+            |registry, name| registry.activate(name, version),
+            reply,
+        );
     }
 
-    async fn load_modules_in_dir(&mut self, modules_path: PathBuf) -> Result<()> {
-        let mut read_dir = fs::read_dir(modules_path).await?;
-        while let Some(dir_entry) = read_dir.next_entry().await? {
-            let Ok(file_name) = dir_entry.file_name().into_string() else {
-                warn!("ignoring module with invalid file name");
-                continue;
-            };
-            let Some((module_name, suffix)) = file_name.split_once('.') else {
-                warn!("ignoring module {file_name}");
-                continue;
-            };
-            if suffix != "wasm" {
-                warn!("ignoring module {file_name} - does not end in .wasm");
-                continue;
+    /// Changes the canary routing of a module and reloads it, restoring the
+    /// previous routing if the module fails to start.
+    fn set_canary(
+        &mut self,
+        name: Category<'static>,
+        canary: Option<CanaryRouting>,
+        reply: oneshot::Sender<Result<()>>,
+    ) {
+        let previous = self
+            .registry
+            .get_module_versions(&name)
+            .and_then(|versions| versions.canary())
+            .map(|(canary, _)| canary.clone());
+        if let Err(err) = self.registry.set_canary(&name, canary) {
+            let _ = reply.send(Err(err));
+            return;
+        }
+
+        let after: AfterReload = Box::new({
+            let name = name.clone();
+            move |cmd_gateway, res| {
+                let res = match res {
+                    Ok(()) => Ok(()),
+                    Err(err) => cmd_gateway
+                        .registry
+                        .set_canary(&name, previous)
+                        .and(Err(err)),
+                };
+                let _ = reply.send(res);
             }
+        });
+        self.reload_module(name, after);
+    }
 
-            let module_name = Category::new(module_name.to_string())?;
-            if let Err(err) = self
-                .start_module_from_file(module_name, dir_entry.path())
-                .await
-            {
-                error!(
-                    "failed to start module '{}': {err}",
-                    dir_entry.path().display()
-                );
+    fn rollback_module(
+        &mut self,
+        name: Category<'static>,
+        reply: oneshot::Sender<Result<Version>>,
+    ) {
+        self.repin_module(name, |registry, name| registry.rollback(name), reply);
+    }
+
+    /// Changes the pinned version of a module and reloads it, restoring the
+    /// previously pinned version if the new version fails to start.
+    fn repin_module<T: Send + 'static>(
+        &mut self,
+        name: Category<'static>,
+        f: impl FnOnce(&mut Registry, &Category<'static>) -> Result<T>,
+        reply: oneshot::Sender<Result<T>>,
+    ) {
+        let pinned = self
+            .registry
+            .get_module_versions(&name)
+            .and_then(|versions| versions.pinned())
+            .cloned();
+        let repinned = match f(&mut self.registry, &name) {
+            Ok(repinned) => repinned,
+            Err(err) => {
+                let _ = reply.send(Err(err));
+                return;
             }
-        }
+        };
+
+        let after: AfterReload = Box::new({
+            let name = name.clone();
+            move |cmd_gateway, res| {
+                let res = match res {
+                    Ok(()) => Ok(repinned),
+                    Err(err) => {
+                        let restored = match pinned {
+                            Some(version) => cmd_gateway.registry.activate(&name, version),
+                            None => cmd_gateway.registry.unpin(&name),
+                        };
+                        restored.and(Err(err))
+                    }
+                };
+                let _ = reply.send(res);
+            }
+        });
+        self.reload_module(name, after);
+    }
+
+    /// Stops dispatching commands sent between aggregates and delivering
+    /// scheduled commands, then drains every running module concurrently and
+    /// relays their remaining outbox batches.
+    async fn shutdown(&mut self) -> Result<()> {
+        self.command_dispatcher.stop().await?;
+        self.scheduler.stop().await?;
+
+        let results = future::join_all(self.modules.drain().map(|(name, running)| async move {
+            for handler in running.handlers() {
+                handler.drain().await;
+            }
+            running.outbox_relay.drain().await?;
+            info!(%name, "stopped module");
+
+            Ok(())
+        }))
+        .await;
+
+        results.into_iter().collect()
+    }
+
+    /// Stops a module, draining its in-flight commands in the background.
+    fn stop_module(&mut self, name: Category<'static>) -> Result<()> {
+        let running = self
+            .modules
+            .remove(&name)
+            .ok_or_else(|| anyhow!("aggregate '{name}' does not exist or is not running"))?;
+        tokio::spawn(async move {
+            for handler in running.handlers() {
+                handler.drain().await;
+            }
+            info!(%name, "stopped module");
+        });
 
         Ok(())
     }
 }
+
+/// Replies with the result of a reload.
+fn reply_after_reload(reply: oneshot::Sender<Result<()>>) -> AfterReload {
+    Box::new(move |_, res| {
+        let _ = reply.send(res);
+    })
+}
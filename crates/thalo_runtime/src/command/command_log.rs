@@ -0,0 +1,88 @@
+//! Persisted log of executed commands.
+//!
+//! When enabled for a category, each command executed on an entity is appended
+//! to the entity's command stream (eg. `account:command-123`), recording the
+//! command name as the message type and a [`CommandLogEntry`] as the data.
+//!
+//! Accepted commands are logged in the same transaction as their resulting
+//! events and the commands they send, so every event has a logged command.
+//! A command which can't be logged fails.
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thalo::stream_name::{Category, EmptyStreamName, StreamName};
+
+use super::{CommandContext, ExpectedVersion};
+
+/// Categories which record executed commands to a command log.
+#[derive(Clone, Debug, Default)]
+pub struct CommandLogConfig {
+    all: bool,
+    categories: HashSet<String>,
+}
+
+impl CommandLogConfig {
+    /// Enables the command log for all categories.
+    pub fn all() -> Self {
+        CommandLogConfig {
+            all: true,
+            categories: HashSet::new(),
+        }
+    }
+
+    /// Enables the command log for the given categories only.
+    pub fn categories(categories: impl IntoIterator<Item = Category<'static>>) -> Self {
+        CommandLogConfig {
+            all: false,
+            categories: categories.into_iter().map(Category::into_string).collect(),
+        }
+    }
+
+    /// Returns whether commands are logged for a category.
+    pub fn is_enabled(&self, category: &Category<'_>) -> bool {
+        self.all || self.categories.contains(AsRef::<str>::as_ref(category))
+    }
+}
+
+/// A command recorded in an entity's command stream.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CommandLogEntry<'a> {
+    pub payload: Cow<'a, Value>,
+    pub metadata: CommandMetadata,
+    pub outcome: CommandOutcome,
+}
+
+/// Metadata the command was executed with.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandMetadata {
+    pub expected_version: ExpectedVersion,
+    /// Context the command was handled with, or `None` for commands logged
+    /// before contexts were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<CommandContext>,
+}
+
+/// Outcome of an executed command.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandOutcome {
+    /// The command was accepted, writing events at the given positions.
+    Accepted { positions: Vec<u64> },
+    /// The command was rejected with an error.
+    Rejected { error: Value },
+}
+
+/// Returns the command stream name for an entity stream.
+///
+/// # Example
+///
+/// `account-123` becomes `account:command-123`.
+pub fn command_stream_name(
+    stream_name: &StreamName<'_>,
+) -> Result<StreamName<'static>, EmptyStreamName> {
+    let category = Category::from_parts(stream_name.category().entity_name(), &["command"])?;
+    StreamName::from_parts(category, stream_name.id().as_ref())
+}
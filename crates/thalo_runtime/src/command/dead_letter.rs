@@ -0,0 +1,120 @@
+//! Commands which couldn't be delivered.
+//!
+//! Commands sent between aggregates are retried when they fail to execute, for
+//! example because their module isn't running. Commands which fail in a way
+//! retrying can't fix, such as trapping the aggregate, or which fail
+//! [`MAX_DELIVERY_ATTEMPTS`] times, are moved to the entity's dead letter
+//! stream (eg. `account:dead_letter-123`), recording the command name as the
+//! message type and a [`DeadLetter`] as the data.
+
+use std::borrow::Cow;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thalo::stream_name::{Category, EmptyStreamName, StreamName};
+use thalo_message_store::MessageStore;
+use tracing::error;
+use wasmtime::Trap;
+
+use crate::broadcaster::BroadcasterHandle;
+use crate::module::budget::ExecutionBudgetExceeded;
+use crate::module::wit_aggregate::AggregateError;
+
+/// Number of times a command is attempted before it's moved to the dead letter
+/// stream.
+pub const MAX_DELIVERY_ATTEMPTS: u32 = 100;
+
+/// A command recorded in an entity's dead letter stream.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter<'a> {
+    pub payload: Cow<'a, Value>,
+    /// Metadata the command was executed with.
+    pub metadata: Cow<'a, Value>,
+    /// Error of the last attempt to deliver the command.
+    pub error: String,
+    /// Number of times the command was attempted.
+    pub attempts: u32,
+}
+
+/// Returns the dead letter stream name of an entity, eg. `account:dead_letter-123`.
+pub fn dead_letter_stream_name(
+    stream_name: &StreamName<'_>,
+) -> Result<StreamName<'static>, EmptyStreamName> {
+    let category = Category::from_parts(stream_name.category().entity_name(), &["dead_letter"])?;
+    StreamName::from_parts(category, stream_name.id().as_ref())
+}
+
+/// A command which failed to be delivered.
+#[derive(Debug)]
+pub(crate) struct DeliveryFailed {
+    pub(crate) error: anyhow::Error,
+    /// Whether retrying the command can't succeed.
+    pub(crate) permanent: bool,
+}
+
+impl DeliveryFailed {
+    pub(crate) fn permanent(error: anyhow::Error) -> Self {
+        DeliveryFailed {
+            error,
+            permanent: true,
+        }
+    }
+}
+
+impl From<anyhow::Error> for DeliveryFailed {
+    /// Classifies an error as permanent if the aggregate trapped, or exceeded
+    /// its execution budget or memory limits, which it would do again.
+    fn from(error: anyhow::Error) -> Self {
+        let trapped = matches!(
+            error.root_cause().downcast_ref(),
+            Some(trap) if !matches!(trap, Trap::CannotEnterComponent)
+        );
+        let permanent = trapped
+            || error.is::<ExecutionBudgetExceeded>()
+            || matches!(
+                error.downcast_ref(),
+                Some(AggregateError::MemoryLimitExceeded(_))
+            );
+        DeliveryFailed { error, permanent }
+    }
+}
+
+/// Writer of commands to dead letter streams.
+#[derive(Clone)]
+pub(crate) struct DeadLetters {
+    message_store: MessageStore,
+    broadcaster: BroadcasterHandle,
+}
+
+impl DeadLetters {
+    pub(crate) fn new(message_store: MessageStore, broadcaster: BroadcasterHandle) -> Self {
+        DeadLetters {
+            message_store,
+            broadcaster,
+        }
+    }
+
+    /// Moves a command sent to `stream_name` to the entity's dead letter
+    /// stream.
+    pub(crate) async fn write(
+        &self,
+        stream_name: &StreamName<'_>,
+        command: &str,
+        dead_letter: DeadLetter<'_>,
+    ) -> Result<()> {
+        let mut stream = self
+            .message_store
+            .stream(dead_letter_stream_name(stream_name)?)?;
+        let data = serde_json::to_value(dead_letter)?;
+        let written_messages = stream.write_messages(&[(command, Cow::Owned(data))], None)?;
+
+        for message in written_messages {
+            if let Err(err) = self.broadcaster.broadcast_event(message.into_owned()).await {
+                error!("failed to broadcast event: {err}");
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+//! Cache of hydrated entities.
+//!
+//! Each running module version keeps its entities hydrated in a cache, so
+//! commands don't replay the entity's stream. The cache is bounded by a total
+//! weight, where each entity is weighed by an [`EntityWeigher`] when it's
+//! hydrated, and entities can be passivated after being idle for a while,
+//! dropping their wasm resource until they're next used.
+//!
+//! Evicted entities stop their command handler once its queued commands are
+//! handled. An entity used again before then is rehydrated only once the
+//! previous handler has stopped, as tracked by [`LiveEntities`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use moka::future::Cache;
+use thalo::stream_name::{Category, StreamName};
+use thiserror::Error;
+use tokio::sync::OwnedMutexGuard;
+
+use super::entity_command_handler::EntityCommandHandlerHandle;
+use crate::module::ModuleInstance;
+
+/// Default capacity of the entity cache.
+pub const DEFAULT_CAPACITY: u64 = 10_000;
+
+/// How entities are weighed against the cache's capacity.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EntityWeigher {
+    /// Each entity weighs 1, so the capacity is a number of entities.
+    #[default]
+    Entries,
+    /// Entities weigh the number of events in their stream.
+    Events,
+    /// Entities weigh the size in bytes of the state reported by their
+    /// instance, or the number of events in their stream if the aggregate
+    /// doesn't export its state.
+    StateSize,
+}
+
+impl EntityWeigher {
+    /// Weighs a hydrated entity's instance.
+    ///
+    /// Entities weigh at least 1, so that every entity counts towards the
+    /// cache's capacity.
+    pub(crate) async fn weigh(self, instance: &ModuleInstance) -> u32 {
+        let events = instance
+            .sequence()
+            .map(|sequence| sequence + 1)
+            .unwrap_or(0);
+        let weight = match self {
+            EntityWeigher::Entries => 1,
+            EntityWeigher::Events => events,
+            EntityWeigher::StateSize => match instance.state().await {
+                Ok(Some(state)) => state.len() as u64,
+                _ => events,
+            },
+        };
+
+        u32::try_from(weight).unwrap_or(u32::MAX).max(1)
+    }
+}
+
+impl fmt::Display for EntityWeigher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntityWeigher::Entries => write!(f, "entries"),
+            EntityWeigher::Events => write!(f, "events"),
+            EntityWeigher::StateSize => write!(f, "state-size"),
+        }
+    }
+}
+
+impl FromStr for EntityWeigher {
+    type Err = ParseEntityWeigherError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "entries" => Ok(EntityWeigher::Entries),
+            "events" => Ok(EntityWeigher::Events),
+            "state-size" => Ok(EntityWeigher::StateSize),
+            _ => Err(ParseEntityWeigherError),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+#[error("invalid entity weigher, expected 'entries', 'events' or 'state-size'")]
+pub struct ParseEntityWeigherError;
+
+/// Settings of a module's entity cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntityCacheSettings {
+    /// Maximum total weight of cached entities.
+    pub capacity: u64,
+    /// Time after which an entity which hasn't been used is passivated, or `None` to keep entities until they're evicted.
+    pub time_to_idle: Option<Duration>,
+    pub weigher: EntityWeigher,
+}
+
+impl Default for EntityCacheSettings {
+    fn default() -> Self {
+        EntityCacheSettings {
+            capacity: DEFAULT_CAPACITY,
+            time_to_idle: None,
+            weigher: EntityWeigher::default(),
+        }
+    }
+}
+
+impl EntityCacheSettings {
+    /// Builds a cache with these settings.
+    pub(crate) fn build(&self) -> Cache<StreamName<'static>, EntityCommandHandlerHandle> {
+        let mut builder = Cache::builder()
+            .max_capacity(self.capacity)
+            .weigher(|_, handle: &EntityCommandHandlerHandle| handle.weight())
+            .eviction_listener(|_, handle: EntityCommandHandlerHandle, _| handle.stop());
+        if let Some(time_to_idle) = self.time_to_idle {
+            builder = builder.time_to_idle(time_to_idle);
+        }
+
+        builder.build()
+    }
+}
+
+/// Overrides of a module's entity cache settings.
+///
+/// Settings left as `None` fall back to the default settings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EntityCacheOverrides {
+    pub capacity: Option<u64>,
+    pub time_to_idle: Option<Duration>,
+    pub weigher: Option<EntityWeigher>,
+}
+
+/// Entity cache settings for modules, with per-module overrides.
+#[derive(Clone, Debug, Default)]
+pub struct EntityCacheConfig {
+    default: EntityCacheSettings,
+    modules: HashMap<String, EntityCacheOverrides>,
+}
+
+impl EntityCacheConfig {
+    /// Creates entity cache settings, using `default` for all modules.
+    pub fn new(default: EntityCacheSettings) -> Self {
+        EntityCacheConfig {
+            default,
+            modules: HashMap::new(),
+        }
+    }
+
+    /// Returns a mutable reference to the default settings.
+    pub fn default_mut(&mut self) -> &mut EntityCacheSettings {
+        &mut self.default
+    }
+
+    /// Returns a mutable reference to a module's overrides.
+    pub fn module_mut(&mut self, name: Category<'static>) -> &mut EntityCacheOverrides {
+        self.modules.entry(name.into_string()).or_default()
+    }
+
+    /// Returns the settings for a module.
+    pub fn get(&self, name: &Category<'_>) -> EntityCacheSettings {
+        match self.modules.get(AsRef::<str>::as_ref(name)) {
+            Some(overrides) => EntityCacheSettings {
+                capacity: overrides.capacity.unwrap_or(self.default.capacity),
+                time_to_idle: overrides.time_to_idle.or(self.default.time_to_idle),
+                weigher: overrides.weigher.unwrap_or(self.default.weigher),
+            },
+            None => self.default,
+        }
+    }
+}
+
+/// Statistics of a module version's entity cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EntityCacheStats {
+    /// Number of cached entities.
+    pub entries: u64,
+    /// Total weight of cached entities.
+    pub weighted_size: u64,
+    /// Number of lookups which found the entity cached.
+    pub hits: u64,
+    /// Number of lookups which hydrated the entity.
+    pub misses: u64,
+}
+
+/// Hit and miss counters, kept across restarts of the cache.
+#[derive(Debug, Default)]
+pub(crate) struct EntityCacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl EntityCacheCounters {
+    pub(crate) fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(
+        &self,
+        cache: &Cache<StreamName<'static>, EntityCommandHandlerHandle>,
+    ) -> EntityCacheStats {
+        EntityCacheStats {
+            entries: cache.entry_count(),
+            weighted_size: cache.weighted_size(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Entities with a running command handler.
+///
+/// Each entity command handler holds a lease on its entity until it stops, so
+/// an entity is never handled by two handlers at once.
+#[derive(Clone, Default)]
+pub(crate) struct LiveEntities {
+    leases: Arc<Mutex<HashMap<StreamName<'static>, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl LiveEntities {
+    /// Waits for the entity's running handler to stop, if any, and leases the
+    /// entity.
+    pub(crate) async fn lease(&self, stream_name: StreamName<'static>) -> EntityLease {
+        let lease = Arc::clone(self.lock().entry(stream_name.clone()).or_default());
+        let guard = lease.lock_owned().await;
+
+        EntityLease {
+            live_entities: self.clone(),
+            stream_name,
+            guard: Some(guard),
+        }
+    }
+
+    /// Waits for every running handler to stop.
+    pub(crate) async fn stopped(&self) {
+        let leases: Vec<_> = self.lock().values().cloned().collect();
+        for lease in leases {
+            drop(lease.lock().await);
+        }
+        self.lock().retain(|_, lease| Arc::strong_count(lease) > 1);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<StreamName<'static>, Arc<tokio::sync::Mutex<()>>>> {
+        // Leases are only inserted and removed while locked, so they remain consistent even if a
+        // holder panicked.
+        self.leases.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A lease on an entity, held by its command handler until it stops.
+pub(crate) struct EntityLease {
+    live_entities: LiveEntities,
+    stream_name: StreamName<'static>,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for EntityLease {
+    fn drop(&mut self) {
+        let mut leases = self.live_entities.lock();
+        drop(self.guard.take());
+        // The entity is forgotten unless another handler is waiting for it.
+        if leases
+            .get(&self.stream_name)
+            .is_some_and(|lease| Arc::strong_count(lease) == 1)
+        {
+            leases.remove(&self.stream_name);
+        }
+    }
+}
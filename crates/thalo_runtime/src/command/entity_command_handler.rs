@@ -1,82 +1,186 @@
 use std::borrow::Cow;
+use std::sync::Arc;
 
 use anyhow::{Context as AnyhowContext, Result};
 use serde_json::Value;
+use thalo_message_store::command_outbox::{CommandOutbox, OutboxCommand};
 use thalo_message_store::message::Message;
-use thalo_message_store::stream::Stream;
-use tokio::sync::{mpsc, oneshot};
+use thalo_message_store::stream::{Alongside, Stream};
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot, Notify};
 use tracing::{error, trace};
 
+use super::command_dispatcher::{outbox_command, CommandDispatcherHandle};
+use super::command_log::{CommandLogEntry, CommandMetadata, CommandOutcome};
+use super::entity_cache::EntityLease;
 use super::outbox_relay::OutboxRelayHandle;
+use super::{CommandContext, EventMetadata, ExpectedVersion, WrongExpectedVersion};
 use crate::broadcaster::BroadcasterHandle;
-use crate::module::{Event, ModuleInstance};
+use crate::module::{Event, ModuleInstance, OutboundCommand};
 
 #[derive(Clone)]
 pub struct EntityCommandHandlerHandle {
-    sender: mpsc::Sender<ExecuteEntityCommand>,
+    sender: mpsc::Sender<EntityCommandHandlerMsg>,
+    stop: Arc<Notify>,
+    /// Weight of the entity in the entity cache, as of when it was hydrated.
+    weight: u32,
 }
 
+/// The entity's command handler stopped after the entity was evicted, before
+/// the message was sent.
+#[derive(Clone, Copy, Debug, Error)]
+#[error("entity command handler stopped")]
+pub struct EntityStopped;
+
 #[derive(Debug)]
-struct ExecuteEntityCommand {
-    command: String,
-    payload: Value,
-    reply: oneshot::Sender<Result<Result<Vec<Message<'static>>, serde_json::Value>>>,
+enum EntityCommandHandlerMsg {
+    Execute {
+        command: String,
+        payload: Value,
+        expected_version: ExpectedVersion,
+        context: CommandContext,
+        reply: oneshot::Sender<Result<Result<Vec<Message<'static>>, serde_json::Value>>>,
+    },
+    GetState {
+        reply: oneshot::Sender<Result<Option<Value>>>,
+    },
+}
+
+/// Services shared by the command handlers of a module's entities.
+#[derive(Clone)]
+pub struct EntityServices {
+    pub outbox_relay: OutboxRelayHandle,
+    pub command_dispatcher: CommandDispatcherHandle,
+    pub command_outbox: CommandOutbox,
+    pub broadcaster: BroadcasterHandle,
+    /// Metadata written with each event, along with the correlation metadata
+    /// of the command.
+    pub event_metadata: EventMetadata,
 }
 
 impl EntityCommandHandlerHandle {
     pub fn new(
-        outbox_relay: OutboxRelayHandle,
-        broadcaster: BroadcasterHandle,
+        services: EntityServices,
         instance: ModuleInstance,
         stream: Stream<'static>,
+        command_stream: Option<Stream<'static>>,
+        weight: u32,
+        lease: EntityLease,
     ) -> Self {
-        let (sender, receiver) = mpsc::channel(16);
-        tokio::spawn(run_entity_command_handler(
-            receiver,
+        let EntityServices {
             outbox_relay,
+            command_dispatcher,
+            command_outbox,
+            broadcaster,
+            event_metadata,
+        } = services;
+        let handler = EntityCommandHandler {
+            outbox_relay,
+            command_dispatcher,
+            command_outbox,
             broadcaster,
-            instance,
             stream,
+            command_stream,
+            instance,
+            event_metadata,
+        };
+        let (sender, receiver) = mpsc::channel(16);
+        let stop = Arc::new(Notify::new());
+        tokio::spawn(run_entity_command_handler(
+            receiver,
+            Arc::clone(&stop),
+            lease,
+            handler,
         ));
 
-        EntityCommandHandlerHandle { sender }
+        EntityCommandHandlerHandle {
+            sender,
+            stop,
+            weight,
+        }
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    /// Stops the handler once the commands already sent are handled.
+    ///
+    /// Messages sent afterwards fail with [`EntityStopped`].
+    pub fn stop(&self) {
+        self.stop.notify_one();
     }
 
     pub async fn execute(
         &self,
         command: String,
         payload: Value,
+        expected_version: ExpectedVersion,
+        context: CommandContext,
     ) -> Result<Result<Vec<Message<'static>>, serde_json::Value>> {
         let (reply, recv) = oneshot::channel();
-        let msg = ExecuteEntityCommand {
+        let msg = EntityCommandHandlerMsg::Execute {
             command,
             payload,
+            expected_version,
+            context,
             reply,
         };
 
-        let _ = self.sender.send(msg).await;
+        self.sender.send(msg).await.map_err(|_| EntityStopped)?;
+        recv.await
+            .context("no response from entity command handler")?
+    }
+
+    pub async fn get_state(&self) -> Result<Option<Value>> {
+        let (reply, recv) = oneshot::channel();
+        let msg = EntityCommandHandlerMsg::GetState { reply };
+
+        self.sender.send(msg).await.map_err(|_| EntityStopped)?;
         recv.await
             .context("no response from entity command handler")?
     }
 }
 
 async fn run_entity_command_handler(
-    mut receiver: mpsc::Receiver<ExecuteEntityCommand>,
-    outbox_relay: OutboxRelayHandle,
-    broadcaster: BroadcasterHandle,
-    instance: ModuleInstance,
-    stream: Stream<'static>,
+    mut receiver: mpsc::Receiver<EntityCommandHandlerMsg>,
+    stop: Arc<Notify>,
+    // Held until the handler stops, after its instance is dropped.
+    _lease: EntityLease,
+    mut handler: EntityCommandHandler,
 ) -> Result<()> {
-    let mut handler = EntityCommandHandler {
-        outbox_relay,
-        broadcaster,
-        stream,
-        instance,
-    };
-
-    while let Some(msg) = receiver.recv().await {
-        let res = handler.execute(msg.command, msg.payload).await;
-        let _ = msg.reply.send(res);
+    loop {
+        let msg = tokio::select! {
+            biased;
+            msg = receiver.recv() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            () = stop.notified() => {
+                // Commands already queued are still handled.
+                receiver.close();
+                continue;
+            }
+        };
+
+        match msg {
+            EntityCommandHandlerMsg::Execute {
+                command,
+                payload,
+                expected_version,
+                context,
+                reply,
+            } => {
+                let res = handler
+                    .execute(command, payload, expected_version, context)
+                    .await;
+                let _ = reply.send(res);
+            }
+            EntityCommandHandlerMsg::GetState { reply } => {
+                let res = handler.get_state().await;
+                let _ = reply.send(res);
+            }
+        }
     }
 
     trace!(stream_name = %handler.stream.stream_name(), "stopping entity command handler");
@@ -87,23 +191,70 @@ async fn run_entity_command_handler(
 
 struct EntityCommandHandler {
     outbox_relay: OutboxRelayHandle,
+    command_dispatcher: CommandDispatcherHandle,
+    command_outbox: CommandOutbox,
     broadcaster: BroadcasterHandle,
     stream: Stream<'static>,
+    command_stream: Option<Stream<'static>>,
     instance: ModuleInstance,
+    /// Metadata written with each event, along with the correlation metadata
+    /// of the command.
+    event_metadata: EventMetadata,
 }
 
 impl EntityCommandHandler {
+    /// Executes a command, logging it to the entity's command stream if the
+    /// command log is enabled.
+    ///
+    /// Accepted commands are logged in the same transaction as the events and
+    /// commands they produced, and the command fails if it can't be logged.
     async fn execute(
         &mut self,
         command: String,
         payload: Value,
+        expected_version: ExpectedVersion,
+        context: CommandContext,
     ) -> Result<Result<Vec<Message<'static>>, serde_json::Value>> {
-        let payload = serde_json::to_string(&payload)?;
-        let events = match self.instance.handle(&command, &payload).await? {
-            Ok(events) => events,
-            Err(err) => return Ok(Err(err)),
+        let command = command.as_str();
+
+        // The instance's sequence always matches the stream version, since this handler is the
+        // only writer to the stream.
+        if !expected_version.matches(self.instance.sequence()) {
+            let err = WrongExpectedVersion {
+                stream_name: self.stream.stream_name().clone(),
+                expected_version,
+                stream_version: self.instance.sequence(),
+            };
+            let outcome = CommandOutcome::Rejected {
+                error: serde_json::to_value(&err)?,
+            };
+            self.log_command(command, &payload, expected_version, &context, outcome)
+                .await?;
+            return Err(err.into());
+        }
+
+        let payload_json = serde_json::to_string(&payload)?;
+        let (events, commands) = match self
+            .instance
+            .handle(command, &payload_json, &context)
+            .await?
+        {
+            Ok(handled) => (handled.events, handled.commands),
+            Err(err) => {
+                let outcome = CommandOutcome::Rejected { error: err.clone() };
+                self.log_command(command, &payload, expected_version, &context, outcome)
+                    .await?;
+                return Ok(Err(err));
+            }
         };
-        if events.is_empty() {
+        let commands = commands
+            .into_iter()
+            .map(|outbound| self.outbox_command(command, &context, outbound))
+            .collect::<Result<Vec<_>>>()?;
+        if events.is_empty() && commands.is_empty() {
+            let outcome = CommandOutcome::Accepted { positions: vec![] };
+            self.log_command(command, &payload, expected_version, &context, outcome)
+                .await?;
             return Ok(Ok(vec![]));
         }
 
@@ -125,8 +276,16 @@ impl EntityCommandHandler {
             })
             .collect();
         self.instance.apply(&events_to_apply).await?;
+        let outcome = CommandOutcome::Accepted {
+            positions: events_to_apply
+                .iter()
+                .map(|(position, _)| *position)
+                .collect(),
+        };
+        let log_entry = self.command_log_entry(&payload, expected_version, &context, outcome)?;
 
-        // Persist events.
+        // Persist events, along with the commands sent to other aggregates and the logged
+        // command.
         let messages: Vec<_> = events
             .iter()
             .map(|event| {
@@ -134,14 +293,38 @@ impl EntityCommandHandler {
                 Ok((event.event.as_ref(), Cow::Owned(payload)))
             })
             .collect::<anyhow::Result<_>>()?;
-        let written_messages = self.stream.write_messages(&messages, sequence)?;
-
-        for message in &written_messages {
-            if let Err(err) = self
-                .broadcaster
-                .broadcast_event(message.clone().into_owned())
-                .await
-            {
+        let event_metadata = EventMetadata {
+            timestamp: Some(context.timestamp),
+            seed: Some(context.seed),
+            correlation: context.metadata.clone(),
+            ..self.event_metadata.clone()
+        }
+        .to_value()?;
+        let log_messages: Vec<_> = log_entry
+            .into_iter()
+            .map(|entry| (command, Cow::Owned(entry)))
+            .collect();
+        let (written_messages, logged_messages) = self.stream.write_messages_alongside(
+            &messages,
+            Cow::Owned(event_metadata),
+            sequence,
+            Alongside {
+                stream: self
+                    .command_stream
+                    .as_ref()
+                    .map(|command_stream| (command_stream, log_messages.as_slice())),
+                commands: Some((&self.command_outbox, &commands)),
+                ..Default::default()
+            },
+        )?;
+
+        // Command messages are broadcasted too, since the broadcaster expects every global ID.
+        let broadcast_messages = written_messages
+            .iter()
+            .map(|message| message.clone().into_owned())
+            .chain(logged_messages);
+        for message in broadcast_messages {
+            if let Err(err) = self.broadcaster.broadcast_event(message).await {
                 error!("failed to broadcast event: {err}");
             }
         }
@@ -150,10 +333,103 @@ impl EntityCommandHandler {
             error!("failed to notify outbox relay: {err}");
         }
 
+        if !commands.is_empty() {
+            if let Err(err) = self.command_dispatcher.dispatch_pending() {
+                error!("failed to notify command dispatcher: {err}");
+            }
+        }
+
         let reply_messages = written_messages
             .into_iter()
             .map(|message| message.into_owned())
             .collect();
         Ok(Ok(reply_messages))
     }
+
+    /// Prepares a command sent by the aggregate for the command outbox.
+    ///
+    /// The command is executed with the correlation metadata of the command
+    /// which sent it, along with the command.
+    fn outbox_command(
+        &self,
+        causation_command: &str,
+        context: &CommandContext,
+        outbound: OutboundCommand,
+    ) -> Result<OutboxCommand<'static>> {
+        let mut metadata = context.metadata.clone();
+        metadata.insert(
+            "causation_command".to_string(),
+            Value::String(causation_command.to_string()),
+        );
+
+        outbox_command(
+            outbound,
+            self.stream.stream_name(),
+            context.timestamp,
+            metadata,
+        )
+    }
+
+    async fn get_state(&self) -> Result<Option<Value>> {
+        self.instance
+            .state()
+            .await?
+            .map(|state| serde_json::from_str(&state).context("failed to deserialize state"))
+            .transpose()
+    }
+
+    /// Logs a command which wrote no messages to the entity's command stream.
+    async fn log_command(
+        &mut self,
+        command: &str,
+        payload: &Value,
+        expected_version: ExpectedVersion,
+        context: &CommandContext,
+        outcome: CommandOutcome,
+    ) -> Result<()> {
+        let Some(entry) = self.command_log_entry(payload, expected_version, context, outcome)?
+        else {
+            return Ok(());
+        };
+        let Some(command_stream) = &mut self.command_stream else {
+            return Ok(());
+        };
+
+        let written_messages =
+            command_stream.write_messages(&[(command, Cow::Owned(entry))], None)?;
+
+        // Command messages are broadcasted too, since the broadcaster expects every global ID.
+        for message in written_messages {
+            self.broadcaster
+                .broadcast_event(message.into_owned())
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the entry logged for a command, or `None` if the command log
+    /// is disabled.
+    fn command_log_entry(
+        &self,
+        payload: &Value,
+        expected_version: ExpectedVersion,
+        context: &CommandContext,
+        outcome: CommandOutcome,
+    ) -> Result<Option<Value>> {
+        if self.command_stream.is_none() {
+            return Ok(None);
+        }
+
+        let entry = CommandLogEntry {
+            payload: Cow::Borrowed(payload),
+            metadata: CommandMetadata {
+                expected_version,
+                context: Some(context.clone()),
+            },
+            outcome,
+        };
+
+        Ok(Some(serde_json::to_value(entry)?))
+    }
 }
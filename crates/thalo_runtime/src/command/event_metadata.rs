@@ -0,0 +1,41 @@
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Metadata recorded with each event produced by an aggregate.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventMetadata {
+    /// Version of the module which produced the event, or `None` if the
+    /// module is unversioned.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub module_version: Option<Version>,
+    /// Unix timestamp in milliseconds of when the command which produced the
+    /// event was received.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// Seed of the random number generator the command was handled with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// Correlation metadata of the command which produced the event.
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub correlation: Map<String, Value>,
+}
+
+impl EventMetadata {
+    /// Returns whether there is no metadata to record.
+    pub fn is_empty(&self) -> bool {
+        self.module_version.is_none()
+            && self.timestamp.is_none()
+            && self.seed.is_none()
+            && self.correlation.is_empty()
+    }
+
+    /// Converts the metadata to json, which is `null` if it's empty.
+    pub fn to_value(&self) -> serde_json::Result<Value> {
+        if self.is_empty() {
+            return Ok(Value::Null);
+        }
+
+        serde_json::to_value(self)
+    }
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thalo::stream_name::StreamName;
+use thiserror::Error;
+
+/// The version an entity's stream is expected to be at when executing a
+/// command.
+///
+/// The version of a stream is the position of its last event, starting at 0.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpectedVersion {
+    /// The stream may be at any version, including not existing.
+    #[default]
+    Any,
+    /// The stream must not exist.
+    NoStream,
+    /// The stream must contain at least one event.
+    StreamExists,
+    /// The stream must be at an exact version.
+    Exact(u64),
+}
+
+impl ExpectedVersion {
+    /// Returns whether a stream at `stream_version` satisfies the expected
+    /// version.
+    pub fn matches(&self, stream_version: Option<u64>) -> bool {
+        match (self, stream_version) {
+            (ExpectedVersion::Any, _) => true,
+            (ExpectedVersion::NoStream, None) => true,
+            (ExpectedVersion::StreamExists, Some(_)) => true,
+            (ExpectedVersion::Exact(expected), Some(version)) => *expected == version,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ExpectedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpectedVersion::Any => write!(f, "any"),
+            ExpectedVersion::NoStream => write!(f, "no stream"),
+            ExpectedVersion::StreamExists => write!(f, "stream exists"),
+            ExpectedVersion::Exact(version) => write!(f, "{version}"),
+        }
+    }
+}
+
+impl FromStr for ExpectedVersion {
+    type Err = ParseExpectedVersionError;
+
+    /// Parses `any`, `no-stream`, `exists`, or an exact version number.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(ExpectedVersion::Any),
+            "no-stream" | "no_stream" => Ok(ExpectedVersion::NoStream),
+            "exists" | "stream-exists" | "stream_exists" => Ok(ExpectedVersion::StreamExists),
+            version => version
+                .parse()
+                .map(ExpectedVersion::Exact)
+                .map_err(|_| ParseExpectedVersionError),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Error)]
+#[error("expected version must be `any`, `no-stream`, `exists`, or a version number")]
+pub struct ParseExpectedVersionError;
+
+/// A command was rejected, since the entity's stream was not at the expected
+/// version.
+#[derive(Clone, Debug, PartialEq, Eq, Error, Serialize, Deserialize)]
+#[error("wrong expected version: {expected_version} (Stream: {stream_name}, Stream Version: {stream_version:?})")]
+pub struct WrongExpectedVersion {
+    pub stream_name: StreamName<'static>,
+    pub expected_version: ExpectedVersion,
+    pub stream_version: Option<u64>,
+}
@@ -1,6 +1,31 @@
 mod aggregate_command_handler;
+mod command_context;
+mod command_dispatcher;
 mod command_gateway;
+pub mod command_log;
+pub mod dead_letter;
+pub mod entity_cache;
 mod entity_command_handler;
+mod event_metadata;
+mod expected_version;
+mod module_stats;
+mod module_watcher;
 mod outbox_relay;
+mod quarantine;
+mod scheduler;
+mod shadow_replay;
+mod state_at;
 
+pub use command_context::{CommandContext, CommandContextOverrides};
+pub(crate) use command_dispatcher::outbox_command;
 pub use command_gateway::CommandGatewayHandle;
+pub use event_metadata::EventMetadata;
+pub use expected_version::{ExpectedVersion, ParseExpectedVersionError, WrongExpectedVersion};
+pub use module_stats::{ModuleStats, RouteStats};
+pub use module_watcher::watch_modules;
+pub use quarantine::EntityQuarantined;
+pub(crate) use shadow_replay::shadow_replay;
+pub use shadow_replay::{
+    Verification, VerificationFailed, VerificationFailure, VerificationReport,
+};
+pub use state_at::{HistoricalState, StateAt};
@@ -0,0 +1,26 @@
+use semver::Version;
+
+use super::entity_cache::EntityCacheStats;
+use super::quarantine::EntityQuarantined;
+
+/// Statistics of a running module.
+#[derive(Clone, Debug, Default)]
+pub struct ModuleStats {
+    /// Versions serving the module's entities, starting with the active
+    /// version.
+    pub routes: Vec<RouteStats>,
+}
+
+/// Statistics of a module version serving commands.
+#[derive(Clone, Debug, Default)]
+pub struct RouteStats {
+    pub version: Option<Version>,
+    /// Percentage of entities routed to the version if it's a canary, or
+    /// `None` for the active version.
+    pub canary_percent: Option<u8>,
+    /// Number of commands routed to the version since it started.
+    pub commands: u64,
+    pub cache: EntityCacheStats,
+    /// Entities quarantined after trapping.
+    pub quarantined: Vec<EntityQuarantined>,
+}
@@ -0,0 +1,83 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Result;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+use tracing::{error, info};
+
+use super::CommandGatewayHandle;
+use crate::registry::ModuleID;
+
+/// Time to wait for a module file to stop changing before reloading it, so
+/// partially written files aren't loaded.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Watches the modules directory, starting, replacing or stopping modules as
+/// `.wasm` files are added, changed or removed.
+pub fn watch_modules(modules_path: PathBuf, command_gateway: CommandGatewayHandle) -> Result<()> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res| {
+        let _ = sender.send(res);
+    })?;
+    watcher.watch(&modules_path, RecursiveMode::NonRecursive)?;
+
+    tokio::spawn(run_module_watcher(watcher, receiver, command_gateway));
+
+    info!(modules_path = %modules_path.display(), "watching modules");
+
+    Ok(())
+}
+
+async fn run_module_watcher(
+    // Watching stops when the watcher is dropped.
+    _watcher: RecommendedWatcher,
+    mut receiver: mpsc::UnboundedReceiver<notify::Result<notify::Event>>,
+    command_gateway: CommandGatewayHandle,
+) {
+    let mut pending = HashSet::new();
+    let deadline = time::sleep(DEBOUNCE);
+    tokio::pin!(deadline);
+
+    loop {
+        tokio::select! {
+            res = receiver.recv() => {
+                let Some(res) = res else {
+                    break;
+                };
+
+                match res {
+                    Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                        pending.extend(event.paths);
+                        deadline.as_mut().reset(Instant::now() + DEBOUNCE);
+                    }
+                    Ok(_) => {}
+                    Err(err) => error!("module watcher error: {err}"),
+                }
+            }
+            () = &mut deadline, if !pending.is_empty() => {
+                for path in pending.drain() {
+                    reload_module(&command_gateway, path).await;
+                }
+            }
+        }
+    }
+}
+
+/// Adds or removes the module file in the registry, reloading the module if
+/// its active version changed.
+async fn reload_module(command_gateway: &CommandGatewayHandle, path: PathBuf) {
+    let Some(id) = path
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .and_then(ModuleID::from_file_name)
+    else {
+        return;
+    };
+
+    if let Err(err) = command_gateway.module_file_changed(id, path.clone()).await {
+        error!("failed to reload module '{}': {err}", path.display());
+    }
+}
@@ -14,7 +14,7 @@ use anyhow::{Context, Result};
 use async_recursion::async_recursion;
 use thalo::stream_name::Category;
 use thalo_message_store::outbox::Outbox;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio::time::interval;
 use tracing::{error, warn};
 
@@ -25,7 +25,7 @@ const FLUSH_INTERVAL: Duration = Duration::from_millis(500);
 
 #[derive(Clone)]
 pub struct OutboxRelayHandle {
-    sender: mpsc::Sender<()>,
+    sender: mpsc::Sender<OutboxRelayMsg>,
 }
 
 impl OutboxRelayHandle {
@@ -38,14 +38,30 @@ impl OutboxRelayHandle {
 
     pub async fn relay_next_batch(&self) -> Result<()> {
         self.sender
-            .send(())
+            .send(OutboxRelayMsg::RelayNextBatch)
             .await
             .context("outbox relay is not running")
     }
+
+    /// Relays the remaining batches and flushes the outbox, then stops the
+    /// relay.
+    pub async fn drain(&self) -> Result<()> {
+        let (reply, recv) = oneshot::channel();
+        self.sender
+            .send(OutboxRelayMsg::Drain { reply })
+            .await
+            .context("outbox relay is not running")?;
+        recv.await.context("no response from outbox relay")?
+    }
+}
+
+enum OutboxRelayMsg {
+    RelayNextBatch,
+    Drain { reply: oneshot::Sender<Result<()>> },
 }
 
 async fn run_outbox_relay(
-    mut receiver: mpsc::Receiver<()>,
+    mut receiver: mpsc::Receiver<OutboxRelayMsg>,
     name: Category<'static>,
     outbox: Outbox,
     relay: Relay,
@@ -68,11 +84,20 @@ async fn run_outbox_relay(
     loop {
         tokio::select! {
             msg = receiver.recv() => match msg {
-                Some(()) => {
+                Some(OutboxRelayMsg::RelayNextBatch) => {
                     if let Err(err) = outbox_relay.relay_next_batch().await {
                         error!("{err}");
                     }
                 }
+                Some(OutboxRelayMsg::Drain { reply }) => {
+                    let res = async {
+                        outbox_relay.relay_next_batch().await?;
+                        outbox_relay.flush().await
+                    }
+                    .await;
+                    let _ = reply.send(res);
+                    break;
+                }
                 None => break,
             },
             _ = timer.tick() => {
@@ -0,0 +1,96 @@
+//! Isolation of entities which trap.
+//!
+//! An entity which traps is quarantined with its last error, rejecting its
+//! commands until it's released, while other entities keep being served. The
+//! module is only restarted if it traps repeatedly, as tracked by a
+//! [`CircuitBreaker`].
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use thalo::stream_name::StreamName;
+use thiserror::Error;
+use tokio::time::Instant;
+
+/// Number of traps within [`TRAP_WINDOW`] which trip the circuit breaker.
+const MAX_TRAPS: usize = 5;
+const TRAP_WINDOW: Duration = Duration::from_secs(60);
+
+/// The entity trapped, and rejects commands until it's released.
+#[derive(Clone, Debug, Error)]
+#[error("entity '{stream_name}' is quarantined after trapping: {error}")]
+pub struct EntityQuarantined {
+    pub stream_name: StreamName<'static>,
+    /// Error the entity trapped with.
+    pub error: String,
+}
+
+/// Entities quarantined by an aggregate command handler.
+#[derive(Debug, Default)]
+pub(crate) struct Quarantine {
+    entities: HashMap<StreamName<'static>, String>,
+}
+
+impl Quarantine {
+    pub(crate) fn insert(&mut self, stream_name: StreamName<'static>, error: String) {
+        self.entities.insert(stream_name, error);
+    }
+
+    /// Returns the entity's quarantine, if it's quarantined.
+    pub(crate) fn get(&self, stream_name: &StreamName<'static>) -> Option<EntityQuarantined> {
+        self.entities
+            .get(stream_name)
+            .map(|error| EntityQuarantined {
+                stream_name: stream_name.clone(),
+                error: error.clone(),
+            })
+    }
+
+    /// Releases an entity, returning whether it was quarantined.
+    pub(crate) fn release(&mut self, stream_name: &StreamName<'static>) -> bool {
+        self.entities.remove(stream_name).is_some()
+    }
+
+    pub(crate) fn entities(&self) -> Vec<EntityQuarantined> {
+        let mut entities: Vec<_> = self
+            .entities
+            .iter()
+            .map(|(stream_name, error)| EntityQuarantined {
+                stream_name: stream_name.clone(),
+                error: error.clone(),
+            })
+            .collect();
+        entities.sort_by(|a, b| a.stream_name.cmp(&b.stream_name));
+        entities
+    }
+}
+
+/// Trips once a module traps [`MAX_TRAPS`] times within [`TRAP_WINDOW`].
+#[derive(Debug, Default)]
+pub(crate) struct CircuitBreaker {
+    traps: VecDeque<Instant>,
+}
+
+impl CircuitBreaker {
+    /// Records a trap, returning whether the breaker tripped.
+    ///
+    /// The breaker resets once tripped.
+    pub(crate) fn record_trap(&mut self) -> bool {
+        let now = Instant::now();
+        while self
+            .traps
+            .front()
+            .is_some_and(|trap| now.duration_since(*trap) > TRAP_WINDOW)
+        {
+            self.traps.pop_front();
+        }
+
+        self.traps.push_back(now);
+        if self.traps.len() >= MAX_TRAPS {
+            self.traps.clear();
+            return true;
+        }
+
+        false
+    }
+}
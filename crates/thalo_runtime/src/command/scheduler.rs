@@ -0,0 +1,326 @@
+//! Scheduler of commands delivered at a future time.
+//!
+//! Commands are scheduled by clients, or by aggregates with
+//! [`thalo::Context::schedule_command`], and are kept in the message store
+//! until they're due, so they survive restarts. Each command is identified by
+//! a key within the stream which scheduled it, or among commands scheduled by
+//! clients, and can be cancelled or replaced until it's delivered.
+//!
+//! Due commands are executed through the [`CommandGatewayHandle`] with the
+//! context they were scheduled with, whose timestamp is the time they were
+//! due. Commands which fail to execute, for example because their module isn't
+//! running, are retried. Like commands sent between aggregates, scheduled
+//! commands are delivered at least once, and are moved to the entity's
+//! [dead letter stream](super::dead_letter) if they fail permanently or
+//! [`MAX_DELIVERY_ATTEMPTS`] times.
+
+use std::borrow::Cow;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use thalo::stream_name::StreamName;
+use thalo_message_store::scheduled_commands::{ScheduledCommand, ScheduledCommands};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+use tracing::{error, info, trace, warn};
+
+use super::dead_letter::{DeadLetter, DeadLetters, DeliveryFailed, MAX_DELIVERY_ATTEMPTS};
+use super::{CommandContext, CommandGatewayHandle, ExpectedVersion};
+
+/// Maximum number of due commands delivered in a batch.
+const BATCH_SIZE: usize = 100;
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct SchedulerHandle {
+    sender: mpsc::Sender<SchedulerMsg>,
+}
+
+enum SchedulerMsg {
+    Schedule {
+        command: ScheduledCommand<'static>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    Cancel {
+        scheduled_by: Option<StreamName<'static>>,
+        key: String,
+        reply: oneshot::Sender<Result<Option<ScheduledCommand<'static>>>>,
+    },
+    List {
+        reply: oneshot::Sender<Result<Vec<ScheduledCommand<'static>>>>,
+    },
+    Stop {
+        reply: oneshot::Sender<()>,
+    },
+}
+
+impl SchedulerHandle {
+    pub fn new(
+        scheduled_commands: ScheduledCommands,
+        dead_letters: DeadLetters,
+        command_gateway: CommandGatewayHandle,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(16);
+        tokio::spawn(run_scheduler(
+            receiver,
+            scheduled_commands,
+            dead_letters,
+            command_gateway,
+        ));
+
+        SchedulerHandle { sender }
+    }
+
+    /// Schedules a command, replacing any command scheduled by the same stream
+    /// with the same key.
+    pub async fn schedule(&self, command: ScheduledCommand<'static>) -> Result<()> {
+        let (reply, recv) = oneshot::channel();
+        let _ = self
+            .sender
+            .send(SchedulerMsg::Schedule { command, reply })
+            .await;
+        recv.await.context("no response from scheduler")?
+    }
+
+    /// Cancels a command scheduled by a stream, or through the runtime's API if
+    /// `scheduled_by` is `None`, returning it if it hadn't been delivered.
+    pub async fn cancel(
+        &self,
+        scheduled_by: Option<StreamName<'static>>,
+        key: String,
+    ) -> Result<Option<ScheduledCommand<'static>>> {
+        let (reply, recv) = oneshot::channel();
+        let _ = self
+            .sender
+            .send(SchedulerMsg::Cancel {
+                scheduled_by,
+                key,
+                reply,
+            })
+            .await;
+        recv.await.context("no response from scheduler")?
+    }
+
+    /// Returns the pending scheduled commands, in the order they're due.
+    pub async fn list(&self) -> Result<Vec<ScheduledCommand<'static>>> {
+        let (reply, recv) = oneshot::channel();
+        let _ = self.sender.send(SchedulerMsg::List { reply }).await;
+        recv.await.context("no response from scheduler")?
+    }
+
+    /// Stops the scheduler, abandoning any command being delivered.
+    ///
+    /// Commands which haven't been delivered are delivered once the runtime
+    /// is started again.
+    pub async fn stop(&self) -> Result<()> {
+        let (reply, recv) = oneshot::channel();
+        let _ = self.sender.send(SchedulerMsg::Stop { reply }).await;
+        recv.await.context("no response from scheduler")
+    }
+}
+
+async fn run_scheduler(
+    mut receiver: mpsc::Receiver<SchedulerMsg>,
+    scheduled_commands: ScheduledCommands,
+    dead_letters: DeadLetters,
+    command_gateway: CommandGatewayHandle,
+) {
+    let mut delivering: Option<JoinHandle<Result<bool>>> = None;
+    let mut retry_at: Option<Instant> = None;
+
+    loop {
+        // Commands aren't delivered again until the current batch is finished.
+        let wake_at = match delivering {
+            Some(_) => None,
+            None => match scheduled_commands.next_due() {
+                Ok(next) => next.map(|command| {
+                    let wake_at = Instant::now() + until(command.due);
+                    retry_at.map_or(wake_at, |retry_at| wake_at.max(retry_at))
+                }),
+                Err(err) => {
+                    error!("failed to read scheduled commands: {err}");
+                    Some(Instant::now() + RETRY_INTERVAL)
+                }
+            },
+        };
+
+        tokio::select! {
+            msg = receiver.recv() => match msg {
+                Some(SchedulerMsg::Schedule { command, reply }) => {
+                    let res = scheduled_commands.schedule(&command).map_err(Into::into);
+                    if res.is_ok() {
+                        trace!(key = %command.key, due = command.due, "scheduled command");
+                    }
+                    let _ = reply.send(res);
+                }
+                Some(SchedulerMsg::Cancel { scheduled_by, key, reply }) => {
+                    let res = scheduled_commands
+                        .cancel(scheduled_by.as_ref(), &key)
+                        .map_err(Into::into);
+                    let _ = reply.send(res);
+                }
+                Some(SchedulerMsg::List { reply }) => {
+                    let res = scheduled_commands
+                        .iter()
+                        .collect::<Result<_, _>>()
+                        .map_err(Into::into);
+                    let _ = reply.send(res);
+                }
+                Some(SchedulerMsg::Stop { reply }) => {
+                    if let Some(delivering) = delivering.take() {
+                        delivering.abort();
+                    }
+                    if let Err(err) = scheduled_commands.flush_async().await {
+                        error!("failed to flush scheduled commands: {err}");
+                    }
+                    let _ = reply.send(());
+                    break;
+                }
+                None => break,
+            },
+            res = async { delivering.as_mut().unwrap().await }, if delivering.is_some() => {
+                delivering = None;
+                retry_at = match res {
+                    Ok(Ok(false)) => None,
+                    Ok(Ok(true)) => Some(Instant::now() + RETRY_INTERVAL),
+                    Ok(Err(err)) => {
+                        error!("failed to deliver scheduled commands: {err}");
+                        Some(Instant::now() + RETRY_INTERVAL)
+                    }
+                    Err(err) => {
+                        error!("scheduler task failed: {err}");
+                        Some(Instant::now() + RETRY_INTERVAL)
+                    }
+                };
+            }
+            () = sleep_until(wake_at), if wake_at.is_some() => {
+                delivering = Some(tokio::spawn(deliver_due(
+                    scheduled_commands.clone(),
+                    dead_letters.clone(),
+                    command_gateway.clone(),
+                )));
+            }
+        }
+    }
+
+    info!("scheduler stopped");
+}
+
+/// Delivers up to [`BATCH_SIZE`] due commands, returning whether any failed to
+/// be delivered.
+///
+/// Once a command fails to be delivered, later commands to the same entity
+/// are skipped until the next batch, so that they're handled in order.
+/// Commands which fail permanently, or [`MAX_DELIVERY_ATTEMPTS`] times, are
+/// moved to the entity's dead letter stream instead.
+async fn deliver_due(
+    scheduled_commands: ScheduledCommands,
+    dead_letters: DeadLetters,
+    command_gateway: CommandGatewayHandle,
+) -> Result<bool> {
+    let now = now_millis();
+    let mut failed = Vec::new();
+    for res in scheduled_commands.iter().take(BATCH_SIZE) {
+        let command = res?;
+        if command.due > now {
+            break;
+        }
+        if failed.contains(&command.stream_name) {
+            continue;
+        }
+
+        let key = command.key.clone();
+        let stream_name = command.stream_name.clone();
+        let Err(DeliveryFailed { error, permanent }) =
+            deliver_command(&command_gateway, command.clone()).await
+        else {
+            // A command rescheduled while it was being delivered is kept.
+            scheduled_commands.complete(&command)?;
+            continue;
+        };
+
+        let attempts = command.failed_attempts + 1;
+        if permanent || attempts >= MAX_DELIVERY_ATTEMPTS {
+            warn!(%key, %stream_name, attempts, "moving scheduled command to dead letter stream: {error:#}");
+            let dead_letter = DeadLetter {
+                payload: Cow::Borrowed(&command.payload),
+                metadata: Cow::Borrowed(&command.metadata),
+                error: format!("{error:#}"),
+                attempts,
+            };
+            dead_letters
+                .write(&stream_name, &command.command, dead_letter)
+                .await?;
+            scheduled_commands.complete(&command)?;
+        } else {
+            warn!(%key, %stream_name, attempts, "failed to deliver scheduled command, retrying: {error:#}");
+            scheduled_commands.record_failure(&command)?;
+            failed.push(stream_name);
+        }
+    }
+
+    Ok(!failed.is_empty())
+}
+
+/// Executes a due command, succeeding if it was accepted or rejected by the
+/// aggregate.
+///
+/// Commands whose context or stream name is invalid fail permanently.
+async fn deliver_command(
+    command_gateway: &CommandGatewayHandle,
+    ScheduledCommand {
+        key,
+        stream_name,
+        command,
+        payload,
+        metadata,
+        ..
+    }: ScheduledCommand<'static>,
+) -> Result<(), DeliveryFailed> {
+    let name = stream_name.category().into_owned();
+    let id = stream_name
+        .id()
+        .context("missing ID")
+        .map_err(DeliveryFailed::permanent)?
+        .into_owned();
+    let context: CommandContext = serde_json::from_value(metadata.into_owned())
+        .context("invalid command context")
+        .map_err(DeliveryFailed::permanent)?;
+    let res = command_gateway
+        .execute(
+            name,
+            id,
+            command.to_string(),
+            payload.into_owned(),
+            ExpectedVersion::Any,
+            context,
+        )
+        .await?;
+    match res {
+        Ok(_) => trace!(%key, %stream_name, %command, "delivered scheduled command"),
+        Err(err) => {
+            warn!(%key, %stream_name, %command, "scheduled command was rejected: {err}")
+        }
+    }
+
+    Ok(())
+}
+
+async fn sleep_until(wake_at: Option<Instant>) {
+    if let Some(wake_at) = wake_at {
+        time::sleep_until(wake_at).await;
+    }
+}
+
+/// Returns the time remaining until a unix timestamp in milliseconds.
+fn until(due: u64) -> Duration {
+    Duration::from_millis(due.saturating_sub(now_millis()))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
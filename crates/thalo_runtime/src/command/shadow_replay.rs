@@ -0,0 +1,265 @@
+//! Verification of a new module build against the active module.
+//!
+//! Each entity stream of the category is replayed through both the active and
+//! the new module. The new module fails verification if it can't deserialize
+//! or apply an event the active module could, or if the entity ends up in a
+//! different state. States are only compared when both modules export them.
+
+use std::borrow::Cow;
+use std::fmt;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use thalo::stream_name::{Category, StreamName};
+use thalo_message_store::MessageStore;
+use thiserror::Error;
+use tracing::{debug, info};
+
+use crate::module::wit_aggregate::AggregateError;
+use crate::module::{Event, Module, ModuleInstance};
+
+/// Maximum number of failures listed when displaying a report.
+const MAX_DISPLAYED_FAILURES: usize = 10;
+
+/// Options for verifying a module before it's activated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Verification {
+    /// Number of streams to replay, evenly spread across the category, or
+    /// `None` to replay every stream.
+    pub sample: Option<usize>,
+    /// Activate the module even if verification fails.
+    pub force: bool,
+}
+
+/// Outcome of replaying a category through a new module.
+#[derive(Clone, Debug, Default)]
+pub struct VerificationReport {
+    /// Number of streams replayed.
+    pub streams: usize,
+    pub failures: Vec<VerificationFailure>,
+}
+
+impl VerificationReport {
+    /// Returns whether every replayed stream passed verification.
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl fmt::Display for VerificationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} streams failed",
+            self.failures.len(),
+            self.streams
+        )?;
+        for failure in self.failures.iter().take(MAX_DISPLAYED_FAILURES) {
+            write!(f, "\n  {failure}")?;
+        }
+        if self.failures.len() > MAX_DISPLAYED_FAILURES {
+            write!(
+                f,
+                "\n  ... and {} more",
+                self.failures.len() - MAX_DISPLAYED_FAILURES
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A stream which the new module replayed differently to the active module.
+#[derive(Clone, Debug, Error)]
+pub enum VerificationFailure {
+    #[error("{stream_name}: failed to deserialize event {event} at position {position}: {error}")]
+    DeserializeEvent {
+        stream_name: StreamName<'static>,
+        position: u64,
+        event: String,
+        error: String,
+    },
+    #[error("{stream_name}: failed to apply event at position {position}: {error}")]
+    Apply {
+        stream_name: StreamName<'static>,
+        position: u64,
+        error: String,
+    },
+    #[error("{stream_name}: failed to export state: {error}")]
+    ExportState {
+        stream_name: StreamName<'static>,
+        error: String,
+    },
+    #[error("{stream_name}: state changed from {old} to {new}")]
+    StateMismatch {
+        stream_name: StreamName<'static>,
+        old: Value,
+        new: Value,
+    },
+}
+
+/// The new module failed verification, and wasn't activated.
+#[derive(Clone, Debug, Error)]
+#[error("module verification failed: {report}")]
+pub struct VerificationFailed {
+    pub report: VerificationReport,
+}
+
+/// Replays the category's entity streams through the active module, if any,
+/// and the new module.
+pub(crate) async fn shadow_replay(
+    message_store: &MessageStore,
+    name: &Category<'static>,
+    mut active: Option<Module>,
+    mut new: Module,
+    sample: Option<usize>,
+) -> Result<VerificationReport> {
+    let stream_names = sample_streams(message_store.stream_names(name), sample);
+    let mut report = VerificationReport::default();
+
+    for stream_name in stream_names {
+        let stream = message_store.stream(stream_name.clone())?;
+        if stream.is_empty() {
+            continue;
+        }
+
+        let id = stream_name.id().context("missing ID")?;
+        let mut active_instance = match &active {
+            Some(active) => Some(active.init(&id).await?),
+            None => None,
+        };
+        let mut new_instance = new.init(&id).await?;
+
+        let mut failure = None;
+        for res in stream.iter_all_messages::<()>() {
+            let raw_message = res?;
+            let message = raw_message.message()?;
+            let events = [(
+                message.position,
+                Event {
+                    event: Cow::Borrowed(&message.msg_type),
+                    payload: Cow::Owned(serde_json::to_string(&message.data)?),
+                },
+            )];
+
+            if let (Some(instance), Some(module)) = (&mut active_instance, &mut active) {
+                if let Err(err) = instance.apply(&events).await {
+                    // The active module can't replay the stream either, so there's nothing
+                    // to compare the new module against.
+                    debug!(%stream_name, "active module failed to replay stream: {err}");
+                    drop_instance(instance, module).await?;
+                    active_instance = None;
+                }
+            }
+
+            if let Err(err) = new_instance.apply(&events).await {
+                let stream_name = stream_name.clone();
+                let position = message.position;
+                failure = Some(match err.downcast_ref::<AggregateError>() {
+                    Some(AggregateError::DeserializeEvent { event, error }) => {
+                        VerificationFailure::DeserializeEvent {
+                            stream_name,
+                            position,
+                            event: event.clone(),
+                            error: error.clone(),
+                        }
+                    }
+                    _ => VerificationFailure::Apply {
+                        stream_name,
+                        position,
+                        error: format!("{err:#}"),
+                    },
+                });
+                break;
+            }
+        }
+
+        if failure.is_none() {
+            if let Some(instance) = &active_instance {
+                failure = compare_states(&stream_name, instance, &new_instance).await?;
+            }
+        }
+        if let (Some(instance), Some(module)) = (&active_instance, &mut active) {
+            drop_instance(instance, module).await?;
+        }
+        drop_instance(&new_instance, &mut new).await?;
+
+        report.streams += 1;
+        report.failures.extend(failure);
+    }
+
+    info!(
+        %name,
+        streams = report.streams,
+        failures = report.failures.len(),
+        "verified module"
+    );
+
+    Ok(report)
+}
+
+/// Picks `sample` streams spread evenly across the category.
+fn sample_streams(
+    stream_names: Vec<StreamName<'static>>,
+    sample: Option<usize>,
+) -> Vec<StreamName<'static>> {
+    match sample {
+        Some(sample) if sample < stream_names.len() => {
+            let step = stream_names.len() as f64 / sample as f64;
+            (0..sample)
+                .map(|i| stream_names[(i as f64 * step) as usize].clone())
+                .collect()
+        }
+        _ => stream_names,
+    }
+}
+
+/// Compares the states of both instances, if both modules export their state.
+async fn compare_states(
+    stream_name: &StreamName<'static>,
+    active: &ModuleInstance,
+    new: &ModuleInstance,
+) -> Result<Option<VerificationFailure>> {
+    let Some(old) = active.state().await.ok().flatten() else {
+        return Ok(None);
+    };
+    let new = new.state().await.and_then(|state| {
+        state
+            .map(|state| {
+                serde_json::from_str::<Value>(&state).context("failed to deserialize state")
+            })
+            .transpose()
+    });
+    let new = match new {
+        Ok(Some(new)) => new,
+        Ok(None) => return Ok(None),
+        Err(err) => {
+            return Ok(Some(VerificationFailure::ExportState {
+                stream_name: stream_name.clone(),
+                error: format!("{err:#}"),
+            }))
+        }
+    };
+
+    let old: Value = serde_json::from_str(&old).context("failed to deserialize state")?;
+    if old == new {
+        return Ok(None);
+    }
+
+    Ok(Some(VerificationFailure::StateMismatch {
+        stream_name: stream_name.clone(),
+        old,
+        new,
+    }))
+}
+
+/// Drops an instance, replacing the module's store if the instance trapped,
+/// as a trapped store can't be called again.
+async fn drop_instance(instance: &ModuleInstance, module: &mut Module) -> Result<()> {
+    if let Err(err) = instance.resource_drop().await {
+        debug!("replacing module store: {err}");
+        *module = module.clone().new_instance().await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,32 @@
+use std::time::SystemTime;
+
+use serde_json::Value;
+use thalo_message_store::message::Message;
+
+/// A point in an entity's history to inspect its state at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StateAt {
+    /// After applying the event at a stream position (inclusive).
+    Position(u64),
+    /// After applying events written at or before a time.
+    Time(SystemTime),
+}
+
+impl StateAt {
+    /// Returns whether a message should be applied to reach this point.
+    pub fn includes<T>(&self, message: &Message<'_, T>) -> bool {
+        match self {
+            StateAt::Position(position) => message.position <= *position,
+            StateAt::Time(time) => message.time <= *time,
+        }
+    }
+}
+
+/// The state of an entity at a point in its history.
+#[derive(Clone, Debug)]
+pub struct HistoricalState {
+    /// Entity state, or `None` if the aggregate's state is not serializable.
+    pub state: Option<Value>,
+    /// Last event applied, or `None` if no events were applied.
+    pub last_event: Option<Message<'static>>,
+}
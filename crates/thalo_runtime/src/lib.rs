@@ -1,11 +1,19 @@
 mod broadcaster;
 mod command;
 pub mod module;
+mod process_manager;
 mod projection;
+pub mod registry;
 pub mod relay;
 pub mod rpc;
 mod runtime;
 
+pub use command::{
+    command_log, dead_letter, entity_cache, CommandContext, CommandContextOverrides,
+    EntityQuarantined, EventMetadata, ExpectedVersion, HistoricalState, ModuleStats,
+    ParseExpectedVersionError, RouteStats, StateAt, Verification, VerificationFailed,
+    VerificationFailure, VerificationReport, WrongExpectedVersion,
+};
 pub use projection::Projection;
-pub use runtime::Runtime;
+pub use runtime::{MissingCheckpointSigningKey, Runtime, RuntimeConfig};
 pub use thalo_message_store::message::Message;
@@ -1,33 +1,67 @@
+pub mod budget;
+pub mod cache;
+pub mod limits;
+pub mod process_manager;
 pub mod wit_aggregate;
+pub mod wit_process_manager;
 
 use std::borrow::Cow;
 use std::ops::DerefMut;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::{fmt, str};
 
 use anyhow::{anyhow, bail, Context as AnyhowContext, Result};
 use async_trait::async_trait;
+use semver::Version;
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
-use tracing::{info, trace};
+use thiserror::Error;
+use tokio::sync::{Mutex, MutexGuard, RwLock};
+use tokio::task;
+use tracing::{info, trace, warn};
 use tracing_tunnel::TracingEventReceiver;
 use wasmtime::component::{Component, InstancePre, Linker, ResourceAny};
-use wasmtime::{Engine, Store};
+use wasmtime::{Engine, Store, Trap};
 use wasmtime_wasi::preview2::{command, Stdout, Table, WasiCtx, WasiCtxBuilder, WasiView};
 
+use self::budget::{
+    BudgetedCall, ExecutionBudget, ExecutionBudgetExceeded, FUEL_ASYNC_YIELD_INTERVAL,
+};
+use self::cache::ComponentCache;
+use self::limits::{MemoryLimiter, MemoryLimits};
 use self::wit_aggregate::Aggregate;
 use crate::module::wit_aggregate::{tracing as wit_tracing, AggregateError};
-
+use crate::CommandContext;
+
+/// A compiled aggregate module, with a pool of instantiated stores.
+///
+/// Entities are initialized on the store with the fewest live entities, so
+/// that entities on different stores can execute in parallel.
+/// A store which traps or exceeds its memory limits is poisoned, and replaced
+/// before the next entity is initialized, leaving entities on other stores
+/// unaffected. Calls to entities left on a poisoned store fail with
+/// [`StorePoisoned`], so they can be hydrated on a healthy store.
 #[derive(Clone)]
 pub struct Module {
+    engine: Engine,
+    component: Component,
+    instance_pre: InstancePre<CommandCtx>,
+    pool: Arc<RwLock<Vec<PooledStore>>>,
+    budget: ExecutionBudget,
+    memory_limits: MemoryLimits,
+    version: Option<Version>,
+}
+
+#[derive(Clone)]
+struct PooledStore {
     // TODO: This Arc shouldn't be necessary, but `wasmtime::component::bindgen` doesn't generate
     // Clone implementations.
     aggregate: Arc<Aggregate>,
-    engine: Engine,
     store: Arc<Mutex<Store<CommandCtx>>>,
-    component: Component,
-    instance_pre: InstancePre<CommandCtx>,
+    /// Set once a call into the store traps, after which it can't be entered
+    /// again.
+    poisoned: Arc<AtomicBool>,
 }
 
 #[derive(Clone)]
@@ -36,29 +70,74 @@ pub struct ModuleInstance {
     store: Arc<Mutex<Store<CommandCtx>>>,
     resource: ResourceAny,
     sequence: Option<u64>,
+    budget: ExecutionBudget,
 }
 
+/// An entity's store was poisoned, by a trap or by exceeding its memory
+/// limits, so the entity can't be called until it's hydrated on another store.
+#[derive(Clone, Copy, Debug, Error)]
+#[error("module store was poisoned")]
+pub struct StorePoisoned;
+
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Event<'a> {
     pub event: Cow<'a, str>,
     pub payload: Cow<'a, str>,
 }
 
+/// Events and outbound commands returned by an aggregate's command handler.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Handled {
+    pub events: Vec<Event<'static>>,
+    pub commands: Vec<OutboundCommand>,
+}
+
+/// A command sent by an aggregate to another aggregate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutboundCommand {
+    /// Name of the aggregate the command is sent to.
+    pub name: String,
+    /// ID of the entity the command is sent to.
+    pub id: String,
+    pub command: String,
+    /// Command payload as json.
+    pub payload: String,
+    /// Schedule of the command, or `None` if it's dispatched straight away.
+    pub schedule: Option<CommandSchedule>,
+}
+
+/// When a scheduled [`OutboundCommand`] is delivered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommandSchedule {
+    pub key: String,
+    /// Unix timestamp in milliseconds of when the command is due.
+    pub due: u64,
+}
+
 pub struct CommandCtx {
     table: Table,
     tracing_subscriber: TracingSubscriber,
     wasi: WasiCtx,
+    limiter: MemoryLimiter,
+    poisoned: Arc<AtomicBool>,
 }
 
 impl CommandCtx {
     pub fn new() -> Self {
+        CommandCtx::with_memory_limits(MemoryLimits::default())
+    }
+
+    pub fn with_memory_limits(memory_limits: MemoryLimits) -> Self {
         let table = Table::new();
         let tracing_subscriber = TracingSubscriber(TracingEventReceiver::default());
         let wasi = WasiCtxBuilder::new().stdout(Stdout).build();
+        let limiter = MemoryLimiter::new(memory_limits);
         CommandCtx {
             table,
             tracing_subscriber,
             wasi,
+            limiter,
+            poisoned: Arc::default(),
         }
     }
 }
@@ -82,71 +161,187 @@ impl wit_tracing::Host for TracingSubscriber {
 }
 
 impl Module {
-    pub async fn new(engine: Engine, component: Component) -> Result<Self> {
-        let ctx = CommandCtx::default();
-        let mut store = Store::new(&engine, ctx);
+    pub async fn new(
+        engine: Engine,
+        component: Component,
+        pool_size: usize,
+        budget: ExecutionBudget,
+        memory_limits: MemoryLimits,
+    ) -> Result<Self> {
         let mut linker: Linker<CommandCtx> = Linker::new(&engine);
         command::add_to_linker(&mut linker)?;
         wit_tracing::add_to_linker(&mut linker, |ctx| &mut ctx.tracing_subscriber)?;
 
-        let instance_pre = linker.instantiate_pre(&component)?;
-        let (aggregate, _instance) =
-            wit_aggregate::Aggregate::instantiate_pre(&mut store, &instance_pre).await?;
+        let instance_pre = linker
+            .instantiate_pre(&component)
+            .with_context(|| incompatible_module(wit_aggregate::WIT_PACKAGE))?;
+        let pool = Self::instantiate_pool(&engine, &instance_pre, pool_size, memory_limits).await?;
 
         Ok(Module {
-            aggregate: Arc::new(aggregate),
             engine,
-            store: Arc::new(Mutex::new(store)),
             component,
             instance_pre,
+            pool,
+            budget,
+            memory_limits,
+            version: None,
         })
     }
 
-    pub async fn from_file<T>(engine: Engine, file: T) -> Result<Self>
+    pub async fn from_file<T>(
+        engine: Engine,
+        file: T,
+        pool_size: usize,
+        budget: ExecutionBudget,
+        memory_limits: MemoryLimits,
+        cache: Option<&ComponentCache>,
+    ) -> Result<Self>
     where
         T: AsRef<Path> + fmt::Debug,
     {
-        let component = Component::from_file(&engine, &file)?;
-        let module = Module::new(engine, component).await?;
+        let component = compile_component(&engine, file.as_ref(), cache).await?;
+        let module = Module::new(engine, component, pool_size, budget, memory_limits).await?;
 
-        info!(?file, "loaded module from file");
+        info!(?file, pool_size, "loaded module from file");
 
         Ok(module)
     }
 
     pub async fn new_instance(self) -> Result<Self> {
-        let ctx = CommandCtx::default();
-        let mut store = Store::new(&self.engine, ctx);
-        let (aggregate, _instance) =
-            wit_aggregate::Aggregate::instantiate_pre(&mut store, &self.instance_pre).await?;
+        let pool_size = self.pool.read().await.len();
+        let pool = Self::instantiate_pool(
+            &self.engine,
+            &self.instance_pre,
+            pool_size,
+            self.memory_limits,
+        )
+        .await?;
 
         Ok(Module {
-            aggregate: Arc::new(aggregate),
             engine: self.engine,
-            store: Arc::new(Mutex::new(store)),
             component: self.component,
             instance_pre: self.instance_pre,
+            pool,
+            budget: self.budget,
+            memory_limits: self.memory_limits,
+            version: self.version,
         })
     }
 
+    /// Sets the version of the module, which is recorded in the metadata of
+    /// events it produces.
+    pub fn with_version(mut self, version: Option<Version>) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn version(&self) -> Option<&Version> {
+        self.version.as_ref()
+    }
+
     pub async fn init(&self, id: &str) -> Result<ModuleInstance> {
+        self.replace_poisoned_stores().await?;
+
+        // Each instance holds a reference to its store, so the strong count reflects the number
+        // of live entities.
+        let pooled = self
+            .pool
+            .read()
+            .await
+            .iter()
+            .min_by_key(|pooled| Arc::strong_count(&pooled.store))
+            .cloned()
+            .context("module store pool is empty")?;
         let resource = {
-            let mut store = self.store.lock().await;
-            self.aggregate
-                .aggregate()
+            let mut store = pooled.store.lock().await;
+            prepare_call(&mut store, self.budget.handle)?;
+            pooled
+                .aggregate
+                .thalo_aggregate_entities()
                 .entity()
                 .call_constructor(store.deref_mut(), id)
-                .await?
+                .await
+                .map_err(|err| {
+                    call_failed(&mut store, err, BudgetedCall::Init, self.budget.handle)
+                })?
         };
 
         trace!(%id, "initialized module");
 
         Ok(ModuleInstance::new(
-            Arc::clone(&self.store),
-            Arc::clone(&self.aggregate),
+            Arc::clone(&pooled.store),
+            Arc::clone(&pooled.aggregate),
             resource,
+            self.budget,
         ))
     }
+
+    /// Replaces stores poisoned by a trap with newly instantiated stores.
+    ///
+    /// Entities on a poisoned store can't be called again, so they're left to
+    /// fail until they're evicted and hydrated on a healthy store.
+    async fn replace_poisoned_stores(&self) -> Result<()> {
+        let poisoned: Vec<_> = self
+            .pool
+            .read()
+            .await
+            .iter()
+            .enumerate()
+            .filter(|(_, pooled)| pooled.poisoned.load(Ordering::Relaxed))
+            .map(|(i, pooled)| (i, Arc::clone(&pooled.store)))
+            .collect();
+
+        for (i, store) in poisoned {
+            let replacement =
+                Self::instantiate_store(&self.engine, &self.instance_pre, self.memory_limits)
+                    .await?;
+            let mut pool = self.pool.write().await;
+            // Another entity may have replaced the store while it was instantiated.
+            if Arc::ptr_eq(&pool[i].store, &store) {
+                pool[i] = replacement;
+                warn!(store = i, "replaced poisoned module store");
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn instantiate_pool(
+        engine: &Engine,
+        instance_pre: &InstancePre<CommandCtx>,
+        pool_size: usize,
+        memory_limits: MemoryLimits,
+    ) -> Result<Arc<RwLock<Vec<PooledStore>>>> {
+        let mut pool = Vec::with_capacity(pool_size.max(1));
+        for _ in 0..pool_size.max(1) {
+            pool.push(Self::instantiate_store(engine, instance_pre, memory_limits).await?);
+        }
+
+        Ok(Arc::new(RwLock::new(pool)))
+    }
+
+    async fn instantiate_store(
+        engine: &Engine,
+        instance_pre: &InstancePre<CommandCtx>,
+        memory_limits: MemoryLimits,
+    ) -> Result<PooledStore> {
+        let ctx = CommandCtx::with_memory_limits(memory_limits);
+        let poisoned = Arc::clone(&ctx.poisoned);
+        let mut store = Store::new(engine, ctx);
+        store.limiter(|ctx| &mut ctx.limiter);
+        store.fuel_async_yield_interval(Some(FUEL_ASYNC_YIELD_INTERVAL))?;
+        // Fuel is refilled with the module's budget before each call.
+        store.set_fuel(u64::MAX)?;
+        let instance = instance_pre.instantiate_async(&mut store).await?;
+        let aggregate = wit_aggregate::Aggregate::new(&mut store, &instance)
+            .with_context(|| incompatible_module(wit_aggregate::WIT_PACKAGE))?;
+
+        Ok(PooledStore {
+            aggregate: Arc::new(aggregate),
+            store: Arc::new(Mutex::new(store)),
+            poisoned,
+        })
+    }
 }
 
 impl ModuleInstance {
@@ -154,12 +349,14 @@ impl ModuleInstance {
         store: Arc<Mutex<Store<CommandCtx>>>,
         aggregate: Arc<Aggregate>,
         resource: ResourceAny,
+        budget: ExecutionBudget,
     ) -> Self {
         ModuleInstance {
             aggregate,
             store,
             resource,
             sequence: None,
+            budget,
         }
     }
 
@@ -172,6 +369,8 @@ impl ModuleInstance {
             return Ok(());
         }
 
+        let mut store = lock_store(&self.store).await?;
+
         // Validate event positions
         let original_sequence = self.sequence;
         let events: Vec<_> = events
@@ -199,10 +398,10 @@ impl ModuleInstance {
             })
             .collect::<Result<_>>()?;
 
-        let mut store = self.store.lock().await;
+        prepare_call(&mut store, self.budget.apply)?;
         let res = self
             .aggregate
-            .aggregate()
+            .thalo_aggregate_entities()
             .entity()
             .call_apply(store.deref_mut(), self.resource, &events)
             .await
@@ -210,7 +409,7 @@ impl ModuleInstance {
                 res.map_err(AggregateError::from)
                     .map_err(anyhow::Error::from)
             })
-            .map_err(anyhow::Error::from);
+            .map_err(|err| call_failed(&mut store, err, BudgetedCall::Apply, self.budget.apply));
         if let Err(err) | Ok(Err(err)) = res {
             self.sequence = original_sequence;
             return Err(err);
@@ -225,23 +424,32 @@ impl ModuleInstance {
         &self,
         command: &str,
         payload: &str,
-    ) -> Result<Result<Vec<Event<'static>>, serde_json::Value>> {
+        context: &CommandContext,
+    ) -> Result<Result<Handled, serde_json::Value>> {
         let command = wit_aggregate::Command { command, payload };
+        let metadata = serde_json::to_string(&context.metadata)?;
+        let ctx = wit_aggregate::Context {
+            timestamp: context.timestamp,
+            seed: context.seed,
+            version: self.sequence,
+            metadata: &metadata,
+        };
 
         let result = {
-            let mut store = self.store.lock().await;
+            let mut store = lock_store(&self.store).await?;
+            prepare_call(&mut store, self.budget.handle)?;
             self.aggregate
-                .aggregate()
+                .thalo_aggregate_entities()
                 .entity()
-                .call_handle(store.deref_mut(), self.resource, command)
-                .await?
+                .call_handle(store.deref_mut(), self.resource, command, ctx)
+                .await
+                .map_err(|err| {
+                    call_failed(&mut store, err, BudgetedCall::Handle, self.budget.handle)
+                })?
                 .map_err(AggregateError::from)
         };
         match result {
-            Ok(events) => Ok(Ok(events
-                .into_iter()
-                .map(Event::try_from)
-                .collect::<Result<Vec<_>, _>>()?)),
+            Ok(handled) => Ok(Ok(Handled::try_from(handled)?)),
             Err(AggregateError::Command { command, error }) => {
                 Ok(Err(serde_json::from_str(&error).with_context(|| {
                     format!("failed to error returned from command '{command}'")
@@ -251,13 +459,106 @@ impl ModuleInstance {
         }
     }
 
+    /// Returns the entity's state as json, or `None` if the aggregate's state is
+    /// not serializable.
+    pub async fn state(&self) -> Result<Option<String>> {
+        let mut store = lock_store(&self.store).await?;
+        prepare_call(&mut store, self.budget.handle)?;
+        let state = self
+            .aggregate
+            .thalo_aggregate_entities()
+            .entity()
+            .call_state(store.deref_mut(), self.resource)
+            .await
+            .map_err(|err| call_failed(&mut store, err, BudgetedCall::State, self.budget.handle))?
+            .map_err(AggregateError::from)?;
+        Ok(state)
+    }
+
     pub async fn resource_drop(&self) -> Result<()> {
         let mut store = self.store.lock().await;
+        prepare_call(&mut store, None)?;
         self.resource.resource_drop_async(store.deref_mut()).await?;
         Ok(())
     }
 }
 
+/// Locks an entity's store, failing with [`StorePoisoned`] if it can't be
+/// entered.
+async fn lock_store(store: &Mutex<Store<CommandCtx>>) -> Result<MutexGuard<'_, Store<CommandCtx>>> {
+    let store = store.lock().await;
+    if store.data().poisoned.load(Ordering::Relaxed) {
+        return Err(StorePoisoned.into());
+    }
+
+    Ok(store)
+}
+
+/// Refills the store's fuel and clears any previously exceeded memory limit
+/// before calling into the module.
+fn prepare_call(store: &mut Store<CommandCtx>, fuel: Option<u64>) -> Result<()> {
+    store.set_fuel(fuel.unwrap_or(u64::MAX))?;
+    store.data_mut().limiter.take_exceeded();
+    Ok(())
+}
+
+/// Compiles a component from a file, using a cache of precompiled components
+/// if there is one.
+///
+/// Compiling a large module can take seconds, so it's done on a blocking
+/// thread rather than holding up the async runtime.
+pub(crate) async fn compile_component(
+    engine: &Engine,
+    file: &Path,
+    cache: Option<&ComponentCache>,
+) -> Result<Component> {
+    let engine = engine.clone();
+    let file = file.to_path_buf();
+    let cache = cache.cloned();
+    task::spawn_blocking(move || match cache {
+        Some(cache) => cache.load(&engine, &file),
+        None => Component::from_file(&engine, &file),
+    })
+    .await
+    .context("failed to compile module")?
+}
+
+/// Describes a module which failed to link, which happens when it was built
+/// against a different version of the runtime's WIT package.
+fn incompatible_module(package: &str) -> String {
+    format!(
+        "module is incompatible with this runtime, which requires {package}; \
+         rebuild it with a matching version of thalo"
+    )
+}
+
+/// Reports a call which ran out of fuel as [`ExecutionBudgetExceeded`], or
+/// which hit a memory limit as [`AggregateError::MemoryLimitExceeded`].
+///
+/// A call which trapped poisons the store, as it can't be entered again. So
+/// does a call which hit a memory limit, as the store's memory can't be
+/// reclaimed.
+fn call_failed(
+    store: &mut Store<CommandCtx>,
+    err: anyhow::Error,
+    call: BudgetedCall,
+    fuel: Option<u64>,
+) -> anyhow::Error {
+    if err.root_cause().is::<Trap>() {
+        store.data().poisoned.store(true, Ordering::Relaxed);
+    }
+
+    if let Some(exceeded) = store.data_mut().limiter.take_exceeded() {
+        store.data().poisoned.store(true, Ordering::Relaxed);
+        return err.context(AggregateError::MemoryLimitExceeded(exceeded));
+    }
+
+    match (fuel, err.root_cause().downcast_ref::<Trap>()) {
+        (Some(fuel), Some(Trap::OutOfFuel)) => err.context(ExecutionBudgetExceeded { call, fuel }),
+        _ => err,
+    }
+}
+
 impl WasiView for CommandCtx {
     fn table(&self) -> &Table {
         &self.table
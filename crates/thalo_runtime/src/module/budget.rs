@@ -0,0 +1,118 @@
+//! CPU limits for aggregate modules.
+//!
+//! Calls into a module are metered with wasmtime fuel, which is refilled with
+//! the module's budget before each call. A call which runs out of fuel traps,
+//! and is reported as [`ExecutionBudgetExceeded`].
+//!
+//! Modules are limited by [`ExecutionBudget::DEFAULT`] unless configured
+//! otherwise, and calls yield to the async executor every
+//! [`FUEL_ASYNC_YIELD_INTERVAL`] units of fuel, so a long running call can't
+//! starve other tasks on its thread.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use thalo::stream_name::Category;
+use thiserror::Error;
+
+/// Units of fuel consumed between yields to the async executor.
+pub const FUEL_ASYNC_YIELD_INTERVAL: u64 = 10_000;
+
+/// Fuel available to a single call into a module, or `None` for unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExecutionBudget {
+    /// Fuel for `handle`, which also covers initializing entities and reading
+    /// their state.
+    pub handle: Option<u64>,
+    /// Fuel for each call to `apply`.
+    pub apply: Option<u64>,
+}
+
+impl ExecutionBudget {
+    /// The default execution budget, which stops runaway calls after roughly a
+    /// second of execution.
+    pub const DEFAULT: Self = ExecutionBudget {
+        handle: Some(1_000_000_000),
+        apply: Some(1_000_000_000),
+    };
+
+    /// An execution budget without limits.
+    pub const UNLIMITED: Self = ExecutionBudget {
+        handle: None,
+        apply: None,
+    };
+}
+
+/// Execution budgets for modules, with per-module overrides.
+#[derive(Clone, Debug)]
+pub struct ExecutionBudgets {
+    default: ExecutionBudget,
+    modules: HashMap<String, ExecutionBudget>,
+}
+
+impl Default for ExecutionBudgets {
+    fn default() -> Self {
+        ExecutionBudgets::new(ExecutionBudget::DEFAULT)
+    }
+}
+
+impl ExecutionBudgets {
+    /// Creates execution budgets, using `default` for all modules.
+    pub fn new(default: ExecutionBudget) -> Self {
+        ExecutionBudgets {
+            default,
+            modules: HashMap::new(),
+        }
+    }
+
+    /// Returns a mutable reference to the default budget.
+    pub fn default_mut(&mut self) -> &mut ExecutionBudget {
+        &mut self.default
+    }
+
+    /// Returns a mutable reference to a module's overrides.
+    ///
+    /// Limits left as `None` fall back to the default budget.
+    pub fn module_mut(&mut self, name: Category<'static>) -> &mut ExecutionBudget {
+        self.modules.entry(name.into_string()).or_default()
+    }
+
+    /// Returns the budget for a module.
+    pub fn get(&self, name: &Category<'_>) -> ExecutionBudget {
+        match self.modules.get(AsRef::<str>::as_ref(name)) {
+            Some(budget) => ExecutionBudget {
+                handle: budget.handle.or(self.default.handle),
+                apply: budget.apply.or(self.default.apply),
+            },
+            None => self.default,
+        }
+    }
+}
+
+/// A call into a module which is metered by an [`ExecutionBudget`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BudgetedCall {
+    Init,
+    Apply,
+    Handle,
+    State,
+}
+
+impl fmt::Display for BudgetedCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BudgetedCall::Init => write!(f, "init"),
+            BudgetedCall::Apply => write!(f, "apply"),
+            BudgetedCall::Handle => write!(f, "command"),
+            BudgetedCall::State => write!(f, "state"),
+        }
+    }
+}
+
+/// A call into a module ran out of fuel.
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+#[error("{call} exceeded execution budget of {fuel} fuel")]
+pub struct ExecutionBudgetExceeded {
+    pub call: BudgetedCall,
+    pub fuel: u64,
+}
@@ -0,0 +1,210 @@
+//! Cache of precompiled components.
+//!
+//! Compiling a component can take seconds for large aggregates, so compiled
+//! artifacts are cached as `{name}@{version}-{key}.cwasm` files, or
+//! `{name}-{key}.cwasm` for unversioned modules. The key hashes the
+//! module's wasm along with the engine's compatibility hash, which covers the
+//! wasmtime version and compilation settings, so upgrading wasmtime or
+//! changing the engine config misses the cache and recompiles.
+
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tracing::{debug, warn};
+use wasmtime::component::Component;
+use wasmtime::Engine;
+
+/// Directory within a modules path for precompiled components.
+pub(crate) const COMPONENT_CACHE_DIR: &str = ".cache";
+
+const EXTENSION: &str = "cwasm";
+
+/// A directory of precompiled components.
+#[derive(Clone, Debug)]
+pub struct ComponentCache {
+    dir: PathBuf,
+}
+
+impl ComponentCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        ComponentCache { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Loads a component from a wasm file, using a precompiled artifact if one
+    /// is cached, or compiling and caching it otherwise.
+    pub fn load(&self, engine: &Engine, file: impl AsRef<Path>) -> Result<Component> {
+        let file = file.as_ref();
+        let name = file
+            .file_stem()
+            .and_then(|name| name.to_str())
+            .context("invalid module file name")?;
+        let wasm = fs::read(file)
+            .with_context(|| format!("failed to read module '{}'", file.display()))?;
+        let path = self.artifact_path(name, &cache_key(engine, &wasm));
+
+        if path.exists() {
+            // SAFETY: Artifacts are only written by the cache from components compiled by
+            // wasmtime, and wasmtime rejects artifacts compiled by an incompatible engine.
+            match unsafe { Component::deserialize_file(engine, &path) } {
+                Ok(component) => {
+                    debug!(path = %path.display(), "loaded precompiled component");
+                    return Ok(component);
+                }
+                Err(err) => {
+                    warn!(path = %path.display(), "discarding precompiled component: {err}");
+                }
+            }
+        }
+
+        let component = Component::new(engine, &wasm)?;
+        if let Err(err) = self.store(name, &path, &component) {
+            warn!(path = %path.display(), "failed to cache precompiled component: {err}");
+        }
+
+        Ok(component)
+    }
+
+    fn artifact_path(&self, name: &str, key: &str) -> PathBuf {
+        self.dir.join(format!("{name}-{key}.{EXTENSION}"))
+    }
+
+    /// Writes a compiled component to the cache, removing stale artifacts of
+    /// the same module version.
+    fn store(&self, name: &str, path: &Path, component: &Component) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        // Written to a temporary file first, so a crash never leaves a partial artifact.
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, component.serialize()?)?;
+        fs::rename(&tmp_path, path)?;
+
+        let (module, version) = split_version(name);
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let dir_entry = dir_entry?;
+            let stale_path = dir_entry.path();
+            let is_stale = stale_path != path
+                && dir_entry
+                    .file_name()
+                    .to_str()
+                    .and_then(ArtifactName::parse)
+                    .is_some_and(|artifact| {
+                        artifact.module == module && artifact.version == version
+                    });
+            if is_stale {
+                if let Err(err) = fs::remove_file(&stale_path) {
+                    warn!(path = %stale_path.display(), "failed to remove stale precompiled component: {err}");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parts of a cached artifact's file name.
+struct ArtifactName<'a> {
+    module: &'a str,
+    version: Option<&'a str>,
+}
+
+impl<'a> ArtifactName<'a> {
+    /// Parses an artifact file name, such as `counter@1.2.0-{key}.cwasm`.
+    ///
+    /// The key is hex, so the module name and version end at its last `-`.
+    fn parse(file_name: &'a str) -> Option<Self> {
+        let stem = file_name.strip_suffix(EXTENSION)?.strip_suffix('.')?;
+        let (name, _key) = stem.rsplit_once('-')?;
+        let (module, version) = split_version(name);
+        Some(ArtifactName { module, version })
+    }
+}
+
+/// Splits a module file stem, such as `counter@1.2.0`, into its name and
+/// version.
+fn split_version(name: &str) -> (&str, Option<&str>) {
+    match name.split_once('@') {
+        Some((module, version)) => (module, Some(version)),
+        None => (name, None),
+    }
+}
+
+/// Hashes a module's wasm with the engine's compatibility hash.
+fn cache_key(engine: &Engine, wasm: &[u8]) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(wasm);
+    engine
+        .precompile_compatibility_hash()
+        .hash(&mut Blake3Hasher(&mut hasher));
+    hasher.finalize().to_hex()[..32].to_string()
+}
+
+/// Adapts a [`blake3::Hasher`] to [`Hasher`], for hashing [`Hash`] types.
+struct Blake3Hasher<'a>(&'a mut blake3::Hasher);
+
+impl Hasher for Blake3Hasher<'_> {
+    fn finish(&self) -> u64 {
+        let hash = self.0.finalize();
+        u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap())
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use anyhow::Result;
+    use wasmtime::{Config, Engine};
+
+    use super::*;
+
+    fn cached_files(cache: &ComponentCache) -> Result<Vec<String>> {
+        let mut files: Vec<_> = fs::read_dir(cache.dir())?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<_>>()?;
+        files.sort();
+        Ok(files)
+    }
+
+    #[test]
+    fn replaces_only_artifacts_of_the_same_version() -> Result<()> {
+        let engine = Engine::new(Config::new().wasm_component_model(true))?;
+        let dir =
+            std::env::temp_dir().join(format!("thalo-component-cache-{}", std::process::id()));
+        let modules = dir.join("modules");
+        fs::create_dir_all(&modules)?;
+        let cache = ComponentCache::new(dir.join(COMPONENT_CACHE_DIR));
+
+        let v1 = modules.join("counter@1.0.0.wasm");
+        let v2 = modules.join("counter@2.0.0.wasm");
+        fs::write(&v1, "(component)")?;
+        fs::write(&v2, "(component)")?;
+        cache.load(&engine, &v1)?;
+        cache.load(&engine, &v2)?;
+        let cached = cached_files(&cache)?;
+        assert_eq!(cached.len(), 2);
+        assert!(cached[0].starts_with("counter@1.0.0-"));
+        assert!(cached[1].starts_with("counter@2.0.0-"));
+
+        // Republishing 1.0.0 with different wasm replaces only its own artifact.
+        fs::write(&v1, "(component (core module))")?;
+        cache.load(&engine, &v1)?;
+        let recached = cached_files(&cache)?;
+        assert_eq!(recached.len(), 2);
+        assert!(recached[0].starts_with("counter@1.0.0-"));
+        assert_ne!(recached[0], cached[0]);
+        assert_eq!(recached[1], cached[1]);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
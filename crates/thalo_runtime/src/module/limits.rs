@@ -0,0 +1,130 @@
+//! Memory limits for aggregate modules.
+//!
+//! Limits apply to each store in a module's pool, which is shared by the
+//! entities initialized on it. Memory can't be attributed to a single entity
+//! within a store, so the limits bound the memory of a store's entities
+//! together, rather than of each entity.
+//!
+//! Growing linear memory or a table beyond a limit fails, which typically
+//! traps the aggregate, and is reported as [`MemoryLimitExceeded`]. The call
+//! which hit the limit fails and its entity is evicted, and the store is
+//! poisoned, since its memory can't be reclaimed. The store's other entities
+//! are hydrated on a fresh store the next time they're called, rather than
+//! failing for memory they may not have used.
+
+use std::collections::HashMap;
+
+use thalo::stream_name::Category;
+use thiserror::Error;
+use wasmtime::ResourceLimiter;
+
+/// Memory limits of each store in a module's pool, or `None` for unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryLimits {
+    /// Maximum size in bytes of each linear memory.
+    pub memory_size: Option<usize>,
+    /// Maximum number of elements in each table.
+    pub table_elements: Option<u32>,
+}
+
+/// Memory limits for modules, with per-module overrides.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryLimitsConfig {
+    default: MemoryLimits,
+    modules: HashMap<String, MemoryLimits>,
+}
+
+impl MemoryLimitsConfig {
+    /// Creates memory limits, using `default` for all modules.
+    pub fn new(default: MemoryLimits) -> Self {
+        MemoryLimitsConfig {
+            default,
+            modules: HashMap::new(),
+        }
+    }
+
+    /// Returns a mutable reference to the default limits.
+    pub fn default_mut(&mut self) -> &mut MemoryLimits {
+        &mut self.default
+    }
+
+    /// Returns a mutable reference to a module's overrides.
+    ///
+    /// Limits left as `None` fall back to the default limits.
+    pub fn module_mut(&mut self, name: Category<'static>) -> &mut MemoryLimits {
+        self.modules.entry(name.into_string()).or_default()
+    }
+
+    /// Returns the limits for a module.
+    pub fn get(&self, name: &Category<'_>) -> MemoryLimits {
+        match self.modules.get(AsRef::<str>::as_ref(name)) {
+            Some(limits) => MemoryLimits {
+                memory_size: limits.memory_size.or(self.default.memory_size),
+                table_elements: limits.table_elements.or(self.default.table_elements),
+            },
+            None => self.default,
+        }
+    }
+}
+
+/// A module's store tried to grow beyond its [`MemoryLimits`].
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+pub enum MemoryLimitExceeded {
+    #[error("growing linear memory to {desired} bytes exceeds limit of {limit} bytes")]
+    Memory { desired: usize, limit: usize },
+    #[error("growing table to {desired} elements exceeds limit of {limit} elements")]
+    Table { desired: u32, limit: u32 },
+}
+
+/// Enforces [`MemoryLimits`] on a store, recording the last limit exceeded.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryLimiter {
+    limits: MemoryLimits,
+    exceeded: Option<MemoryLimitExceeded>,
+}
+
+impl MemoryLimiter {
+    pub(crate) fn new(limits: MemoryLimits) -> Self {
+        MemoryLimiter {
+            limits,
+            exceeded: None,
+        }
+    }
+
+    /// Takes the limit exceeded since last called, if any.
+    pub(crate) fn take_exceeded(&mut self) -> Option<MemoryLimitExceeded> {
+        self.exceeded.take()
+    }
+}
+
+impl ResourceLimiter for MemoryLimiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        match self.limits.memory_size {
+            Some(limit) if desired > limit => {
+                self.exceeded = Some(MemoryLimitExceeded::Memory { desired, limit });
+                Ok(false)
+            }
+            _ => Ok(true),
+        }
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: u32,
+        desired: u32,
+        _maximum: Option<u32>,
+    ) -> anyhow::Result<bool> {
+        match self.limits.table_elements {
+            Some(limit) if desired > limit => {
+                self.exceeded = Some(MemoryLimitExceeded::Table { desired, limit });
+                Ok(false)
+            }
+            _ => Ok(true),
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use anyhow::{anyhow, Context as AnyhowContext, Result};
+use tracing::{info, trace, warn};
+use wasmtime::component::{InstancePre, Linker, ResourceAny};
+use wasmtime::{Engine, Store};
+use wasmtime_wasi::preview2::command;
+
+use super::budget::{BudgetedCall, ExecutionBudget, FUEL_ASYNC_YIELD_INTERVAL};
+use super::cache::ComponentCache;
+use super::limits::MemoryLimits;
+use super::wit_process_manager::{
+    self, tracing as wit_tracing, ProcessManager, ProcessManagerError,
+};
+use super::{
+    call_failed, compile_component, incompatible_module, prepare_call, CommandCtx, Event, Handled,
+};
+use crate::CommandContext;
+
+/// A compiled process manager module, with a single instantiated store.
+///
+/// Processes may be kept between messages, but only live as long as the store
+/// they were initialized in. A store which traps is replaced before the next
+/// call, and its processes are no longer [live](Self::is_live).
+pub struct ProcessManagerModule {
+    engine: Engine,
+    instance_pre: InstancePre<CommandCtx>,
+    process_manager: ProcessManager,
+    store: Store<CommandCtx>,
+    /// Incremented each time the store is replaced.
+    generation: u64,
+    budget: ExecutionBudget,
+    memory_limits: MemoryLimits,
+}
+
+/// The state of a process, identified by its correlation ID.
+pub struct ProcessInstance {
+    resource: ResourceAny,
+    sequence: Option<u64>,
+    /// Generation of the store the process was initialized in.
+    generation: u64,
+}
+
+/// An event of another aggregate, passed to a process manager.
+pub struct ProcessMessage<'a> {
+    pub stream_name: &'a str,
+    pub global_id: u64,
+    pub event: &'a str,
+    /// Event payload as json.
+    pub payload: &'a str,
+    /// Correlation metadata of the event as a json object.
+    pub metadata: &'a str,
+}
+
+impl ProcessManagerModule {
+    pub async fn from_file<T>(
+        engine: Engine,
+        file: T,
+        budget: ExecutionBudget,
+        memory_limits: MemoryLimits,
+        cache: Option<&ComponentCache>,
+    ) -> Result<Self>
+    where
+        T: AsRef<Path> + fmt::Debug,
+    {
+        let component = compile_component(&engine, file.as_ref(), cache).await?;
+        let mut linker: Linker<CommandCtx> = Linker::new(&engine);
+        command::add_to_linker(&mut linker)?;
+        wit_tracing::add_to_linker(&mut linker, |ctx| &mut ctx.tracing_subscriber)?;
+
+        let instance_pre = linker
+            .instantiate_pre(&component)
+            .with_context(|| incompatible_module(wit_process_manager::WIT_PACKAGE))?;
+        let (process_manager, store) =
+            Self::instantiate_store(&engine, &instance_pre, memory_limits).await?;
+
+        info!(?file, "loaded process manager from file");
+
+        Ok(ProcessManagerModule {
+            engine,
+            instance_pre,
+            process_manager,
+            store,
+            generation: 0,
+            budget,
+            memory_limits,
+        })
+    }
+
+    /// Returns whether a process's store is still in use, and it can handle
+    /// more messages.
+    pub fn is_live(&self, process: &ProcessInstance) -> bool {
+        process.generation == self.generation && !self.store.data().poisoned.load(Ordering::Relaxed)
+    }
+
+    /// Returns the events the process manager handles.
+    pub async fn interests(&mut self) -> Result<Vec<wit_process_manager::EventInterest>> {
+        self.replace_poisoned_store().await?;
+        prepare_call(&mut self.store, self.budget.handle)?;
+        self.process_manager
+            .process_manager()
+            .call_interests(&mut self.store)
+            .await
+            .map_err(|err| {
+                call_failed(&mut self.store, err, BudgetedCall::Init, self.budget.handle)
+            })
+    }
+
+    /// Returns the correlation ID of the process which handles a message, or
+    /// `None` if the message is ignored.
+    pub async fn correlate(&mut self, message: &ProcessMessage<'_>) -> Result<Option<String>> {
+        self.replace_poisoned_store().await?;
+        prepare_call(&mut self.store, self.budget.handle)?;
+        let correlation_id = self
+            .process_manager
+            .process_manager()
+            .call_correlate(&mut self.store, message.to_wit())
+            .await
+            .map_err(|err| {
+                call_failed(
+                    &mut self.store,
+                    err,
+                    BudgetedCall::Handle,
+                    self.budget.handle,
+                )
+            })?
+            .map_err(ProcessManagerError::from)?;
+        Ok(correlation_id)
+    }
+
+    pub async fn init(&mut self, correlation_id: &str) -> Result<ProcessInstance> {
+        self.replace_poisoned_store().await?;
+        prepare_call(&mut self.store, self.budget.handle)?;
+        let resource = self
+            .process_manager
+            .process_manager()
+            .process()
+            .call_constructor(&mut self.store, correlation_id)
+            .await
+            .map_err(|err| {
+                call_failed(&mut self.store, err, BudgetedCall::Init, self.budget.handle)
+            })?;
+
+        trace!(%correlation_id, "initialized process");
+
+        Ok(ProcessInstance {
+            resource,
+            sequence: None,
+            generation: self.generation,
+        })
+    }
+
+    /// Applies an event of the process's own stream.
+    pub async fn apply(
+        &mut self,
+        process: &mut ProcessInstance,
+        position: u64,
+        event: &Event<'_>,
+    ) -> Result<()> {
+        let expected = process.sequence.map(|seq| seq + 1).unwrap_or(0);
+        if position != expected {
+            return Err(anyhow!(
+                "wrong event position {position}, expected {expected}"
+            ));
+        }
+
+        let events = [wit_process_manager::EventParam {
+            event: &event.event,
+            payload: &event.payload,
+        }];
+        prepare_call(&mut self.store, self.budget.apply)?;
+        self.process_manager
+            .process_manager()
+            .process()
+            .call_apply(&mut self.store, process.resource, &events)
+            .await
+            .map_err(|err| {
+                call_failed(&mut self.store, err, BudgetedCall::Apply, self.budget.apply)
+            })?
+            .map_err(ProcessManagerError::from)?;
+        process.sequence = Some(position);
+
+        Ok(())
+    }
+
+    /// Handles a message, returning the process's new events and the commands
+    /// it sent, or the error returned by the process manager.
+    pub async fn handle(
+        &mut self,
+        process: &ProcessInstance,
+        message: &ProcessMessage<'_>,
+        context: &CommandContext,
+    ) -> Result<Result<Handled, serde_json::Value>> {
+        let metadata = serde_json::to_string(&context.metadata)?;
+        let ctx = wit_process_manager::Context {
+            timestamp: context.timestamp,
+            seed: context.seed,
+            version: process.sequence,
+            metadata: &metadata,
+        };
+
+        prepare_call(&mut self.store, self.budget.handle)?;
+        let result = self
+            .process_manager
+            .process_manager()
+            .process()
+            .call_handle(&mut self.store, process.resource, message.to_wit(), ctx)
+            .await
+            .map_err(|err| {
+                call_failed(
+                    &mut self.store,
+                    err,
+                    BudgetedCall::Handle,
+                    self.budget.handle,
+                )
+            })?
+            .map_err(ProcessManagerError::from);
+        match result {
+            Ok(handled) => Ok(Ok(Handled::try_from(handled)?)),
+            Err(ProcessManagerError::Handle { event, error }) => {
+                Ok(Err(serde_json::from_str(&error).with_context(|| {
+                    format!("failed to deserialize error returned handling '{event}'")
+                })?))
+            }
+            Err(err) => Err(anyhow!(err)),
+        }
+    }
+
+    pub async fn resource_drop(&mut self, process: ProcessInstance) -> Result<()> {
+        // A poisoned store can't be entered, and is replaced along with its resources.
+        if !self.is_live(&process) {
+            return Ok(());
+        }
+
+        prepare_call(&mut self.store, None)?;
+        process
+            .resource
+            .resource_drop_async(&mut self.store)
+            .await?;
+        Ok(())
+    }
+
+    /// Replaces the store if it was poisoned by a trap or by exceeding its
+    /// memory limits.
+    async fn replace_poisoned_store(&mut self) -> Result<()> {
+        if !self.store.data().poisoned.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let (process_manager, store) =
+            Self::instantiate_store(&self.engine, &self.instance_pre, self.memory_limits).await?;
+        self.process_manager = process_manager;
+        self.store = store;
+        self.generation += 1;
+        warn!("replaced poisoned process manager store");
+
+        Ok(())
+    }
+
+    async fn instantiate_store(
+        engine: &Engine,
+        instance_pre: &InstancePre<CommandCtx>,
+        memory_limits: MemoryLimits,
+    ) -> Result<(ProcessManager, Store<CommandCtx>)> {
+        let mut store = Store::new(engine, CommandCtx::with_memory_limits(memory_limits));
+        store.limiter(|ctx| &mut ctx.limiter);
+        store.fuel_async_yield_interval(Some(FUEL_ASYNC_YIELD_INTERVAL))?;
+        // Fuel is refilled with the module's budget before each call.
+        store.set_fuel(u64::MAX)?;
+        let instance = instance_pre.instantiate_async(&mut store).await?;
+        let process_manager = ProcessManager::new(&mut store, &instance)
+            .with_context(|| incompatible_module(wit_process_manager::WIT_PACKAGE))?;
+
+        Ok((process_manager, store))
+    }
+}
+
+impl ProcessInstance {
+    /// Position of the process's last event, or `None` if it has no events.
+    pub fn sequence(&self) -> Option<u64> {
+        self.sequence
+    }
+}
+
+impl ProcessMessage<'_> {
+    fn to_wit(&self) -> wit_process_manager::Message<'_> {
+        wit_process_manager::Message {
+            stream_name: self.stream_name,
+            global_id: self.global_id,
+            event: self.event,
+            payload: self.payload,
+            metadata: self.metadata,
+        }
+    }
+}
@@ -10,10 +10,18 @@ mod wit {
 use std::borrow::Cow;
 
 use thiserror::Error;
-pub use wit::exports::aggregate::{Command, EventParam, EventResult};
+
+use super::limits::MemoryLimitExceeded;
+pub use wit::exports::thalo::aggregate::entities::{
+    Command, Context, EventParam, EventResult, Handled, OutboundCommand,
+};
 pub use wit::thalo::aggregate::tracing;
 pub use wit::Aggregate;
 
+/// WIT package aggregate modules must be built against, as declared in
+/// `wit/aggregate.wit`.
+pub const WIT_PACKAGE: &str = "thalo:aggregate@0.2.0";
+
 #[derive(Clone, Debug, Error)]
 pub enum AggregateError {
     #[error("command {command} returned an error: {error}")]
@@ -28,11 +36,15 @@ pub enum AggregateError {
     SerializeError { command: String, error: String },
     #[error("failed to serialize event: {0}")]
     SerializeEvent(String),
+    #[error("failed to serialize state: {0}")]
+    SerializeState(String),
+    #[error("aggregate exceeded memory limit: {0}")]
+    MemoryLimitExceeded(MemoryLimitExceeded),
 }
 
-impl From<wit::exports::aggregate::Error> for AggregateError {
-    fn from(err: wit::exports::aggregate::Error) -> Self {
-        use wit::exports::aggregate::Error;
+impl From<wit::exports::thalo::aggregate::entities::Error> for AggregateError {
+    fn from(err: wit::exports::thalo::aggregate::entities::Error) -> Self {
+        use wit::exports::thalo::aggregate::entities::Error;
 
         match err {
             Error::Command((command, error)) => AggregateError::Command { command, error },
@@ -47,6 +59,41 @@ impl From<wit::exports::aggregate::Error> for AggregateError {
                 AggregateError::SerializeError { command, error }
             }
             Error::SerializeEvent(err) => AggregateError::SerializeEvent(err),
+            Error::SerializeState(err) => AggregateError::SerializeState(err),
+        }
+    }
+}
+
+impl TryFrom<Handled> for super::Handled {
+    type Error = anyhow::Error;
+
+    fn try_from(handled: Handled) -> Result<Self, Self::Error> {
+        Ok(super::Handled {
+            events: handled
+                .events
+                .into_iter()
+                .map(super::Event::try_from)
+                .collect::<Result<_, _>>()?,
+            commands: handled
+                .commands
+                .into_iter()
+                .map(super::OutboundCommand::from)
+                .collect(),
+        })
+    }
+}
+
+impl From<OutboundCommand> for super::OutboundCommand {
+    fn from(command: OutboundCommand) -> Self {
+        super::OutboundCommand {
+            name: command.name,
+            id: command.id,
+            command: command.command,
+            payload: command.payload,
+            schedule: command.schedule.map(|schedule| super::CommandSchedule {
+                key: schedule.key,
+                due: schedule.due,
+            }),
         }
     }
 }
@@ -0,0 +1,113 @@
+mod wit {
+    wasmtime::component::bindgen!({
+        path: "wit/process-manager.wit",
+        world: "process-manager",
+        ownership: Borrowing { duplicate_if_necessary: true },
+        async: true,
+    });
+}
+
+use async_trait::async_trait;
+use thiserror::Error;
+
+use super::TracingSubscriber;
+pub use wit::exports::process_manager::{
+    Context, EventInterest, EventParam, EventResult, Handled, Message, OutboundCommand,
+};
+pub use wit::thalo::process_manager::tracing;
+pub use wit::ProcessManager;
+
+/// WIT package process manager modules must be built against, as declared in
+/// `wit/process-manager.wit`.
+pub const WIT_PACKAGE: &str = "thalo:process-manager@0.2.0";
+
+#[derive(Clone, Debug, Error)]
+pub enum ProcessManagerError {
+    #[error("process manager returned an error handling {event}: {error}")]
+    Handle { event: String, error: String },
+    #[error("failed to deserialize context: {0}")]
+    DeserializeContext(String),
+    #[error("failed to deserialize event {event}: {error}")]
+    DeserializeEvent { event: String, error: String },
+    #[error("failed to deserialize message {event}: {error}")]
+    DeserializeMessage { event: String, error: String },
+    #[error("failed to serialize error handling {event}: {error}")]
+    SerializeError { event: String, error: String },
+    #[error("failed to serialize event: {0}")]
+    SerializeEvent(String),
+}
+
+impl From<wit::exports::process_manager::Error> for ProcessManagerError {
+    fn from(err: wit::exports::process_manager::Error) -> Self {
+        use wit::exports::process_manager::Error;
+
+        match err {
+            Error::Handle((event, error)) => ProcessManagerError::Handle { event, error },
+            Error::DeserializeContext(err) => ProcessManagerError::DeserializeContext(err),
+            Error::DeserializeEvent((event, error)) => {
+                ProcessManagerError::DeserializeEvent { event, error }
+            }
+            Error::DeserializeMessage((event, error)) => {
+                ProcessManagerError::DeserializeMessage { event, error }
+            }
+            Error::SerializeError((event, error)) => {
+                ProcessManagerError::SerializeError { event, error }
+            }
+            Error::SerializeEvent(err) => ProcessManagerError::SerializeEvent(err),
+        }
+    }
+}
+
+impl TryFrom<Handled> for super::Handled {
+    type Error = anyhow::Error;
+
+    fn try_from(handled: Handled) -> Result<Self, Self::Error> {
+        Ok(super::Handled {
+            events: handled
+                .events
+                .into_iter()
+                .map(super::Event::try_from)
+                .collect::<Result<_, _>>()?,
+            commands: handled
+                .commands
+                .into_iter()
+                .map(super::OutboundCommand::from)
+                .collect(),
+        })
+    }
+}
+
+impl From<OutboundCommand> for super::OutboundCommand {
+    fn from(command: OutboundCommand) -> Self {
+        super::OutboundCommand {
+            name: command.name,
+            id: command.id,
+            command: command.command,
+            payload: command.payload,
+            schedule: command.schedule.map(|schedule| super::CommandSchedule {
+                key: schedule.key,
+                due: schedule.due,
+            }),
+        }
+    }
+}
+
+impl TryFrom<EventResult> for super::Event<'static> {
+    type Error = anyhow::Error;
+
+    fn try_from(event: EventResult) -> Result<Self, Self::Error> {
+        Ok(super::Event {
+            event: event.event.into(),
+            payload: event.payload.into(),
+        })
+    }
+}
+
+#[async_trait]
+impl tracing::Host for TracingSubscriber {
+    async fn send_event(&mut self, event: Vec<u8>) -> wasmtime::Result<()> {
+        let event = serde_json::from_slice(&event).map_err(wasmtime::Error::new)?;
+        self.0.try_receive(event).map_err(wasmtime::Error::new)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+//! Process managers, hosted alongside aggregates.
+//!
+//! Process manager modules are loaded from the `process_managers` directory
+//! within the modules path when the runtime starts. Each process manager
+//! receives the events it's interested in through a projection named
+//! `process_manager:{name}`, and keeps a process for each correlation ID in a
+//! `{name}:process-{correlation_id}` stream.
+//!
+//! Compiled process managers are cached in the directory's own `.cache`
+//! directory, apart from aggregates which may share their names.
+
+mod process_manager_handler;
+
+use std::path::Path;
+
+use anyhow::Result;
+use thalo::stream_name::Category;
+use tokio::fs;
+use tracing::{error, warn};
+use wasmtime::Engine;
+
+pub use self::process_manager_handler::ProcessManagerHandle;
+use crate::module::budget::ExecutionBudgets;
+use crate::module::cache::{ComponentCache, COMPONENT_CACHE_DIR};
+use crate::module::limits::MemoryLimitsConfig;
+use crate::module::process_manager::ProcessManagerModule;
+
+/// Loads each `{name}.wasm` process manager module in a directory.
+///
+/// Modules which fail to load are logged and skipped, and a missing directory
+/// has no process managers.
+pub async fn load_process_managers(
+    engine: &Engine,
+    path: &Path,
+    execution_budgets: &ExecutionBudgets,
+    memory_limits: &MemoryLimitsConfig,
+) -> Result<Vec<(Category<'static>, ProcessManagerModule)>> {
+    let mut dir = match fs::read_dir(path).await {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+
+    let component_cache = ComponentCache::new(path.join(COMPONENT_CACHE_DIR));
+    let mut process_managers = Vec::new();
+    while let Some(entry) = dir.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("wasm") {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            warn!(path = %path.display(), "ignoring process manager with invalid file name");
+            continue;
+        };
+        let name = Category::new(name.to_string())?;
+
+        let module = ProcessManagerModule::from_file(
+            engine.clone(),
+            &path,
+            execution_budgets.get(&name),
+            memory_limits.get(&name),
+            Some(&component_cache),
+        )
+        .await;
+        match module {
+            Ok(module) => process_managers.push((name, module)),
+            Err(err) => error!(%name, "failed to load process manager: {err}"),
+        }
+    }
+
+    Ok(process_managers)
+}
@@ -0,0 +1,480 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde_json::{json, Map, Value};
+use thalo::stream_name::{Category, StreamName, ID};
+use thalo_message_store::command_outbox::{CommandOutbox, OutboxCommand};
+use thalo_message_store::message::Message;
+use thalo_message_store::stream::{Alongside, Stream};
+use thalo_message_store::MessageStore;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Instant};
+use tracing::{error, info, trace, warn};
+
+use crate::broadcaster::BroadcasterHandle;
+use crate::command::dead_letter::{DeliveryFailed, MAX_DELIVERY_ATTEMPTS};
+use crate::command::{outbox_command, CommandContext, CommandGatewayHandle};
+use crate::module::process_manager::{ProcessInstance, ProcessManagerModule, ProcessMessage};
+use crate::module::wit_process_manager::ProcessManagerError;
+use crate::module::Event;
+use crate::projection::{CategoryInterest, EventInterest, ProjectionGatewayHandle};
+
+/// Time to wait before handling an event again after it failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of processes kept between events, rather than rebuilt from their
+/// streams.
+const PROCESS_CACHE_CAPACITY: usize = 1_000;
+
+#[derive(Clone)]
+pub struct ProcessManagerHandle {
+    sender: mpsc::Sender<ProcessManagerMsg>,
+}
+
+enum ProcessManagerMsg {
+    Stop { reply: oneshot::Sender<()> },
+}
+
+impl ProcessManagerHandle {
+    pub fn new(
+        name: Category<'static>,
+        module: ProcessManagerModule,
+        message_store: MessageStore,
+        broadcaster: BroadcasterHandle,
+        command_gateway: CommandGatewayHandle,
+        projection_gateway: ProjectionGatewayHandle,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(1);
+        tokio::spawn(run_process_manager(
+            receiver,
+            name,
+            module,
+            message_store,
+            broadcaster,
+            command_gateway,
+            projection_gateway,
+        ));
+
+        ProcessManagerHandle { sender }
+    }
+
+    /// Stops the process manager once it's finished handling the current
+    /// event.
+    pub async fn stop(&self) -> Result<()> {
+        let (reply, recv) = oneshot::channel();
+        let _ = self.sender.send(ProcessManagerMsg::Stop { reply }).await;
+        recv.await.context("no response from process manager")
+    }
+}
+
+async fn run_process_manager(
+    mut receiver: mpsc::Receiver<ProcessManagerMsg>,
+    name: Category<'static>,
+    module: ProcessManagerModule,
+    message_store: MessageStore,
+    broadcaster: BroadcasterHandle,
+    command_gateway: CommandGatewayHandle,
+    projection_gateway: ProjectionGatewayHandle,
+) {
+    let (tx, mut events) = mpsc::channel(16);
+    let mut process_manager = ProcessManager {
+        process_category: Category::from_parts(name.to_string(), &["process"])
+            .expect("process manager name is not empty"),
+        projection_name: format!("process_manager:{name}"),
+        name,
+        module,
+        message_store,
+        broadcaster,
+        command_gateway,
+        projection_gateway,
+        processes: HashMap::new(),
+        uses: 0,
+        failed_attempts: 0,
+    };
+
+    // Once the projection stops, the process manager only waits to be stopped.
+    let mut is_subscribed = match process_manager.start(tx).await {
+        Ok(()) => true,
+        Err(err) => {
+            error!(name = %process_manager.name, "failed to start process manager: {err}");
+            false
+        }
+    };
+    let mut retrying: Option<Message<'static>> = None;
+    let retry = time::sleep(RETRY_INTERVAL);
+    tokio::pin!(retry);
+
+    loop {
+        tokio::select! {
+            msg = receiver.recv() => {
+                if let Some(ProcessManagerMsg::Stop { reply }) = msg {
+                    let _ = reply.send(());
+                }
+                break;
+            }
+            event = events.recv(), if is_subscribed && retrying.is_none() => match event {
+                Some(event) => retrying = process_manager.process(event).await,
+                None => {
+                    error!(name = %process_manager.name, "process manager projection stopped");
+                    is_subscribed = false;
+                }
+            },
+            () = &mut retry, if retrying.is_some() => {
+                if let Some(event) = retrying.take() {
+                    retrying = process_manager.process(event).await;
+                }
+            }
+        }
+
+        if retrying.is_some() {
+            retry.as_mut().reset(Instant::now() + RETRY_INTERVAL);
+        }
+    }
+
+    info!(name = %process_manager.name, "process manager stopped");
+}
+
+struct ProcessManager {
+    name: Category<'static>,
+    /// Category of the process streams.
+    process_category: Category<'static>,
+    projection_name: String,
+    module: ProcessManagerModule,
+    message_store: MessageStore,
+    broadcaster: BroadcasterHandle,
+    command_gateway: CommandGatewayHandle,
+    projection_gateway: ProjectionGatewayHandle,
+    /// Processes kept after handling an event, by correlation ID.
+    processes: HashMap<String, CachedProcess>,
+    /// Number of processes used, for evicting the least recently used.
+    uses: u64,
+    /// Number of times the current event failed to be handled.
+    failed_attempts: u32,
+}
+
+/// A process kept between events.
+struct CachedProcess {
+    process: ProcessInstance,
+    /// Global ID of the last event the process handled.
+    last_handled_id: Option<u64>,
+    last_used: u64,
+}
+
+impl ProcessManager {
+    /// Subscribes to the events the process manager is interested in,
+    /// starting after the last acknowledged event.
+    async fn start(&mut self, tx: mpsc::Sender<Message<'static>>) -> Result<()> {
+        let events = self
+            .module
+            .interests()
+            .await?
+            .into_iter()
+            .map(|interest| {
+                let category = match interest.category {
+                    Some(category) => CategoryInterest::Category(Category::new(category)?),
+                    None => CategoryInterest::Any,
+                };
+                Ok(EventInterest {
+                    category,
+                    event: interest.event,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        self.projection_gateway
+            .start_projection(tx, self.projection_name.clone(), events, None)
+            .await?;
+
+        info!(name = %self.name, "started process manager");
+
+        Ok(())
+    }
+
+    /// Handles and acknowledges an event, returning the event if it should be
+    /// retried.
+    ///
+    /// Errors returned by the process manager itself are logged and the event
+    /// is skipped, as handling it again would fail the same way. So are traps
+    /// and exceeded budgets or memory limits, and events which failed
+    /// [`MAX_DELIVERY_ATTEMPTS`] times.
+    async fn process(&mut self, event: Message<'static>) -> Option<Message<'static>> {
+        let global_id = event.global_id;
+        match self.handle_event(&event).await {
+            Ok(()) => {}
+            Err(err) if err.is::<ProcessManagerError>() => {
+                error!(name = %self.name, global_id, "skipping event process manager failed to handle: {err}");
+            }
+            Err(err) => {
+                self.failed_attempts += 1;
+                let attempts = self.failed_attempts;
+                let failed = DeliveryFailed::from(err);
+                if !failed.permanent && attempts < MAX_DELIVERY_ATTEMPTS {
+                    warn!(name = %self.name, global_id, attempts, "failed to handle event, retrying: {:#}", failed.error);
+                    return Some(event);
+                }
+                error!(name = %self.name, global_id, attempts, "skipping event process manager failed to handle: {:#}", failed.error);
+            }
+        }
+        self.failed_attempts = 0;
+
+        if let Err(err) = self
+            .projection_gateway
+            .acknowledge_event(self.projection_name.clone(), global_id)
+            .await
+        {
+            error!(name = %self.name, global_id, "failed to acknowledge event: {err}");
+        }
+
+        None
+    }
+
+    async fn handle_event(&mut self, event: &Message<'static>) -> Result<()> {
+        // Events of the process manager's own processes are never handled.
+        if event.stream_name.category() == self.process_category {
+            return Ok(());
+        }
+
+        let stream_name = event.stream_name.to_string();
+        let payload = serde_json::to_string(&event.data)?;
+        let correlation = match event.metadata.get("correlation") {
+            Some(Value::Object(correlation)) => correlation.clone(),
+            _ => Map::new(),
+        };
+        let metadata = serde_json::to_string(&correlation)?;
+        let message = ProcessMessage {
+            stream_name: &stream_name,
+            global_id: event.global_id,
+            event: &event.msg_type,
+            payload: &payload,
+            metadata: &metadata,
+        };
+
+        let Some(correlation_id) = self.module.correlate(&message).await? else {
+            trace!(name = %self.name, global_id = event.global_id, "ignoring uncorrelated event");
+            return Ok(());
+        };
+        let id = ID::new(correlation_id)?;
+        let stream = self.message_store.stream(StreamName::from_parts(
+            self.process_category.clone(),
+            Some(&id),
+        )?)?;
+
+        let mut cached = self.load_process(&stream, &id).await?;
+        let res = self
+            .handle_process_event(stream, &mut cached, event, &message, correlation, &id)
+            .await;
+        match res {
+            // A process which failed may have applied events which weren't written.
+            Ok(()) => self.cache_process(id.to_string(), cached).await,
+            Err(_) => self.drop_process(cached.process).await,
+        }
+
+        res
+    }
+
+    /// Returns a process from the cache, or rebuilds it from its stream if it
+    /// isn't cached or its stream changed.
+    async fn load_process(
+        &mut self,
+        stream: &Stream<'static>,
+        correlation_id: &ID<'_>,
+    ) -> Result<CachedProcess> {
+        let version = stream.version()?;
+        if let Some(cached) = self.processes.remove::<str>(correlation_id.as_ref()) {
+            if self.module.is_live(&cached.process) && cached.process.sequence() == version {
+                return Ok(cached);
+            }
+            self.drop_process(cached.process).await;
+        }
+
+        let mut process = self.module.init(correlation_id).await?;
+        match self.rehydrate(stream, &mut process).await {
+            Ok(last_handled_id) => Ok(CachedProcess {
+                process,
+                last_handled_id,
+                last_used: 0,
+            }),
+            Err(err) => {
+                self.drop_process(process).await;
+                Err(err)
+            }
+        }
+    }
+
+    /// Applies a process's stream, returning the global ID of the last event
+    /// it handled.
+    async fn rehydrate(
+        &mut self,
+        stream: &Stream<'static>,
+        process: &mut ProcessInstance,
+    ) -> Result<Option<u64>> {
+        let mut last_handled_id = stream.last_handled()?;
+        for res in stream.iter_all_messages::<()>() {
+            let raw_message = res?;
+            let message = raw_message.message()?;
+            // Processes written before handled events were recorded only have their causation.
+            if let Some(global_id) = message
+                .metadata
+                .get("causation_global_id")
+                .and_then(Value::as_u64)
+            {
+                last_handled_id = last_handled_id.max(Some(global_id));
+            }
+            let process_event = Event {
+                event: message.msg_type,
+                payload: Cow::Owned(serde_json::to_string(&message.data)?),
+            };
+            self.module
+                .apply(process, message.position, &process_event)
+                .await?;
+        }
+
+        Ok(last_handled_id)
+    }
+
+    /// Keeps a process for the next event, evicting the least recently used
+    /// process if the cache is full.
+    async fn cache_process(&mut self, correlation_id: String, mut cached: CachedProcess) {
+        if self.processes.len() >= PROCESS_CACHE_CAPACITY {
+            let evicted = self
+                .processes
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(correlation_id, _)| correlation_id.clone());
+            if let Some(evicted) = evicted.and_then(|id| self.processes.remove(&id)) {
+                self.drop_process(evicted.process).await;
+            }
+        }
+
+        self.uses += 1;
+        cached.last_used = self.uses;
+        self.processes.insert(correlation_id, cached);
+    }
+
+    async fn drop_process(&mut self, process: ProcessInstance) {
+        if let Err(err) = self.module.resource_drop(process).await {
+            warn!(name = %self.name, "failed to drop process: {err}");
+        }
+    }
+
+    /// Handles an event with a process, unless the process has already
+    /// handled it.
+    async fn handle_process_event(
+        &mut self,
+        mut stream: Stream<'static>,
+        cached: &mut CachedProcess,
+        event: &Message<'static>,
+        message: &ProcessMessage<'_>,
+        mut metadata: Map<String, Value>,
+        correlation_id: &ID<'_>,
+    ) -> Result<()> {
+        let process = &mut cached.process;
+        // The event may have been handled before it was acknowledged.
+        if cached
+            .last_handled_id
+            .is_some_and(|global_id| global_id >= event.global_id)
+        {
+            trace!(stream_name = %stream.stream_name(), global_id = event.global_id, "event already handled");
+            return Ok(());
+        }
+
+        metadata.insert(
+            "correlation_id".to_string(),
+            Value::String(correlation_id.to_string()),
+        );
+        let context = CommandContext {
+            timestamp: event
+                .time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            // Seeded by the event, so handling it again produces the same values.
+            seed: event.global_id,
+            metadata,
+        };
+        let handled = match self.module.handle(process, message, &context).await? {
+            Ok(handled) => handled,
+            Err(err) => {
+                warn!(
+                    stream_name = %stream.stream_name(),
+                    event = %event.msg_type,
+                    "process manager returned an error: {err}"
+                );
+                return Ok(());
+            }
+        };
+
+        let commands = handled
+            .commands
+            .into_iter()
+            .map(|outbound| {
+                let command = outbox_command(
+                    outbound,
+                    stream.stream_name(),
+                    context.timestamp,
+                    context.metadata.clone(),
+                )?;
+                Ok(OutboxCommand {
+                    causation_global_id: Some(event.global_id),
+                    ..command
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if handled.events.is_empty() && commands.is_empty() {
+            return Ok(());
+        }
+
+        let sequence = process.sequence();
+        for (i, process_event) in handled.events.iter().enumerate() {
+            let position = sequence.map(|v| v + 1 + i as u64).unwrap_or(i as u64);
+            self.module.apply(process, position, process_event).await?;
+        }
+
+        let messages: Vec<_> = handled
+            .events
+            .iter()
+            .map(|process_event| {
+                let payload = serde_json::from_str(&process_event.payload)?;
+                Ok((process_event.event.as_ref(), Cow::Owned(payload)))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let process_metadata = json!({
+            "causation_global_id": event.global_id,
+            "correlation": context.metadata,
+        });
+        let command_outbox: CommandOutbox = self.message_store.command_outbox()?;
+        // Recording the event as handled keeps commands from being sent again, even if the
+        // process wrote no events.
+        let (written_messages, _) = stream.write_messages_alongside(
+            &messages,
+            Cow::Owned(process_metadata),
+            sequence,
+            Alongside {
+                commands: Some((&command_outbox, &commands)),
+                handled: Some(event.global_id),
+                ..Default::default()
+            },
+        )?;
+        cached.last_handled_id = Some(event.global_id);
+
+        for message in &written_messages {
+            if let Err(err) = self
+                .broadcaster
+                .broadcast_event(message.clone().into_owned())
+                .await
+            {
+                error!("failed to broadcast event: {err}");
+            }
+        }
+
+        if !commands.is_empty() {
+            if let Err(err) = self.command_gateway.dispatch_pending_commands().await {
+                error!("failed to notify command dispatcher: {err}");
+            }
+        }
+
+        Ok(())
+    }
+}
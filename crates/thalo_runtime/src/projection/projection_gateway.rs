@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use anyhow::{Context, Result};
 use thalo::stream_name::Category;
@@ -8,7 +8,7 @@ use thalo_message_store::projection::Projection;
 use thalo_message_store::MessageStore;
 use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio::time::interval;
-use tracing::{error, warn};
+use tracing::{error, info, warn};
 
 use super::projection_subscription::ProjectionSubscriptionHandle;
 
@@ -51,18 +51,29 @@ impl ProjectionGatewayHandle {
         tx: mpsc::Sender<Message<'static>>,
         name: String,
         events: Vec<EventInterest<'static>>,
+        start_from: Option<SystemTime>,
     ) -> Result<()> {
         let (reply, recv) = oneshot::channel();
         let msg = ProjectionGatewayMsg::StartProjection {
             tx,
             name,
             events,
+            start_from,
             reply,
         };
         let _ = self.sender.send(msg).await;
         recv.await.context("no response from projection gateway")?
     }
 
+    /// Stops all projections, ending their event streams, and flushes their
+    /// positions.
+    pub async fn shutdown(&self) -> Result<()> {
+        let (reply, recv) = oneshot::channel();
+        let msg = ProjectionGatewayMsg::Shutdown { reply };
+        let _ = self.sender.send(msg).await;
+        recv.await.context("no response from projection gateway")?
+    }
+
     pub(crate) async fn set_subscription_to_process_new_events(&self, name: String) -> Result<()> {
         let (reply, recv) = oneshot::channel();
         let msg = ProjectionGatewayMsg::SetProjectionToProcessNewEvents { name, reply };
@@ -105,6 +116,7 @@ enum ProjectionGatewayMsg {
         tx: mpsc::Sender<Message<'static>>,
         name: String,
         events: Vec<EventInterest<'static>>,
+        start_from: Option<SystemTime>,
         reply: oneshot::Sender<Result<()>>,
     },
     StopProjection {
@@ -114,6 +126,9 @@ enum ProjectionGatewayMsg {
         name: String,
         reply: oneshot::Sender<()>,
     },
+    Shutdown {
+        reply: oneshot::Sender<Result<()>>,
+    },
 }
 
 async fn run_projection_gateway(
@@ -141,8 +156,8 @@ async fn run_projection_gateway(
                         let res = projection_gateway.acknowledge_event(name, global_id);
                         let _ = reply.send(res);
                     }
-                    ProjectionGatewayMsg::StartProjection { tx, name, events, reply } => {
-                        let res = projection_gateway.start_projection(tx, name, events);
+                    ProjectionGatewayMsg::StartProjection { tx, name, events, start_from, reply } => {
+                        let res = projection_gateway.start_projection(tx, name, events, start_from);
                         let _ = reply.send(res);
                     }
                     ProjectionGatewayMsg::StopProjection { name } => {
@@ -152,6 +167,13 @@ async fn run_projection_gateway(
                         let res = projection_gateway.set_projection_to_process_new_events(name);
                         let _ = reply.send(res);
                     }
+                    ProjectionGatewayMsg::Shutdown { reply } => {
+                        projection_gateway.projections.clear();
+                        let res = projection_gateway.flush().await;
+                        let _ = reply.send(res);
+                        info!("projection gateway stopped");
+                        return;
+                    }
                 }
                 None => break,
             },
@@ -216,8 +238,25 @@ impl ProjectionGateway {
         tx: mpsc::Sender<Message<'static>>,
         name: String,
         events: Vec<EventInterest<'static>>,
+        start_from: Option<SystemTime>,
     ) -> Result<()> {
         let projection = self.message_store.projection(name.clone())?;
+        let global_event_log = self.message_store.global_event_log()?;
+
+        // Starting from a timestamp only applies to projections which have no saved
+        // position.
+        let start_position = match start_from {
+            Some(time) if projection.last_seen_event_id().is_none() => {
+                match global_event_log.position_at(time)? {
+                    Some(position) => Some(position),
+                    None => global_event_log
+                        .last_position()?
+                        .map(|position| position + 1),
+                }
+            }
+            _ => None,
+        };
+
         let projection_subscription = ProjectionSubscriptionHandle::new(
             name.clone(),
             ProjectionGatewayHandle {
@@ -226,7 +265,8 @@ impl ProjectionGateway {
             events.clone(),
             tx,
             projection.last_relevant_event_id(),
-            self.message_store.global_event_log()?,
+            start_position,
+            global_event_log,
         );
 
         let subscription = Subscription {
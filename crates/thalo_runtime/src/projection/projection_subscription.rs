@@ -1,4 +1,3 @@
-use std::iter::Skip;
 use std::time::Duration;
 
 use anyhow::{bail, Context, Result};
@@ -23,6 +22,7 @@ impl ProjectionSubscriptionHandle {
         events: Vec<EventInterest<'static>>,
         tx: mpsc::Sender<Message<'static>>,
         last_acknowledged_id: Option<u64>,
+        start_position: Option<u64>,
         global_event_log: GlobalEventLog,
     ) -> Self {
         let (sender, receiver) = mpsc::channel(1024);
@@ -33,6 +33,7 @@ impl ProjectionSubscriptionHandle {
             events,
             tx,
             last_acknowledged_id,
+            start_position,
             global_event_log,
         ));
 
@@ -77,11 +78,13 @@ async fn run_projection_subscription(
     events: Vec<EventInterest<'static>>,
     tx: mpsc::Sender<Message<'static>>,
     last_acknowledged_id: Option<u64>,
+    start_position: Option<u64>,
     global_event_log: GlobalEventLog,
 ) -> Result<()> {
-    let iter = global_event_log.iter_all_messages().skip(
+    let iter = global_event_log.iter_from(
         last_acknowledged_id
-            .map(|global_id| (global_id as usize) + 1)
+            .map(|global_id| global_id + 1)
+            .or(start_position)
             .unwrap_or(0),
     );
 
@@ -138,7 +141,7 @@ struct ProjectionSubscription {
     last_processed_id: Option<u64>,
     pending_events: Vec<Message<'static>>,
     state: ProjectionSubscriptionState,
-    iter: Skip<GlobalEventLogIter>,
+    iter: GlobalEventLogIter,
 }
 
 impl ProjectionSubscription {
@@ -1,73 +1,464 @@
+//! Versioned aggregate modules.
+//!
+//! Modules are stored in the modules directory as `{name}@{version}.wasm`, or
+//! `{name}.wasm` for unversioned modules, which are ordered before any
+//! version. Only one version of each module is active: the version pinned in
+//! `{name}.active` if any, or the latest version otherwise.
+//!
+//! A canary version can be set in `{name}.canary`, which serves a percentage
+//! of the module's entities alongside the active version.
+
 use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fmt, fs, io};
 
+use anyhow::{anyhow, bail, Context, Result};
 use semver::{Version, VersionReq};
+use thalo::stream_name::Category;
+use tracing::warn;
 
-use crate::module::ModuleID;
+const MODULE_EXTENSION: &str = ".wasm";
+const ACTIVE_EXTENSION: &str = ".active";
+const CANARY_EXTENSION: &str = ".canary";
 
-#[derive(Default)]
+/// Characters which can't appear in a module name, as they separate the parts
+/// of module file names and stream names, or would escape the modules
+/// directory.
+const RESERVED_NAME_CHARS: [char; 4] = ['@', '/', '.', '-'];
+
+/// Returns whether a module can be published with the name.
+pub fn is_valid_module_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(RESERVED_NAME_CHARS)
+}
+
+/// A module name, with an optional version.
+///
+/// Formatted as `name@version`, or `name` if unversioned.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ModuleID {
+    pub name: Category<'static>,
+    pub version: Option<Version>,
+}
+
+impl ModuleID {
+    pub fn new(name: Category<'static>, version: Option<Version>) -> Self {
+        ModuleID { name, version }
+    }
+
+    /// Parses a module file name, such as `counter@1.2.0.wasm`.
+    pub fn from_file_name(file_name: &str) -> Option<Self> {
+        file_name.strip_suffix(MODULE_EXTENSION)?.parse().ok()
+    }
+
+    /// Returns the module's file name, such as `counter@1.2.0.wasm`.
+    pub fn file_name(&self) -> String {
+        format!("{self}{MODULE_EXTENSION}")
+    }
+}
+
+impl fmt::Display for ModuleID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{}@{version}", self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+impl FromStr for ModuleID {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, version) = match s.split_once('@') {
+            Some((name, version)) => (
+                name,
+                Some(
+                    version
+                        .parse()
+                        .with_context(|| format!("invalid module version '{version}'"))?,
+                ),
+            ),
+            None => (s, None),
+        };
+        if !is_valid_module_name(name) {
+            bail!("invalid module name '{name}'");
+        }
+        let name =
+            Category::new(name.to_string()).map_err(|_| anyhow!("invalid module name '{name}'"))?;
+
+        Ok(ModuleID { name, version })
+    }
+}
+
+/// Routes a percentage of a module's entities to a canary version.
+///
+/// Entities are routed by a stable hash of their ID, so each entity is always
+/// served by the same version while the routing is unchanged.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CanaryRouting {
+    pub version: Version,
+    /// Percentage of entities routed to the canary version, from 0 to 100.
+    pub percent: u8,
+}
+
+impl CanaryRouting {
+    pub fn new(version: Version, percent: u8) -> Result<Self> {
+        if percent > 100 {
+            bail!("canary percent must be between 0 and 100");
+        }
+
+        Ok(CanaryRouting { version, percent })
+    }
+
+    /// Returns whether an entity is routed to the canary version.
+    pub fn routes(&self, id: &str) -> bool {
+        let hash = blake3::hash(id.as_bytes());
+        let bucket = u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap()) % 100;
+        bucket < u64::from(self.percent)
+    }
+}
+
+impl fmt::Display for CanaryRouting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.version, self.percent)
+    }
+}
+
+impl FromStr for CanaryRouting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (version, percent) = s
+            .trim()
+            .split_once(' ')
+            .context("expected canary as '{version} {percent}'")?;
+
+        CanaryRouting::new(version.parse()?, percent.parse()?)
+    }
+}
+
+/// Versions of modules in the modules directory.
 pub struct Registry {
-    pub modules: HashMap<ModuleName, ModuleVersions>,
+    modules_path: PathBuf,
+    modules: HashMap<Category<'static>, ModuleVersions>,
 }
 
+/// Versions of a module, the pinned active version, and the canary version.
 #[derive(Default)]
-pub struct ModuleVersions(BTreeMap<Version, Vec<u8>>);
+pub struct ModuleVersions {
+    versions: BTreeMap<Option<Version>, PathBuf>,
+    pinned: Option<Version>,
+    canary: Option<CanaryRouting>,
+}
 
 impl Registry {
-    pub fn get_module(&self, name: &str, req: &VersionReq) -> Option<(&Version, &[u8])> {
+    /// Creates an empty registry for a modules directory.
+    pub fn new(modules_path: impl Into<PathBuf>) -> Self {
+        Registry {
+            modules_path: modules_path.into(),
+            modules: HashMap::new(),
+        }
+    }
+
+    /// Loads the modules and pinned versions in a modules directory.
+    pub fn load(modules_path: impl Into<PathBuf>) -> Result<Self> {
+        let mut registry = Registry::new(modules_path);
+
+        let mut pins = Vec::new();
+        let mut canaries = Vec::new();
+        for dir_entry in fs::read_dir(&registry.modules_path)? {
+            let dir_entry = dir_entry?;
+            if dir_entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let Ok(file_name) = dir_entry.file_name().into_string() else {
+                warn!("ignoring module with invalid file name");
+                continue;
+            };
+            if let Some(name) = file_name.strip_suffix(ACTIVE_EXTENSION) {
+                pins.push((name.to_string(), dir_entry.path()));
+                continue;
+            }
+            if let Some(name) = file_name.strip_suffix(CANARY_EXTENSION) {
+                canaries.push((name.to_string(), dir_entry.path()));
+                continue;
+            }
+            let Some(module_id) = ModuleID::from_file_name(&file_name) else {
+                warn!("ignoring module {file_name}");
+                continue;
+            };
+
+            registry.insert(module_id, dir_entry.path());
+        }
+
+        for (name, path) in pins {
+            let version = fs::read_to_string(&path)?;
+            let versions = Category::new(name)
+                .ok()
+                .and_then(|name| registry.modules.get_mut(&name));
+            match (versions, version.trim().parse::<Version>()) {
+                (Some(versions), Ok(version))
+                    if versions.versions.contains_key(&Some(version.clone())) =>
+                {
+                    versions.pinned = Some(version);
+                }
+                _ => warn!(path = %path.display(), "ignoring invalid pinned module version"),
+            }
+        }
+
+        for (name, path) in canaries {
+            let canary = fs::read_to_string(&path)?;
+            let versions = Category::new(name)
+                .ok()
+                .and_then(|name| registry.modules.get_mut(&name));
+            match (versions, canary.parse::<CanaryRouting>()) {
+                (Some(versions), Ok(canary)) if versions.get_version(&canary.version).is_some() => {
+                    versions.canary = Some(canary);
+                }
+                _ => warn!(path = %path.display(), "ignoring invalid canary module version"),
+            }
+        }
+
+        Ok(registry)
+    }
+
+    pub fn modules_path(&self) -> &Path {
+        &self.modules_path
+    }
+
+    /// Returns the names of all modules.
+    pub fn module_names(&self) -> impl Iterator<Item = &Category<'static>> {
+        self.modules.keys()
+    }
+
+    pub fn get_module(
+        &self,
+        name: &Category<'static>,
+        req: &VersionReq,
+    ) -> Option<(&Version, &Path)> {
         self.modules
             .get(name)
             .and_then(|versions| versions.get(req))
     }
 
-    pub fn get_module_latest(&self, name: &str) -> Option<(&Version, &[u8])> {
+    pub fn get_module_latest(&self, name: &Category<'static>) -> Option<(Option<&Version>, &Path)> {
         self.modules
             .get(name)
             .and_then(|versions| versions.get_latest())
     }
 
-    pub fn get_module_versions(&self, name: &str) -> Option<&ModuleVersions> {
+    pub fn get_module_versions(&self, name: &Category<'static>) -> Option<&ModuleVersions> {
         self.modules.get(name)
     }
 
-    pub fn add_module(&mut self, module_id: ModuleID, module: Vec<u8>) -> Option<Vec<u8>> {
-        let module_versions = self.modules.entry(module_id.name).or_default();
-        module_versions.insert(module_id.version, module)
+    /// Adds a module file, returning the path it replaced, if any.
+    pub fn insert(&mut self, module_id: ModuleID, path: PathBuf) -> Option<PathBuf> {
+        self.modules
+            .entry(module_id.name)
+            .or_default()
+            .insert(module_id.version, path)
+    }
+
+    /// Removes a module file, unpinning it if it was the pinned version.
+    pub fn remove(&mut self, module_id: &ModuleID) -> Result<Option<PathBuf>> {
+        let Some(versions) = self.modules.get_mut(&module_id.name) else {
+            return Ok(None);
+        };
+
+        let path = versions.versions.remove(&module_id.version);
+        let is_canary = versions
+            .canary
+            .as_ref()
+            .is_some_and(|canary| Some(&canary.version) == module_id.version.as_ref());
+        if module_id.version.is_some() && versions.pinned == module_id.version {
+            self.unpin(&module_id.name)?;
+        }
+        if is_canary {
+            self.set_canary(&module_id.name, None)?;
+        }
+
+        if self
+            .modules
+            .get(&module_id.name)
+            .is_some_and(|versions| versions.versions.is_empty())
+        {
+            self.modules.remove(&module_id.name);
+        }
+
+        Ok(path)
+    }
+
+    /// Pins the active version of a module.
+    ///
+    /// Activating the canary version promotes it, clearing the canary.
+    pub fn activate(&mut self, name: &Category<'static>, version: Version) -> Result<()> {
+        let active_path = self.active_path(name);
+        let canary_path = self.canary_path(name);
+        let versions = self
+            .modules
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("module '{name}' does not exist"))?;
+        if !versions.versions.contains_key(&Some(version.clone())) {
+            bail!("module '{name}@{version}' does not exist");
+        }
+
+        fs::write(active_path, version.to_string())?;
+        if versions
+            .canary
+            .as_ref()
+            .is_some_and(|canary| canary.version == version)
+        {
+            remove_file_if_exists(&canary_path)?;
+            versions.canary = None;
+        }
+        versions.pinned = Some(version);
+
+        Ok(())
+    }
+
+    /// Sets or clears the canary version of a module.
+    pub fn set_canary(
+        &mut self,
+        name: &Category<'static>,
+        canary: Option<CanaryRouting>,
+    ) -> Result<()> {
+        let canary_path = self.canary_path(name);
+        let versions = self
+            .modules
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("module '{name}' does not exist"))?;
+
+        match canary {
+            Some(canary) => {
+                if versions.get_version(&canary.version).is_none() {
+                    bail!("module '{name}@{}' does not exist", canary.version);
+                }
+                if versions.active().and_then(|(version, _)| version) == Some(&canary.version) {
+                    bail!("module '{name}@{}' is already active", canary.version);
+                }
+
+                fs::write(canary_path, canary.to_string())?;
+                versions.canary = Some(canary);
+            }
+            None => {
+                remove_file_if_exists(&canary_path)?;
+                versions.canary = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pins the version before the active version of a module, returning the
+    /// version rolled back to.
+    pub fn rollback(&mut self, name: &Category<'static>) -> Result<Version> {
+        let versions = self
+            .modules
+            .get(name)
+            .ok_or_else(|| anyhow!("module '{name}' does not exist"))?;
+        let (active, _) = versions
+            .active()
+            .ok_or_else(|| anyhow!("module '{name}' has no active version"))?;
+        let previous = versions
+            .versions
+            .range(..active.cloned())
+            .rev()
+            .find_map(|(version, _)| version.clone())
+            .ok_or_else(|| anyhow!("module '{name}' has no previous version to roll back to"))?;
+
+        self.activate(name, previous.clone())?;
+
+        Ok(previous)
+    }
+
+    /// Unpins the active version of a module, making the latest version
+    /// active.
+    pub fn unpin(&mut self, name: &Category<'static>) -> Result<()> {
+        if let Some(versions) = self.modules.get_mut(name) {
+            versions.pinned = None;
+        }
+
+        remove_file_if_exists(&self.active_path(name))
+    }
+
+    fn active_path(&self, name: &Category<'_>) -> PathBuf {
+        self.modules_path.join(format!("{name}{ACTIVE_EXTENSION}"))
+    }
+
+    fn canary_path(&self, name: &Category<'_>) -> PathBuf {
+        self.modules_path.join(format!("{name}{CANARY_EXTENSION}"))
     }
 }
 
 impl ModuleVersions {
-    pub fn get(&self, req: &VersionReq) -> Option<(&Version, &[u8])> {
-        self.0
-            .iter()
-            .rev()
-            .find(|(version, _)| req.matches(version))
-            .map(|(version, module)| (version, module.as_slice()))
+    /// Returns the active version, which is the pinned version if any, or the
+    /// latest version otherwise.
+    pub fn active(&self) -> Option<(Option<&Version>, &Path)> {
+        match &self.pinned {
+            Some(pinned) => self
+                .versions
+                .get_key_value(&Some(pinned.clone()))
+                .map(|(version, path)| (version.as_ref(), path.as_path())),
+            None => self.get_latest(),
+        }
+    }
+
+    /// Returns the pinned version, if any.
+    pub fn pinned(&self) -> Option<&Version> {
+        self.pinned.as_ref()
+    }
+
+    /// Returns the canary routing and module path, if any.
+    pub fn canary(&self) -> Option<(&CanaryRouting, &Path)> {
+        let canary = self.canary.as_ref()?;
+        Some((canary, self.get_version(&canary.version)?))
+    }
+
+    pub fn get_version(&self, version: &Version) -> Option<&Path> {
+        self.versions
+            .get(&Some(version.clone()))
+            .map(PathBuf::as_path)
+    }
+
+    pub fn get(&self, req: &VersionReq) -> Option<(&Version, &Path)> {
+        self.versions.iter().rev().find_map(|(version, path)| {
+            version
+                .as_ref()
+                .filter(|version| req.matches(version))
+                .map(|version| (version, path.as_path()))
+        })
     }
 
-    pub fn get_latest(&self) -> Option<(&Version, &[u8])> {
-        self.0
+    pub fn get_latest(&self) -> Option<(Option<&Version>, &Path)> {
+        self.versions
             .iter()
             .last()
-            .map(|(version, module)| (version, module.as_slice()))
-    }
-
-    pub fn get_all(&self, req: Option<&VersionReq>) -> Vec<(&Version, &[u8])> {
-        match req {
-            Some(req) => self
-                .0
-                .iter()
-                .filter(|(version, _)| req.matches(version))
-                .map(|(version, module)| (version, module.as_slice()))
-                .collect(),
-            None => self
-                .0
-                .iter()
-                .map(|(version, module)| (version, module.as_slice()))
-                .collect(),
-        }
+            .map(|(version, path)| (version.as_ref(), path.as_path()))
+    }
+
+    pub fn get_all(&self, req: Option<&VersionReq>) -> Vec<(Option<&Version>, &Path)> {
+        self.versions
+            .iter()
+            .filter(|(version, _)| match (req, version) {
+                (Some(req), Some(version)) => req.matches(version),
+                (Some(_), None) => false,
+                (None, _) => true,
+            })
+            .map(|(version, path)| (version.as_ref(), path.as_path()))
+            .collect()
     }
 
-    pub fn insert(&mut self, version: Version, module: Vec<u8>) -> Option<Vec<u8>> {
-        self.0.insert(version, module)
+    pub fn insert(&mut self, version: Option<Version>, path: PathBuf) -> Option<PathBuf> {
+        self.versions.insert(version, path)
+    }
+}
+
+fn remove_file_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
     }
 }
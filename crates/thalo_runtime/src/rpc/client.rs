@@ -1,13 +1,17 @@
 use std::convert::Into;
 use std::mem;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use async_trait::async_trait;
 use proto::Acknowledgement;
+use semver::Version;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use thalo::stream_name::{Category, ID};
+use thalo::stream_name::{Category, StreamName, ID};
 use thalo::{Aggregate, Handle};
+use thalo_message_store::hash_chain::{Checkpoint, VerifyingKey};
 use thalo_message_store::message::Message;
+use tokio_stream::Stream;
 use tonic::codegen::*;
 use tonic::{Request, Status};
 
@@ -15,6 +19,11 @@ pub use super::proto::command_center_client::*;
 pub use super::proto::projection_client::*;
 use super::{proto, EventInterest, SubscriptionRequest};
 use crate::projection::Projection;
+use crate::registry::CanaryRouting;
+use crate::{CommandContextOverrides, ExpectedVersion, HistoricalState, StateAt, Verification};
+
+/// Result of a command executed in a batch.
+pub type ExecuteResult = Result<Result<Vec<Message<'static>>, serde_json::Value>, Status>;
 
 #[async_trait]
 pub trait CommandCenterClientExt {
@@ -24,14 +33,131 @@ pub trait CommandCenterClientExt {
         id: ID<'static>,
         cmd: String,
         payload: &serde_json::Value,
-    ) -> Result<Result<Vec<Message>, serde_json::Value>, Status>;
+    ) -> Result<Result<Vec<Message<'static>>, serde_json::Value>, Status> {
+        self.execute_anonymous_command_expecting(name, id, cmd, payload, ExpectedVersion::Any)
+            .await
+    }
+
+    /// Executes a command only if the entity's stream is at the expected
+    /// version.
+    ///
+    /// A mismatch is returned as a `FAILED_PRECONDITION` status, which can be
+    /// inspected with [`WrongExpectedVersion::from_status`](crate::WrongExpectedVersion::from_status).
+    async fn execute_anonymous_command_expecting(
+        &mut self,
+        name: Category<'static>,
+        id: ID<'static>,
+        cmd: String,
+        payload: &serde_json::Value,
+        expected_version: ExpectedVersion,
+    ) -> Result<Result<Vec<Message<'static>>, serde_json::Value>, Status> {
+        self.execute_anonymous_command_with_context(
+            name,
+            id,
+            cmd,
+            payload,
+            expected_version,
+            CommandContextOverrides::default(),
+        )
+        .await
+    }
+
+    /// Executes a command with correlation metadata, and optionally the
+    /// timestamp and seed the aggregate handles it with.
+    ///
+    /// A command recorded in the command log can be replayed with the same
+    /// context by converting its [`CommandContext`](crate::CommandContext)
+    /// into [`CommandContextOverrides`].
+    async fn execute_anonymous_command_with_context(
+        &mut self,
+        name: Category<'static>,
+        id: ID<'static>,
+        cmd: String,
+        payload: &serde_json::Value,
+        expected_version: ExpectedVersion,
+        context: CommandContextOverrides,
+    ) -> Result<Result<Vec<Message<'static>>, serde_json::Value>, Status>;
+
+    /// Executes many commands in a single request, concurrently across
+    /// entities and in the order they're given for each entity.
+    ///
+    /// Returns the result of each command in the order they were given, as
+    /// [`CommandCenterClientExt::execute_anonymous_command_with_context`]
+    /// would return it, so a command failing with a status such as a
+    /// [`WrongExpectedVersion`](crate::WrongExpectedVersion) doesn't fail the
+    /// batch. Requests can be created with
+    /// [`ExecuteCommand::new`](proto::ExecuteCommand::new).
+    async fn batch_execute(
+        &mut self,
+        commands: Vec<proto::ExecuteCommand>,
+    ) -> Result<Vec<ExecuteResult>, Status>;
+
+    /// Executes a stream of commands like
+    /// [`CommandCenterClientExt::batch_execute`], with each command starting
+    /// as soon as the runtime receives it.
+    ///
+    /// Results are returned once the stream ends.
+    async fn execute_stream<S>(&mut self, commands: S) -> Result<Vec<ExecuteResult>, Status>
+    where
+        S: Stream<Item = proto::ExecuteCommand> + Send + 'static;
 
     async fn execute<A, C>(
         &mut self,
         name: Category<'static>,
         id: ID<'static>,
         cmd: C,
-    ) -> Result<Result<Vec<Message<A::Event>>, <A as Handle<C>>::Error>, Status>
+    ) -> Result<Result<Vec<Message<'static, A::Event>>, <A as Handle<C>>::Error>, Status>
+    where
+        A: Aggregate,
+        A::Command: Serialize,
+        A: Handle<C>,
+        <A as Handle<C>>::Error: DeserializeOwned,
+        C: Into<A::Command> + Send,
+    {
+        Self::execute_expecting::<A, C>(self, name, id, cmd, ExpectedVersion::Any).await
+    }
+
+    /// Executes a command only if the entity's stream is at the expected
+    /// version.
+    ///
+    /// See [`CommandCenterClientExt::execute_anonymous_command_expecting`].
+    async fn execute_expecting<A, C>(
+        &mut self,
+        name: Category<'static>,
+        id: ID<'static>,
+        cmd: C,
+        expected_version: ExpectedVersion,
+    ) -> Result<Result<Vec<Message<'static, A::Event>>, <A as Handle<C>>::Error>, Status>
+    where
+        A: Aggregate,
+        A::Command: Serialize,
+        A: Handle<C>,
+        <A as Handle<C>>::Error: DeserializeOwned,
+        C: Into<A::Command> + Send,
+    {
+        Self::execute_with_context::<A, C>(
+            self,
+            name,
+            id,
+            cmd,
+            expected_version,
+            CommandContextOverrides::default(),
+        )
+        .await
+    }
+
+    /// Executes a command with correlation metadata, and optionally the
+    /// timestamp and seed the aggregate handles it with.
+    ///
+    /// See [`CommandCenterClientExt::execute_anonymous_command_with_context`].
+    async fn execute_with_context<A, C>(
+        &mut self,
+        name: Category<'static>,
+        id: ID<'static>,
+        cmd: C,
+        expected_version: ExpectedVersion,
+        context: CommandContextOverrides,
+    ) -> Result<Result<Vec<Message<'static, A::Event>>, <A as Handle<C>>::Error>, Status>
     where
         A: Aggregate,
         A::Command: Serialize,
@@ -45,7 +171,17 @@ pub trait CommandCenterClientExt {
         })?;
         let (cmd, payload) = thalo::__macro_helpers::extract_event_name_payload(cmd_value)
             .map_err(|err| Status::invalid_argument(err.to_string()))?;
-        match Self::execute_anonymous_command(self, name, id, cmd, &payload).await? {
+        match Self::execute_anonymous_command_with_context(
+            self,
+            name,
+            id,
+            cmd,
+            &payload,
+            expected_version,
+            context,
+        )
+        .await?
+        {
             Ok(messages) => Ok(Ok(unsafe { mem::transmute(messages) })),
             Err(err) => {
                 let err = serde_json::from_value(err).map_err(|err| {
@@ -58,7 +194,136 @@ pub trait CommandCenterClientExt {
         }
     }
 
-    async fn publish(&mut self, name: Category<'static>, module: Vec<u8>) -> Result<(), Status>;
+    /// Returns the current state of an entity.
+    ///
+    /// Returns an `UNIMPLEMENTED` status if the aggregate's state is not
+    /// serializable.
+    async fn get_state(
+        &mut self,
+        name: Category<'static>,
+        id: ID<'static>,
+    ) -> Result<serde_json::Value, Status>;
+
+    async fn get_state_at(
+        &mut self,
+        name: Category<'static>,
+        id: ID<'static>,
+        at: StateAt,
+    ) -> Result<HistoricalState, Status>;
+
+    async fn publish(&mut self, name: Category<'static>, module: Vec<u8>) -> Result<(), Status> {
+        self.publish_version(name, None, module).await
+    }
+
+    /// Publishes a module, activating it if it's versioned.
+    async fn publish_version(
+        &mut self,
+        name: Category<'static>,
+        version: Option<Version>,
+        module: Vec<u8>,
+    ) -> Result<(), Status>;
+
+    /// Publishes a module after replaying its category through both the new
+    /// and active module, failing if they replay differently unless forced.
+    async fn publish_verified(
+        &mut self,
+        name: Category<'static>,
+        version: Option<Version>,
+        module: Vec<u8>,
+        verification: Verification,
+    ) -> Result<proto::VerificationReport, Status>;
+
+    /// Pins the active version of a module.
+    async fn activate_module(
+        &mut self,
+        name: Category<'static>,
+        version: Version,
+    ) -> Result<(), Status>;
+
+    /// Activates the version before the active version of a module, returning
+    /// the version rolled back to.
+    async fn rollback_module(&mut self, name: Category<'static>) -> Result<Version, Status>;
+
+    /// Routes a percentage of a module's entities to a canary version, or
+    /// routes every entity to the active version if `canary` is `None`.
+    async fn set_canary(
+        &mut self,
+        name: Category<'static>,
+        canary: Option<CanaryRouting>,
+    ) -> Result<(), Status>;
+
+    /// Returns the number of commands routed to, and the entity cache of,
+    /// each running version of a module.
+    async fn module_stats(&mut self, name: Category<'static>)
+        -> Result<proto::ModuleStats, Status>;
+
+    /// Releases an entity quarantined after trapping, returning whether it was
+    /// quarantined.
+    async fn unquarantine(
+        &mut self,
+        name: Category<'static>,
+        id: ID<'static>,
+    ) -> Result<bool, Status>;
+
+    /// Schedules a command to be executed at `due`, replacing any pending
+    /// command scheduled through the API with the same key.
+    async fn schedule_anonymous_command(
+        &mut self,
+        key: String,
+        due: SystemTime,
+        name: Category<'static>,
+        id: ID<'static>,
+        cmd: String,
+        payload: &serde_json::Value,
+    ) -> Result<(), Status>;
+
+    /// Schedules a command to be executed at `due`.
+    ///
+    /// See [`CommandCenterClientExt::schedule_anonymous_command`].
+    async fn schedule_command<A, C>(
+        &mut self,
+        key: String,
+        due: SystemTime,
+        name: Category<'static>,
+        id: ID<'static>,
+        cmd: C,
+    ) -> Result<(), Status>
+    where
+        A: Aggregate,
+        A::Command: Serialize,
+        C: Into<A::Command> + Send,
+    {
+        let cmd: A::Command = cmd.into();
+        let cmd_value = serde_json::to_value(cmd).map_err(|err| {
+            Status::invalid_argument(format!("failed to serialize command: {err}"))
+        })?;
+        let (cmd, payload) = thalo::__macro_helpers::extract_event_name_payload(cmd_value)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        Self::schedule_anonymous_command(self, key, due, name, id, cmd, &payload).await
+    }
+
+    /// Cancels a command scheduled by the entity `scheduled_by`, or through the
+    /// API if `None`, returning whether it was pending.
+    async fn cancel_scheduled_command(
+        &mut self,
+        scheduled_by: Option<StreamName<'static>>,
+        key: String,
+    ) -> Result<bool, Status>;
+
+    /// Returns the pending scheduled commands, in the order they're due.
+    async fn scheduled_commands(&mut self) -> Result<Vec<proto::ScheduledCommand>, Status>;
+
+    /// Signs a checkpoint of the latest hash in the global event log's hash
+    /// chain, returning `None` if the chain is empty.
+    async fn export_checkpoint(&mut self) -> Result<Option<Checkpoint>, Status>;
+
+    /// Verifies the hash chain against a checkpoint signed by `verifying_key`,
+    /// returning the reason verification failed if the chain is invalid.
+    async fn verify_chain(
+        &mut self,
+        checkpoint: Checkpoint,
+        verifying_key: &VerifyingKey,
+    ) -> Result<Result<(), String>, Status>;
 }
 
 #[async_trait]
@@ -70,43 +335,110 @@ where
     T::ResponseBody: Body<Data = tonic::codegen::Bytes> + Send + 'static,
     <T::ResponseBody as Body>::Error: Into<StdError> + Send,
 {
-    async fn execute_anonymous_command(
+    async fn execute_anonymous_command_with_context(
         &mut self,
         name: Category<'static>,
         id: ID<'static>,
         cmd: String,
         payload: &serde_json::Value,
-    ) -> Result<Result<Vec<Message>, serde_json::Value>, Status> {
-        let payload = serde_json::to_string(&payload).map_err(|err| {
-            Status::invalid_argument(format!("failed to serialize payload: {err}"))
-        })?;
+        expected_version: ExpectedVersion,
+        context: CommandContextOverrides,
+    ) -> Result<Result<Vec<Message<'static>>, serde_json::Value>, Status> {
+        let req = proto::ExecuteCommand::new(name, id, cmd, payload, expected_version, context)
+            .map_err(|err| {
+                Status::invalid_argument(format!("failed to serialize command: {err}"))
+            })?;
+        let resp = CommandCenterClient::execute(self, Request::new(req))
+            .await?
+            .into_inner();
+        execute_response(resp).map_err(|err| Status::internal(err.to_string()))
+    }
 
-        let req = Request::new(proto::ExecuteCommand {
+    async fn batch_execute(
+        &mut self,
+        commands: Vec<proto::ExecuteCommand>,
+    ) -> Result<Vec<ExecuteResult>, Status> {
+        let req = Request::new(proto::BatchExecuteRequest { commands });
+        let resp = CommandCenterClient::batch_execute(self, req)
+            .await?
+            .into_inner();
+        Ok(batch_execute_results(resp))
+    }
+
+    async fn execute_stream<S>(&mut self, commands: S) -> Result<Vec<ExecuteResult>, Status>
+    where
+        S: Stream<Item = proto::ExecuteCommand> + Send + 'static,
+    {
+        let resp = CommandCenterClient::execute_stream(self, Request::new(commands))
+            .await?
+            .into_inner();
+        Ok(batch_execute_results(resp))
+    }
+
+    async fn get_state(
+        &mut self,
+        name: Category<'static>,
+        id: ID<'static>,
+    ) -> Result<serde_json::Value, Status> {
+        let req = Request::new(proto::GetStateRequest {
             name: name.into_string(),
             id: id.into_string(),
-            command: cmd,
-            payload,
         });
-        let resp = CommandCenterClient::execute(self, req).await?.into_inner();
-        if resp.success {
-            let events = resp
-                .events
-                .into_iter()
-                .map(Message::try_from)
-                .collect::<Result<_, _>>()
-                .map_err(|err| Status::internal(err.to_string()))?;
-            Ok(Ok(events))
-        } else {
-            let err = serde_json::from_str(&resp.message)
-                .map_err(|err| Status::internal(format!("failed to deserialize error: {err}")))?;
-            Ok(Err(err))
-        }
+        let resp = CommandCenterClient::get_state(self, req)
+            .await?
+            .into_inner();
+        serde_json::from_str(&resp.state)
+            .map_err(|err| Status::internal(format!("failed to deserialize state: {err}")))
     }
 
-    async fn publish(&mut self, name: Category<'static>, module: Vec<u8>) -> Result<(), Status> {
+    async fn get_state_at(
+        &mut self,
+        name: Category<'static>,
+        id: ID<'static>,
+        at: StateAt,
+    ) -> Result<HistoricalState, Status> {
+        let at = match at {
+            StateAt::Position(position) => proto::get_state_at_request::At::Position(position),
+            StateAt::Time(time) => {
+                let time = time
+                    .duration_since(UNIX_EPOCH)
+                    .map_err(|_| Status::invalid_argument("time is before unix epoch"))?;
+                proto::get_state_at_request::At::Time(time.as_millis() as u64)
+            }
+        };
+        let req = Request::new(proto::GetStateAtRequest {
+            name: name.into_string(),
+            id: id.into_string(),
+            at: Some(at),
+        });
+        let resp = CommandCenterClient::get_state_at(self, req)
+            .await?
+            .into_inner();
+        let state = serde_json::from_str(&resp.state)
+            .map_err(|err| Status::internal(format!("failed to deserialize state: {err}")))?;
+        let last_event = resp
+            .last_event
+            .map(Message::try_from)
+            .transpose()
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(HistoricalState {
+            state: Some(state),
+            last_event,
+        })
+    }
+
+    async fn publish_version(
+        &mut self,
+        name: Category<'static>,
+        version: Option<Version>,
+        module: Vec<u8>,
+    ) -> Result<(), Status> {
         let req = Request::new(proto::PublishModule {
             name: name.into_string(),
             module,
+            version: version.map(|version| version.to_string()),
+            verification: None,
         });
         let resp = CommandCenterClient::publish(self, req).await?.into_inner();
         if resp.success {
@@ -115,6 +447,211 @@ where
             Err(Status::internal(resp.message))
         }
     }
+
+    async fn publish_verified(
+        &mut self,
+        name: Category<'static>,
+        version: Option<Version>,
+        module: Vec<u8>,
+        verification: Verification,
+    ) -> Result<proto::VerificationReport, Status> {
+        let req = Request::new(proto::PublishModule {
+            name: name.into_string(),
+            module,
+            version: version.map(|version| version.to_string()),
+            verification: Some(verification.into()),
+        });
+        let resp = CommandCenterClient::publish(self, req).await?.into_inner();
+        match (resp.success, resp.report) {
+            (true, Some(report)) => Ok(report),
+            (true, None) => Err(Status::internal("missing verification report")),
+            (false, Some(_)) => Err(Status::failed_precondition(resp.message)),
+            (false, None) => Err(Status::internal(resp.message)),
+        }
+    }
+
+    async fn activate_module(
+        &mut self,
+        name: Category<'static>,
+        version: Version,
+    ) -> Result<(), Status> {
+        let req = Request::new(proto::ActivateModuleRequest {
+            name: name.into_string(),
+            version: version.to_string(),
+        });
+        CommandCenterClient::activate_module(self, req).await?;
+
+        Ok(())
+    }
+
+    async fn rollback_module(&mut self, name: Category<'static>) -> Result<Version, Status> {
+        let req = Request::new(proto::RollbackModuleRequest {
+            name: name.into_string(),
+        });
+        let resp = CommandCenterClient::rollback_module(self, req)
+            .await?
+            .into_inner();
+
+        resp.version
+            .parse()
+            .map_err(|err| Status::internal(format!("invalid version: {err}")))
+    }
+    async fn set_canary(
+        &mut self,
+        name: Category<'static>,
+        canary: Option<CanaryRouting>,
+    ) -> Result<(), Status> {
+        let req = Request::new(proto::SetCanaryRequest {
+            name: name.into_string(),
+            canary: canary.map(proto::CanaryRouting::from),
+        });
+        CommandCenterClient::set_canary(self, req).await?;
+
+        Ok(())
+    }
+
+    async fn module_stats(
+        &mut self,
+        name: Category<'static>,
+    ) -> Result<proto::ModuleStats, Status> {
+        let req = Request::new(proto::GetModuleStatsRequest {
+            name: name.into_string(),
+        });
+        let resp = CommandCenterClient::get_module_stats(self, req).await?;
+
+        Ok(resp.into_inner())
+    }
+
+    async fn unquarantine(
+        &mut self,
+        name: Category<'static>,
+        id: ID<'static>,
+    ) -> Result<bool, Status> {
+        let req = Request::new(proto::UnquarantineRequest {
+            name: name.into_string(),
+            id: id.into_string(),
+        });
+        let resp = CommandCenterClient::unquarantine(self, req).await?;
+
+        Ok(resp.into_inner().released)
+    }
+
+    async fn schedule_anonymous_command(
+        &mut self,
+        key: String,
+        due: SystemTime,
+        name: Category<'static>,
+        id: ID<'static>,
+        cmd: String,
+        payload: &serde_json::Value,
+    ) -> Result<(), Status> {
+        let req = Request::new(proto::ScheduleCommandRequest {
+            key,
+            due: due
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_millis() as u64)
+                .unwrap_or(0),
+            name: name.into_string(),
+            id: id.into_string(),
+            command: cmd,
+            payload: serde_json::to_string(payload).map_err(|err| {
+                Status::invalid_argument(format!("failed to serialize payload: {err}"))
+            })?,
+            metadata: String::new(),
+            seed: None,
+        });
+        CommandCenterClient::schedule_command(self, req).await?;
+
+        Ok(())
+    }
+
+    async fn cancel_scheduled_command(
+        &mut self,
+        scheduled_by: Option<StreamName<'static>>,
+        key: String,
+    ) -> Result<bool, Status> {
+        let req = Request::new(proto::CancelScheduledCommandRequest {
+            key,
+            scheduled_by: scheduled_by
+                .map(StreamName::into_string)
+                .unwrap_or_default(),
+        });
+        let resp = CommandCenterClient::cancel_scheduled_command(self, req).await?;
+
+        Ok(resp.into_inner().cancelled)
+    }
+
+    async fn scheduled_commands(&mut self) -> Result<Vec<proto::ScheduledCommand>, Status> {
+        let req = Request::new(proto::ListScheduledCommandsRequest {});
+        let resp = CommandCenterClient::list_scheduled_commands(self, req).await?;
+
+        Ok(resp.into_inner().commands)
+    }
+
+    async fn export_checkpoint(&mut self) -> Result<Option<Checkpoint>, Status> {
+        let req = Request::new(proto::ExportCheckpointRequest {});
+        let resp = CommandCenterClient::export_checkpoint(self, req).await?;
+
+        resp.into_inner()
+            .checkpoint
+            .map(Checkpoint::try_from)
+            .transpose()
+            .map_err(|err| Status::internal(err.to_string()))
+    }
+
+    async fn verify_chain(
+        &mut self,
+        checkpoint: Checkpoint,
+        verifying_key: &VerifyingKey,
+    ) -> Result<Result<(), String>, Status> {
+        let req = Request::new(proto::VerifyChainRequest {
+            checkpoint: Some(checkpoint.into()),
+            public_key: verifying_key.to_bytes().to_vec(),
+        });
+        let resp = CommandCenterClient::verify_chain(self, req)
+            .await?
+            .into_inner();
+
+        if resp.valid {
+            Ok(Ok(()))
+        } else {
+            Ok(Err(resp.error))
+        }
+    }
+}
+
+/// Returns the events of an executed command, or the error returned by the
+/// aggregate.
+fn execute_response(
+    resp: proto::ExecuteResponse,
+) -> Result<Result<Vec<Message<'static>>, serde_json::Value>, proto::TryFromMessageError> {
+    if resp.success {
+        let events = resp
+            .events
+            .into_iter()
+            .map(Message::try_from)
+            .collect::<Result<_, _>>()?;
+        Ok(Ok(events))
+    } else {
+        Ok(Err(serde_json::from_str(&resp.message)?))
+    }
+}
+
+/// Returns the result of each command of a batch.
+fn batch_execute_results(resp: proto::BatchExecuteResponse) -> Vec<ExecuteResult> {
+    let mut results = Vec::with_capacity(resp.results.len());
+    for result in resp.results {
+        let result = match result.result {
+            Some(proto::execute_result::Result::Response(resp)) => {
+                execute_response(resp).map_err(|err| Status::internal(err.to_string()))
+            }
+            Some(proto::execute_result::Result::Status(status)) => Err(status.into()),
+            None => Err(Status::internal("missing command result")),
+        };
+        results.push(result);
+    }
+
+    results
 }
 
 #[async_trait]
@@ -127,6 +664,18 @@ pub trait ProjectionClientExt {
     ) -> anyhow::Result<()>
     where
         P: Projection + Send;
+
+    /// Starts a projection, beginning with events written at or after
+    /// `start_from` if the projection has no saved position.
+    async fn start_projection_from<P>(
+        &mut self,
+        name: &str,
+        projection: P,
+        events: Vec<EventInterest>,
+        start_from: Option<SystemTime>,
+    ) -> anyhow::Result<()>
+    where
+        P: Projection + Send;
 }
 
 #[async_trait]
@@ -139,18 +688,38 @@ where
     <T::ResponseBody as Body>::Error: Into<StdError> + Send,
 {
     async fn start_projection<P>(
+        &mut self,
+        name: &str,
+        projection: P,
+        events: Vec<EventInterest>,
+    ) -> anyhow::Result<()>
+    where
+        P: Projection + Send,
+    {
+        self.start_projection_from(name, projection, events, None)
+            .await
+    }
+
+    async fn start_projection_from<P>(
         &mut self,
         name: &str,
         mut projection: P,
         events: Vec<EventInterest>,
+        start_from: Option<SystemTime>,
     ) -> anyhow::Result<()>
     where
         P: Projection + Send,
     {
+        let start_from = start_from.map(|time| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64
+        });
         let mut streaming = self
             .subscribe_to_events(SubscriptionRequest {
                 name: name.to_string(),
                 events,
+                start_from,
             })
             .await?
             .into_inner();
@@ -2,8 +2,10 @@ use std::borrow::Cow;
 use std::marker::PhantomData;
 use std::time::{Duration, UNIX_EPOCH};
 
+use prost::Message as _;
 use thalo::stream_name::{Category, EmptyStreamName, StreamName};
 use thiserror::Error;
+use tonic::{Code, Status};
 
 tonic::include_proto!("thalo");
 
@@ -25,6 +27,11 @@ impl<T> TryFrom<thalo_message_store::message::Message<'static, T>> for Message {
                 .duration_since(UNIX_EPOCH)
                 .unwrap_or_default()
                 .as_millis() as u64,
+            metadata: if msg.metadata.is_null() {
+                String::new()
+            } else {
+                serde_json::to_string(&msg.metadata)?
+            },
         })
     }
 }
@@ -50,6 +57,11 @@ impl<T> TryFrom<Message> for thalo_message_store::message::Message<'static, T> {
             msg_type: Cow::Owned(msg.msg_type),
             data: serde_json::from_str(&msg.data)?,
             time: UNIX_EPOCH + Duration::from_millis(msg.time),
+            metadata: if msg.metadata.is_empty() {
+                Cow::Owned(serde_json::Value::Null)
+            } else {
+                serde_json::from_str(&msg.metadata)?
+            },
             _marker: PhantomData,
         })
     }
@@ -70,3 +82,291 @@ impl TryFrom<EventInterest> for crate::projection::EventInterest<'static> {
         })
     }
 }
+
+impl From<crate::ExpectedVersion> for ExpectedVersion {
+    fn from(expected_version: crate::ExpectedVersion) -> Self {
+        let version = match expected_version {
+            crate::ExpectedVersion::Any => {
+                expected_version::Version::State(ExpectedStreamState::Any.into())
+            }
+            crate::ExpectedVersion::NoStream => {
+                expected_version::Version::State(ExpectedStreamState::NoStream.into())
+            }
+            crate::ExpectedVersion::StreamExists => {
+                expected_version::Version::State(ExpectedStreamState::StreamExists.into())
+            }
+            crate::ExpectedVersion::Exact(version) => expected_version::Version::Exact(version),
+        };
+        ExpectedVersion {
+            version: Some(version),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("invalid expected version")]
+pub struct InvalidExpectedVersion;
+
+impl TryFrom<ExpectedVersion> for crate::ExpectedVersion {
+    type Error = InvalidExpectedVersion;
+
+    fn try_from(expected_version: ExpectedVersion) -> Result<Self, Self::Error> {
+        match expected_version.version {
+            None => Ok(crate::ExpectedVersion::Any),
+            Some(expected_version::Version::State(state)) => {
+                match ExpectedStreamState::try_from(state).map_err(|_| InvalidExpectedVersion)? {
+                    ExpectedStreamState::Any => Ok(crate::ExpectedVersion::Any),
+                    ExpectedStreamState::NoStream => Ok(crate::ExpectedVersion::NoStream),
+                    ExpectedStreamState::StreamExists => Ok(crate::ExpectedVersion::StreamExists),
+                }
+            }
+            Some(expected_version::Version::Exact(version)) => {
+                Ok(crate::ExpectedVersion::Exact(version))
+            }
+        }
+    }
+}
+
+impl From<Verification> for crate::Verification {
+    fn from(verification: Verification) -> Self {
+        crate::Verification {
+            sample: verification.sample.map(|sample| sample as usize),
+            force: verification.force,
+        }
+    }
+}
+
+impl From<crate::Verification> for Verification {
+    fn from(verification: crate::Verification) -> Self {
+        Verification {
+            sample: verification.sample.map(|sample| sample as u64),
+            force: verification.force,
+        }
+    }
+}
+
+impl From<crate::VerificationReport> for VerificationReport {
+    fn from(report: crate::VerificationReport) -> Self {
+        VerificationReport {
+            streams: report.streams as u64,
+            failures: report
+                .failures
+                .iter()
+                .map(|failure| failure.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<CanaryRouting> for crate::registry::CanaryRouting {
+    type Error = anyhow::Error;
+
+    fn try_from(canary: CanaryRouting) -> Result<Self, Self::Error> {
+        let version = canary.version.parse()?;
+        let percent = u8::try_from(canary.percent)?;
+        crate::registry::CanaryRouting::new(version, percent)
+    }
+}
+
+impl From<crate::registry::CanaryRouting> for CanaryRouting {
+    fn from(canary: crate::registry::CanaryRouting) -> Self {
+        CanaryRouting {
+            version: canary.version.to_string(),
+            percent: u32::from(canary.percent),
+        }
+    }
+}
+
+impl From<crate::ModuleStats> for ModuleStats {
+    fn from(stats: crate::ModuleStats) -> Self {
+        ModuleStats {
+            routes: stats.routes.into_iter().map(RouteStats::from).collect(),
+        }
+    }
+}
+
+impl From<crate::RouteStats> for RouteStats {
+    fn from(stats: crate::RouteStats) -> Self {
+        RouteStats {
+            version: stats
+                .version
+                .map(|version| version.to_string())
+                .unwrap_or_default(),
+            canary_percent: stats.canary_percent.map(u32::from),
+            commands: stats.commands,
+            cache: Some(stats.cache.into()),
+            quarantined: stats
+                .quarantined
+                .into_iter()
+                .map(QuarantinedEntity::from)
+                .collect(),
+        }
+    }
+}
+
+impl From<crate::EntityQuarantined> for QuarantinedEntity {
+    fn from(quarantined: crate::EntityQuarantined) -> Self {
+        QuarantinedEntity {
+            stream_name: quarantined.stream_name.into_string(),
+            error: quarantined.error,
+        }
+    }
+}
+
+impl TryFrom<thalo_message_store::scheduled_commands::ScheduledCommand<'static>>
+    for ScheduledCommand
+{
+    type Error = serde_json::Error;
+
+    fn try_from(
+        command: thalo_message_store::scheduled_commands::ScheduledCommand<'static>,
+    ) -> Result<Self, Self::Error> {
+        let context: crate::CommandContext = serde_json::from_value(command.metadata.into_owned())?;
+        Ok(ScheduledCommand {
+            scheduled_by: command
+                .scheduled_by
+                .map(StreamName::into_string)
+                .unwrap_or_default(),
+            key: command.key.into_owned(),
+            due: command.due,
+            name: command.stream_name.category().to_string(),
+            id: command
+                .stream_name
+                .id()
+                .map(|id| id.to_string())
+                .unwrap_or_default(),
+            command: command.command.into_owned(),
+            payload: serde_json::to_string(&command.payload)?,
+            metadata: if context.metadata.is_empty() {
+                String::new()
+            } else {
+                serde_json::to_string(&context.metadata)?
+            },
+        })
+    }
+}
+
+impl From<thalo_message_store::hash_chain::Checkpoint> for Checkpoint {
+    fn from(checkpoint: thalo_message_store::hash_chain::Checkpoint) -> Self {
+        Checkpoint {
+            global_id: checkpoint.global_id,
+            hash: checkpoint.hash.to_vec(),
+            public_key: checkpoint.public_key.to_vec(),
+            signature: checkpoint.signature.to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("invalid checkpoint")]
+pub struct InvalidCheckpoint;
+
+impl TryFrom<Checkpoint> for thalo_message_store::hash_chain::Checkpoint {
+    type Error = InvalidCheckpoint;
+
+    fn try_from(checkpoint: Checkpoint) -> Result<Self, Self::Error> {
+        Ok(thalo_message_store::hash_chain::Checkpoint {
+            global_id: checkpoint.global_id,
+            hash: checkpoint.hash.try_into().map_err(|_| InvalidCheckpoint)?,
+            public_key: checkpoint
+                .public_key
+                .try_into()
+                .map_err(|_| InvalidCheckpoint)?,
+            signature: checkpoint
+                .signature
+                .try_into()
+                .map_err(|_| InvalidCheckpoint)?,
+        })
+    }
+}
+
+impl From<crate::entity_cache::EntityCacheStats> for EntityCacheStats {
+    fn from(stats: crate::entity_cache::EntityCacheStats) -> Self {
+        EntityCacheStats {
+            entries: stats.entries,
+            weighted_size: stats.weighted_size,
+            hits: stats.hits,
+            misses: stats.misses,
+        }
+    }
+}
+
+impl ExecuteCommand {
+    /// Creates a request to execute a command.
+    pub fn new(
+        name: Category<'static>,
+        id: thalo::stream_name::ID<'static>,
+        command: String,
+        payload: &serde_json::Value,
+        expected_version: crate::ExpectedVersion,
+        context: crate::CommandContextOverrides,
+    ) -> Result<Self, serde_json::Error> {
+        Ok(ExecuteCommand {
+            name: name.into_string(),
+            id: id.into_string(),
+            command,
+            payload: serde_json::to_string(payload)?,
+            expected_version: Some(expected_version.into()),
+            metadata: if context.metadata.is_empty() {
+                String::new()
+            } else {
+                serde_json::to_string(&context.metadata)?
+            },
+            timestamp: context.timestamp,
+            seed: context.seed,
+        })
+    }
+}
+
+impl From<Status> for ExecuteStatus {
+    fn from(status: Status) -> Self {
+        ExecuteStatus {
+            code: status.code() as i32,
+            message: status.message().to_string(),
+            details: status.details().to_vec(),
+        }
+    }
+}
+
+impl From<ExecuteStatus> for Status {
+    fn from(status: ExecuteStatus) -> Self {
+        Status::with_details(
+            Code::from_i32(status.code),
+            status.message,
+            status.details.into(),
+        )
+    }
+}
+
+impl From<crate::WrongExpectedVersion> for Status {
+    fn from(err: crate::WrongExpectedVersion) -> Self {
+        let message = err.to_string();
+        let details = WrongExpectedVersion {
+            stream_name: err.stream_name.into_string(),
+            expected_version: Some(err.expected_version.into()),
+            stream_version: err.stream_version,
+        };
+        Status::with_details(
+            Code::FailedPrecondition,
+            message,
+            details.encode_to_vec().into(),
+        )
+    }
+}
+
+impl crate::WrongExpectedVersion {
+    /// Extracts a wrong expected version error from the details of a
+    /// `FAILED_PRECONDITION` status.
+    pub fn from_status(status: &Status) -> Option<Self> {
+        if status.code() != Code::FailedPrecondition {
+            return None;
+        }
+
+        let details = WrongExpectedVersion::decode(status.details()).ok()?;
+        Some(crate::WrongExpectedVersion {
+            stream_name: StreamName::new(details.stream_name).ok()?,
+            expected_version: details.expected_version?.try_into().ok()?,
+            stream_version: details.stream_version,
+        })
+    }
+}
@@ -1,9 +1,18 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
 
 use futures::StreamExt as _;
-use thalo::stream_name::{Category, ID};
+use semver::Version;
+use serde_json::Map;
+use thalo::stream_name::{Category, StreamName, ID};
+use thalo_message_store::hash_chain::VerifyingKey;
 use thalo_message_store::message::Message;
-use tokio::sync::mpsc;
+use thalo_message_store::scheduled_commands::{self, ScheduledCommand};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::task::JoinHandle;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::{Stream, StreamExt};
 use tonic::{Request, Response, Status};
@@ -11,7 +20,17 @@ use tonic::{Request, Response, Status};
 use super::proto;
 pub use super::proto::command_center_server::*;
 pub use super::proto::projection_server::*;
-use crate::Runtime;
+use crate::module::budget::ExecutionBudgetExceeded;
+use crate::module::wit_aggregate::AggregateError;
+use crate::registry::{self, CanaryRouting, ModuleID};
+use crate::{
+    CommandContextOverrides, EntityQuarantined, ExpectedVersion, HistoricalState,
+    MissingCheckpointSigningKey, Runtime, StateAt, Verification, VerificationFailed,
+    WrongExpectedVersion,
+};
+
+/// Maximum number of commands of a batch pending at once.
+const BATCH_CONCURRENCY: usize = 64;
 
 #[tonic::async_trait]
 impl proto::command_center_server::CommandCenter for Runtime {
@@ -19,59 +38,373 @@ impl proto::command_center_server::CommandCenter for Runtime {
         &self,
         request: Request<proto::ExecuteCommand>,
     ) -> Result<Response<proto::ExecuteResponse>, Status> {
-        let proto::ExecuteCommand {
-            name,
-            id,
-            command,
-            payload,
-        } = request.into_inner();
+        let resp = execute_command(self, request.into_inner()).await?;
+
+        Ok(Response::new(resp))
+    }
+
+    async fn batch_execute(
+        &self,
+        request: Request<proto::BatchExecuteRequest>,
+    ) -> Result<Response<proto::BatchExecuteResponse>, Status> {
+        let proto::BatchExecuteRequest { commands } = request.into_inner();
+
+        let mut batch = BatchExecution::new(self.clone());
+        for command in commands {
+            batch.execute(command).await?;
+        }
+
+        Ok(Response::new(batch.finish().await?))
+    }
+
+    async fn execute_stream(
+        &self,
+        request: Request<tonic::Streaming<proto::ExecuteCommand>>,
+    ) -> Result<Response<proto::BatchExecuteResponse>, Status> {
+        let mut commands = request.into_inner();
+
+        let mut batch = BatchExecution::new(self.clone());
+        while let Some(command) = commands.message().await? {
+            batch.execute(command).await?;
+        }
+
+        Ok(Response::new(batch.finish().await?))
+    }
+
+    async fn get_state(
+        &self,
+        request: Request<proto::GetStateRequest>,
+    ) -> Result<Response<proto::GetStateResponse>, Status> {
+        let proto::GetStateRequest { name, id } = request.into_inner();
         let name = Category::new(name).map_err(|_| Status::invalid_argument("invalid name"))?;
         let id = ID::new(id).map_err(|_| Status::invalid_argument("invalid id"))?;
-        let payload = serde_json::from_str(&payload)
-            .map_err(|err| Status::invalid_argument(format!("invalid payload: {err}")))?;
 
-        let resp = match self.execute(name, id, command, payload).await {
-            Ok(Ok(events)) => proto::ExecuteResponse {
-                success: true,
-                events: events
-                    .into_iter()
-                    .map(proto::Message::try_from)
-                    .collect::<Result<_, _>>()
-                    .map_err(|err| Status::internal(err.to_string()))?,
-                message: "ok".to_string(),
-            },
-            Ok(Err(err)) => proto::ExecuteResponse {
-                success: false,
-                events: vec![],
-                message: serde_json::to_string(&err)
-                    .map_err(|err| Status::internal(format!("failed to serialize error: {err}")))?,
-            },
-            Err(err) => return Err(Status::internal(err.to_string())),
+        let state = self
+            .get_state(name, id)
+            .await
+            .map_err(|err| {
+                if err.is::<EntityQuarantined>() {
+                    Status::unavailable(err.to_string())
+                } else {
+                    Status::internal(err.to_string())
+                }
+            })?
+            .ok_or_else(|| Status::unimplemented("aggregate state is not serializable"))?;
+        let state = serde_json::to_string(&state)
+            .map_err(|err| Status::internal(format!("failed to serialize state: {err}")))?;
+
+        Ok(Response::new(proto::GetStateResponse { state }))
+    }
+
+    async fn get_state_at(
+        &self,
+        request: Request<proto::GetStateAtRequest>,
+    ) -> Result<Response<proto::GetStateAtResponse>, Status> {
+        let proto::GetStateAtRequest { name, id, at } = request.into_inner();
+        let name = Category::new(name).map_err(|_| Status::invalid_argument("invalid name"))?;
+        let id = ID::new(id).map_err(|_| Status::invalid_argument("invalid id"))?;
+        let at = match at.ok_or_else(|| Status::invalid_argument("missing position or time"))? {
+            proto::get_state_at_request::At::Position(position) => StateAt::Position(position),
+            proto::get_state_at_request::At::Time(time) => {
+                StateAt::Time(UNIX_EPOCH + Duration::from_millis(time))
+            }
         };
 
-        Ok(Response::new(resp))
+        let HistoricalState { state, last_event } = self
+            .get_state_at(name, id, at)
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+        let state =
+            state.ok_or_else(|| Status::unimplemented("aggregate state is not serializable"))?;
+        let state = serde_json::to_string(&state)
+            .map_err(|err| Status::internal(format!("failed to serialize state: {err}")))?;
+        let last_event = last_event
+            .map(proto::Message::try_from)
+            .transpose()
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(Response::new(proto::GetStateAtResponse {
+            state,
+            last_event,
+        }))
     }
 
     async fn publish(
         &self,
         request: Request<proto::PublishModule>,
     ) -> Result<Response<proto::PublishResponse>, Status> {
-        let proto::PublishModule { name, module } = request.into_inner();
+        let proto::PublishModule {
+            name,
+            module,
+            version,
+            verification,
+        } = request.into_inner();
+        if !registry::is_valid_module_name(&name) {
+            return Err(Status::invalid_argument(
+                "invalid name, which can't be empty or contain '@', '/', '.' or '-'",
+            ));
+        }
         let name = Category::new(name).map_err(|_| Status::invalid_argument("invalid name"))?;
+        let version = version
+            .map(|version| version.parse())
+            .transpose()
+            .map_err(invalid_version)?;
 
-        let resp = match self.save_module(name, module).await {
-            Ok(()) => proto::PublishResponse {
+        let resp = match self
+            .save_module(
+                ModuleID::new(name, version),
+                module,
+                verification.map(Verification::from),
+            )
+            .await
+        {
+            Ok(report) => proto::PublishResponse {
                 success: true,
                 message: "ok".to_string(),
+                report: report.map(proto::VerificationReport::from),
             },
             Err(err) => proto::PublishResponse {
                 success: false,
                 message: err.to_string(),
+                report: err
+                    .downcast_ref::<VerificationFailed>()
+                    .map(|failed| failed.report.clone().into()),
             },
         };
 
         Ok(Response::new(resp))
     }
+
+    async fn activate_module(
+        &self,
+        request: Request<proto::ActivateModuleRequest>,
+    ) -> Result<Response<proto::ActivateModuleResponse>, Status> {
+        let proto::ActivateModuleRequest { name, version } = request.into_inner();
+        let name = Category::new(name).map_err(|_| Status::invalid_argument("invalid name"))?;
+        let version: Version = version.parse().map_err(invalid_version)?;
+
+        self.activate_module(name, version.clone())
+            .await
+            .map_err(|err| Status::failed_precondition(err.to_string()))?;
+
+        Ok(Response::new(proto::ActivateModuleResponse {
+            version: version.to_string(),
+        }))
+    }
+
+    async fn rollback_module(
+        &self,
+        request: Request<proto::RollbackModuleRequest>,
+    ) -> Result<Response<proto::ActivateModuleResponse>, Status> {
+        let proto::RollbackModuleRequest { name } = request.into_inner();
+        let name = Category::new(name).map_err(|_| Status::invalid_argument("invalid name"))?;
+
+        let version = self
+            .rollback_module(name)
+            .await
+            .map_err(|err| Status::failed_precondition(err.to_string()))?;
+
+        Ok(Response::new(proto::ActivateModuleResponse {
+            version: version.to_string(),
+        }))
+    }
+
+    async fn set_canary(
+        &self,
+        request: Request<proto::SetCanaryRequest>,
+    ) -> Result<Response<proto::SetCanaryResponse>, Status> {
+        let proto::SetCanaryRequest { name, canary } = request.into_inner();
+        let name = Category::new(name).map_err(|_| Status::invalid_argument("invalid name"))?;
+        let canary = canary
+            .map(CanaryRouting::try_from)
+            .transpose()
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+
+        self.set_canary(name, canary)
+            .await
+            .map_err(|err| Status::failed_precondition(err.to_string()))?;
+
+        Ok(Response::new(proto::SetCanaryResponse {}))
+    }
+
+    async fn get_module_stats(
+        &self,
+        request: Request<proto::GetModuleStatsRequest>,
+    ) -> Result<Response<proto::ModuleStats>, Status> {
+        let proto::GetModuleStatsRequest { name } = request.into_inner();
+        let name = Category::new(name).map_err(|_| Status::invalid_argument("invalid name"))?;
+
+        let stats = self
+            .module_stats(name)
+            .await
+            .map_err(|err| Status::not_found(err.to_string()))?;
+
+        Ok(Response::new(stats.into()))
+    }
+
+    async fn unquarantine(
+        &self,
+        request: Request<proto::UnquarantineRequest>,
+    ) -> Result<Response<proto::UnquarantineResponse>, Status> {
+        let proto::UnquarantineRequest { name, id } = request.into_inner();
+        let name = Category::new(name).map_err(|_| Status::invalid_argument("invalid name"))?;
+        let id = ID::new(id).map_err(|_| Status::invalid_argument("invalid id"))?;
+
+        let released = self
+            .unquarantine(name, id)
+            .await
+            .map_err(|err| Status::not_found(err.to_string()))?;
+
+        Ok(Response::new(proto::UnquarantineResponse { released }))
+    }
+
+    async fn schedule_command(
+        &self,
+        request: Request<proto::ScheduleCommandRequest>,
+    ) -> Result<Response<proto::ScheduleCommandResponse>, Status> {
+        let proto::ScheduleCommandRequest {
+            key,
+            due,
+            name,
+            id,
+            command,
+            payload,
+            metadata,
+            seed,
+        } = request.into_inner();
+        if !scheduled_commands::is_valid_key(&key) {
+            return Err(Status::invalid_argument("invalid key"));
+        }
+        let name = Category::new(name).map_err(|_| Status::invalid_argument("invalid name"))?;
+        let id = ID::new(id).map_err(|_| Status::invalid_argument("invalid id"))?;
+        let stream_name = StreamName::from_parts(name, Some(&id))
+            .map_err(|_| Status::invalid_argument("invalid stream name"))?;
+        let payload = serde_json::from_str(&payload)
+            .map_err(|err| Status::invalid_argument(format!("invalid payload: {err}")))?;
+        let metadata = if metadata.is_empty() {
+            Map::new()
+        } else {
+            serde_json::from_str(&metadata)
+                .map_err(|err| Status::invalid_argument(format!("invalid metadata: {err}")))?
+        };
+        // Handled with the time it's due, rather than when it's delivered.
+        let context = CommandContextOverrides {
+            timestamp: Some(due),
+            seed,
+            metadata,
+        }
+        .resolve();
+        let context = serde_json::to_value(context)
+            .map_err(|err| Status::internal(format!("failed to serialize context: {err}")))?;
+
+        self.schedule_command(ScheduledCommand {
+            scheduled_by: None,
+            key: key.into(),
+            due,
+            stream_name,
+            command: command.into(),
+            payload: Cow::Owned(payload),
+            metadata: Cow::Owned(context),
+            failed_attempts: 0,
+        })
+        .await
+        .map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(Response::new(proto::ScheduleCommandResponse {}))
+    }
+
+    async fn cancel_scheduled_command(
+        &self,
+        request: Request<proto::CancelScheduledCommandRequest>,
+    ) -> Result<Response<proto::CancelScheduledCommandResponse>, Status> {
+        let proto::CancelScheduledCommandRequest { key, scheduled_by } = request.into_inner();
+        let scheduled_by = if scheduled_by.is_empty() {
+            None
+        } else {
+            Some(
+                StreamName::new(scheduled_by)
+                    .map_err(|_| Status::invalid_argument("invalid scheduled by stream name"))?,
+            )
+        };
+
+        let cancelled = self
+            .cancel_scheduled_command(scheduled_by, key)
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(Response::new(proto::CancelScheduledCommandResponse {
+            cancelled: cancelled.is_some(),
+        }))
+    }
+
+    async fn list_scheduled_commands(
+        &self,
+        _request: Request<proto::ListScheduledCommandsRequest>,
+    ) -> Result<Response<proto::ListScheduledCommandsResponse>, Status> {
+        let commands = self
+            .scheduled_commands()
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?
+            .into_iter()
+            .map(proto::ScheduledCommand::try_from)
+            .collect::<Result<_, _>>()
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(Response::new(proto::ListScheduledCommandsResponse {
+            commands,
+        }))
+    }
+
+    async fn export_checkpoint(
+        &self,
+        _request: Request<proto::ExportCheckpointRequest>,
+    ) -> Result<Response<proto::ExportCheckpointResponse>, Status> {
+        let checkpoint = self.export_checkpoint().map_err(|err| {
+            if err.is::<MissingCheckpointSigningKey>() {
+                Status::failed_precondition(err.to_string())
+            } else {
+                Status::internal(err.to_string())
+            }
+        })?;
+
+        Ok(Response::new(proto::ExportCheckpointResponse {
+            checkpoint: checkpoint.map(proto::Checkpoint::from),
+        }))
+    }
+
+    async fn verify_chain(
+        &self,
+        request: Request<proto::VerifyChainRequest>,
+    ) -> Result<Response<proto::VerifyChainResponse>, Status> {
+        let proto::VerifyChainRequest {
+            checkpoint,
+            public_key,
+        } = request.into_inner();
+        let checkpoint = checkpoint
+            .ok_or_else(|| Status::invalid_argument("missing checkpoint"))?
+            .try_into()
+            .map_err(|err: proto::InvalidCheckpoint| Status::invalid_argument(err.to_string()))?;
+        let verifying_key = public_key
+            .as_slice()
+            .try_into()
+            .ok()
+            .and_then(|public_key| VerifyingKey::from_bytes(public_key).ok())
+            .ok_or_else(|| Status::invalid_argument("invalid public key"))?;
+
+        let resp = match self.verify_chain(&checkpoint, &verifying_key) {
+            Ok(()) => proto::VerifyChainResponse {
+                valid: true,
+                error: String::new(),
+            },
+            Err(err) if is_chain_verification_failure(&err) => proto::VerifyChainResponse {
+                valid: false,
+                error: err.to_string(),
+            },
+            Err(err) => return Err(Status::internal(err.to_string())),
+        };
+
+        Ok(Response::new(resp))
+    }
 }
 
 #[tonic::async_trait]
@@ -83,7 +416,11 @@ impl proto::projection_server::Projection for Runtime {
         &self,
         request: Request<proto::SubscriptionRequest>,
     ) -> Result<Response<Self::SubscribeToEventsStream>, Status> {
-        let proto::SubscriptionRequest { name, events } = request.into_inner();
+        let proto::SubscriptionRequest {
+            name,
+            events,
+            start_from,
+        } = request.into_inner();
 
         let (tx, rx) = mpsc::channel::<Message>(1);
         let events = events
@@ -91,7 +428,8 @@ impl proto::projection_server::Projection for Runtime {
             .map(crate::projection::EventInterest::try_from)
             .collect::<Result<_, _>>()
             .map_err(|err| Status::invalid_argument(err.to_string()))?;
-        self.start_projection(tx, name, events)
+        let start_from = start_from.map(|time| UNIX_EPOCH + Duration::from_millis(time));
+        self.start_projection(tx, name, events, start_from)
             .await
             .map_err(|err| Status::internal(err.to_string()))?;
 
@@ -121,3 +459,178 @@ impl proto::projection_server::Projection for Runtime {
         Ok(Response::new(resp))
     }
 }
+
+/// Executes a command received by [`CommandCenter::execute`], or as part of a
+/// batch.
+async fn execute_command(
+    runtime: &Runtime,
+    command: proto::ExecuteCommand,
+) -> Result<proto::ExecuteResponse, Status> {
+    let proto::ExecuteCommand {
+        name,
+        id,
+        command,
+        payload,
+        expected_version,
+        metadata,
+        timestamp,
+        seed,
+    } = command;
+    let name = Category::new(name).map_err(|_| Status::invalid_argument("invalid name"))?;
+    let id = ID::new(id).map_err(|_| Status::invalid_argument("invalid id"))?;
+    let payload = serde_json::from_str(&payload)
+        .map_err(|err| Status::invalid_argument(format!("invalid payload: {err}")))?;
+    let expected_version = expected_version
+        .map(ExpectedVersion::try_from)
+        .transpose()
+        .map_err(|err| Status::invalid_argument(err.to_string()))?
+        .unwrap_or_default();
+    let metadata = if metadata.is_empty() {
+        Map::new()
+    } else {
+        serde_json::from_str(&metadata)
+            .map_err(|err| Status::invalid_argument(format!("invalid metadata: {err}")))?
+    };
+    let context = CommandContextOverrides {
+        timestamp,
+        seed,
+        metadata,
+    }
+    .resolve();
+
+    let resp = match runtime
+        .execute(name, id, command, payload, expected_version, context)
+        .await
+    {
+        Ok(Ok(events)) => proto::ExecuteResponse {
+            success: true,
+            events: events
+                .into_iter()
+                .map(proto::Message::try_from)
+                .collect::<Result<_, _>>()
+                .map_err(|err| Status::internal(err.to_string()))?,
+            message: "ok".to_string(),
+        },
+        Ok(Err(err)) => proto::ExecuteResponse {
+            success: false,
+            events: vec![],
+            message: serde_json::to_string(&err)
+                .map_err(|err| Status::internal(format!("failed to serialize error: {err}")))?,
+        },
+        Err(err) => match err.downcast::<WrongExpectedVersion>() {
+            Ok(err) => return Err(err.into()),
+            Err(err) if is_resource_exhausted(&err) => {
+                return Err(Status::resource_exhausted(err.to_string()))
+            }
+            Err(err) if err.is::<EntityQuarantined>() => {
+                return Err(Status::unavailable(err.to_string()))
+            }
+            Err(err) => return Err(Status::internal(err.to_string())),
+        },
+    };
+
+    Ok(resp)
+}
+
+/// Commands of a [`CommandCenter::batch_execute`] or
+/// [`CommandCenter::execute_stream`] request.
+///
+/// Each command is executed in its own task as soon as it's received, after
+/// the previous command to the same entity has finished, so commands run
+/// concurrently across entities and in order for each entity. A failed command
+/// doesn't stop later commands from executing.
+///
+/// At most [`BATCH_CONCURRENCY`] commands are pending at once, and receiving
+/// more waits for one to finish, so a large stream is never buffered in
+/// memory.
+struct BatchExecution {
+    runtime: Runtime,
+    permits: Arc<Semaphore>,
+    /// Completion of the last command received for each entity.
+    last_by_entity: HashMap<(String, String), oneshot::Receiver<()>>,
+    results: Vec<JoinHandle<proto::ExecuteResult>>,
+}
+
+impl BatchExecution {
+    fn new(runtime: Runtime) -> Self {
+        BatchExecution {
+            runtime,
+            permits: Arc::new(Semaphore::new(BATCH_CONCURRENCY)),
+            last_by_entity: HashMap::new(),
+            results: Vec::new(),
+        }
+    }
+
+    async fn execute(&mut self, command: proto::ExecuteCommand) -> Result<(), Status> {
+        let permit = Arc::clone(&self.permits)
+            .acquire_owned()
+            .await
+            .map_err(|_| Status::internal("batch execution was closed"))?;
+        let (done, next) = oneshot::channel();
+        let previous = self
+            .last_by_entity
+            .insert((command.name.clone(), command.id.clone()), next);
+        let runtime = self.runtime.clone();
+        self.results.push(tokio::spawn(async move {
+            if let Some(previous) = previous {
+                // The previous command's sender is dropped once it's finished.
+                let _ = previous.await;
+            }
+            let result = execute_command(&runtime, command).await;
+            drop(done);
+            drop(permit);
+
+            proto::ExecuteResult {
+                result: Some(match result {
+                    Ok(resp) => proto::execute_result::Result::Response(resp),
+                    Err(status) => proto::execute_result::Result::Status(status.into()),
+                }),
+            }
+        }));
+
+        Ok(())
+    }
+
+    /// Waits for every command to finish, returning their results in the
+    /// order they were received.
+    async fn finish(self) -> Result<proto::BatchExecuteResponse, Status> {
+        let mut results = Vec::with_capacity(self.results.len());
+        for result in self.results {
+            results.push(
+                result
+                    .await
+                    .map_err(|err| Status::internal(err.to_string()))?,
+            );
+        }
+
+        Ok(proto::BatchExecuteResponse { results })
+    }
+}
+
+/// Returns whether the hash chain failed verification, rather than couldn't be
+/// read.
+fn is_chain_verification_failure(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref(),
+        Some(
+            thalo_message_store::error::Error::CheckpointMismatch { .. }
+                | thalo_message_store::error::Error::HashChainMismatch { .. }
+                | thalo_message_store::error::Error::HashChainTruncated { .. }
+                | thalo_message_store::error::Error::InvalidCheckpointSignature
+                | thalo_message_store::error::Error::MissingHash { .. }
+        )
+    )
+}
+
+/// Returns whether the aggregate exceeded its execution budget or memory limits.
+fn is_resource_exhausted(err: &anyhow::Error) -> bool {
+    err.is::<ExecutionBudgetExceeded>()
+        || matches!(
+            err.downcast_ref(),
+            Some(AggregateError::MemoryLimitExceeded(_))
+        )
+}
+
+fn invalid_version(err: semver::Error) -> Status {
+    Status::invalid_argument(format!("invalid version: {err}"))
+}
@@ -1,27 +1,84 @@
+use std::future::Future;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::thread;
+use std::time::SystemTime;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use semver::Version;
 use serde_json::Value;
-use thalo::stream_name::{Category, ID};
+use thalo::stream_name::{Category, StreamName, ID};
+use thalo_message_store::hash_chain::{Checkpoint, SigningKey, VerifyingKey};
 use thalo_message_store::message::Message;
+use thalo_message_store::scheduled_commands::ScheduledCommand;
 use thalo_message_store::MessageStore;
+use thiserror::Error;
 use tokio::fs;
 use tokio::sync::{broadcast, mpsc};
-use tracing::instrument;
+use tokio::time::{self, Instant};
+use tracing::{error, instrument, warn};
 use wasmtime::Engine;
 
 use crate::broadcaster::BroadcasterHandle;
-use crate::command::CommandGatewayHandle;
+use crate::command::command_log::CommandLogConfig;
+use crate::command::entity_cache::EntityCacheConfig;
+use crate::command::{
+    watch_modules, CommandContext, CommandGatewayHandle, ExpectedVersion, HistoricalState,
+    ModuleStats, StateAt, Verification, VerificationFailed, VerificationReport,
+};
+use crate::module::budget::ExecutionBudgets;
+use crate::module::limits::MemoryLimitsConfig;
+use crate::process_manager::{load_process_managers, ProcessManagerHandle};
 use crate::projection::{EventInterest, ProjectionGatewayHandle};
+use crate::registry::{self, CanaryRouting, ModuleID};
 use crate::relay::Relay;
 
+/// Directory within the modules path for modules awaiting verification.
+const STAGING_DIR: &str = ".staging";
+/// Directory within the modules path for process manager modules.
+const PROCESS_MANAGERS_DIR: &str = "process_managers";
+
+/// The runtime has no key to sign checkpoints with.
+#[derive(Clone, Copy, Debug, Error)]
+#[error("no checkpoint signing key configured")]
+pub struct MissingCheckpointSigningKey;
+
+/// Configuration of a [`Runtime`].
+#[derive(Clone, Debug)]
+pub struct RuntimeConfig {
+    /// Key checkpoints of the hash chain are signed with, if they can be
+    /// exported.
+    pub checkpoint_signing_key: Option<SigningKey>,
+    pub entity_cache: EntityCacheConfig,
+    /// Number of instances pooled per module.
+    pub module_pool_size: usize,
+    pub command_log: CommandLogConfig,
+    pub execution_budgets: ExecutionBudgets,
+    pub memory_limits: MemoryLimitsConfig,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            checkpoint_signing_key: None,
+            entity_cache: EntityCacheConfig::default(),
+            module_pool_size: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            command_log: CommandLogConfig::default(),
+            execution_budgets: ExecutionBudgets::default(),
+            memory_limits: MemoryLimitsConfig::default(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Runtime {
     message_store: MessageStore,
+    checkpoint_signing_key: Option<SigningKey>,
     modules_path: PathBuf,
     event_tx: broadcast::Sender<Message<'static>>,
     command_gateway: CommandGatewayHandle,
     projection_gateway: ProjectionGatewayHandle,
+    process_managers: Vec<ProcessManagerHandle>,
 }
 
 impl Runtime {
@@ -29,11 +86,15 @@ impl Runtime {
         message_store: MessageStore,
         relay: Relay,
         modules_path: impl Into<PathBuf>,
-        cache_size: u64,
+        mut config: RuntimeConfig,
     ) -> Result<Self> {
-        let mut config = wasmtime::Config::new();
-        config.async_support(true).wasm_component_model(true);
-        let engine = Engine::new(&config)?;
+        let checkpoint_signing_key = config.checkpoint_signing_key.take();
+        let mut engine_config = wasmtime::Config::new();
+        engine_config
+            .async_support(true)
+            .wasm_component_model(true)
+            .consume_fuel(true);
+        let engine = Engine::new(&engine_config)?;
 
         let (event_tx, subscriber) = broadcast::channel(1024);
         let broadcaster = BroadcasterHandle::new(
@@ -44,21 +105,49 @@ impl Runtime {
         let projection_gateway = ProjectionGatewayHandle::new(message_store.clone(), subscriber);
 
         let modules_path = modules_path.into();
+        let process_manager_modules = load_process_managers(
+            &engine,
+            &modules_path.join(PROCESS_MANAGERS_DIR),
+            &config.execution_budgets,
+            &config.memory_limits,
+        )
+        .await?;
         let command_gateway = CommandGatewayHandle::new(
             engine,
             message_store.clone(),
             relay.clone(),
             broadcaster.clone(),
-            cache_size,
+            config,
             modules_path.clone(),
-        );
+        )?;
+        let process_managers = process_manager_modules
+            .into_iter()
+            .map(|(name, module)| {
+                ProcessManagerHandle::new(
+                    name,
+                    module,
+                    message_store.clone(),
+                    broadcaster.clone(),
+                    command_gateway.clone(),
+                    projection_gateway.clone(),
+                )
+            })
+            .collect();
+        if let Err(err) = watch_modules(modules_path.clone(), command_gateway.clone()) {
+            error!(
+                modules_path = %modules_path.display(),
+                "failed to watch modules dir: {err}"
+            );
+        }
 
         Ok(Runtime {
             message_store,
+            checkpoint_signing_key,
             modules_path,
             event_tx,
             command_gateway,
             projection_gateway,
+            process_managers,
         })
     }
 
@@ -73,33 +162,226 @@ impl Runtime {
         id: ID<'static>,
         command: String,
         payload: Value,
+        expected_version: ExpectedVersion,
+        context: CommandContext,
     ) -> Result<Result<Vec<Message<'static>>, serde_json::Value>> {
         self.command_gateway
-            .execute(name, id, command, payload)
+            .execute(name, id, command, payload, expected_version, context)
             .await
     }
 
-    pub async fn save_module(
+    /// Returns the current state of an entity, or `None` if the aggregate's
+    /// state is not serializable.
+    pub async fn get_state(
         &self,
         name: Category<'static>,
+        id: ID<'static>,
+    ) -> Result<Option<Value>> {
+        self.command_gateway.get_state(name, id).await
+    }
+
+    /// Returns the state of an entity at a point in its history.
+    ///
+    /// Events are replayed on a throwaway instance, leaving the cached entity
+    /// untouched.
+    pub async fn get_state_at(
+        &self,
+        name: Category<'static>,
+        id: ID<'static>,
+        at: StateAt,
+    ) -> Result<HistoricalState> {
+        self.command_gateway.get_state_at(name, id, at).await
+    }
+
+    /// Writes a module to the modules directory and starts it, activating it
+    /// if it's versioned.
+    ///
+    /// With a [`Verification`], the category's streams are first replayed
+    /// through both the active and the new module, and the module is only
+    /// saved if they replay the same, unless forced.
+    pub async fn save_module(
+        &self,
+        id: ModuleID,
         module: impl AsRef<[u8]>,
+        verification: Option<Verification>,
+    ) -> Result<Option<VerificationReport>> {
+        if !registry::is_valid_module_name(&id.name) {
+            bail!("invalid module name '{}'", id.name);
+        }
+
+        let path = self.modules_path.join(id.file_name());
+        let Some(verification) = verification else {
+            fs::write(&path, module).await?;
+            self.command_gateway.publish_module(id, path).await?;
+            return Ok(None);
+        };
+
+        // Staged outside the watched modules directory, so it's not loaded before it's
+        // verified.
+        let staging_path = self.modules_path.join(STAGING_DIR);
+        fs::create_dir_all(&staging_path).await?;
+        let staged_path = staging_path.join(id.file_name());
+        fs::write(&staged_path, module).await?;
+
+        let report = self
+            .command_gateway
+            .verify_module(id.name.clone(), staged_path.clone(), verification.sample)
+            .await;
+        let report = match report {
+            Ok(report) if report.is_ok() || verification.force => report,
+            Ok(report) => {
+                fs::remove_file(&staged_path).await?;
+                return Err(VerificationFailed { report }.into());
+            }
+            Err(err) => {
+                fs::remove_file(&staged_path).await?;
+                return Err(err);
+            }
+        };
+        if !report.is_ok() {
+            warn!(module = %id, "activating module despite failed verification: {report}");
+        }
+
+        fs::rename(&staged_path, &path).await?;
+        self.command_gateway.publish_module(id, path).await?;
+
+        Ok(Some(report))
+    }
+
+    /// Pins the active version of a module, replacing the running version.
+    pub async fn activate_module(&self, name: Category<'static>, version: Version) -> Result<()> {
+        self.command_gateway.activate_module(name, version).await
+    }
+
+    /// Activates the version before the active version of a module, returning
+    /// the version rolled back to.
+    pub async fn rollback_module(&self, name: Category<'static>) -> Result<Version> {
+        self.command_gateway.rollback_module(name).await
+    }
+
+    /// Routes a percentage of a module's entities to a canary version, or
+    /// routes every entity to the active version if `canary` is `None`.
+    pub async fn set_canary(
+        &self,
+        name: Category<'static>,
+        canary: Option<CanaryRouting>,
     ) -> Result<()> {
-        let path = self.modules_path.join(&format!("{name}.wasm"));
-        fs::write(&path, module).await?;
+        self.command_gateway.set_canary(name, canary).await
+    }
+
+    /// Signs a checkpoint of the latest hash in the global event log's hash
+    /// chain, returning `None` if the chain is empty.
+    ///
+    /// Fails with [`MissingCheckpointSigningKey`] if the runtime has no signing
+    /// key.
+    pub fn export_checkpoint(&self) -> Result<Option<Checkpoint>> {
+        let signing_key = self
+            .checkpoint_signing_key
+            .as_ref()
+            .ok_or(MissingCheckpointSigningKey)?;
+        Ok(self
+            .message_store
+            .global_event_log()?
+            .checkpoint(signing_key)?)
+    }
 
+    /// Verifies the global event log's hash chain against a checkpoint signed
+    /// by `verifying_key`.
+    pub fn verify_chain(
+        &self,
+        checkpoint: &Checkpoint,
+        verifying_key: &VerifyingKey,
+    ) -> Result<()> {
+        self.message_store
+            .global_event_log()?
+            .verify_checkpoint(checkpoint, verifying_key)?;
+        Ok(())
+    }
+
+    /// Releases an entity quarantined after trapping, returning whether it was
+    /// quarantined.
+    pub async fn unquarantine(&self, name: Category<'static>, id: ID<'static>) -> Result<bool> {
+        self.command_gateway.unquarantine(name, id).await
+    }
+
+    pub async fn module_stats(&self, name: Category<'static>) -> Result<ModuleStats> {
+        self.command_gateway.module_stats(name).await
+    }
+
+    /// Schedules a command to be executed once it's due, replacing any pending
+    /// command scheduled with the same key.
+    ///
+    /// The command's metadata is the [`CommandContext`] it's executed with.
+    pub async fn schedule_command(&self, command: ScheduledCommand<'static>) -> Result<()> {
+        self.command_gateway.schedule_command(command).await
+    }
+
+    /// Cancels a command scheduled by a stream, or through the API if
+    /// `scheduled_by` is `None`, returning it if it was pending.
+    pub async fn cancel_scheduled_command(
+        &self,
+        scheduled_by: Option<StreamName<'static>>,
+        key: String,
+    ) -> Result<Option<ScheduledCommand<'static>>> {
         self.command_gateway
-            .start_module_from_file(name, path)
+            .cancel_scheduled_command(scheduled_by, key)
             .await
     }
 
+    /// Returns the pending scheduled commands, in the order they're due.
+    pub async fn scheduled_commands(&self) -> Result<Vec<ScheduledCommand<'static>>> {
+        self.command_gateway.scheduled_commands().await
+    }
+
+    /// Stops process managers and the command scheduler, drains in-flight
+    /// commands, relays remaining outbox batches, stops projections and
+    /// flushes the message store.
+    ///
+    /// Draining is abandoned at the first stage which doesn't finish before
+    /// `deadline`, but the message store is flushed regardless, so messages
+    /// written before the deadline are persisted.
+    ///
+    /// Commands can't be executed once the runtime is shut down.
+    pub async fn shutdown(&self, deadline: Instant) -> Result<()> {
+        let drained = self.drain(deadline).await;
+        self.message_store.flush().await?;
+        drained
+    }
+
+    async fn drain(&self, deadline: Instant) -> Result<()> {
+        for process_manager in &self.process_managers {
+            shutdown_stage(
+                "stopping process managers",
+                deadline,
+                process_manager.stop(),
+            )
+            .await?;
+        }
+        shutdown_stage(
+            "draining commands",
+            deadline,
+            self.command_gateway.shutdown(),
+        )
+        .await?;
+        shutdown_stage(
+            "stopping projections",
+            deadline,
+            self.projection_gateway.shutdown(),
+        )
+        .await?;
+
+        Ok(())
+    }
+
     pub async fn start_projection(
         &self,
         tx: mpsc::Sender<Message<'static>>,
         name: String,
         events: Vec<EventInterest<'static>>,
+        start_from: Option<SystemTime>,
     ) -> Result<()> {
         self.projection_gateway
-            .start_projection(tx, name, events)
+            .start_projection(tx, name, events, start_from)
             .await
     }
 
@@ -113,3 +395,94 @@ impl Runtime {
             .await
     }
 }
+
+/// Runs a stage of shutdown, failing if it doesn't finish before the deadline.
+async fn shutdown_stage(
+    stage: &str,
+    deadline: Instant,
+    fut: impl Future<Output = Result<()>>,
+) -> Result<()> {
+    match time::timeout_at(deadline, fut).await {
+        Ok(res) => res.with_context(|| format!("{stage} failed")),
+        Err(_) => {
+            warn!("shutdown timed out {stage}");
+            bail!("shutdown timed out {stage}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Map};
+
+    use super::*;
+    use crate::module::limits::MemoryLimits;
+    use crate::module::wit_aggregate::AggregateError;
+
+    /// Aggregate whose entities with an ID starting with `r` grow linear memory
+    /// by 1 MiB on each command.
+    const MEMORY_HOG: &str = include_str!("../testdata/memory_hog.wat");
+
+    async fn execute(runtime: &Runtime, id: &str) -> Result<()> {
+        runtime
+            .execute(
+                Category::new("hog")?,
+                ID::new(id.to_string())?,
+                "Grow".to_string(),
+                json!({}),
+                ExpectedVersion::Any,
+                CommandContext::new(Map::new()),
+            )
+            .await?
+            .map_err(|err| anyhow::anyhow!("command rejected: {err}"))?;
+        Ok(())
+    }
+
+    async fn cache_misses(runtime: &Runtime) -> Result<u64> {
+        let stats = runtime.module_stats(Category::new("hog")?).await?;
+        Ok(stats.routes[0].cache.misses)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn evicts_entity_exceeding_memory_limit() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("thalo-memory-limit-{}", std::process::id()));
+        let modules_path = dir.join("modules");
+        fs::create_dir_all(&modules_path).await?;
+        fs::write(modules_path.join("hog.wasm"), MEMORY_HOG).await?;
+        let message_store = MessageStore::open(dir.join("message_store"))?;
+        let runtime = Runtime::new(
+            message_store,
+            Relay::Noop,
+            &modules_path,
+            RuntimeConfig {
+                // Both entities share a single store.
+                module_pool_size: 1,
+                memory_limits: MemoryLimitsConfig::new(MemoryLimits {
+                    memory_size: Some(1 << 20),
+                    table_elements: None,
+                }),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        execute(&runtime, "steady").await?;
+        let err = execute(&runtime, "runaway").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(AggregateError::MemoryLimitExceeded(_))
+        ));
+
+        // The steady entity is hydrated on a fresh store.
+        execute(&runtime, "steady").await?;
+        execute(&runtime, "steady").await?;
+
+        // The runaway entity was evicted, so it's hydrated again.
+        let misses = cache_misses(&runtime).await?;
+        assert!(execute(&runtime, "runaway").await.is_err());
+        assert_eq!(cache_misses(&runtime).await?, misses + 1);
+
+        fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+;; An aggregate whose entities grow linear memory by 1 MiB on each command if
+;; their ID starts with `r`, for testing memory limits.
+;;
+;; Implements the `thalo:aggregate/entities` interface by hand, as the test
+;; environment can't build modules with the SDK.
+(component
+  (type $entity (resource (rep i32)))
+  (core func $entity_new (canon resource.new $entity))
+  (core module $m
+    (import "entity" "new" (func $new (param i32) (result i32)))
+    (memory (export "memory") 1)
+    (global $heap (mut i32) (i32.const 1024))
+    ;; Bump allocator, which never frees memory.
+    (func (export "realloc") (param i32 i32 i32 i32) (result i32)
+      (local $ptr i32)
+      (local.set $ptr
+        (i32.and
+          (i32.add (global.get $heap) (i32.sub (local.get 2) (i32.const 1)))
+          (i32.sub (i32.const 0) (local.get 2))))
+      (global.set $heap (i32.add (local.get $ptr) (local.get 3)))
+      (local.get $ptr))
+    ;; The entity's rep is 1 for runaway entities, and 0 otherwise.
+    (func (export "constructor") (param i32 i32) (result i32)
+      (call $new (i32.eq (i32.load8_u (local.get 0)) (i32.const 114))))
+    ;; Results are written to the start of memory. Events are ignored, and
+    ;; commands return `ok` with no events or commands.
+    (func (export "apply") (param i32 i32 i32) (result i32)
+      (i32.store8 (i32.const 0) (i32.const 0))
+      (i32.const 0))
+    (func (export "handle") (param i32 i32 i32 i32 i32 i64 i64 i32 i64 i32 i32) (result i32)
+      (if (local.get 0)
+        (then
+          (if (i32.eq (memory.grow (i32.const 16)) (i32.const -1))
+            (then unreachable))))
+      (i32.store8 (i32.const 0) (i32.const 0))
+      (i64.store (i32.const 4) (i64.const 0))
+      (i64.store (i32.const 12) (i64.const 0))
+      (i32.const 0))
+    (func (export "state") (param i32) (result i32)
+      (i32.store8 (i32.const 0) (i32.const 0))
+      (i32.store8 (i32.const 4) (i32.const 0))
+      (i32.const 0))
+  )
+  (core instance $entity_funcs (export "new" (func $entity_new)))
+  (core instance $i (instantiate $m (with "entity" (instance $entity_funcs))))
+  (type $event (record (field "event" string) (field "payload" string)))
+  (type $command (record (field "command" string) (field "payload" string)))
+  (type $schedule (record (field "key" string) (field "due" u64)))
+  (type $outbound-command (record
+    (field "name" string)
+    (field "id" string)
+    (field "command" string)
+    (field "payload" string)
+    (field "schedule" (option $schedule))))
+  (type $handled (record
+    (field "events" (list $event))
+    (field "commands" (list $outbound-command))))
+  (type $context (record
+    (field "timestamp" u64)
+    (field "seed" u64)
+    (field "version" (option u64))
+    (field "metadata" string)))
+  (type $error (variant
+    (case "command" (tuple string string))
+    (case "deserialize-command" (tuple string string))
+    (case "deserialize-context" string)
+    (case "deserialize-event" (tuple string string))
+    (case "serialize-error" (tuple string string))
+    (case "serialize-event" string)
+    (case "serialize-state" string)))
+  (func $constructor (param "id" string) (result (own $entity))
+    (canon lift (core func $i "constructor") (memory $i "memory") (realloc (func $i "realloc"))))
+  (func $apply (param "self" (borrow $entity)) (param "events" (list $event))
+    (result (result (error $error)))
+    (canon lift (core func $i "apply") (memory $i "memory") (realloc (func $i "realloc"))))
+  (func $handle (param "self" (borrow $entity)) (param "command" $command) (param "ctx" $context)
+    (result (result $handled (error $error)))
+    (canon lift (core func $i "handle") (memory $i "memory") (realloc (func $i "realloc"))))
+  (func $state (param "self" (borrow $entity))
+    (result (result (option string) (error $error)))
+    (canon lift (core func $i "state") (memory $i "memory") (realloc (func $i "realloc"))))
+  ;; Names the types of the exported interface.
+  (component $entities_shim
+    (import "import-type-entity" (type $entity (sub resource)))
+    (export $entity_e "entity" (type $entity))
+    (type $event_def (record (field "event" string) (field "payload" string)))
+    (import "import-type-event" (type $event (eq $event_def)))
+    (export $event_e "event" (type $event))
+    (type $command_def (record (field "command" string) (field "payload" string)))
+    (import "import-type-command" (type $command (eq $command_def)))
+    (export $command_e "command" (type $command))
+    (type $schedule_def (record (field "key" string) (field "due" u64)))
+    (import "import-type-schedule" (type $schedule (eq $schedule_def)))
+    (export $schedule_e "schedule" (type $schedule))
+    (type $outbound-command_def (record
+      (field "name" string)
+      (field "id" string)
+      (field "command" string)
+      (field "payload" string)
+      (field "schedule" (option $schedule))))
+    (import "import-type-outbound-command" (type $outbound-command (eq $outbound-command_def)))
+    (type $outbound-command_export (record
+      (field "name" string)
+      (field "id" string)
+      (field "command" string)
+      (field "payload" string)
+      (field "schedule" (option $schedule_e))))
+    (export $outbound-command_e "outbound-command" (type $outbound-command)
+      (type (eq $outbound-command_export)))
+    (type $handled_def (record
+      (field "events" (list $event))
+      (field "commands" (list $outbound-command))))
+    (import "import-type-handled" (type $handled (eq $handled_def)))
+    (type $handled_export (record
+      (field "events" (list $event_e))
+      (field "commands" (list $outbound-command_e))))
+    (export $handled_e "handled" (type $handled) (type (eq $handled_export)))
+    (type $context_def (record
+      (field "timestamp" u64)
+      (field "seed" u64)
+      (field "version" (option u64))
+      (field "metadata" string)))
+    (import "import-type-context" (type $context (eq $context_def)))
+    (export $context_e "context" (type $context))
+    (type $error_def (variant
+      (case "command" (tuple string string))
+      (case "deserialize-command" (tuple string string))
+      (case "deserialize-context" string)
+      (case "deserialize-event" (tuple string string))
+      (case "serialize-error" (tuple string string))
+      (case "serialize-event" string)
+      (case "serialize-state" string)))
+    (import "import-type-error" (type $error (eq $error_def)))
+    (export $error_e "error" (type $error))
+    (import "import-constructor" (func $constructor (param "id" string) (result (own $entity))))
+    (import "import-apply" (func $apply (param "self" (borrow $entity)) (param "events" (list $event))
+      (result (result (error $error)))))
+    (import "import-handle" (func $handle (param "self" (borrow $entity)) (param "command" $command)
+      (param "ctx" $context) (result (result $handled (error $error)))))
+    (import "import-state" (func $state (param "self" (borrow $entity))
+      (result (result (option string) (error $error)))))
+    (export "[constructor]entity" (func $constructor)
+      (func (param "id" string) (result (own $entity_e))))
+    (export "[method]entity.apply" (func $apply)
+      (func (param "self" (borrow $entity_e)) (param "events" (list $event_e))
+        (result (result (error $error_e)))))
+    (export "[method]entity.handle" (func $handle)
+      (func (param "self" (borrow $entity_e)) (param "command" $command_e)
+        (param "ctx" $context_e) (result (result $handled_e (error $error_e)))))
+    (export "[method]entity.state" (func $state)
+      (func (param "self" (borrow $entity_e))
+        (result (result (option string) (error $error_e)))))
+  )
+  (instance $entities (instantiate $entities_shim
+    (with "import-type-entity" (type $entity))
+    (with "import-type-event" (type $event))
+    (with "import-type-command" (type $command))
+    (with "import-type-schedule" (type $schedule))
+    (with "import-type-outbound-command" (type $outbound-command))
+    (with "import-type-handled" (type $handled))
+    (with "import-type-context" (type $context))
+    (with "import-type-error" (type $error))
+    (with "import-constructor" (func $constructor))
+    (with "import-apply" (func $apply))
+    (with "import-handle" (func $handle))
+    (with "import-state" (func $state))))
+  (export "thalo:aggregate/entities@0.2.0" (instance $entities))
+)
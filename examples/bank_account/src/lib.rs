@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use thalo::{events, export_aggregate, Aggregate, Apply, Command, Event, Handle};
+use thalo::{events, export_aggregate, Aggregate, Apply, Command, Context, Event, Handle};
 use thiserror::Error;
 
 export_aggregate!(BankAccount);
@@ -46,7 +46,11 @@ pub struct OpenAccount {}
 impl Handle<OpenAccount> for BankAccount {
     type Error = BankAccountError;
 
-    fn handle(&self, _cmd: OpenAccount) -> Result<Vec<BankAccountEvent>, Self::Error> {
+    fn handle(
+        &self,
+        _cmd: OpenAccount,
+        _ctx: &mut Context,
+    ) -> Result<Vec<BankAccountEvent>, Self::Error> {
         if self.opened {
             return Err(BankAccountError::AccountAlreadyOpened);
         }
@@ -63,7 +67,11 @@ pub struct DepositFunds {
 impl Handle<DepositFunds> for BankAccount {
     type Error = BankAccountError;
 
-    fn handle(&self, cmd: DepositFunds) -> Result<Vec<BankAccountEvent>, Self::Error> {
+    fn handle(
+        &self,
+        cmd: DepositFunds,
+        _ctx: &mut Context,
+    ) -> Result<Vec<BankAccountEvent>, Self::Error> {
         if !self.opened {
             return Err(BankAccountError::AccountNotOpen);
         }
@@ -84,7 +92,11 @@ pub struct WithdrawFunds {
 impl Handle<WithdrawFunds> for BankAccount {
     type Error = BankAccountError;
 
-    fn handle(&self, cmd: WithdrawFunds) -> Result<Vec<BankAccountEvent>, Self::Error> {
+    fn handle(
+        &self,
+        cmd: WithdrawFunds,
+        _ctx: &mut Context,
+    ) -> Result<Vec<BankAccountEvent>, Self::Error> {
         if !self.opened {
             return Err(BankAccountError::AccountNotOpen);
         }
@@ -1,8 +1,9 @@
 use serde::{Deserialize, Serialize};
-use thalo::{events, export_aggregate, Aggregate, Apply, Command, Event, Handle};
+use thalo::{events, export_aggregate, Aggregate, Apply, Command, Context, Event, Handle};
 
 export_aggregate!(Counter);
 
+#[derive(Serialize)]
 pub struct Counter {
     count: u64,
 }
@@ -24,7 +25,11 @@ pub enum CounterCommand {
 impl Handle<CounterCommand> for Counter {
     type Error = ();
 
-    fn handle(&self, cmd: CounterCommand) -> Result<Vec<CounterEvent>, Self::Error> {
+    fn handle(
+        &self,
+        cmd: CounterCommand,
+        _ctx: &mut Context,
+    ) -> Result<Vec<CounterEvent>, Self::Error> {
         match cmd {
             CounterCommand::Increment { amount } => {
                 events![Incremented { amount }]
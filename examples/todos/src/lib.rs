@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
-use thalo::{events, export_aggregate, Aggregate, Apply, Command, Event, Handle};
+use thalo::{events, export_aggregate, Aggregate, Apply, Command, Context, Event, Handle};
 use thiserror::Error;
 
 export_aggregate!(Todos);
@@ -40,7 +40,11 @@ pub enum TodosError {
 impl Handle<TodosCommand> for Todos {
     type Error = TodosError;
 
-    fn handle(&self, cmd: TodosCommand) -> Result<Vec<TodosEvent>, Self::Error> {
+    fn handle(
+        &self,
+        cmd: TodosCommand,
+        _ctx: &mut Context,
+    ) -> Result<Vec<TodosEvent>, Self::Error> {
         use TodosCommand::*;
 
         match cmd {
@@ -0,0 +1,96 @@
+//! Pays a one-off bonus into bank accounts which receive a large deposit.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use thalo::stream_name::StreamName;
+use thalo::{
+    events, export_process_manager, Aggregate, Apply, Command, Context, Event, EventInterest,
+    Handle, ProcessManager,
+};
+
+export_process_manager!(WelcomeBonus);
+
+const BONUS_THRESHOLD: u32 = 1000;
+const BONUS_AMOUNT: u32 = 10;
+
+pub struct WelcomeBonus {
+    paid: bool,
+}
+
+impl Aggregate for WelcomeBonus {
+    type Command = WelcomeBonusInput;
+    type Event = WelcomeBonusEvent;
+
+    fn init(_account_id: String) -> Self {
+        WelcomeBonus { paid: false }
+    }
+}
+
+impl ProcessManager for WelcomeBonus {
+    fn interests() -> Vec<EventInterest> {
+        vec![EventInterest::new("bank_account", "DepositedFunds")]
+    }
+
+    /// Each bank account has its own process.
+    fn correlation_id(
+        _event: &WelcomeBonusInput,
+        stream_name: &StreamName<'_>,
+        _metadata: &Map<String, Value>,
+    ) -> Option<String> {
+        stream_name.id().map(|id| id.to_string())
+    }
+}
+
+#[derive(Command, Deserialize)]
+pub enum WelcomeBonusInput {
+    DepositedFunds(DepositedFunds),
+}
+
+#[derive(Deserialize)]
+pub struct DepositedFunds {
+    amount: u32,
+}
+
+impl Handle<DepositedFunds> for WelcomeBonus {
+    type Error = ();
+
+    fn handle(
+        &self,
+        event: DepositedFunds,
+        ctx: &mut Context,
+    ) -> Result<Vec<WelcomeBonusEvent>, Self::Error> {
+        if self.paid || event.amount < BONUS_THRESHOLD {
+            return events![];
+        }
+
+        let Some(account_id) = ctx.correlation_id().map(ToOwned::to_owned) else {
+            return events![];
+        };
+        ctx.send_command(
+            "bank_account",
+            account_id,
+            json!({ "DepositFunds": { "amount": BONUS_AMOUNT } }),
+        )
+        .expect("command is an object with a single key");
+
+        events![BonusPaid {
+            amount: BONUS_AMOUNT
+        }]
+    }
+}
+
+#[derive(Event, Serialize, Deserialize)]
+pub enum WelcomeBonusEvent {
+    BonusPaid(BonusPaid),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BonusPaid {
+    pub amount: u32,
+}
+
+impl Apply<BonusPaid> for WelcomeBonus {
+    fn apply(&mut self, _event: BonusPaid) {
+        self.paid = true;
+    }
+}